@@ -1,52 +1,1205 @@
 mod vrm;
 
 use self::vrm::*;
-use std::fs::create_dir;
-use std::io::BufRead;
+use log::{error, info, warn, Level, LevelFilter, Log, Metadata, Record};
+use std::collections::VecDeque;
+use std::fs::create_dir_all;
+use std::io::{BufRead, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
+#[structopt(raw(
+    setting = "structopt::clap::AppSettings::SubcommandsNegateReqs",
+    setting = "structopt::clap::AppSettings::ArgsNegateSubcommands",
+    setting = "structopt::clap::AppSettings::DisableHelpSubcommand"
+))]
 struct Opt {
-    #[structopt(parse(from_os_str), help = "VRM file exported by VRoid Studio.")]
-    path: PathBuf,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+    #[structopt(
+        parse(from_os_str),
+        raw(required = "true"),
+        help = "VRM files exported by VRoid Studio, directories (searched recursively for .vrm and .glb) or glob patterns such as 'models/**/*.vrm'. '-' reads from standard input and writes to standard output unless -o is given."
+    )]
+    paths: Vec<PathBuf>,
     #[structopt(
         short = "f",
         long = "force",
         help = "Overwrite file if already exists same file."
     )]
     force: bool,
+    #[structopt(
+        short = "n",
+        long = "no-clobber",
+        raw(conflicts_with = "\"force\""),
+        help = "Skip files whose output already exists instead of asking."
+    )]
+    no_clobber: bool,
+    #[structopt(
+        short = "o",
+        long = "output",
+        parse(from_os_str),
+        raw(conflicts_with = "\"output_dir\""),
+        help = "Save the reduced file to this path. '-' writes to standard output. Only for a single input."
+    )]
+    output: Option<PathBuf>,
+    #[structopt(
+        long = "output-dir",
+        parse(from_os_str),
+        help = "Save reduced files under this directory instead of 'result' next to each file. Subdirectories of directory and glob inputs are kept."
+    )]
+    output_dir: Option<PathBuf>,
+    #[structopt(
+        long = "output-name",
+        default_value = "{name}",
+        help = "File name template for --output-dir and 'result'. {stem} is the input file name without extension, {ext} its extension (glb with --strip-vrm) and {name} both, e.g. '{stem}_reduced.{ext}'."
+    )]
+    output_name: String,
+    #[structopt(
+        short = "j",
+        long = "jobs",
+        default_value = "1",
+        help = "Number of files to process in parallel."
+    )]
+    jobs: usize,
+    #[structopt(
+        long = "dry-run",
+        help = "Run the reduction and print what each pass changes without writing any file."
+    )]
+    dry_run: bool,
+    #[structopt(
+        long = "report-json",
+        parse(from_os_str),
+        help = "Write what each reduction pass changed to this file as JSON."
+    )]
+    report_json: Option<PathBuf>,
+    #[structopt(flatten)]
+    reduce: ReduceOpt,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    #[structopt(
+        name = "stats",
+        about = "Print model statistics before and after reduction without writing any file. To reduce a file named stats, give it as ./stats or after another option."
+    )]
+    Stats(StatsOpt),
+}
+
+// 削減と表示のオプション。statsと共通
+#[derive(Debug, StructOpt)]
+struct ReduceOpt {
+    #[structopt(
+        short = "v",
+        long = "verbose",
+        parse(from_occurrences),
+        help = "Print more details. Repeat for more."
+    )]
+    verbose: u8,
+    #[structopt(
+        short = "q",
+        long = "quiet",
+        parse(from_occurrences),
+        help = "Print only warnings. Repeat to print only errors."
+    )]
+    quiet: u8,
+    #[structopt(
+        long = "max-texture-size",
+        help = "Downscale textures larger than this width or height."
+    )]
+    max_texture_size: Option<u32>,
+    #[structopt(
+        long = "texture-size",
+        parse(try_from_str = "parse_texture_size"),
+        help = "Max texture size for materials whose name contains PATTERN. (PATTERN=SIZE)"
+    )]
+    texture_sizes: Vec<(String, u32)>,
+    #[structopt(
+        long = "texture-format",
+        help = "Re-encode textures as png or jpeg. jpeg is applied only to opaque color textures, not to normal maps or other data textures."
+    )]
+    texture_format: Option<TextureFormat>,
+    #[structopt(
+        long = "jpeg-quality",
+        default_value = "90",
+        help = "JPEG quality of re-encoded textures."
+    )]
+    jpeg_quality: u8,
+    #[structopt(
+        long = "ktx2",
+        help = "Store textures as KTX2 (Basis Universal) with KHR_texture_basisu. Requires toktx."
+    )]
+    ktx2: bool,
+    #[structopt(
+        long = "ktx2-mode",
+        default_value = "etc1s",
+        help = "Basis Universal encoding: etc1s or uastc."
+    )]
+    ktx2_mode: BasisMode,
+    #[structopt(
+        long = "ktx2-no-fallback",
+        help = "Remove the original image instead of keeping it as the fallback texture source. The output can be loaded only by viewers supporting KHR_texture_basisu, not by VRM 0.x loaders."
+    )]
+    ktx2_no_fallback: bool,
+    #[structopt(
+        long = "toktx",
+        default_value = "toktx",
+        help = "Path to toktx command."
+    )]
+    toktx: String,
+    #[structopt(
+        long = "webp",
+        help = "Store textures as WebP with EXT_texture_webp. Requires cwebp."
+    )]
+    webp: bool,
+    #[structopt(long = "webp-lossless", help = "Encode WebP textures losslessly.")]
+    webp_lossless: bool,
+    #[structopt(
+        long = "webp-quality",
+        default_value = "90",
+        help = "Quality of lossy WebP textures."
+    )]
+    webp_quality: u8,
+    #[structopt(
+        long = "webp-fallback",
+        help = "Keep the original image as the fallback texture source."
+    )]
+    webp_fallback: bool,
+    #[structopt(
+        long = "cwebp",
+        default_value = "cwebp",
+        help = "Path to cwebp command."
+    )]
+    cwebp: String,
+    #[structopt(
+        long = "keep-blendshape",
+        help = "Keep only blend shape groups with this name or preset name."
+    )]
+    keep_blend_shapes: Vec<String>,
+    #[structopt(
+        long = "keep-blendshape-presets",
+        help = "Keep only preset blend shape groups (and those given by --keep-blendshape)."
+    )]
+    keep_blend_shape_presets: bool,
+    #[structopt(
+        long = "bake-blendshape",
+        parse(try_from_str = "parse_blend_shape_weight"),
+        help = "Bake a blend shape group into the mesh and remove it. (NAME=WEIGHT, WEIGHT is 0 to 1)"
+    )]
+    bake_blend_shapes: Vec<(String, f64)>,
+    #[structopt(
+        long = "remove-spring-bones",
+        help = "Remove all spring bones (secondaryAnimation)."
+    )]
+    remove_spring_bones: bool,
+    #[structopt(
+        long = "max-spring-chains",
+        help = "Maximum number of spring bone chains."
+    )]
+    max_spring_chains: Option<usize>,
+    #[structopt(
+        long = "max-spring-joints",
+        help = "Maximum number of joints per spring bone chain. Weights of deeper joints are moved to their ancestors."
+    )]
+    max_spring_joints: Option<usize>,
+    #[structopt(
+        long = "dedup",
+        help = "Merge identical bufferViews, accessors, images, samplers, textures and materials."
+    )]
+    dedup: bool,
+    #[structopt(
+        long = "merge-skinned-meshes",
+        help = "Merge skinned meshes sharing a skeleton into a single mesh and skin."
+    )]
+    merge_skinned_meshes: bool,
+    #[structopt(
+        long = "optimize-meshes",
+        help = "Shrink index types and reorder triangles and vertices for vertex cache and fetch efficiency."
+    )]
+    optimize_meshes: bool,
+    #[structopt(
+        long = "max-bone-influences",
+        help = "Maximum number of joints that influence a vertex."
+    )]
+    max_bone_influences: Option<usize>,
+    #[structopt(
+        long = "reduce-bones",
+        help = "Remove joints that have no vertex weights from skins."
+    )]
+    reduce_bones: bool,
+    #[structopt(
+        long = "collapse-bone-chains",
+        raw(requires = "\"reduce_bones\""),
+        help = "With --reduce-bones, move the weights of joints that are not humanoid bones, spring bones or animated to their nearest animated ancestor and remove them."
+    )]
+    collapse_bone_chains: bool,
+    #[structopt(
+        long = "quantize",
+        help = "Quantize vertex attributes with KHR_mesh_quantization."
+    )]
+    quantize: bool,
+    #[structopt(
+        long = "bake-mtoon",
+        help = "Bake MToon shade, rim light and matcap into the base texture for low-spec rendering."
+    )]
+    bake_mtoon: bool,
+    #[structopt(
+        long = "shrink-material",
+        parse(try_from_str = "parse_shrink_rule"),
+        help = "Remove a material feature: normal-map, matcap, outline, outline-width-texture, emission or black-emission. Prefix with PATTERN= to apply only to materials whose name contains PATTERN."
+    )]
+    shrink_rules: Vec<(String, ShrinkRule)>,
+    #[structopt(
+        long = "convert-materials",
+        help = "Convert MToon materials to unlit (KHR_materials_unlit) or pbr (pbrMetallicRoughness) for viewers without VRM support."
+    )]
+    convert_materials: Option<MaterialConversion>,
+    #[structopt(
+        long = "strip-vrm",
+        help = "Remove the VRM extension and save as plain glTF binary (.glb)."
+    )]
+    strip_vrm: bool,
+    #[structopt(
+        long = "vertex-layout",
+        help = "Rewrite vertex attributes as interleaved (one bufferView with byteStride per primitive) or separate (one tightly packed bufferView per attribute)."
+    )]
+    vertex_layout: Option<VertexLayout>,
+    #[structopt(
+        long = "repack-buffers",
+        help = "Rewrite all buffer views into a single binary chunk ordered by usage. External buffers are merged."
+    )]
+    repack_buffers: bool,
+    #[structopt(
+        long = "budget",
+        help = "Check the reduced model against a budget: a preset (mobile, desktop, excellent, good, medium, poor) or a file of limits. Exits with status 1 if exceeded. The file is a TOML subset of `key = integer` lines; [table] headers and # comments are ignored, other TOML syntax is rejected. Keys: triangles, materials, texture_memory (MiB), bones, skinned_meshes, blend_shapes, spring_bones."
+    )]
+    budget: Option<Budget>,
+    #[structopt(
+        long = "fit-budget",
+        help = "Repeat reduction with stronger settings until the model fits --budget."
+    )]
+    fit_budget: bool,
+    #[structopt(
+        long = "meshopt",
+        help = "Compress vertex and index data with EXT_meshopt_compression."
+    )]
+    meshopt: bool,
+}
+
+fn parse_texture_size(s: &str) -> Result<(String, u32), String> {
+    let mut split = s.rsplitn(2, '=');
+    match (split.next(), split.next()) {
+        (Some(size), Some(pattern)) => size
+            .parse()
+            .map(|size| (pattern.to_string(), size))
+            .map_err(|e| format!("{:?}", e)),
+        _ => Err(format!("expected PATTERN=SIZE: {}", s)),
+    }
+}
+
+fn parse_blend_shape_weight(s: &str) -> Result<(String, f64), String> {
+    let mut split = s.rsplitn(2, '=');
+    match (split.next(), split.next()) {
+        (Some(weight), Some(name)) => weight
+            .parse()
+            .map(|weight| (name.to_string(), weight))
+            .map_err(|e| format!("{:?}", e)),
+        _ => Err(format!("expected NAME=WEIGHT: {}", s)),
+    }
+}
+
+fn parse_shrink_rule(s: &str) -> Result<(String, ShrinkRule), String> {
+    let mut split = s.rsplitn(2, '=');
+    match (split.next(), split.next()) {
+        (Some(rule), pattern) => rule
+            .parse()
+            .map(|rule| (pattern.unwrap_or("").to_string(), rule)),
+        _ => Err(format!("expected [PATTERN=]RULE: {}", s)),
+    }
+}
+
+#[derive(Debug, StructOpt)]
+struct StatsOpt {
+    #[structopt(
+        parse(from_os_str),
+        raw(required = "true"),
+        help = "VRM files, directories (searched recursively for .vrm and .glb) or glob patterns. '-' reads from standard input."
+    )]
+    paths: Vec<PathBuf>,
+    #[structopt(
+        long = "json",
+        parse(from_os_str),
+        help = "Write the statistics before and after reduction to this file as JSON."
+    )]
+    json: Option<PathBuf>,
+    #[structopt(flatten)]
+    reduce: ReduceOpt,
+}
+
+// 標準エラー出力に出すロガー。警告は分類(logのtarget)を付けて表示する
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = match record.level() {
+            Level::Error => "error",
+            Level::Warn => "warning",
+            _ => {
+                eprintln!("{}", record.args());
+                return;
+            }
+        };
+        // モジュールパス以外のtargetは警告の分類
+        if record.target().contains("::") {
+            eprintln!("{}: {}", level, record.args());
+        } else {
+            eprintln!("{}[{}]: {}", level, record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+fn init_logger(opt: &ReduceOpt) {
+    let filters = [
+        LevelFilter::Off,
+        LevelFilter::Error,
+        LevelFilter::Warn,
+        LevelFilter::Info,
+        LevelFilter::Debug,
+        LevelFilter::Trace,
+    ];
+    let level = (3 + i32::from(opt.verbose) - i32::from(opt.quiet))
+        .max(0)
+        .min(filters.len() as i32 - 1);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(filters[level as usize]);
+    }
+}
+
+fn reduce_options(opt: &ReduceOpt) -> ReduceOptions {
+    ReduceOptions {
+        texture: TextureOptions {
+            max_size: opt.max_texture_size,
+            max_size_overrides: opt.texture_sizes.clone(),
+            format: opt.texture_format,
+            jpeg_quality: opt.jpeg_quality,
+        },
+        ktx2: if opt.ktx2 {
+            Some(Ktx2Options {
+                mode: opt.ktx2_mode,
+                fallback: !opt.ktx2_no_fallback,
+                toktx: opt.toktx.clone(),
+            })
+        } else {
+            None
+        },
+        webp: if opt.webp {
+            Some(WebpOptions {
+                lossless: opt.webp_lossless,
+                quality: opt.webp_quality,
+                fallback: opt.webp_fallback,
+                cwebp: opt.cwebp.clone(),
+            })
+        } else {
+            None
+        },
+        blend_shape: BlendShapeOptions {
+            keep: opt.keep_blend_shapes.clone(),
+            keep_presets: opt.keep_blend_shape_presets,
+            bake: opt.bake_blend_shapes.clone(),
+        },
+        spring_bone: SpringBoneOptions {
+            remove: opt.remove_spring_bones,
+            max_chains: opt.max_spring_chains,
+            max_joints: opt.max_spring_joints,
+        },
+        dedup: opt.dedup,
+        merge_meshes: opt.merge_skinned_meshes,
+        optimize_meshes: opt.optimize_meshes,
+        max_bone_influences: opt.max_bone_influences,
+        reduce_bones: opt.reduce_bones,
+        collapse_bone_chains: opt.collapse_bone_chains,
+        quantize: opt.quantize,
+        bake_mtoon: opt.bake_mtoon,
+        shrink_rules: opt.shrink_rules.clone(),
+        material_conversion: opt.convert_materials,
+        strip_vrm: opt.strip_vrm,
+        vertex_layout: opt.vertex_layout,
+        repack: opt.repack_buffers,
+        meshopt: opt.meshopt,
+    }
+}
+
+// 処理するファイルと、--output-dirの下に保存するときの相対パス
+struct Input {
+    path: PathBuf,
+    relative: PathBuf,
+}
+
+// globの特殊文字を含むか
+fn is_glob(s: &str) -> bool {
+    s.contains('*') || s.contains('?')
+}
+
+// *と?だけのワイルドカード
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| wildcard_match(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && wildcard_match(rest, &name[1..]),
+        Some((c, rest)) => name.first() == Some(c) && wildcard_match(rest, &name[1..]),
+    }
+}
+
+// 前回の出力先。--output-dirがなければ各ファイルの隣のresult
+fn is_output_dir(dir: &Path, output_dir: Option<&Path>) -> bool {
+    match output_dir {
+        Some(output_dir) => match (dir.canonicalize(), output_dir.canonicalize()) {
+            (Ok(dir), Ok(output_dir)) => dir == output_dir,
+            _ => false,
+        },
+        None => dir.file_name().map(|v| v == "result").unwrap_or(false),
+    }
+}
+
+fn sorted_entries(dir: &Path) -> Vec<PathBuf> {
+    let mut entries = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_else(|e| {
+            warn!("Failed to read dir {:?}: {}", dir, e);
+            Vec::new()
+        });
+    entries.sort();
+    entries
+}
+
+// ディレクトリ以下の.vrmと.glb。出力先のディレクトリは除く
+fn collect_dir(dir: &Path, output_dir: Option<&Path>, files: &mut Vec<PathBuf>) {
+    for path in sorted_entries(dir) {
+        if path.is_dir() {
+            if !is_output_dir(&path, output_dir) {
+                collect_dir(&path, output_dir, files);
+            }
+        } else if path
+            .extension()
+            .and_then(|v| v.to_str())
+            .map(|v| ["vrm", "glb"].contains(&v.to_lowercase().as_str()))
+            .unwrap_or(false)
+        {
+            files.push(path);
+        }
+    }
+}
+
+// globのパターンを1階層ずつ照合する。**は0個以上のディレクトリ
+fn collect_glob(
+    dir: &Path,
+    pattern: &[String],
+    output_dir: Option<&Path>,
+    files: &mut Vec<PathBuf>,
+) {
+    let (first, rest) = match pattern.split_first() {
+        Some(v) => v,
+        None => return,
+    };
+    if first == "**" {
+        collect_glob(dir, rest, output_dir, files);
+        for path in sorted_entries(dir) {
+            if path.is_dir() && !is_output_dir(&path, output_dir) {
+                collect_glob(&path, pattern, output_dir, files);
+            }
+        }
+        return;
+    }
+    let first = first.chars().collect::<Vec<_>>();
+    for path in sorted_entries(dir) {
+        let name = match path.file_name().and_then(|v| v.to_str()) {
+            Some(name) => name.chars().collect::<Vec<_>>(),
+            None => continue,
+        };
+        if !wildcard_match(&first, &name) {
+            continue;
+        }
+        if rest.is_empty() {
+            if path.is_file() {
+                files.push(path);
+            }
+        } else if path.is_dir() {
+            collect_glob(&path, rest, output_dir, files);
+        }
+    }
+}
+
+// 引数のファイル、ディレクトリ、globを処理するファイルに展開する
+// 見つからないものはそのまま返し、読み込みの失敗として報告する
+fn collect_inputs(paths: &[PathBuf], output_dir: Option<&Path>) -> Vec<Input> {
+    let mut inputs = Vec::new();
+    for path in paths {
+        let (base, files) = if path.is_dir() {
+            let mut files = Vec::new();
+            collect_dir(path, output_dir, &mut files);
+            (path.clone(), files)
+        } else if path.to_str().map(is_glob).unwrap_or(false) && !path.exists() {
+            // 最初の特殊文字を含む階層より前が基準のディレクトリ
+            let mut base = PathBuf::new();
+            let mut pattern = Vec::new();
+            for component in path.components() {
+                let component = component.as_os_str().to_string_lossy().to_string();
+                if pattern.is_empty() && !is_glob(&component) {
+                    base.push(component);
+                } else {
+                    pattern.push(component);
+                }
+            }
+            if base.as_os_str().is_empty() {
+                base.push(".");
+            }
+            let mut files = Vec::new();
+            if base.is_dir() {
+                collect_glob(&base, &pattern, output_dir, &mut files);
+            }
+            if files.is_empty() {
+                warn!("No files match {:?}", path);
+                files.push(path.clone());
+            }
+            (base, files)
+        } else {
+            let base = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            (base, vec![path.clone()])
+        };
+        for file in files {
+            if inputs.iter().any(|input: &Input| input.path == file) {
+                continue;
+            }
+            let relative = file
+                .strip_prefix(&base)
+                .map(|v| v.to_path_buf())
+                .unwrap_or_else(|_| file.file_name().map(PathBuf::from).unwrap_or_default());
+            inputs.push(Input {
+                path: file,
+                relative,
+            });
+        }
+    }
+    inputs
+}
+
+// 他のスレッドがパニックしても出力の順序を守るためのロック
+fn lock(output: &Mutex<()>) -> MutexGuard<'_, ()> {
+    output.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// 標準入出力を表すパス
+fn is_stdio(path: &Path) -> bool {
+    path == Path::new("-")
+}
+
+// 読み込んだVRMと削減したVRM、削減の記録。パスが-なら標準入力から読む
+fn load_vrm(opt: &ReduceOpt, path: &Path) -> Result<(Vrm, Vrm, ReductionReport), String> {
+    let stdin = std::io::stdin();
+    let vrm = if is_stdio(path) {
+        Vrm::read_reader(stdin.lock())
+    } else {
+        Vrm::read(path)
+    }
+    .map_err(|e| format!("Failed to parse file: {}", e))?;
+    let options = reduce_options(opt);
+    let (reduced, report) = match opt.budget {
+        Some(ref budget) if opt.fit_budget => {
+            reduce_to_budget(&vrm, path.parent(), &options, budget)
+        }
+        _ => vrm.reduce_with_report(path.parent(), &options),
+    }
+    .map_err(|e| format!("Failed to reduce: {}", e))?;
+    Ok((vrm, reduced, report))
+}
+
+// 予算を表示し、超えていたらfalseを返す
+fn check_budget<W>(opt: &ReduceOpt, vrm: &Vrm, out: &mut W) -> bool
+where
+    W: Write,
+{
+    match opt.budget {
+        Some(ref budget) => budget.write_report(&vrm.stats(), out).unwrap_or(false),
+        None => true,
+    }
+}
+
+// --output-nameの{stem}、{ext}、{name}を入力のファイル名で置き換える
+// --strip-vrmのときの{ext}はglb
+fn output_name(opt: &Opt, input: &Input) -> String {
+    let lossy = |v: Option<&std::ffi::OsStr>| v.map(|v| v.to_string_lossy().to_string());
+    let stem = lossy(input.relative.file_stem()).unwrap_or_default();
+    let ext = if opt.reduce.strip_vrm {
+        "glb".to_string()
+    } else {
+        lossy(input.relative.extension()).unwrap_or_default()
+    };
+    let name = if ext.is_empty() {
+        stem.clone()
+    } else {
+        format!("{}.{}", stem, ext)
+    };
+    opt.output_name
+        .replace("{name}", &name)
+        .replace("{stem}", &stem)
+        .replace("{ext}", &ext)
+}
+
+// 保存先。-oがなければ--output-dirか各ファイルの隣のresultに--output-nameの名前で保存する
+// 標準入力から読んだときの既定は標準出力
+fn save_path(opt: &Opt, input: &Input) -> PathBuf {
+    if let Some(ref output) = opt.output {
+        return output.clone();
+    }
+    if is_stdio(&input.path) {
+        return PathBuf::from("-");
+    }
+    let save_dir = match opt.output_dir {
+        Some(ref output_dir) => output_dir.join(input.relative.parent().unwrap_or(Path::new(""))),
+        None => input.path.parent().unwrap_or(Path::new(".")).join("result"),
+    };
+    save_dir.join(output_name(opt, input))
+}
+
+// 保存先が既にあるときに上書きするか。上書きしないときは飛ばす理由を返す
+// 端末から実行していないときは確認できないのでエラーにする
+fn confirm_overwrite(opt: &Opt, input: &Input, single: bool) -> Result<Option<String>, String> {
+    if opt.force {
+        return Ok(None);
+    }
+    if opt.no_clobber {
+        return Ok(Some("already exists".to_string()));
+    }
+    if !single {
+        return Ok(Some("already exists (use -f to overwrite)".to_string()));
+    }
+    if is_stdio(&input.path) || !atty::is(atty::Stream::Stdin) {
+        return Err(
+            "Output already exists. Use --force to overwrite or --no-clobber to skip".to_string(),
+        );
+    }
+    println!("Already exists file. Overwrite?(y/N): ");
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| format!("Failed to read answer from stdin: {}", e))?;
+    if ["y", "yes"].contains(&line.trim().to_lowercase().as_str()) {
+        Ok(None)
+    } else {
+        Ok(Some("not overwritten".to_string()))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Status {
+    Saved,
+    DryRun,
+    Skipped(String),
+    Failed(String),
+}
+
+// 1ファイルの処理結果
+struct FileResult {
+    path: PathBuf,
+    status: Status,
+    input_bytes: Option<u64>,
+    output_bytes: Option<u64>,
+    within_budget: bool,
+    report: Option<ReductionReport>,
+}
+
+impl FileResult {
+    fn succeeded(&self) -> bool {
+        match self.status {
+            Status::Failed(_) => false,
+            _ => self.within_budget,
+        }
+    }
+}
+
+// 1ファイルを削減して保存する。複数ファイルのときは表の前にファイル名を表示し、上書きは確認せず飛ばす
+fn reduce_file(
+    opt: &Opt,
+    input: &Input,
+    single: bool,
+    output: &Mutex<()>,
+    result: &mut FileResult,
+) -> Result<(), String> {
+    let print_header = || {
+        if !single {
+            println!("{}:", input.path.display());
+        }
+    };
+    if opt.dry_run {
+        let (_, vrm, report) = load_vrm(&opt.reduce, &input.path)?;
+        let _output = lock(output);
+        print_header();
+        report.print();
+        result.status = Status::DryRun;
+        result.within_budget = check_budget(&opt.reduce, &vrm, &mut std::io::stdout());
+        result.report = Some(report);
+        return Ok(());
+    }
+
+    // 上書きの確認は時間のかかる削減の前にする
+    let save_path = save_path(opt, input);
+    if !is_stdio(&save_path) && save_path.exists() {
+        if let Some(reason) = confirm_overwrite(opt, input, single)? {
+            result.status = Status::Skipped(reason);
+            return Ok(());
+        }
+    }
+    let (_, vrm, report) = load_vrm(&opt.reduce, &input.path)?;
+    result.report = Some(report);
+
+    if is_stdio(&save_path) {
+        // 標準出力にはモデルだけを書き、予算の表は標準エラー出力に出す
+        let stdout = std::io::stdout();
+        vrm.save_writer(stdout.lock())
+            .map_err(|e| format!("Failed to write to stdout: {}", e))?;
+        result.status = Status::Saved;
+        result.within_budget = check_budget(&opt.reduce, &vrm, &mut std::io::stderr());
+        return Ok(());
+    }
+    if let Some(save_dir) = save_path.parent() {
+        create_dir_all(save_dir)
+            .map_err(|e| format!("Failed to create dir {:?}: {}", save_dir, e))?;
+    }
+    vrm.save(&save_path)
+        .map_err(|e| format!("Failed to save {:?}: {}", save_path, e))?;
+    info!("saved {:?}.", save_path);
+    result.status = Status::Saved;
+    result.output_bytes = std::fs::metadata(&save_path).ok().map(|v| v.len());
+    if opt.reduce.budget.is_some() {
+        let _output = lock(output);
+        print_header();
+        result.within_budget = check_budget(&opt.reduce, &vrm, &mut std::io::stdout());
+    }
+    Ok(())
+}
+
+fn process_file(opt: &Opt, input: &Input, single: bool, output: &Mutex<()>) -> FileResult {
+    info!("{:?}", input.path);
+    let mut result = FileResult {
+        path: input.path.clone(),
+        status: Status::Failed(String::new()),
+        input_bytes: std::fs::metadata(&input.path).ok().map(|v| v.len()),
+        output_bytes: None,
+        within_budget: true,
+        report: None,
+    };
+    if let Err(e) = reduce_file(opt, input, single, output, &mut result) {
+        error!("{}: {}", input.path.display(), e);
+        result.status = Status::Failed(e);
+    }
+    result
+}
+
+// キューが空になるまでファイルを処理する
+fn work(
+    opt: &Opt,
+    queue: &Mutex<VecDeque<(usize, Input)>>,
+    single: bool,
+    output: &Mutex<()>,
+) -> Vec<(usize, FileResult)> {
+    let mut results = Vec::new();
+    loop {
+        let next = queue.lock().unwrap_or_else(|e| e.into_inner()).pop_front();
+        match next {
+            Some((index, input)) => {
+                results.push((index, process_file(opt, &input, single, output)))
+            }
+            None => return results,
+        }
+    }
+}
+
+// --jobsのスレッドでファイルを処理し、入力の順に結果を返す
+fn process_files(opt: Opt, inputs: Vec<Input>) -> Vec<FileResult> {
+    let single = inputs.len() == 1;
+    let jobs = opt.jobs.max(1).min(inputs.len().max(1));
+    let queue = Arc::new(Mutex::new(
+        inputs.into_iter().enumerate().collect::<VecDeque<_>>(),
+    ));
+    let output = Arc::new(Mutex::new(()));
+    let mut results = if jobs == 1 {
+        work(&opt, &queue, single, &output)
+    } else {
+        let opt = Arc::new(opt);
+        let threads = (0..jobs)
+            .map(|_| {
+                let (opt, queue, output) = (opt.clone(), queue.clone(), output.clone());
+                std::thread::spawn(move || work(&opt, &queue, single, &output))
+            })
+            .collect::<Vec<_>>();
+        threads
+            .into_iter()
+            .flat_map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|e| std::panic::resume_unwind(e))
+            })
+            .collect()
+    };
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+fn format_bytes(bytes: Option<u64>) -> String {
+    match bytes {
+        Some(bytes) if bytes >= 1_000_000 => format!("{:.1}MB", bytes as f64 / 1_000_000.0),
+        Some(bytes) if bytes >= 1_000 => format!("{:.1}KB", bytes as f64 / 1_000.0),
+        Some(bytes) => format!("{}B", bytes),
+        None => "-".to_string(),
+    }
+}
+
+impl Status {
+    fn name(&self) -> &'static str {
+        match self {
+            Status::Saved => "saved",
+            Status::DryRun => "dry-run",
+            Status::Skipped(_) => "skipped",
+            Status::Failed(_) => "FAILED",
+        }
+    }
+}
+
+// ファイルごとの結果と集計
+fn print_summary(results: &[FileResult]) {
+    println!(
+        "{:<10} {:>10} {:>10} {:>7}  {}",
+        "status", "before", "after", "ratio", "file"
+    );
+    for result in results {
+        let ratio = match (result.input_bytes, result.output_bytes) {
+            (Some(before), Some(after)) if before > 0 => {
+                format!("{:.1}%", after as f64 / before as f64 * 100.0)
+            }
+            _ => "-".to_string(),
+        };
+        let note = match result.status {
+            Status::Skipped(ref reason) | Status::Failed(ref reason) => {
+                format!(" ({})", reason.lines().next().unwrap_or(""))
+            }
+            _ if !result.within_budget => " (budget exceeded)".to_string(),
+            _ => String::new(),
+        };
+        println!(
+            "{:<10} {:>10} {:>10} {:>7}  {}{}",
+            result.status.name(),
+            format_bytes(result.input_bytes),
+            format_bytes(result.output_bytes),
+            ratio,
+            result.path.display(),
+            note
+        );
+    }
+    let count = |name| results.iter().filter(|v| v.status.name() == name).count();
+    println!(
+        "{} files: {} saved, {} dry-run, {} skipped, {} failed, {} over budget",
+        results.len(),
+        count("saved"),
+        count("dry-run"),
+        count("skipped"),
+        count("FAILED"),
+        results.iter().filter(|v| !v.within_budget).count(),
+    );
+}
+
+// 1ファイルならその値、複数ならパスをキーにしたオブジェクト
+fn json_by_path(values: Vec<(PathBuf, serde_json::Value)>) -> serde_json::Value {
+    if values.len() == 1 {
+        return values
+            .into_iter()
+            .next()
+            .map(|(_, v)| v)
+            .unwrap_or_default();
+    }
+    let mut json = serde_json::Map::new();
+    for (path, value) in values {
+        json.insert(path.display().to_string(), value);
+    }
+    json.into()
+}
+
+// 削減前と削減後のモデル情報を表示する。ファイルは保存しない
+fn stats(stats_opt: StatsOpt) {
+    let opt = &stats_opt.reduce;
+    let inputs = collect_inputs(&stats_opt.paths, None);
+    let single = inputs.len() == 1;
+    let mut succeeded = true;
+    let mut json = Vec::new();
+    for input in &inputs {
+        let (before, after) = match load_vrm(opt, &input.path) {
+            Ok((vrm, reduced, _)) => (vrm.stats(), reduced.stats()),
+            Err(e) => {
+                error!("{}: {}", input.path.display(), e);
+                succeeded = false;
+                continue;
+            }
+        };
+        if !single {
+            println!("{}:", input.path.display());
+        }
+        print_stats(&before, &after);
+        if let Some(ref budget) = opt.budget {
+            succeeded &= budget.report(&after);
+        }
+        let mut value = serde_json::Map::new();
+        value.insert("before".into(), before.to_json());
+        value.insert("after".into(), after.to_json());
+        json.push((input.path.clone(), value.into()));
+    }
+    if let Some(ref json_path) = stats_opt.json {
+        if let Err(e) = std::fs::write(json_path, json_by_path(json).to_string()) {
+            error!("Failed to write {:?}: {}", json_path, e);
+            succeeded = false;
+        }
+    }
+    if !succeeded {
+        std::process::exit(1);
+    }
+}
+
+// 標準出力に書くのは1ファイルだけで、端末には書かない
+fn check_outputs(opt: &Opt, inputs: &[Input]) -> Result<(), String> {
+    if opt.output.is_some() && inputs.len() != 1 {
+        return Err(format!(
+            "-o/--output needs exactly one input, but {} given. Use --output-dir",
+            inputs.len()
+        ));
+    }
+    if inputs.iter().filter(|v| is_stdio(&v.path)).count() > 1 {
+        return Err("Standard input can be read only once".to_string());
+    }
+    let to_stdout = !opt.dry_run && inputs.iter().any(|v| is_stdio(&save_path(opt, v)));
+    if to_stdout && inputs.len() != 1 {
+        return Err("Only a single input can be written to standard output".to_string());
+    }
+    if to_stdout && atty::is(atty::Stream::Stdout) {
+        return Err("Refusing to write a binary file to a terminal. Use -o".to_string());
+    }
+    Ok(())
 }
 
 fn main() {
     let opt = Opt::from_args();
-    let path = opt.path;
-    println!("{:?}", path);
-
-    // vrm読み込み
-    let vrm = Vrm::load(path.as_path())
-        .unwrap_or_else(|e| panic!("Failed to parse file {:?}: {:?}", path, e));
-    let save_dir = path.parent().unwrap_or(Path::new(".")).join("result");
-    if !save_dir.exists() {
-        create_dir(&save_dir)
-            .unwrap_or_else(|e| panic!("failed to create dir '{:?}': {:?}", save_dir, e));
-    }
-    let save_path = save_dir.join(
-        path.file_name()
-            .unwrap_or_else(|| panic!("path '{:?}' doesn't have file component", path)),
-    );
-    if !opt.force && save_path.exists() {
-        println!("Already exists file. Overwrite?(y/N): ");
-        let mut line = String::new();
-        std::io::stdin()
-            .lock()
-            .read_line(&mut line)
-            .expect("Failed to read answer from stdin");
-        if !["y", "yes"].contains(&line.trim().to_lowercase().as_str()) {
-            return;
+    if let Some(Command::Stats(stats_opt)) = opt.command {
+        init_logger(&stats_opt.reduce);
+        if let Err(e) = reduce_options(&stats_opt.reduce).validate() {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        stats(stats_opt);
+        return;
+    }
+
+    init_logger(&opt.reduce);
+    let inputs = collect_inputs(&opt.paths, opt.output_dir.as_ref().map(|v| v.as_path()));
+    let single = inputs.len() == 1;
+    if let Err(e) = reduce_options(&opt.reduce)
+        .validate()
+        .and_then(|_| check_outputs(&opt, &inputs))
+    {
+        error!("{}", e);
+        std::process::exit(1);
+    }
+    let report_json = opt.report_json.clone();
+    let results = process_files(opt, inputs);
+    if !single {
+        print_summary(&results);
+    }
+
+    let mut succeeded = results.iter().all(|v| v.succeeded());
+    if let Some(report_path) = report_json {
+        let reports = results
+            .iter()
+            .filter_map(|v| Some((v.path.clone(), v.report.as_ref()?.to_json())))
+            .collect();
+        if let Err(e) = std::fs::write(&report_path, json_by_path(reports).to_string()) {
+            error!("Failed to write {:?}: {}", report_path, e);
+            succeeded = false;
+        }
+    }
+    if !succeeded {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // テストごとの一時ディレクトリに空のファイルを作る
+    fn create_files(name: &str, files: &[&str]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("vreducer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        for file in files {
+            let path = dir.join(file);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, b"").unwrap();
+        }
+        dir
+    }
+
+    fn relative_paths(inputs: &[Input]) -> Vec<PathBuf> {
+        inputs.iter().map(|input| input.relative.clone()).collect()
+    }
+
+    const FILES: [&str; 7] = [
+        "a.vrm",
+        "notes.txt",
+        "result/a.vrm",
+        "sub/b.glb",
+        "sub/deep/c.vrm",
+        "sub/result/b.vrm",
+        "out/a.vrm",
+    ];
+
+    #[test]
+    fn wildcard_match_star_and_question() {
+        let matches = |pattern: &str, name: &str| {
+            wildcard_match(
+                &pattern.chars().collect::<Vec<_>>(),
+                &name.chars().collect::<Vec<_>>(),
+            )
+        };
+        assert!(matches("*.vrm", "a.vrm"));
+        assert!(matches("*.vrm", ".vrm"));
+        assert!(!matches("*.vrm", "a.glb"));
+        assert!(matches("a?c*", "abc.vrm"));
+        assert!(!matches("a?c", "ac"));
+        assert!(matches("*_*_*", "a_b_c"));
+    }
+
+    #[test]
+    fn collect_inputs_expands_double_star_glob() {
+        let dir = create_files("glob", &FILES);
+        let inputs = collect_inputs(&[dir.join("**").join("*.vrm")], None);
+        assert_eq!(
+            relative_paths(&inputs),
+            vec![
+                PathBuf::from("a.vrm"),
+                PathBuf::from("out/a.vrm"),
+                PathBuf::from("sub/deep/c.vrm")
+            ]
+        );
+        assert_eq!(inputs[0].path, dir.join("a.vrm"));
+
+        let inputs = collect_inputs(&[dir.join("sub").join("**").join("*")], None);
+        assert_eq!(
+            relative_paths(&inputs),
+            vec![PathBuf::from("b.glb"), PathBuf::from("deep/c.vrm")]
+        );
+
+        // 一致するファイルがなければそのまま返す
+        let missing = dir.join("**").join("*.gltf");
+        let inputs = collect_inputs(&[missing.clone()], None);
+        assert_eq!(inputs.len(), 1);
+        assert_eq!(inputs[0].path, missing);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn collect_inputs_skips_output_dirs() {
+        let dir = create_files("dir", &FILES);
+        // 既定ではresultを除く
+        let inputs = collect_inputs(&[dir.clone()], None);
+        assert_eq!(
+            relative_paths(&inputs),
+            vec![
+                PathBuf::from("a.vrm"),
+                PathBuf::from("out/a.vrm"),
+                PathBuf::from("sub/b.glb"),
+                PathBuf::from("sub/deep/c.vrm")
+            ]
+        );
+
+        // --output-dirがあればresultではなくそのディレクトリを除く
+        let output_dir = dir.join("out");
+        let inputs = collect_inputs(&[dir.clone()], Some(&output_dir));
+        assert_eq!(
+            relative_paths(&inputs),
+            vec![
+                PathBuf::from("a.vrm"),
+                PathBuf::from("result/a.vrm"),
+                PathBuf::from("sub/b.glb"),
+                PathBuf::from("sub/deep/c.vrm"),
+                PathBuf::from("sub/result/b.vrm")
+            ]
+        );
+        let inputs = collect_inputs(&[dir.join("**").join("a.vrm")], Some(&output_dir));
+        assert_eq!(
+            relative_paths(&inputs),
+            vec![PathBuf::from("a.vrm"), PathBuf::from("result/a.vrm")]
+        );
+
+        // 同じファイルは1度だけ処理する
+        let inputs = collect_inputs(&[dir.join("a.vrm"), dir.join("*.vrm")], None);
+        assert_eq!(inputs.len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn new_input(path: &str, relative: &str) -> Input {
+        Input {
+            path: PathBuf::from(path),
+            relative: PathBuf::from(relative),
         }
     }
 
-    vrm.save(&save_path).expect("Failed to save");
-    println!("saved.");
+    #[test]
+    fn output_name_substitutes_template() {
+        let input = new_input("models/sub/avatar.vrm", "sub/avatar.vrm");
+        let opt = Opt::from_iter(&["vreducer", "--output-name", "{stem}_x.{ext}", "a.vrm"]);
+        assert_eq!(output_name(&opt, &input), "avatar_x.vrm");
+        let opt = Opt::from_iter(&[
+            "vreducer",
+            "--strip-vrm",
+            "--output-name",
+            "{stem}_x.{ext}",
+            "a.vrm",
+        ]);
+        assert_eq!(output_name(&opt, &input), "avatar_x.glb");
+        let opt = Opt::from_iter(&["vreducer", "--strip-vrm", "a.vrm"]);
+        assert_eq!(output_name(&opt, &input), "avatar.glb");
+        let opt = Opt::from_iter(&["vreducer", "a.vrm"]);
+        assert_eq!(output_name(&opt, &input), "avatar.vrm");
+    }
+
+    #[test]
+    fn save_path_uses_output_options() {
+        let input = new_input("models/sub/avatar.vrm", "sub/avatar.vrm");
+        let opt = Opt::from_iter(&["vreducer", "--output-name", "{stem}_x.{ext}", "a.vrm"]);
+        assert_eq!(
+            save_path(&opt, &input),
+            PathBuf::from("models/sub/result/avatar_x.vrm")
+        );
+        // --output-dirの下では入力の相対パスを保つ
+        let opt = Opt::from_iter(&["vreducer", "--strip-vrm", "--output-dir", "out", "a.vrm"]);
+        assert_eq!(save_path(&opt, &input), PathBuf::from("out/sub/avatar.glb"));
+        let opt = Opt::from_iter(&["vreducer", "-o", "reduced.vrm", "a.vrm"]);
+        assert_eq!(save_path(&opt, &input), PathBuf::from("reduced.vrm"));
+        // 標準入力から読んだときは標準出力に書く
+        let opt = Opt::from_iter(&["vreducer", "-"]);
+        assert_eq!(save_path(&opt, &new_input("-", "-")), PathBuf::from("-"));
+    }
 }
@@ -1,13 +1,46 @@
+mod accessor;
+mod blendshape;
+mod budget;
+mod buffer;
 mod cleaner;
 mod debug;
+mod dedup;
 mod gltf;
+mod ktx2;
+mod layout;
+mod merge;
+mod meshopt;
+mod mtoon;
+mod optimize;
+mod quantize;
 mod reducer;
+mod report;
+mod skin;
+mod spring;
+mod texture;
 mod version;
+mod webp;
 
+pub use self::blendshape::*;
+pub use self::budget::*;
+pub use self::buffer::*;
 pub use self::cleaner::*;
 pub use self::debug::*;
+pub use self::dedup::*;
 pub use self::gltf::*;
+pub use self::ktx2::*;
+pub use self::layout::*;
+pub use self::merge::*;
+pub use self::meshopt::*;
+pub use self::mtoon::*;
+pub use self::optimize::*;
+pub use self::quantize::*;
 pub use self::reducer::*;
+pub use self::report::*;
+pub use self::skin::*;
+pub use self::spring::*;
+pub use self::texture::*;
+pub use self::webp::*;
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use serde_json::Value;
 use std::fs::OpenOptions;
@@ -17,28 +50,78 @@ use std::path::Path;
 const GLTF_MAGIC: u32 = 0x46546c67;
 const JSON_TYPE: u32 = 0x4e4f534a;
 
+#[derive(Debug, Clone, Default)]
+pub struct ReduceOptions {
+    pub texture: TextureOptions,
+    pub ktx2: Option<Ktx2Options>,
+    pub webp: Option<WebpOptions>,
+    pub blend_shape: BlendShapeOptions,
+    pub spring_bone: SpringBoneOptions,
+    /// 同じ内容のbufferView、accessor、image、sampler、texture、materialを統合する
+    pub dedup: bool,
+    pub merge_meshes: bool,
+    /// インデックスの型を小さくし、頂点キャッシュとフェッチの効率が良くなるように並べ替える
+    pub optimize_meshes: bool,
+    pub max_bone_influences: Option<usize>,
+    pub reduce_bones: bool,
+    /// reduce_bonesで、動かないジョイントのウェイトを動く祖先に移して削除する
+    pub collapse_bone_chains: bool,
+    pub quantize: bool,
+    pub bake_mtoon: bool,
+    pub shrink_rules: Vec<(String, ShrinkRule)>,
+    pub material_conversion: Option<MaterialConversion>,
+    pub strip_vrm: bool,
+    pub vertex_layout: Option<VertexLayout>,
+    /// 全てのbufferViewを1つのバッファに用途順で詰め直す。外部バッファも取り込む
+    pub repack: bool,
+    pub meshopt: bool,
+}
+
+impl ReduceOptions {
+    /// 組み合わせられないオプションがあればエラーを返す
+    pub fn validate(&self) -> Result<(), String> {
+        // KTX2のフォールバックを残さないときは元の画像がなくなり、WebPで変換するものがない
+        if let (Some(ktx2), Some(_)) = (&self.ktx2, &self.webp) {
+            if !ktx2.fallback {
+                return Err(
+                    "WebP output with KTX2 needs the KTX2 fallback: KTX2 replaces the original image"
+                        .to_string(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
 pub struct Vrm {
     version: u32,
-    total_chunk_bytes: u32,
     pub chunk0: Value,
     chunks: Vec<Vec<u8>>,
 }
 
 impl Vrm {
     pub fn save(&self, path: &Path) -> Result<(), Box<std::error::Error>> {
-        let mut file = BufWriter::new(
+        self.save_writer(BufWriter::new(
             OpenOptions::new()
                 .write(true)
                 .truncate(true)
                 .create(true)
                 .open(path)?,
-        );
+        ))
+    }
+
+    /// glTFバイナリとして書き出す
+    pub fn save_writer<W>(&self, mut file: W) -> Result<(), Box<std::error::Error>>
+    where
+        W: Write,
+    {
         let gltf_string = self.chunk0.to_string();
         let mut gltf_encoded = gltf_string.as_bytes().to_vec();
         if gltf_encoded.len() % 4 != 0 {
             gltf_encoded.resize((gltf_encoded.len() + 3) / 4 * 4, 0x20);
         }
-        let glb_length: usize = 12 + 8 + gltf_encoded.len() + self.total_chunk_bytes as usize;
+        let total_chunk_bytes = self.chunks.iter().fold(0, |sum, c| sum + 8 + c.len());
+        let glb_length: usize = 12 + 8 + gltf_encoded.len() + total_chunk_bytes;
         file.write_u32::<LE>(GLTF_MAGIC)?;
         file.write_u32::<LE>(self.version)?;
         file.write_u32::<LE>(glb_length as u32)?;
@@ -52,6 +135,7 @@ impl Vrm {
             file.write_u32::<LE>(CHUNK_TYPE)?;
             file.write_all(&mut chunk)?;
         }
+        file.flush()?;
 
         Ok(())
     }
@@ -158,42 +242,280 @@ impl Vrm {
         chunk0
     }
 
-    /// VRM読み込み
-    pub fn load(path: &Path) -> Result<Vrm, Box<std::error::Error>> {
-        Self::load_reader(BufReader::new(std::fs::File::open(path)?))
+    /// VRMを削減せずに読み込む
+    pub fn read(path: &Path) -> Result<Vrm, Box<std::error::Error>> {
+        Self::read_reader(BufReader::new(std::fs::File::open(path)?))
     }
 
-    /// VRM読み込み
-    pub fn load_reader<R>(mut reader: R) -> Result<Vrm, Box<std::error::Error>>
+    /// VRMを削減せずに読み込む
+    pub fn read_reader<R>(mut reader: R) -> Result<Vrm, Box<std::error::Error>>
     where
         R: Read,
     {
         let gltf_magic = reader.read_u32::<LE>()?;
         let version = reader.read_u32::<LE>()?;
         let length = reader.read_u32::<LE>()?;
-        assert_eq!(gltf_magic, GLTF_MAGIC);
+        if gltf_magic != GLTF_MAGIC {
+            return Err(format!("not a glTF binary: magic {:#x}", gltf_magic).into());
+        }
 
         let json_length = reader.read_u32::<LE>()?; // TODO: json lengthを信用しない方法
         let json_type = reader.read_u32::<LE>()?;
-
-        assert_eq!(json_type, JSON_TYPE);
+        if json_type != JSON_TYPE {
+            return Err(format!("first chunk is not JSON: type {:#x}", json_type).into());
+        }
+        // ヘッダー12バイトとJSONチャンクのヘッダー8バイトを除いた残りがバイナリチャンク
+        let chunks_length = length
+            .checked_sub(20)
+            .and_then(|v| v.checked_sub(json_length))
+            .ok_or_else(|| {
+                format!(
+                    "JSON chunk length {} exceeds file length {}",
+                    json_length, length
+                )
+            })?;
 
         let mut json_bytes = Vec::new();
         json_bytes.resize(json_length as usize, 0);
         reader.read_exact(&mut json_bytes)?;
         let json_string = String::from_utf8(json_bytes)?;
 
-        let mut chunk0: Value = Vrm::upgrade_chunk0(serde_json::from_str(&json_string)?);
-        chunk0 = reduce_vroid(chunk0);
-        chunk0 = clean(chunk0);
-        let (chunk0, relocator) = relocate_buffers(chunk0);
-        let chunks = relocator.relocate(reader, length - 20 - json_length)?;
-
+        let chunk0: Value = serde_json::from_str(&json_string)?;
+        let chunks = read_chunks(reader, chunks_length)?;
         Ok(Vrm {
             version,
-            total_chunk_bytes: relocator.total_chunk_bytes(),
             chunk0,
             chunks,
         })
     }
+
+    /// 削減したVRMと、削減処理ごとの変更の記録を作る。base_dirは外部バッファを探すディレクトリ
+    /// 圧縮された入力を復号できない場合はエラーを返す
+    pub fn reduce_with_report(
+        &self,
+        base_dir: Option<&Path>,
+        options: &ReduceOptions,
+    ) -> Result<(Vrm, ReductionReport), Box<std::error::Error>> {
+        options.validate()?;
+        let mut report = ReductionReport::default();
+        let mut chunk0 = Vrm::upgrade_chunk0(self.chunk0.clone());
+        let mut chunks = self.chunks.clone();
+        warn_unknown_extensions(&chunk0);
+        let chunk_bytes = |chunks: &[Vec<u8>]| chunks.iter().map(|c| c.len()).sum::<usize>();
+
+        // 削減処理を実行し、前後の違いを記録する
+        macro_rules! pass {
+            ($name: expr, $pass: expr) => {{
+                let before = chunk0.clone();
+                let before_bytes = chunk_bytes(&chunks);
+                chunk0 = $pass;
+                report.record($name, &before, &chunk0, before_bytes, chunk_bytes(&chunks));
+            }};
+        }
+
+        if options.repack {
+            pass!(
+                "load_external_buffers",
+                load_external_buffers(chunk0, &mut chunks, base_dir)
+            );
+        }
+        pass!(
+            "decompress_meshopt",
+            decompress_meshopt(chunk0, &mut chunks)?
+        );
+        pass!("reduce_vroid", reduce_vroid(chunk0));
+        if options.dedup {
+            pass!("dedup", dedup(chunk0, &chunks));
+        }
+        if options.blend_shape.is_enabled() {
+            pass!(
+                "reduce_blend_shapes",
+                reduce_blend_shapes(chunk0, &mut chunks, &options.blend_shape)
+            );
+        }
+        if options.spring_bone.is_enabled() {
+            pass!(
+                "simplify_spring_bones",
+                simplify_spring_bones(chunk0, &mut chunks, &options.spring_bone)
+            );
+        }
+        if options.merge_meshes {
+            pass!(
+                "merge_skinned_meshes",
+                merge_skinned_meshes(chunk0, &mut chunks)
+            );
+        }
+        if options.optimize_meshes {
+            pass!("optimize_meshes", optimize_meshes(chunk0, &mut chunks));
+        }
+        if let Some(max_bone_influences) = options.max_bone_influences {
+            pass!(
+                "limit_bone_influences",
+                limit_bone_influences(chunk0, &mut chunks, max_bone_influences)
+            );
+        }
+        if options.reduce_bones {
+            pass!(
+                "reduce_bones",
+                reduce_bones(chunk0, &mut chunks, options.collapse_bone_chains)
+            );
+        }
+        if options.quantize {
+            pass!("quantize_meshes", quantize_meshes(chunk0, &mut chunks));
+        }
+        if options.bake_mtoon {
+            pass!(
+                "bake_mtoon_materials",
+                bake_mtoon_materials(chunk0, &mut chunks)
+            );
+        }
+        if !options.shrink_rules.is_empty() {
+            pass!(
+                "shrink_materials",
+                shrink_materials(chunk0, &options.shrink_rules)
+            );
+        }
+        if let Some(conversion) = options.material_conversion {
+            pass!("convert_materials", convert_materials(chunk0, conversion));
+        }
+        pass!(
+            "reduce_textures",
+            reduce_textures(chunk0, &mut chunks, &options.texture)
+        );
+        if let Some(ref ktx2_options) = options.ktx2 {
+            pass!(
+                "transcode_ktx2",
+                transcode_ktx2(chunk0, &mut chunks, ktx2_options)
+            );
+        }
+        if let Some(ref webp_options) = options.webp {
+            pass!(
+                "transcode_webp",
+                transcode_webp(chunk0, &mut chunks, webp_options)
+            );
+        }
+        if let Some(layout) = options.vertex_layout {
+            pass!(
+                "layout_vertex_attributes",
+                layout_vertex_attributes(chunk0, &mut chunks, layout)
+            );
+        }
+        if options.strip_vrm {
+            pass!("strip_vrm", strip_vrm(chunk0));
+        }
+
+        let (cleaned, removed) = clean_with_removed(chunk0.clone());
+        report.record_removed("clean", &chunk0, &removed);
+        let before_bytes = chunk_bytes(&chunks);
+        if options.repack {
+            let (repacked, repacked_chunks) = repack_buffers(cleaned, &chunks);
+            chunk0 = repacked;
+            chunks = repacked_chunks;
+        } else {
+            let (relocated, relocator) = relocate_buffers(cleaned);
+            chunk0 = relocated;
+            chunks = relocator.relocate(&chunks);
+        }
+        report.push(
+            if options.repack {
+                "repack_buffers"
+            } else {
+                "relocate_buffers"
+            },
+            vec![format!(
+                "binary bytes: {} -> {} ({} bytes dropped)",
+                before_bytes,
+                chunk_bytes(&chunks),
+                before_bytes as i64 - chunk_bytes(&chunks) as i64
+            )],
+        );
+        if options.meshopt {
+            pass!("compress_meshopt", {
+                let (compressed, relocator) =
+                    relocate_buffers(compress_meshopt(chunk0, &mut chunks));
+                chunks = relocator.relocate(&chunks);
+                compressed
+            });
+        }
+
+        let vrm = Vrm {
+            version: self.version,
+            chunk0,
+            chunks,
+        };
+        Ok((vrm, report))
+    }
+
+    /// モデル情報
+    pub fn stats(&self) -> Stats {
+        collect_stats(&self.chunk0, &self.chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ヘッダー、JSONチャンク、BINチャンクを並べたglTFバイナリ
+    fn glb(magic: u32, length: Option<u32>, json_type: u32, chunk_type: u32) -> Vec<u8> {
+        let json = b"{}  ";
+        let chunk = [0u8; 4];
+        let mut bytes = Vec::new();
+        bytes.write_u32::<LE>(magic).unwrap();
+        bytes.write_u32::<LE>(2).unwrap();
+        bytes
+            .write_u32::<LE>(length.unwrap_or(12 + 8 + 4 + 8 + 4))
+            .unwrap();
+        bytes.write_u32::<LE>(json.len() as u32).unwrap();
+        bytes.write_u32::<LE>(json_type).unwrap();
+        bytes.extend_from_slice(json);
+        bytes.write_u32::<LE>(chunk.len() as u32).unwrap();
+        bytes.write_u32::<LE>(chunk_type).unwrap();
+        bytes.extend_from_slice(&chunk);
+        bytes
+    }
+
+    #[test]
+    fn read_reader_rejects_malformed_headers() {
+        let vrm = Vrm::read_reader(&glb(GLTF_MAGIC, None, JSON_TYPE, CHUNK_TYPE)[..]).unwrap();
+        assert_eq!(vrm.chunks, vec![vec![0u8; 4]]);
+
+        for bytes in &[
+            glb(0, None, JSON_TYPE, CHUNK_TYPE),
+            glb(GLTF_MAGIC, None, CHUNK_TYPE, CHUNK_TYPE),
+            glb(GLTF_MAGIC, None, JSON_TYPE, JSON_TYPE),
+            // 長さがJSONチャンクより短い、BINチャンクより短い
+            glb(GLTF_MAGIC, Some(16), JSON_TYPE, CHUNK_TYPE),
+            glb(GLTF_MAGIC, Some(30), JSON_TYPE, CHUNK_TYPE),
+        ] {
+            assert!(Vrm::read_reader(&bytes[..]).is_err());
+        }
+    }
+
+    #[test]
+    fn reduce_with_report_rejects_webp_with_ktx2_without_fallback() {
+        let vrm = Vrm::read_reader(&glb(GLTF_MAGIC, None, JSON_TYPE, CHUNK_TYPE)[..]).unwrap();
+        let ktx2 = |fallback| Ktx2Options {
+            mode: BasisMode::Etc1s,
+            fallback,
+            toktx: String::new(),
+        };
+        let webp = WebpOptions {
+            lossless: false,
+            quality: 90,
+            fallback: true,
+            cwebp: String::new(),
+        };
+        let options = ReduceOptions {
+            ktx2: Some(ktx2(false)),
+            webp: Some(webp),
+            ..Default::default()
+        };
+        assert!(vrm.reduce_with_report(None, &options).is_err());
+        let options = ReduceOptions {
+            ktx2: Some(ktx2(true)),
+            ..options
+        };
+        assert!(options.validate().is_ok());
+    }
 }
@@ -0,0 +1,221 @@
+use super::buffer::*;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use serde_json::Value;
+use std::io::Cursor;
+
+pub const BYTE: u64 = 5120;
+pub const UNSIGNED_BYTE: u64 = 5121;
+pub const SHORT: u64 = 5122;
+pub const UNSIGNED_SHORT: u64 = 5123;
+pub const UNSIGNED_INT: u64 = 5125;
+pub const FLOAT: u64 = 5126;
+
+pub const ARRAY_BUFFER: u64 = 34962;
+pub const ELEMENT_ARRAY_BUFFER: u64 = 34963;
+
+/// componentTypeのバイト数
+pub fn component_size(component_type: u64) -> Option<usize> {
+    match component_type {
+        BYTE | UNSIGNED_BYTE => Some(1),
+        SHORT | UNSIGNED_SHORT => Some(2),
+        UNSIGNED_INT | FLOAT => Some(4),
+        _ => None,
+    }
+}
+
+/// typeの要素数
+pub fn component_count(type_: &str) -> Option<usize> {
+    match type_ {
+        "SCALAR" => Some(1),
+        "VEC2" => Some(2),
+        "VEC3" => Some(3),
+        "VEC4" | "MAT2" => Some(4),
+        "MAT3" => Some(9),
+        "MAT4" => Some(16),
+        _ => None,
+    }
+}
+
+/// normalizedな整数型で1.0に相当する値
+pub fn normalized_max(component_type: u64) -> f64 {
+    match component_type {
+        BYTE => 127.0,
+        UNSIGNED_BYTE => 255.0,
+        SHORT => 32767.0,
+        UNSIGNED_SHORT => 65535.0,
+        _ => 1.0,
+    }
+}
+
+/// accessorの1要素のバイト数
+pub fn element_size(accessor: &Value) -> Option<usize> {
+    let component_type = accessor.get("componentType").and_then(|v| v.as_u64())?;
+    let type_ = accessor.get("type").and_then(|v| v.as_str())?;
+    Some(component_size(component_type)? * component_count(type_)?)
+}
+
+fn read_component(bytes: &[u8], component_type: u64) -> f64 {
+    let mut reader = Cursor::new(bytes);
+    let value = match component_type {
+        BYTE => reader.read_i8().map(f64::from),
+        UNSIGNED_BYTE => reader.read_u8().map(f64::from),
+        SHORT => reader.read_i16::<LE>().map(f64::from),
+        UNSIGNED_SHORT => reader.read_u16::<LE>().map(f64::from),
+        UNSIGNED_INT => reader.read_u32::<LE>().map(f64::from),
+        _ => reader.read_f32::<LE>().map(f64::from),
+    };
+    value.unwrap_or(0.0)
+}
+
+fn write_component(output: &mut Vec<u8>, value: f64, component_type: u64) {
+    let _ = match component_type {
+        BYTE => output.write_i8(value.round() as i8),
+        UNSIGNED_BYTE => output.write_u8(value.round() as u8),
+        SHORT => output.write_i16::<LE>(value.round() as i16),
+        UNSIGNED_SHORT => output.write_u16::<LE>(value.round() as u16),
+        UNSIGNED_INT => output.write_u32::<LE>(value.round() as u32),
+        _ => output.write_f32::<LE>(value as f32),
+    };
+}
+
+/// accessorの値を読み込む。sparseは適用し、normalizedは適用しない
+pub fn read_accessor(gltf: &Value, chunks: &[Vec<u8>], index: u64) -> Option<Vec<f64>> {
+    let accessor = gltf.get("accessors")?.get(index as usize)?;
+    let component_type = accessor.get("componentType")?.as_u64()?;
+    let size = component_size(component_type)?;
+    let components = component_count(accessor.get("type")?.as_str()?)?;
+    let count = accessor.get("count")?.as_u64()? as usize;
+
+    let mut values = vec![0.0; count * components];
+    if let Some(buffer_view_index) = accessor.get("bufferView").and_then(|v| v.as_u64()) {
+        let bytes = buffer_view_bytes(gltf, chunks, buffer_view_index)?;
+        let byte_offset = accessor
+            .get("byteOffset")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+        let byte_stride = gltf["bufferViews"][buffer_view_index as usize]
+            .get("byteStride")
+            .and_then(|v| v.as_u64())
+            .map(|v| v as usize)
+            .unwrap_or(size * components);
+        for i in 0..count {
+            for j in 0..components {
+                let start = byte_offset + i * byte_stride + j * size;
+                values[i * components + j] =
+                    read_component(bytes.get(start..start + size)?, component_type);
+            }
+        }
+    }
+
+    if let Some(sparse) = accessor.get("sparse") {
+        let sparse_count = sparse.get("count")?.as_u64()? as usize;
+        let indices_type = sparse.pointer("/indices/componentType")?.as_u64()?;
+        let indices_size = component_size(indices_type)?;
+        let indices_bytes = buffer_view_bytes(
+            gltf,
+            chunks,
+            sparse.pointer("/indices/bufferView")?.as_u64()?,
+        )?;
+        let indices_offset = sparse
+            .pointer("/indices/byteOffset")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+        let values_bytes = buffer_view_bytes(
+            gltf,
+            chunks,
+            sparse.pointer("/values/bufferView")?.as_u64()?,
+        )?;
+        let values_offset = sparse
+            .pointer("/values/byteOffset")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+        for i in 0..sparse_count {
+            let start = indices_offset + i * indices_size;
+            let target = read_component(
+                indices_bytes.get(start..start + indices_size)?,
+                indices_type,
+            ) as usize;
+            if target >= count {
+                return None;
+            }
+            for j in 0..components {
+                let start = values_offset + (i * components + j) * size;
+                values[target * components + j] =
+                    read_component(values_bytes.get(start..start + size)?, component_type);
+            }
+        }
+    }
+    Some(values)
+}
+
+/// 値を新しいbufferViewに書き込み、それを参照するaccessorを作成する
+/// 頂点属性は要素が4バイト境界に揃うようにbyteStrideを設定する
+pub fn push_accessor(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    component_type: u64,
+    normalized: bool,
+    type_: &str,
+    values: &[f64],
+    target: Option<u64>,
+) -> u64 {
+    let components = component_count(type_).unwrap_or(1);
+    let element_size = component_size(component_type).unwrap_or(4) * components;
+    let byte_stride = if target == Some(ARRAY_BUFFER) {
+        (element_size + 3) / 4 * 4
+    } else {
+        element_size
+    };
+
+    let mut bytes = Vec::with_capacity(values.len() / components * byte_stride);
+    let mut min = vec![std::f64::INFINITY; components];
+    let mut max = vec![std::f64::NEG_INFINITY; components];
+    for element in values.chunks(components) {
+        for (j, value) in element.iter().enumerate() {
+            // min、maxは実際に書き込まれる値から求める
+            let stored = match component_type {
+                FLOAT => f64::from(*value as f32),
+                _ => value.round(),
+            };
+            write_component(&mut bytes, stored, component_type);
+            min[j] = min[j].min(stored);
+            max[j] = max[j].max(stored);
+        }
+        bytes.resize(bytes.len() + byte_stride - element_size, 0);
+    }
+
+    let buffer_view_index = push_buffer_view(gltf, chunks, &bytes, target);
+    if byte_stride != element_size {
+        gltf["bufferViews"][buffer_view_index as usize]["byteStride"] = byte_stride.into();
+    }
+
+    let bounds = |values: Vec<f64>| -> Value {
+        values
+            .into_iter()
+            .map(|v| match component_type {
+                FLOAT => Value::from(v),
+                _ => Value::from(v as i64),
+            })
+            .collect::<Vec<_>>()
+            .into()
+    };
+    let mut accessor = serde_json::map::Map::new();
+    accessor.insert("bufferView".into(), buffer_view_index.into());
+    accessor.insert("componentType".into(), component_type.into());
+    if normalized {
+        accessor.insert("normalized".into(), true.into());
+    }
+    accessor.insert("count".into(), (values.len() / components).into());
+    accessor.insert("type".into(), type_.into());
+    if target.is_some() && !values.is_empty() {
+        accessor.insert("min".into(), bounds(min));
+        accessor.insert("max".into(), bounds(max));
+    }
+
+    if !gltf.get("accessors").map(|v| v.is_array()).unwrap_or(false) {
+        gltf["accessors"] = Value::Array(Vec::new());
+    }
+    let accessors = gltf["accessors"].as_array_mut().unwrap();
+    accessors.push(accessor.into());
+    (accessors.len() - 1) as u64
+}
@@ -0,0 +1,451 @@
+use super::accessor::*;
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, Default)]
+pub struct BlendShapeOptions {
+    /// 残すブレンドシェイプグループの名前またはプリセット名
+    pub keep: Vec<String>,
+    /// プリセットのブレンドシェイプグループを残す
+    pub keep_presets: bool,
+    /// メッシュに焼き込むブレンドシェイプグループと、その値(0から1)
+    pub bake: Vec<(String, f64)>,
+}
+
+impl BlendShapeOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.keep_presets || !self.keep.is_empty() || !self.bake.is_empty()
+    }
+}
+
+fn group_matches(group: &Value, name: &str) -> bool {
+    ["name", "presetName"]
+        .iter()
+        .any(|key| group.get(*key).and_then(|v| v.as_str()) == Some(name))
+}
+
+fn is_preset(group: &Value) -> bool {
+    match group.get("presetName").and_then(|v| v.as_str()) {
+        Some("unknown") | Some("") | None => false,
+        Some(_) => true,
+    }
+}
+
+// ブレンドシェイプグループが使うモーフターゲット(mesh, index)とウェイト(0から1)
+fn group_binds(group: &Value) -> Vec<(u64, usize, f64)> {
+    group
+        .get("binds")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .filter_map(|bind| {
+            Some((
+                bind.get("mesh")?.as_u64()?,
+                bind.get("index")?.as_u64()? as usize,
+                bind.get("weight").and_then(|v| v.as_f64()).unwrap_or(100.0) / 100.0,
+            ))
+        })
+        .collect()
+}
+
+// 頂点属性にモーフターゲットの差分を加える
+fn apply_targets(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    index: u64,
+    targets: &[(u64, f64)],
+    normalize: bool,
+) -> Option<u64> {
+    let accessor = gltf.get("accessors")?.get(index as usize)?;
+    if accessor.get("componentType").and_then(|v| v.as_u64()) != Some(FLOAT) {
+        return None;
+    }
+    let type_ = accessor.get("type")?.as_str()?.to_string();
+    let mut values = read_accessor(gltf, chunks, index)?;
+    for (target_index, weight) in targets {
+        let deltas = read_accessor(gltf, chunks, *target_index)?;
+        if deltas.len() != values.len() {
+            return None;
+        }
+        for (value, delta) in values.iter_mut().zip(deltas.iter()) {
+            *value += delta * weight;
+        }
+    }
+    if normalize {
+        for normal in values.chunks_mut(3) {
+            let length = normal.iter().map(|v| v * v).sum::<f64>().sqrt();
+            if length > 0.0 {
+                for v in normal.iter_mut() {
+                    *v /= length;
+                }
+            }
+        }
+    }
+    Some(push_accessor(
+        gltf,
+        chunks,
+        FLOAT,
+        false,
+        &type_,
+        &values,
+        Some(ARRAY_BUFFER),
+    ))
+}
+
+// 焼き込むブレンドシェイプグループの番号と値
+fn baked_groups(groups: &[Value], bake: &[(String, f64)]) -> Vec<(usize, f64)> {
+    bake.iter()
+        .filter_map(|(name, value)| {
+            let index = groups.iter().position(|g| group_matches(g, name));
+            if index.is_none() {
+                warn!("Blend shape group not found: {}", name);
+            }
+            Some((index?, *value))
+        })
+        .collect()
+}
+
+// 焼き込まず、削除もしないブレンドシェイプグループ
+fn keep_group(
+    group_index: usize,
+    group: &Value,
+    baked_groups: &[(usize, f64)],
+    options: &BlendShapeOptions,
+) -> bool {
+    let prune = options.keep_presets || !options.keep.is_empty();
+    !baked_groups.iter().any(|(index, _)| *index == group_index)
+        && (!prune
+            || (options.keep_presets && is_preset(group))
+            || options.keep.iter().any(|name| group_matches(group, name)))
+}
+
+// ブレンドシェイプグループをメッシュのPOSITION、NORMALに焼き込む
+// 残すグループも使うモーフターゲットは、焼き込むと二重に効くので焼き込まない
+fn bake_blend_shapes(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    groups: &[Value],
+    baked_groups: &[(usize, f64)],
+    kept_targets: &BTreeSet<(u64, usize)>,
+) {
+    let mut mesh_weights = BTreeMap::new();
+    for (group_index, value) in baked_groups {
+        for (mesh, index, weight) in group_binds(&groups[*group_index]) {
+            if kept_targets.contains(&(mesh, index)) {
+                warn!(
+                    "Blend shape group {} is not baked into mesh {} target {}: another group uses it",
+                    groups[*group_index]
+                        .get("name")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or(""),
+                    mesh,
+                    index
+                );
+                continue;
+            }
+            *mesh_weights
+                .entry(mesh)
+                .or_insert_with(BTreeMap::new)
+                .entry(index)
+                .or_insert(0.0) += weight * *value;
+        }
+    }
+
+    let mut cache = BTreeMap::new();
+    for (mesh_index, weights) in mesh_weights {
+        let primitive_len = gltf
+            .get("meshes")
+            .and_then(|v| v.get(mesh_index as usize))
+            .and_then(|v| v.get("primitives"))
+            .and_then(|v| v.as_array())
+            .map(|v| v.len())
+            .unwrap_or(0);
+        for primitive_index in 0..primitive_len {
+            let primitive =
+                gltf["meshes"][mesh_index as usize]["primitives"][primitive_index].clone();
+            for key in &["POSITION", "NORMAL"] {
+                let base = if let Some(base) = primitive
+                    .get("attributes")
+                    .and_then(|v| v.get(*key))
+                    .and_then(|v| v.as_u64())
+                {
+                    base
+                } else {
+                    continue;
+                };
+                let targets = weights
+                    .iter()
+                    .filter_map(|(index, weight)| {
+                        primitive
+                            .get("targets")
+                            .and_then(|v| v.get(*index))
+                            .and_then(|v| v.get(*key))
+                            .and_then(|v| v.as_u64())
+                            .map(|target| (target, *weight))
+                    })
+                    .collect::<Vec<_>>();
+                if targets.is_empty() {
+                    continue;
+                }
+                let cache_key = format!("{} {:?}", base, targets);
+                let baked = if let Some(baked) = cache.get(&cache_key) {
+                    *baked
+                } else {
+                    let baked = apply_targets(gltf, chunks, base, &targets, *key == "NORMAL");
+                    if baked.is_none() {
+                        warn!(
+                            "Failed to bake blend shapes into mesh {} primitive {} {}",
+                            mesh_index, primitive_index, key
+                        );
+                    }
+                    cache.insert(cache_key, baked);
+                    baked
+                };
+                if let Some(baked) = baked {
+                    gltf["meshes"][mesh_index as usize]["primitives"][primitive_index]
+                        ["attributes"][*key] = baked.into();
+                }
+            }
+        }
+    }
+}
+
+// 配列から指定した番号の要素を削除する。空になった配列はキーごと削除する
+fn remove_indices(value: &mut Value, pointer: &str, removed: &BTreeSet<usize>) {
+    let (parent_pointer, key) = pointer.split_at(pointer.rfind('/').unwrap_or(0));
+    let key = key.trim_start_matches('/');
+    let parent = if let Some(parent) = value.pointer_mut(parent_pointer) {
+        parent
+    } else {
+        return;
+    };
+    let empty = if let Some(array) = parent.get_mut(key).and_then(|v| v.as_array_mut()) {
+        let mut index = 0;
+        array.retain(|_| {
+            index += 1;
+            !removed.contains(&(index - 1))
+        });
+        array.is_empty()
+    } else {
+        return;
+    };
+    if empty {
+        parent.as_object_mut().map(|p| p.remove(key));
+    }
+}
+
+// メッシュごとに指定したモーフターゲットを削除し、参照している番号を詰める
+fn remove_morph_targets(gltf: &mut Value, removed_targets: &BTreeMap<u64, BTreeSet<usize>>) {
+    for (mesh_index, removed) in removed_targets {
+        let mesh = if let Some(mesh) = gltf
+            .get_mut("meshes")
+            .and_then(|v| v.get_mut(*mesh_index as usize))
+        {
+            mesh
+        } else {
+            continue;
+        };
+        for primitive in mesh
+            .get_mut("primitives")
+            .and_then(|v| v.as_array_mut())
+            .unwrap_or(&mut Vec::new())
+        {
+            for pointer in &["/targets", "/extras/targetNames"] {
+                remove_indices(primitive, pointer, removed);
+            }
+        }
+        for pointer in &["/weights", "/extras/targetNames"] {
+            remove_indices(mesh, pointer, removed);
+        }
+
+        for node in gltf
+            .get_mut("nodes")
+            .and_then(|v| v.as_array_mut())
+            .unwrap_or(&mut Vec::new())
+        {
+            if node.get("mesh").and_then(|v| v.as_u64()) == Some(*mesh_index) {
+                remove_indices(node, "/weights", removed);
+            }
+        }
+    }
+
+    for group in gltf
+        .pointer_mut("/extensions/VRM/blendShapeMaster/blendShapeGroups")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(binds) = group.get_mut("binds").and_then(|v| v.as_array_mut()) {
+            binds.retain(|bind| {
+                match (
+                    bind.get("mesh").and_then(|v| v.as_u64()),
+                    bind.get("index").and_then(|v| v.as_u64()),
+                ) {
+                    (Some(mesh), Some(index)) => removed_targets
+                        .get(&mesh)
+                        .map(|removed| !removed.contains(&(index as usize)))
+                        .unwrap_or(true),
+                    _ => true,
+                }
+            });
+            for bind in binds {
+                if let (Some(mesh), Some(index)) = (
+                    bind.get("mesh").and_then(|v| v.as_u64()),
+                    bind.get("index").and_then(|v| v.as_u64()),
+                ) {
+                    if let Some(removed) = removed_targets.get(&mesh) {
+                        let shift = removed.iter().filter(|r| **r < index as usize).count();
+                        bind["index"] = (index as usize - shift).into();
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// ブレンドシェイプグループを焼き込み、残さないグループを削除する
+/// 削除したグループだけが使っていたモーフターゲットも削除する
+pub fn reduce_blend_shapes(
+    gltf_: Value,
+    chunks: &mut Vec<Vec<u8>>,
+    options: &BlendShapeOptions,
+) -> Value {
+    let mut gltf = gltf_.clone();
+    let groups = gltf
+        .pointer("/extensions/VRM/blendShapeMaster/blendShapeGroups")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let baked_groups = baked_groups(&groups, &options.bake);
+    let keep = groups
+        .iter()
+        .enumerate()
+        .map(|(group_index, group)| keep_group(group_index, group, &baked_groups, options))
+        .collect::<Vec<_>>();
+
+    let mut kept_groups = Vec::new();
+    let mut kept_targets = BTreeSet::new();
+    let mut removed_targets = BTreeSet::new();
+    for (group, keep) in groups.iter().zip(keep.iter()) {
+        for (mesh, index, _) in group_binds(group) {
+            if *keep {
+                kept_targets.insert((mesh, index));
+            } else {
+                removed_targets.insert((mesh, index));
+            }
+        }
+        if *keep {
+            kept_groups.push(group.clone());
+        } else {
+            debug!(
+                "remove blend shape group: {}",
+                group.get("name").and_then(|v| v.as_str()).unwrap_or("")
+            );
+        }
+    }
+    bake_blend_shapes(&mut gltf, chunks, &groups, &baked_groups, &kept_targets);
+
+    let mut removed_targets_by_mesh = BTreeMap::new();
+    for (mesh, index) in removed_targets.difference(&kept_targets) {
+        removed_targets_by_mesh
+            .entry(*mesh)
+            .or_insert_with(BTreeSet::new)
+            .insert(*index);
+    }
+    if let Some(groups) = gltf.pointer_mut("/extensions/VRM/blendShapeMaster/blendShapeGroups") {
+        *groups = kept_groups.into();
+    }
+    remove_morph_targets(&mut gltf, &removed_targets_by_mesh);
+    info!(
+        "remove {} morph targets",
+        removed_targets_by_mesh
+            .values()
+            .fold(0, |sum, removed| sum + removed.len())
+    );
+    gltf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reduce_blend_shapes_does_not_bake_targets_of_kept_groups() {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{"extensions": {"VRM": {"blendShapeMaster": {"blendShapeGroups": [
+                {"name": "Blink", "presetName": "blink",
+                 "binds": [{"mesh": 0, "index": 0, "weight": 100}]},
+                {"name": "Smile", "presetName": "unknown",
+                 "binds": [{"mesh": 0, "index": 0, "weight": 100},
+                           {"mesh": 0, "index": 1, "weight": 100}]}
+            ]}}}}"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        let position = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "VEC3",
+            &[0.0, 0.0, 0.0],
+            Some(ARRAY_BUFFER),
+        );
+        let targets = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0]]
+            .iter()
+            .map(|delta| {
+                let target = push_accessor(
+                    &mut gltf,
+                    &mut chunks,
+                    FLOAT,
+                    false,
+                    "VEC3",
+                    delta,
+                    Some(ARRAY_BUFFER),
+                );
+                let mut attributes = serde_json::map::Map::new();
+                attributes.insert("POSITION".to_string(), target.into());
+                Value::from(attributes)
+            })
+            .collect::<Vec<_>>();
+        let mut attributes = serde_json::map::Map::new();
+        attributes.insert("POSITION".to_string(), position.into());
+        let mut primitive = serde_json::map::Map::new();
+        primitive.insert("attributes".to_string(), attributes.into());
+        primitive.insert("targets".to_string(), targets.into());
+        let mut mesh = serde_json::map::Map::new();
+        mesh.insert(
+            "primitives".to_string(),
+            vec![Value::from(primitive)].into(),
+        );
+        gltf["meshes"] = vec![Value::from(mesh)].into();
+
+        let options = BlendShapeOptions {
+            keep_presets: true,
+            bake: vec![("Smile".to_string(), 1.0)],
+            ..Default::default()
+        };
+        let gltf = reduce_blend_shapes(gltf, &mut chunks, &options);
+
+        // Blinkと共有するターゲット0は焼き込まず残し、Smileだけのターゲット1を焼き込む
+        let position = gltf["meshes"][0]["primitives"][0]["attributes"]["POSITION"]
+            .as_u64()
+            .unwrap();
+        assert_eq!(
+            read_accessor(&gltf, &chunks, position),
+            Some(vec![0.0, 1.0, 0.0])
+        );
+        assert_eq!(
+            gltf["meshes"][0]["primitives"][0]["targets"]
+                .as_array()
+                .map(|v| v.len()),
+            Some(1)
+        );
+        let groups = gltf
+            .pointer("/extensions/VRM/blendShapeMaster/blendShapeGroups")
+            .and_then(|v| v.as_array())
+            .unwrap();
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0]["binds"][0]["index"], Value::from(0));
+    }
+}
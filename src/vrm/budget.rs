@@ -0,0 +1,338 @@
+use super::debug::*;
+use super::report::*;
+use super::{ReduceOptions, Vrm};
+use log::info;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+// 予算に収めるために削減を繰り返す最大回数
+const MAX_FIT_ITERATIONS: usize = 16;
+// 予算に収めるために縮小するテクスチャの最小サイズ
+const MIN_FIT_TEXTURE_SIZE: u32 = 64;
+
+/// モデルの上限。Noneの項目は確認しない
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Budget {
+    pub triangles: Option<usize>,
+    pub materials: Option<usize>,
+    /// テクスチャのメモリ使用量(MiB)。RGBA8、ミップマップ付きとして計算する
+    pub texture_memory: Option<usize>,
+    pub bones: Option<usize>,
+    pub skinned_meshes: Option<usize>,
+    pub blend_shapes: Option<usize>,
+    /// 揺れもののチェーンに含まれるノードの数
+    pub spring_bones: Option<usize>,
+}
+
+impl Budget {
+    /// 組み込みのプリセット
+    /// excellent、good、medium、poorはVRChatのパフォーマンスランクに近い値
+    pub fn preset(name: &str) -> Option<Budget> {
+        let budget = |triangles,
+                      materials,
+                      texture_memory,
+                      bones,
+                      skinned_meshes,
+                      blend_shapes,
+                      spring_bones| Budget {
+            triangles: Some(triangles),
+            materials: Some(materials),
+            texture_memory: Some(texture_memory),
+            bones: Some(bones),
+            skinned_meshes: Some(skinned_meshes),
+            blend_shapes: Some(blend_shapes),
+            spring_bones: Some(spring_bones),
+        };
+        match name.to_lowercase().as_str() {
+            "mobile" => Some(budget(20_000, 4, 40, 150, 2, 64, 64)),
+            "desktop" => Some(budget(70_000, 16, 110, 256, 8, 256, 256)),
+            "excellent" => Some(budget(32_000, 4, 40, 75, 1, 64, 16)),
+            "good" => Some(budget(70_000, 8, 75, 150, 2, 128, 64)),
+            "medium" => Some(budget(70_000, 16, 110, 256, 8, 256, 128)),
+            "poor" => Some(budget(70_000, 32, 150, 400, 16, 512, 256)),
+            _ => None,
+        }
+    }
+
+    /// `キー = 整数`の行だけからなるTOMLを読み込む。`[budget]`などのテーブル見出しとコメントは無視する
+    pub fn parse_toml(s: &str) -> Result<Budget, String> {
+        let mut budget = Budget::default();
+        for (line_index, line_) in s.lines().enumerate() {
+            let line = line_.split('#').next().unwrap_or("").trim();
+            if line.is_empty() || line.starts_with('[') {
+                continue;
+            }
+            let mut split = line.splitn(2, '=');
+            let (key, value) = match (split.next(), split.next()) {
+                (Some(key), Some(value)) => (key.trim().trim_matches('"'), value.trim()),
+                _ => return Err(format!("line {}: expected KEY = VALUE", line_index + 1)),
+            };
+            let value = value
+                .replace('_', "")
+                .parse::<usize>()
+                .map_err(|e| format!("line {}: {:?}", line_index + 1, e))?;
+            let field = match key {
+                "triangles" => &mut budget.triangles,
+                "materials" => &mut budget.materials,
+                "texture_memory" => &mut budget.texture_memory,
+                "bones" => &mut budget.bones,
+                "skinned_meshes" => &mut budget.skinned_meshes,
+                "blend_shapes" => &mut budget.blend_shapes,
+                "spring_bones" => &mut budget.spring_bones,
+                _ => {
+                    return Err(format!(
+                        "line {}: unknown budget key: {}",
+                        line_index + 1,
+                        key
+                    ))
+                }
+            };
+            *field = Some(value);
+        }
+        Ok(budget)
+    }
+
+    /// 項目ごとの(名前, 値, 上限)
+    pub fn metrics(&self, stats: &Stats) -> Vec<(&'static str, usize, Option<usize>)> {
+        vec![
+            ("triangles", stats.triangles(), self.triangles),
+            ("materials", stats.materials, self.materials),
+            (
+                "texture_memory",
+                (stats.texture_memory() + (1 << 20) - 1) >> 20,
+                self.texture_memory,
+            ),
+            ("bones", stats.joints, self.bones),
+            ("skinned_meshes", stats.skinned_meshes, self.skinned_meshes),
+            ("blend_shapes", stats.morph_targets(), self.blend_shapes),
+            ("spring_bones", stats.spring_bones, self.spring_bones),
+        ]
+    }
+
+    /// 上限を超えている項目の名前
+    pub fn exceeded(&self, stats: &Stats) -> Vec<&'static str> {
+        self.metrics(stats)
+            .into_iter()
+            .filter(|(_, value, limit)| limit.map(|limit| *value > limit).unwrap_or(false))
+            .map(|(name, _, _)| name)
+            .collect()
+    }
+
+    /// 項目ごとに値と上限を表示し、全て上限以下ならtrueを返す
+    pub fn report(&self, stats: &Stats) -> bool {
+        self.write_report(stats, &mut std::io::stdout())
+            .unwrap_or(false)
+    }
+
+    /// reportの出力先を指定する版
+    pub fn write_report<W>(&self, stats: &Stats, out: &mut W) -> std::io::Result<bool>
+    where
+        W: Write,
+    {
+        writeln!(out, "{:<16} {:>10} {:>10}", "budget", "value", "limit")?;
+        for (name, value, limit) in self.metrics(stats) {
+            let (limit, status) = match limit {
+                Some(limit) if value > limit => (limit.to_string(), "EXCEEDED"),
+                Some(limit) => (limit.to_string(), "ok"),
+                None => ("-".to_string(), ""),
+            };
+            writeln!(out, "{:<16} {:>10} {:>10} {}", name, value, limit, status)?;
+        }
+        Ok(self.exceeded(stats).is_empty())
+    }
+}
+
+impl FromStr for Budget {
+    type Err = String;
+
+    /// プリセット名かTOMLファイルのパス
+    fn from_str(s: &str) -> Result<Budget, String> {
+        if let Some(budget) = Budget::preset(s) {
+            return Ok(budget);
+        }
+        let toml = std::fs::read_to_string(s)
+            .map_err(|e| format!("unknown budget preset or unreadable file {}: {:?}", s, e))?;
+        Budget::parse_toml(&toml)
+    }
+}
+
+// 上限を超えている項目に効く削減を強める。変更できなかった場合はfalse
+fn tighten_options(options: &mut ReduceOptions, exceeded: &[&str], stats: &Stats) -> bool {
+    let mut changed = false;
+    for name in exceeded {
+        match *name {
+            "bones" if !options.reduce_bones => {
+                options.reduce_bones = true;
+                changed = true;
+            }
+            "skinned_meshes" if !options.merge_meshes => {
+                options.merge_meshes = true;
+                changed = true;
+            }
+            "texture_memory" => {
+                let largest = stats
+                    .images
+                    .iter()
+                    .filter_map(|image| Some(image.width?.max(image.height?)))
+                    .max()
+                    .unwrap_or(0);
+                let max_size = options.texture.max_size.unwrap_or(largest).min(largest) / 2;
+                if max_size >= MIN_FIT_TEXTURE_SIZE {
+                    options.texture.max_size = Some(max_size);
+                    changed = true;
+                }
+            }
+            "spring_bones" => {
+                let spring_bone = &mut options.spring_bone;
+                match spring_bone.max_joints {
+                    Some(max_joints) if max_joints <= 1 => {
+                        if !spring_bone.remove {
+                            spring_bone.remove = true;
+                            changed = true;
+                        }
+                    }
+                    max_joints => {
+                        spring_bone.max_joints = Some(max_joints.map(|v| v / 2).unwrap_or(8));
+                        changed = true;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    changed
+}
+
+/// 予算に収まるまで削減を強めながら繰り返す。最後の削減の記録も返す
+/// 三角形、マテリアル、ブレンドシェイプを減らす削減はないので、それらが超えている場合は収まらないことがある
+pub fn reduce_to_budget(
+    vrm: &Vrm,
+    base_dir: Option<&Path>,
+    options_: &ReduceOptions,
+    budget: &Budget,
+) -> Result<(Vrm, ReductionReport), Box<std::error::Error>> {
+    let mut options = options_.clone();
+    let (mut reduced, mut report) = vrm.reduce_with_report(base_dir, &options)?;
+    for _ in 0..MAX_FIT_ITERATIONS {
+        let stats = reduced.stats();
+        let exceeded = budget.exceeded(&stats);
+        if exceeded.is_empty() || !tighten_options(&mut options, &exceeded, &stats) {
+            break;
+        }
+        info!(
+            "budget exceeded: {}. retry with stronger reduction",
+            exceeded.join(", ")
+        );
+        let (next_reduced, next_report) = vrm.reduce_with_report(base_dir, &options)?;
+        reduced = next_reduced;
+        report = next_report;
+    }
+    Ok((reduced, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_stats(width: u32, height: u32) -> ImageStats {
+        ImageStats {
+            width: Some(width),
+            height: Some(height),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_toml_reads_limits() {
+        let budget = Budget::parse_toml(
+            r#"
+            # quest
+            [budget]
+            triangles = 10_000 # comment
+            "bones" = 75
+
+            spring_bones=8
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            budget,
+            Budget {
+                triangles: Some(10_000),
+                bones: Some(75),
+                spring_bones: Some(8),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn parse_toml_rejects_unsupported_lines() {
+        for toml in &[
+            "triangles",
+            "triangles = 1.5",
+            "triangles = \"100\"",
+            "triangles = -1",
+            "vertices = 100",
+        ] {
+            let error = Budget::parse_toml(toml).unwrap_err();
+            assert!(error.starts_with("line 1: "), "{}: {}", toml, error);
+        }
+    }
+
+    #[test]
+    fn exceeded_compares_limits() {
+        let budget = Budget {
+            materials: Some(2),
+            bones: Some(10),
+            texture_memory: Some(1),
+            ..Default::default()
+        };
+        let stats = Stats {
+            materials: 2,
+            joints: 11,
+            skinned_meshes: 100,
+            // 512x384はミップマップ込みで1MiBちょうど、1ピクセル増えると2MiB
+            images: vec![image_stats(512, 384), image_stats(1, 1)],
+            ..Default::default()
+        };
+        assert_eq!(budget.exceeded(&stats), vec!["texture_memory", "bones"]);
+        let stats = Stats {
+            images: vec![image_stats(512, 384)],
+            ..stats
+        };
+        assert_eq!(budget.exceeded(&stats), vec!["bones"]);
+    }
+
+    #[test]
+    fn tighten_options_strengthens_reduction() {
+        let stats = Stats {
+            images: vec![image_stats(1024, 512), image_stats(256, 256)],
+            ..Default::default()
+        };
+        let exceeded = ["bones", "skinned_meshes", "texture_memory", "spring_bones"];
+        let mut options = ReduceOptions::default();
+        assert!(tighten_options(&mut options, &exceeded, &stats));
+        assert!(options.reduce_bones);
+        assert!(options.merge_meshes);
+        assert_eq!(options.texture.max_size, Some(512));
+        assert_eq!(options.spring_bone.max_joints, Some(8));
+        assert!(!options.spring_bone.remove);
+
+        assert!(tighten_options(&mut options, &exceeded, &stats));
+        assert_eq!(options.texture.max_size, Some(256));
+        assert_eq!(options.spring_bone.max_joints, Some(4));
+
+        // テクスチャは最小サイズ未満にせず、揺れものはジョイントが1つになったら削除する
+        options.texture.max_size = Some(MIN_FIT_TEXTURE_SIZE);
+        options.spring_bone.max_joints = Some(1);
+        assert!(tighten_options(&mut options, &exceeded, &stats));
+        assert_eq!(options.texture.max_size, Some(MIN_FIT_TEXTURE_SIZE));
+        assert!(options.spring_bone.remove);
+
+        // これ以上強められない
+        assert!(!tighten_options(&mut options, &exceeded, &stats));
+        assert!(!tighten_options(&mut options, &["triangles"], &stats));
+    }
+}
@@ -0,0 +1,147 @@
+use log::{info, warn};
+use serde_json::Value;
+use std::path::Path;
+
+/// bufferViewが指すバイト列を取得する
+pub fn buffer_view_bytes<'a>(gltf: &Value, chunks: &'a [Vec<u8>], index: u64) -> Option<&'a [u8]> {
+    let buffer_view = gltf.get("bufferViews")?.get(index as usize)?;
+    let buffer = buffer_view.get("buffer").and_then(|v| v.as_u64())?;
+    let byte_offset = buffer_view
+        .get("byteOffset")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0) as usize;
+    let byte_length = buffer_view.get("byteLength").and_then(|v| v.as_u64())? as usize;
+    chunks
+        .get(buffer as usize)?
+        .get(byte_offset..byte_offset + byte_length)
+}
+
+/// 先頭バッファの末尾にデータを追加し、そのオフセットを返す
+pub fn append_to_buffer(gltf: &mut Value, chunks: &mut Vec<Vec<u8>>, bytes: &[u8]) -> usize {
+    if chunks.is_empty() {
+        chunks.push(Vec::new());
+    }
+    let chunk = &mut chunks[0];
+    let byte_offset = (chunk.len() + 3) / 4 * 4;
+    chunk.resize(byte_offset, 0);
+    chunk.extend_from_slice(bytes);
+
+    if !gltf.get("buffers").map(|v| v.is_array()).unwrap_or(false) {
+        gltf["buffers"] = Value::Array(Vec::new());
+    }
+    if let Some(buffers) = gltf["buffers"].as_array_mut() {
+        if buffers.is_empty() {
+            buffers.push(serde_json::map::Map::new().into());
+        }
+        buffers[0]["byteLength"] = chunk.len().into();
+    }
+    byte_offset
+}
+
+/// 先頭バッファの末尾にデータを追加し、それを指すbufferViewを作成する
+/// 置き換えられた古いbufferViewはcleanとrelocate_buffersで削除される
+pub fn push_buffer_view(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    bytes: &[u8],
+    target: Option<u64>,
+) -> u64 {
+    let byte_offset = append_to_buffer(gltf, chunks, bytes);
+
+    let mut buffer_view = serde_json::map::Map::new();
+    buffer_view.insert("buffer".into(), 0.into());
+    buffer_view.insert("byteOffset".into(), byte_offset.into());
+    buffer_view.insert("byteLength".into(), bytes.len().into());
+    if let Some(target) = target {
+        buffer_view.insert("target".into(), target.into());
+    }
+
+    if !gltf
+        .get("bufferViews")
+        .map(|v| v.is_array())
+        .unwrap_or(false)
+    {
+        gltf["bufferViews"] = Value::Array(Vec::new());
+    }
+    let buffer_views = gltf["bufferViews"].as_array_mut().unwrap();
+    buffer_views.push(buffer_view.into());
+    (buffer_views.len() - 1) as u64
+}
+
+// data URIのbase64を復号する
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut bits = 0u32;
+    let mut bit_len = 0;
+    for c in s.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            b'=' | b'\r' | b'\n' => continue,
+            _ => return None,
+        };
+        bits = bits << 6 | u32::from(value);
+        bit_len += 6;
+        if bit_len >= 8 {
+            bit_len -= 8;
+            bytes.push((bits >> bit_len) as u8);
+            bits &= (1 << bit_len) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+// bufferのuriが指すデータを読み込む。相対パスはbase_dirから探す
+fn read_buffer_uri(uri: &str, base_dir: Option<&Path>) -> Result<Vec<u8>, Box<std::error::Error>> {
+    if uri.starts_with("data:") {
+        let data = uri
+            .find(";base64,")
+            .map(|index| &uri[index + 8..])
+            .ok_or("data URI must be base64")?;
+        return Ok(decode_base64(data).ok_or("invalid base64")?);
+    }
+    let base_dir = base_dir.ok_or("external buffer needs the input path")?;
+    Ok(std::fs::read(base_dir.join(uri))?)
+}
+
+/// uriで参照している外部バッファを読み込み、chunksの同じ番号に置く
+/// 読み込んだバッファのuriは削除する
+pub fn load_external_buffers(
+    gltf_: Value,
+    chunks: &mut Vec<Vec<u8>>,
+    base_dir: Option<&Path>,
+) -> Value {
+    let mut gltf = gltf_.clone();
+    let buffer_len = gltf
+        .get("buffers")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+    for buffer_index in 0..buffer_len {
+        let uri = if let Some(uri) = gltf["buffers"][buffer_index]
+            .get("uri")
+            .and_then(|v| v.as_str())
+        {
+            uri.to_string()
+        } else {
+            continue;
+        };
+        match read_buffer_uri(&uri, base_dir) {
+            Ok(bytes) => {
+                info!("external buffer {}: {} bytes", buffer_index, bytes.len());
+                while chunks.len() <= buffer_index {
+                    chunks.push(Vec::new());
+                }
+                chunks[buffer_index] = bytes;
+                if let Some(buffer) = gltf["buffers"][buffer_index].as_object_mut() {
+                    buffer.remove("uri");
+                }
+            }
+            Err(e) => warn!("Failed to load buffer {}: {:?}", buffer_index, e),
+        }
+    }
+    gltf
+}
@@ -1,8 +1,31 @@
+use super::accessor::{component_size, ARRAY_BUFFER};
 use byteorder::{ReadBytesExt, LE};
+use log::{debug, warn};
 use serde_json::Value;
 use std::collections::{BTreeMap, BTreeSet};
 use std::io::Read;
 
+/// 警告の分類。logのtargetとして使う
+/// 範囲外のインデックス
+pub const INDEX_OVERFLOW: &str = "index_overflow";
+/// 宣言より短いバッファ
+pub const TRUNCATED_CHUNK: &str = "truncated_chunk";
+/// 削減処理が扱わない拡張
+pub const UNKNOWN_EXTENSION: &str = "unknown_extension";
+
+// 削減処理が参照を書き換えられる拡張
+const KNOWN_EXTENSIONS: [&str; 6] = [
+    "VRM",
+    "KHR_materials_unlit",
+    "KHR_texture_basisu",
+    "EXT_texture_webp",
+    "KHR_mesh_quantization",
+    "EXT_meshopt_compression",
+];
+
+// bufferViewのEXT_meshopt_compressionの圧縮データ
+const MESHOPT_COMPRESSION: &str = "/extensions/EXT_meshopt_compression";
+
 pub const CHUNK_TYPE: u32 = 0x4e4942;
 
 pub fn for_each_material_index_references<F>(gltf: &mut Value, mut f: F)
@@ -109,6 +132,16 @@ where
         if let Some(Value::Number(ref mut index)) = texture.get_mut("source") {
             f(index);
         }
+
+        for key in &["KHR_texture_basisu", "EXT_texture_webp"] {
+            if let Some(Value::Number(ref mut index)) = texture
+                .get_mut("extensions")
+                .and_then(|v| v.get_mut(*key))
+                .and_then(|v| v.get_mut("source"))
+            {
+                f(index);
+            }
+        }
     }
 }
 
@@ -156,21 +189,14 @@ where
         .and_then(|v| v.as_array_mut())
         .unwrap_or(&mut Vec::new())
     {
-        // https://github.com/Santarh/MToon/blob/bf08610b38e27ae915b0613ee4dfb574bc8e381b/MToon/Resources/Shaders/MToon.shader
-        for key in &[
-            "_MainTex",
-            "_ShadeTexture",
-            //"_BumpMap",
-            "_ReceiveShadowTexture",
-            "_ShadingGradeTexture",
-            //"_SphereAdd",
-            "_EmissionMap",
-            "_OutlineWidthTexture",
-        ] {
-            if let Some(Value::Number(ref mut index)) = material_properties
-                .get_mut("textureProperties")
-                .and_then(|v| v.get_mut(*key))
-            {
+        // _BumpMap、_SphereAdd、_RimTextureなどシェーダーごとに異なるので全て辿る
+        for index in material_properties
+            .get_mut("textureProperties")
+            .and_then(|v| v.as_object_mut())
+            .map(|v| v.values_mut().collect())
+            .unwrap_or_else(Vec::new)
+        {
+            if let Value::Number(ref mut index) = index {
                 f(index)
             }
         }
@@ -230,18 +256,239 @@ where
         if let Some(Value::Number(ref mut index)) = buffer_view.get_mut("buffer") {
             f(index);
         }
+        if let Some(Value::Number(ref mut index)) =
+            buffer_view.pointer_mut(&format!("{}/buffer", MESHOPT_COMPRESSION))
+        {
+            f(index);
+        }
+    }
+}
+
+pub fn for_each_skin_index_references<F>(gltf: &mut Value, mut f: F)
+where
+    F: FnMut(&mut serde_json::Number),
+{
+    for node in gltf
+        .get_mut("nodes")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(Value::Number(ref mut index)) = node.get_mut("skin") {
+            f(index);
+        }
+    }
+}
+
+pub fn for_each_mesh_index_references<F>(gltf: &mut Value, mut f: F)
+where
+    F: FnMut(&mut serde_json::Number),
+{
+    for node in gltf
+        .get_mut("nodes")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(Value::Number(ref mut index)) = node.get_mut("mesh") {
+            f(index);
+        }
+    }
+
+    let vrm = if let Some(vrm) = gltf.get_mut("extensions").and_then(|v| v.get_mut("VRM")) {
+        vrm
+    } else {
+        return;
+    };
+
+    for group in vrm
+        .get_mut("blendShapeMaster")
+        .and_then(|v| v.get_mut("blendShapeGroups"))
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        for bind in group
+            .get_mut("binds")
+            .and_then(|v| v.as_array_mut())
+            .unwrap_or(&mut Vec::new())
+        {
+            if let Some(Value::Number(ref mut index)) = bind.get_mut("mesh") {
+                f(index);
+            }
+        }
+    }
+
+    for mesh_annotation in vrm
+        .get_mut("firstPerson")
+        .and_then(|v| v.get_mut("meshAnnotations"))
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(Value::Number(ref mut index)) = mesh_annotation.get_mut("mesh") {
+            f(index);
+        }
+    }
+}
+
+/// VRM拡張から参照されているノード
+pub fn for_each_vrm_node_index_references<F>(gltf: &mut Value, mut f: F)
+where
+    F: FnMut(&mut serde_json::Number),
+{
+    let vrm = if let Some(vrm) = gltf.get_mut("extensions").and_then(|v| v.get_mut("VRM")) {
+        vrm
+    } else {
+        return;
+    };
+
+    for human_bone in vrm
+        .get_mut("humanoid")
+        .and_then(|v| v.get_mut("humanBones"))
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(Value::Number(ref mut index)) = human_bone.get_mut("node") {
+            f(index);
+        }
+    }
+
+    if let Some(Value::Number(ref mut index)) = vrm
+        .get_mut("firstPerson")
+        .and_then(|v| v.get_mut("firstPersonBone"))
+    {
+        f(index);
+    }
+
+    let secondary_animation = if let Some(secondary_animation) = vrm.get_mut("secondaryAnimation") {
+        secondary_animation
+    } else {
+        return;
+    };
+
+    for bone_group in secondary_animation
+        .get_mut("boneGroups")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(Value::Number(ref mut index)) = bone_group.get_mut("center") {
+            f(index);
+        }
+
+        for bone in bone_group
+            .get_mut("bones")
+            .and_then(|v| v.as_array_mut())
+            .unwrap_or(&mut Vec::new())
+        {
+            if let Value::Number(ref mut index) = bone {
+                f(index);
+            }
+        }
+    }
+
+    for collider_group in secondary_animation
+        .get_mut("colliderGroups")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(Value::Number(ref mut index)) = collider_group.get_mut("node") {
+            f(index);
+        }
     }
 }
 
+pub fn for_each_node_index_references<F>(gltf: &mut Value, mut f: F)
+where
+    F: FnMut(&mut serde_json::Number),
+{
+    for scene in gltf
+        .get_mut("scenes")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        for node in scene
+            .get_mut("nodes")
+            .and_then(|v| v.as_array_mut())
+            .unwrap_or(&mut Vec::new())
+        {
+            if let Value::Number(ref mut index) = node {
+                f(index);
+            }
+        }
+    }
+
+    for node in gltf
+        .get_mut("nodes")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        for child in node
+            .get_mut("children")
+            .and_then(|v| v.as_array_mut())
+            .unwrap_or(&mut Vec::new())
+        {
+            if let Value::Number(ref mut index) = child {
+                f(index);
+            }
+        }
+    }
+
+    for skin in gltf
+        .get_mut("skins")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(Value::Number(ref mut index)) = skin.get_mut("skeleton") {
+            f(index);
+        }
+
+        for joint in skin
+            .get_mut("joints")
+            .and_then(|v| v.as_array_mut())
+            .unwrap_or(&mut Vec::new())
+        {
+            if let Value::Number(ref mut index) = joint {
+                f(index);
+            }
+        }
+    }
+
+    for animation in gltf
+        .get_mut("animations")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        for channel in animation
+            .get_mut("channels")
+            .and_then(|v| v.as_array_mut())
+            .unwrap_or(&mut Vec::new())
+        {
+            if let Some(Value::Number(ref mut index)) =
+                channel.get_mut("target").and_then(|v| v.get_mut("node"))
+            {
+                f(index);
+            }
+        }
+    }
+
+    for_each_vrm_node_index_references(gltf, f);
+}
+
+// 番号で対応する配列($paired_pointers)からも同じ要素を削除する
 macro_rules! clean_resources {
-    ($generator_function: ident, $resource_pointer: expr, $json: expr) => {{
+    ($generator_function: ident, $resource_pointer: expr, $json: expr) => {
+        clean_resources!($generator_function, $resource_pointer, [], $json)
+    };
+    ($generator_function: ident, $resource_pointer: expr, [$($paired_pointer: expr),*], $json: expr) => {{
         let mut json = $json.clone();
         let mut original_indexes = BTreeSet::new();
         $generator_function(&mut json, |index| {
             if let Some(i) = index.as_u64() {
                 original_indexes.insert(i);
             } else {
-                println!("Too large {} index: {:?}", $resource_pointer, index);
+                warn!(
+                    target: INDEX_OVERFLOW,
+                    "Too large {} index: {:?}",
+                    $resource_pointer,
+                    index
+                );
             }
         });
 
@@ -255,6 +502,12 @@ macro_rules! clean_resources {
                 json.pointer_mut($resource_pointer)
                     .and_then(|v| v.as_array_mut())
                     .map(|t| t.remove(index as usize));
+                $(
+                    json.pointer_mut($paired_pointer)
+                        .and_then(|v| v.as_array_mut())
+                        .filter(|t| (index as usize) < t.len())
+                        .map(|t| t.remove(index as usize));
+                )*
             }
         }
 
@@ -267,7 +520,12 @@ macro_rules! clean_resources {
             if let Some(i) = index.as_u64() {
                 *index = index_map[&i].into();
             } else {
-                println!("Too large {} index: {:?}", $resource_pointer, index);
+                warn!(
+                    target: INDEX_OVERFLOW,
+                    "Too large {} index: {:?}",
+                    $resource_pointer,
+                    index
+                );
             }
         });
 
@@ -281,19 +539,90 @@ macro_rules! clean_resources {
     }};
 }
 
-pub fn fix_extension_vrm(gltf_: Value) -> Value {
-    let mut gltf = gltf_.clone();
+/// extensionsUsed、extensionsRequiredに拡張を追加する
+pub fn use_extension(gltf: &mut Value, name: &str, required: bool) {
+    let keys: &[&str] = if required {
+        &["extensionsUsed", "extensionsRequired"]
+    } else {
+        &["extensionsUsed"]
+    };
+    for key in keys {
+        if let Some(extensions) = gltf.get_mut(*key).and_then(|v| v.as_array_mut()) {
+            if !extensions.contains(&Value::String(name.into())) {
+                extensions.push(Value::String(name.into()));
+            }
+            continue;
+        }
+        gltf[*key] = vec![name].into();
+    }
+}
 
-    if let Some(extensions_used) = gltf
-        .get_mut("extensionsUsed")
-        .and_then(|v| v.as_array_mut())
-    {
-        if !extensions_used.contains(&Value::String("VRM".into())) {
-            extensions_used.push(Value::String("VRM".into()));
+fn collect_extension_names(value: &Value, names: &mut BTreeSet<String>) {
+    match value {
+        Value::Object(object) => {
+            for (key, child) in object {
+                if key == "extensions" {
+                    if let Value::Object(extensions) = child {
+                        names.extend(extensions.keys().cloned());
+                    }
+                }
+                collect_extension_names(child, names);
+            }
+        }
+        Value::Array(array) => {
+            for child in array {
+                collect_extension_names(child, names);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// 削減処理が扱わない拡張を警告する。拡張の中のインデックスは書き換えられない
+pub fn warn_unknown_extensions(gltf: &Value) {
+    let mut names = BTreeSet::new();
+    collect_extension_names(gltf, &mut names);
+    for name in names {
+        if !KNOWN_EXTENSIONS.contains(&name.as_str()) {
+            warn!(
+                target: UNKNOWN_EXTENSION,
+                "{} is not supported; indices inside it are not updated",
+                name
+            );
+        }
+    }
+}
+
+/// 使われなくなったテクスチャ、マテリアル拡張をextensionsUsed、extensionsRequiredから削除する
+pub fn fix_extensions_used(gltf_: Value) -> Value {
+    let mut gltf = gltf_.clone();
+    let mut names = BTreeSet::new();
+    collect_extension_names(&gltf, &mut names);
+    for key in &["extensionsUsed", "extensionsRequired"] {
+        if let Some(extensions) = gltf.get_mut(*key).and_then(|v| v.as_array_mut()) {
+            extensions.retain(|extension| match extension.as_str() {
+                Some(name @ "KHR_texture_basisu")
+                | Some(name @ "EXT_texture_webp")
+                | Some(name @ "KHR_materials_unlit") => names.contains(name),
+                _ => true,
+            });
+        }
+        if gltf
+            .get(*key)
+            .and_then(|v| v.as_array())
+            .map(|v| v.is_empty())
+            == Some(true)
+        {
+            gltf.as_object_mut().map(|g| g.remove(*key));
         }
-    } else {
-        gltf["extensionsUsed"] = vec!["VRM"].into();
     }
+    gltf
+}
+
+pub fn fix_extension_vrm(gltf_: Value) -> Value {
+    let mut gltf = gltf_.clone();
+
+    use_extension(&mut gltf, "VRM", false);
 
     if !gltf
         .pointer("extensions/VRM/meta")
@@ -326,32 +655,152 @@ pub fn fix_extension_vrm(gltf_: Value) -> Value {
     gltf
 }
 
-pub fn clean(gltf: Value) -> Value {
-    let (gltf, _) = clean_resources!(for_each_material_index_references, "/materials", gltf);
-    let (gltf, _) = clean_resources!(for_each_texture_index_references, "/textures", gltf);
-    let (gltf, _) = clean_resources!(for_each_image_index_references, "/images", gltf);
-    let (gltf, _) = clean_resources!(for_each_accessor_index_references, "/accessors", gltf);
-    let (gltf, _) = clean_resources!(for_each_sampler_index_references, "/samplers", gltf);
-    let (gltf, _) = clean_resources!(for_each_buffer_view_index_references, "/bufferViews", gltf);
-    fix_extension_vrm(gltf)
+// 削除されたリソースの元の番号
+fn removed_indexes(gltf: &Value, resource_pointer: &str, remaining_indexes: &[u64]) -> Vec<u64> {
+    let len = gltf
+        .pointer(resource_pointer)
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0) as u64;
+    (0..len)
+        .filter(|index| !remaining_indexes.contains(index))
+        .collect()
+}
+
+/// 参照されていないリソースを削除し、削除したリソースの元の番号を返す
+pub fn clean_with_removed(gltf_: Value) -> (Value, Vec<(&'static str, Vec<u64>)>) {
+    let mut gltf = gltf_;
+    let mut removed = Vec::new();
+    macro_rules! clean_and_record {
+        ($generator_function: ident, $resource_pointer: expr $(, $paired_pointer: expr)*) => {
+            let (cleaned, remaining_indexes) = clean_resources!(
+                $generator_function,
+                $resource_pointer,
+                [$($paired_pointer),*],
+                gltf
+            );
+            removed.push((
+                $resource_pointer,
+                removed_indexes(&gltf, $resource_pointer, &remaining_indexes),
+            ));
+            gltf = cleaned;
+        };
+    }
+    clean_and_record!(for_each_mesh_index_references, "/meshes");
+    // VRMのmaterialPropertiesはglTFのマテリアルと同じ番号のものが対応する
+    clean_and_record!(
+        for_each_material_index_references,
+        "/materials",
+        "/extensions/VRM/materialProperties"
+    );
+    clean_and_record!(for_each_texture_index_references, "/textures");
+    clean_and_record!(for_each_image_index_references, "/images");
+    clean_and_record!(for_each_skin_index_references, "/skins");
+    clean_and_record!(for_each_accessor_index_references, "/accessors");
+    clean_and_record!(for_each_sampler_index_references, "/samplers");
+    clean_and_record!(for_each_buffer_view_index_references, "/bufferViews");
+    let gltf = fix_extensions_used(gltf);
+    // VRM拡張を削除した場合は通常のglTFとして出力する
+    let gltf = if gltf.pointer("/extensions/VRM").is_some() {
+        fix_extension_vrm(gltf)
+    } else {
+        gltf
+    };
+    (gltf, removed)
+}
+
+/// どこからも参照されていないノードを削除する
+pub fn clean_nodes(gltf: Value) -> Value {
+    let (gltf, _) = clean_resources!(for_each_node_index_references, "/nodes", gltf);
+    gltf
 }
 
 #[derive(Clone)]
 pub struct BufferViewRegion {
     byte_offset: u64,
     byte_length: u64,
+    /// 詰めた後のオフセット
+    relocated_byte_offset: u64,
 }
 
-pub fn relocate_buffers(gltf_: Value) -> (Value, BufferRelocator) {
-    let gltf = gltf_.clone();
-    let (mut gltf, remaining_chunk_indexes) =
-        clean_resources!(for_each_buffer_index_references, "/buffers", gltf);
-    let mut buffer_view_regions_by_index = Vec::new();
+// bufferViewに必要なアラインメント
+// 参照しているaccessorの要素の大きさ。頂点属性は4バイト
+fn buffer_view_alignments(gltf: &Value) -> Vec<u64> {
+    let buffer_views = gltf
+        .get("bufferViews")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let mut alignments = buffer_views
+        .iter()
+        .map(|buffer_view| {
+            if buffer_view.get("byteStride").is_some()
+                || buffer_view.get("target").and_then(|v| v.as_u64()) == Some(ARRAY_BUFFER)
+            {
+                4
+            } else {
+                1
+            }
+        })
+        .collect::<Vec<_>>();
 
-    for buffer_view in gltf
+    let mut align = |buffer_view: Option<&Value>, component_type: Option<&Value>| {
+        if let (Some(buffer_view), Some(size)) = (
+            buffer_view.and_then(|v| v.as_u64()),
+            component_type
+                .and_then(|v| v.as_u64())
+                .and_then(component_size),
+        ) {
+            if let Some(alignment) = alignments.get_mut(buffer_view as usize) {
+                *alignment = (*alignment).max(size as u64);
+            }
+        }
+    };
+    for accessor in gltf
+        .get("accessors")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        align(accessor.get("bufferView"), accessor.get("componentType"));
+        if let Some(sparse) = accessor.get("sparse") {
+            align(
+                sparse.pointer("/indices/bufferView"),
+                sparse.pointer("/indices/componentType"),
+            );
+            align(
+                sparse.pointer("/values/bufferView"),
+                accessor.get("componentType"),
+            );
+        }
+    }
+    alignments
+}
+
+// バッファごとの(byteOffset, byteLength, アラインメント, bufferViewの番号)
+// bufferViewの数以降の番号はEXT_meshopt_compressionの圧縮データで、4バイトに揃える
+fn buffer_views_by_buffer(gltf: &Value) -> Vec<Vec<(u64, u64, u64, usize)>> {
+    let mut alignments = buffer_view_alignments(gltf);
+    let buffer_views = gltf
         .get("bufferViews")
         .and_then(|v| v.as_array())
-        .unwrap_or(&mut Vec::new())
+        .cloned()
+        .unwrap_or_default();
+    let compressed_regions = buffer_views
+        .iter()
+        .map(|buffer_view| {
+            buffer_view
+                .pointer(MESHOPT_COMPRESSION)
+                .cloned()
+                .unwrap_or(Value::Null)
+        })
+        .collect::<Vec<_>>();
+    alignments.resize(buffer_views.len() * 2, 4);
+
+    let mut buffer_views_by_index = Vec::new();
+    for (buffer_view_index, buffer_view) in buffer_views
+        .iter()
+        .chain(compressed_regions.iter())
+        .enumerate()
     {
         if let (Some(buffer), byte_offset, Some(byte_length)) = (
             buffer_view.get("buffer").and_then(|v| v.as_u64()),
@@ -361,139 +810,101 @@ pub fn relocate_buffers(gltf_: Value) -> (Value, BufferRelocator) {
                 .unwrap_or(0),
             buffer_view.get("byteLength").and_then(|v| v.as_u64()),
         ) {
-            //println!("buffer_view: {} {} {}", buffer, byte_offset, byte_length);
-            while buffer_view_regions_by_index.len() <= buffer as usize {
-                buffer_view_regions_by_index.push(Vec::new());
+            while buffer_views_by_index.len() <= buffer as usize {
+                buffer_views_by_index.push(Vec::new());
             }
-
-            buffer_view_regions_by_index
-                .get_mut(buffer as usize)
-                .map(|buffer_view_regions| {
-                    buffer_view_regions.push(BufferViewRegion {
-                        byte_offset,
-                        byte_length,
-                    })
-                });
+            buffer_views_by_index[buffer as usize].push((
+                byte_offset,
+                byte_length,
+                alignments[buffer_view_index],
+                buffer_view_index,
+            ));
         }
     }
+    buffer_views_by_index
+}
 
-    buffer_view_regions_by_index = buffer_view_regions_by_index
-        .iter()
-        .map(|buffer_view_regions_| {
-            let mut buffer_view_regions = buffer_view_regions_.clone();
-            buffer_view_regions.sort_by(|l, r| {
-                l.byte_offset
-                    .cmp(&r.byte_offset)
-                    .then(l.byte_length.cmp(&r.byte_length))
-            });
-            buffer_view_regions
-        })
-        .collect::<Vec<_>>();
+// buffer_views_by_bufferの番号が指すbufferViewか圧縮データ
+fn buffer_view_region_mut(gltf: &mut Value, index: usize) -> &mut Value {
+    let buffer_view_len = gltf
+        .get("bufferViews")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+    if index < buffer_view_len {
+        &mut gltf["bufferViews"][index]
+    } else {
+        gltf["bufferViews"][index - buffer_view_len]
+            .pointer_mut(MESHOPT_COMPRESSION)
+            .unwrap()
+    }
+}
 
-    let mut deleted_buffer_view_regions_by_index = Vec::new();
-    for buffer_view_regions in &buffer_view_regions_by_index {
-        //println!("buffer_view_regions: len={}", buffer_view_regions.len());
-        let mut deleted_buffer_view_regions = Vec::new();
-        let mut next_offset = 0;
-        for buffer_view_region in buffer_view_regions {
-            // TODO: accessorからアラインメントを取得
-            let alignment = 8;
-            let aligned_next_offset = (next_offset + alignment - 1) / alignment * alignment;
-            let aligned_byte_offset = buffer_view_region.byte_offset / alignment * alignment;
-            if aligned_next_offset < aligned_byte_offset {
-                //println!(
-                //    "deleted buffer view region: start={}->{} end={}->{} len={}",
-                //    next_offset,
-                //    aligned_next_offset,
-                //    buffer_view_region.byte_offset,
-                //    aligned_byte_offset,
-                //    aligned_byte_offset - aligned_next_offset,
-                //);
-
-                deleted_buffer_view_regions.push(BufferViewRegion {
-                    byte_offset: aligned_next_offset,
-                    byte_length: aligned_byte_offset - aligned_next_offset,
-                });
-            }
-            if next_offset < buffer_view_region.byte_offset + buffer_view_region.byte_length {
-                next_offset = buffer_view_region.byte_offset + buffer_view_region.byte_length;
+// 重なっているbufferViewをまとめる
+// (開始位置, 終了位置, [(byteOffset, アラインメント, bufferViewの番号)])
+fn buffer_view_clusters(
+    buffer_views_: &[(u64, u64, u64, usize)],
+) -> Vec<(u64, u64, Vec<(u64, u64, usize)>)> {
+    let mut buffer_views = buffer_views_.to_vec();
+    buffer_views.sort();
+    let mut clusters: Vec<(u64, u64, Vec<(u64, u64, usize)>)> = Vec::new();
+    for (byte_offset, byte_length, alignment, buffer_view_index) in buffer_views {
+        let end = byte_offset + byte_length;
+        if let Some(cluster) = clusters.last_mut() {
+            if byte_offset < cluster.1 {
+                cluster.1 = cluster.1.max(end);
+                cluster.2.push((byte_offset, alignment, buffer_view_index));
+                continue;
             }
         }
-        deleted_buffer_view_regions_by_index.push(deleted_buffer_view_regions);
+        clusters.push((
+            byte_offset,
+            end,
+            vec![(byte_offset, alignment, buffer_view_index)],
+        ));
     }
+    clusters
+}
 
-    for buffer_view in gltf
-        .get_mut("bufferViews")
-        .and_then(|v| v.as_array_mut())
-        .unwrap_or(&mut Vec::new())
-    {
-        if let (Some(buffer), byte_offset) = (
-            buffer_view.get_mut("buffer").and_then(|v| v.as_u64()),
-            buffer_view
-                .get_mut("byteOffset")
-                .and_then(|v| v.as_u64())
-                .unwrap_or(0),
-        ) {
-            deleted_buffer_view_regions_by_index
-                .get(buffer as usize)
-                .map(|deleted_buffer_views| {
-                    let shift = deleted_buffer_views
-                        .iter()
-                        .filter(|deleted_buffer_view| {
-                            deleted_buffer_view.byte_offset + deleted_buffer_view.byte_length
-                                <= byte_offset
-                        })
-                        .fold(0, |sum, deleted_buffer_view| {
-                            sum + deleted_buffer_view.byte_length
-                        });
-                    buffer_view["byteOffset"] = (byte_offset - shift).into();
-                });
-        }
-    }
+// next_offset以降でまとめたbufferViewを置く位置
+// 最もアラインメントが大きいbufferViewに合わせる。他のbufferViewとの相対位置は変えない
+fn aligned_cluster_offset(next_offset: u64, start: u64, members: &[(u64, u64, usize)]) -> u64 {
+    let (relative_offset, alignment) = members
+        .iter()
+        .map(|(byte_offset, alignment, _)| (byte_offset - start, *alignment))
+        .max_by_key(|(_, alignment)| *alignment)
+        .unwrap_or((0, 1));
+    (next_offset + relative_offset + alignment - 1) / alignment * alignment - relative_offset
+}
 
-    let mut remaining_buffer_view_regions_by_index = Vec::new();
-    for (index, buffer_view_regions) in buffer_view_regions_by_index.iter().enumerate() {
-        //println!("remaining_buffer_view_region: {}", index);
-        let buffer_length = if let Some(buffer_view_region) = buffer_view_regions.last() {
-            let alignment = 4;
-            (buffer_view_region.byte_offset + buffer_view_region.byte_length + alignment - 1)
-                / alignment
-                * alignment
-        } else {
-            remaining_buffer_view_regions_by_index.push(Vec::new());
-            continue;
-        };
+/// bufferViewから参照されていない領域を削除し、アラインメントを保ったままバッファを詰める
+/// 重なっているbufferViewはまとめて移動する。EXT_meshopt_compressionの圧縮データも同じように詰める
+pub fn relocate_buffers(gltf_: Value) -> (Value, BufferRelocator) {
+    let gltf = gltf_.clone();
+    let (mut gltf, remaining_chunk_indexes) =
+        clean_resources!(for_each_buffer_index_references, "/buffers", gltf);
+    let buffer_views_by_index = buffer_views_by_buffer(&gltf);
 
-        let mut byte_offset = 0;
+    let mut remaining_buffer_view_regions_by_index = Vec::new();
+    for (buffer_index, buffer_views) in buffer_views_by_index.iter().enumerate() {
+        let mut next_offset = 0;
         let mut remaining_buffer_view_regions = Vec::new();
-        for deleted_buffer_view_region in deleted_buffer_view_regions_by_index
-            .get(index)
-            .unwrap_or(&Vec::new())
-        {
-            if byte_offset < deleted_buffer_view_region.byte_offset {
-                //println!(
-                //    "add {} {}",
-                //    byte_offset,
-                //    deleted_buffer_view_region.byte_offset - byte_offset
-                //);
-                remaining_buffer_view_regions.push(BufferViewRegion {
-                    byte_offset,
-                    byte_length: deleted_buffer_view_region.byte_offset - byte_offset,
-                });
+        for (start, end, members) in buffer_view_clusters(buffer_views) {
+            let relocated_byte_offset = aligned_cluster_offset(next_offset, start, &members);
+            for (byte_offset, _, buffer_view_index) in members {
+                buffer_view_region_mut(&mut gltf, buffer_view_index)["byteOffset"] =
+                    (relocated_byte_offset + byte_offset - start).into();
             }
-            byte_offset =
-                deleted_buffer_view_region.byte_offset + deleted_buffer_view_region.byte_length
-        }
-        if buffer_length > byte_offset {
             remaining_buffer_view_regions.push(BufferViewRegion {
-                byte_offset,
-                byte_length: buffer_length - byte_offset,
+                byte_offset: start,
+                byte_length: end - start,
+                relocated_byte_offset,
             });
+            next_offset = relocated_byte_offset + end - start;
+        }
+        if next_offset > 0 {
+            gltf["buffers"][buffer_index]["byteLength"] = next_offset.into();
         }
-        gltf["buffers"][index]["byteLength"] = remaining_buffer_view_regions
-            .iter()
-            .fold(0, |sum, r| sum + r.byte_length)
-            .into();
         remaining_buffer_view_regions_by_index.push(remaining_buffer_view_regions);
     }
 
@@ -522,85 +933,575 @@ impl BufferRelocator {
         }
     }
 
-    pub fn total_chunk_bytes(&self) -> u32 {
-        self.remaining_buffer_view_regions_by_index
-            .iter()
-            .fold(0, |s, r| {
-                s + 8 + r.iter().fold(0, |ss, rr| ss + rr.byte_length as u32)
-            })
+    pub fn relocate(&self, chunks: &[Vec<u8>]) -> Vec<Vec<u8>> {
+        let mut relocated_chunks = Vec::new();
+        for (chunk_index, chunk) in chunks.iter().enumerate() {
+            if !self.remaining_chunk_indexes.contains(&(chunk_index as u64)) {
+                continue;
+            }
+
+            let mut chunk_bytes = Vec::new();
+            for remaining_buffer_view_region in self
+                .remaining_buffer_view_regions_by_index
+                .get(relocated_chunks.len())
+                .unwrap_or(&Vec::new())
+            {
+                let read_start = remaining_buffer_view_region.byte_offset as usize;
+                let read_bytes = remaining_buffer_view_region.byte_length as usize;
+                let read_end = read_start + read_bytes;
+                // アラインメントのための隙間は0で埋める
+                chunk_bytes.resize(
+                    remaining_buffer_view_region.relocated_byte_offset as usize,
+                    0,
+                );
+                if read_end > chunk.len() {
+                    // 実際に読めるサイズが必要とされるサイズと違うことがある？
+                    let actual_read_bytes = chunk.len().saturating_sub(read_start);
+                    warn!(
+                        target: TRUNCATED_CHUNK,
+                        "read expected={} bytes, actual={} bytes",
+                        read_bytes, actual_read_bytes
+                    );
+                    if actual_read_bytes > 0 {
+                        chunk_bytes.extend_from_slice(&chunk[read_start..]);
+                    }
+                    chunk_bytes.resize(chunk_bytes.len() + read_bytes - actual_read_bytes, 0);
+                } else {
+                    chunk_bytes.extend_from_slice(&chunk[read_start..read_end]);
+                }
+            }
+            chunk_bytes.resize((chunk_bytes.len() + 3) / 4 * 4, 0);
+            relocated_chunks.push(chunk_bytes);
+        }
+        relocated_chunks
     }
+}
 
-    pub fn relocate<R>(
-        &self,
-        mut reader: R,
-        total_bytes: u32,
-    ) -> Result<Vec<Vec<u8>>, Box<std::error::Error>>
-    where
-        R: Read,
+/// bufferViewの用途。詰め直すときはこの順に並べる
+/// 0: インデックス, 1: 頂点属性, 2: モーフターゲット, 3: その他のアクセサー, 4: 画像, 5: その他
+pub fn buffer_view_categories(gltf: &Value) -> Vec<u8> {
+    let accessor_len = gltf
+        .get("accessors")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+    let mut accessor_categories = vec![3; accessor_len];
     {
-        let mut chunk_index = 0;
-        let mut offset = 0;
-        let mut chunks = Vec::new();
-        println!("relocate: {}/{}", offset, total_bytes);
-        while offset < total_bytes {
-            let chunk_length = reader.read_u32::<LE>()?;
-            let chunk_type = reader.read_u32::<LE>()?;
-            assert_eq!(chunk_type, CHUNK_TYPE);
-
-            if self.remaining_chunk_indexes.contains(&chunk_index) {
-                let mut chunk_bytes = Vec::new();
-                let mut chunk_offset = 0;
-                for remaining_buffer_view_region in self
-                    .remaining_buffer_view_regions_by_index
-                    .get(chunk_index as usize)
+        let mut categorize = |accessor: Option<&Value>, category: u8| {
+            if let Some(c) = accessor
+                .and_then(|v| v.as_u64())
+                .and_then(|v| accessor_categories.get_mut(v as usize))
+            {
+                *c = (*c).min(category);
+            }
+        };
+        for mesh in gltf
+            .get("meshes")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+        {
+            for primitive in mesh
+                .get("primitives")
+                .and_then(|v| v.as_array())
+                .unwrap_or(&Vec::new())
+            {
+                categorize(primitive.get("indices"), 0);
+                for accessor in primitive
+                    .get("attributes")
+                    .and_then(|v| v.as_object())
+                    .map(|v| v.values().collect::<Vec<_>>())
+                    .unwrap_or_default()
+                {
+                    categorize(Some(accessor), 1);
+                }
+                for target in primitive
+                    .get("targets")
+                    .and_then(|v| v.as_array())
                     .unwrap_or(&Vec::new())
                 {
-                    if chunk_offset < remaining_buffer_view_region.byte_offset as usize {
-                        let skip_bytes =
-                            remaining_buffer_view_region.byte_offset as usize - chunk_offset;
-                        let mut skip = Vec::new();
-                        skip.resize(skip_bytes, 0);
-                        println!("skip {}", skip.len());
-                        reader.read_exact(&mut skip)?;
-                        chunk_offset += skip_bytes;
-                    }
-                    let read_start = chunk_bytes.len();
-                    let read_bytes = remaining_buffer_view_region.byte_length as usize;
-                    chunk_bytes.resize(chunk_bytes.len() + read_bytes, 0);
-                    let mut actual_read_bytes = 0;
-                    loop {
-                        // 実際に読めるサイズが必要とされるサイズと違うことがある？
-                        let n = reader.read(&mut chunk_bytes[(read_start + actual_read_bytes)..(read_start + read_bytes)])?;
-                        actual_read_bytes += n;
-                        if actual_read_bytes == read_bytes {
-                            break;
-                        }
-                        if n == 0 {
-                            println!("read expected={} bytes, actual={} bytes", read_bytes, actual_read_bytes);
-                            break
-                        }
+                    for accessor in target
+                        .as_object()
+                        .map(|v| v.values().collect::<Vec<_>>())
+                        .unwrap_or_default()
+                    {
+                        categorize(Some(accessor), 2);
                     }
-                    chunk_offset += read_bytes;
                 }
-                if chunk_offset < chunk_length as usize {
-                    let mut skip_bytes = Vec::new();
-                    skip_bytes.resize(chunk_length as usize - chunk_offset, 0);
-                    println!("skip {}", skip_bytes.len());
-                    reader.read_exact(&mut skip_bytes)?;
-                    //println!("last chunk skipped: len={}", skip_bytes.len());
+            }
+        }
+    }
+
+    let buffer_view_len = gltf
+        .get("bufferViews")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+    let mut categories = vec![5; buffer_view_len];
+    {
+        let mut categorize = |buffer_view: Option<&Value>, category: u8| {
+            if let Some(c) = buffer_view
+                .and_then(|v| v.as_u64())
+                .and_then(|v| categories.get_mut(v as usize))
+            {
+                *c = (*c).min(category);
+            }
+        };
+        for (accessor, category) in gltf
+            .get("accessors")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+            .iter()
+            .zip(accessor_categories.iter())
+        {
+            categorize(accessor.get("bufferView"), *category);
+            categorize(accessor.pointer("/sparse/indices/bufferView"), *category);
+            categorize(accessor.pointer("/sparse/values/bufferView"), *category);
+        }
+        for image in gltf
+            .get("images")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+        {
+            categorize(image.get("bufferView"), 4);
+        }
+    }
+    categories
+}
+
+/// 全てのbufferViewを1つのバッファに詰め直す
+/// インデックス、頂点属性、モーフターゲット、画像の順に並べ、アラインメントを揃える
+/// 重なっているbufferViewはまとめて移動する
+pub fn repack_buffers(gltf_: Value, chunks: &[Vec<u8>]) -> (Value, Vec<Vec<u8>>) {
+    let mut gltf = gltf_.clone();
+    let categories = buffer_view_categories(&gltf);
+
+    // (用途, バッファの番号, 開始位置, 終了位置, まとめたbufferView)
+    let mut clusters = Vec::new();
+    for (buffer_index, buffer_views) in buffer_views_by_buffer(&gltf).iter().enumerate() {
+        for (start, end, members) in buffer_view_clusters(buffer_views) {
+            let category = members
+                .iter()
+                .filter_map(|(_, _, buffer_view_index)| categories.get(*buffer_view_index))
+                .cloned()
+                .min()
+                .unwrap_or(5);
+            clusters.push((category, buffer_index, start, end, members));
+        }
+    }
+    clusters
+        .sort_by_key(|(category, buffer_index, start, _, _)| (*category, *buffer_index, *start));
+
+    let mut bytes = Vec::new();
+    for (_, buffer_index, start, end, members) in clusters {
+        let relocated_byte_offset = aligned_cluster_offset(bytes.len() as u64, start, &members);
+        for (byte_offset, _, buffer_view_index) in members {
+            let buffer_view = buffer_view_region_mut(&mut gltf, buffer_view_index);
+            buffer_view["buffer"] = 0.into();
+            buffer_view["byteOffset"] = (relocated_byte_offset + byte_offset - start).into();
+        }
+
+        bytes.resize(relocated_byte_offset as usize, 0);
+        let chunk = chunks
+            .get(buffer_index)
+            .map(|v| v.as_slice())
+            .unwrap_or(&[]);
+        let read_start = (start as usize).min(chunk.len());
+        let read_end = (end as usize).min(chunk.len());
+        if read_end - read_start < (end - start) as usize {
+            warn!(
+                target: TRUNCATED_CHUNK,
+                "buffer {}: read expected={} bytes, actual={} bytes",
+                buffer_index,
+                end - start,
+                read_end - read_start
+            );
+        }
+        bytes.extend_from_slice(&chunk[read_start..read_end]);
+        bytes.resize((relocated_byte_offset + end - start) as usize, 0);
+    }
+    bytes.resize((bytes.len() + 3) / 4 * 4, 0);
+
+    if let Some(gltf) = gltf.as_object_mut() {
+        gltf.remove("buffers");
+    }
+    if bytes.is_empty() {
+        return (gltf, Vec::new());
+    }
+    let mut buffer = serde_json::Map::new();
+    buffer.insert("byteLength".into(), bytes.len().into());
+    gltf["buffers"] = vec![Value::from(buffer)].into();
+    (gltf, vec![bytes])
+}
+
+/// バイナリチャンクを全て読み込む
+pub fn read_chunks<R>(
+    mut reader: R,
+    total_bytes: u32,
+) -> Result<Vec<Vec<u8>>, Box<std::error::Error>>
+where
+    R: Read,
+{
+    let mut offset = 0;
+    let mut chunks = Vec::new();
+    debug!("relocate: {}/{}", offset, total_bytes);
+    while offset < total_bytes {
+        let chunk_length = reader.read_u32::<LE>()?;
+        let chunk_type = reader.read_u32::<LE>()?;
+        if chunk_type != CHUNK_TYPE {
+            return Err(format!("chunk is not BIN: type {:#x}", chunk_type).into());
+        }
+        let next_offset = offset
+            .checked_add(8)
+            .and_then(|v| v.checked_add(chunk_length))
+            .filter(|v| *v <= total_bytes)
+            .ok_or_else(|| {
+                format!(
+                    "chunk length {} exceeds file length: {}/{}",
+                    chunk_length, offset, total_bytes
+                )
+            })?;
+
+        let mut chunk_bytes = Vec::new();
+        chunk_bytes.resize(chunk_length as usize, 0);
+        reader.read_exact(&mut chunk_bytes)?;
+        chunks.push(chunk_bytes);
+        offset = next_offset;
+    }
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MToonのテクスチャスロット
+    const MTOON_TEXTURE_SLOTS: [&str; 10] = [
+        "_MainTex",
+        "_ShadeTexture",
+        "_BumpMap",
+        "_ReceiveShadowTexture",
+        "_ShadingGradeTexture",
+        "_RimTexture",
+        "_SphereAdd",
+        "_EmissionMap",
+        "_OutlineWidthTexture",
+        "_UvAnimMaskTexture",
+    ];
+
+    // 0番目のテクスチャは参照されず、i+1番目のテクスチャをMToonのi番目のスロットが参照するVRM
+    fn mtoon_gltf() -> Value {
+        let len = MTOON_TEXTURE_SLOTS.len() + 1;
+        let images = (0..len)
+            .map(|i| {
+                let mut image = serde_json::Map::new();
+                image.insert("name".into(), format!("image{}", i).into());
+                image.insert("uri".into(), format!("image{}.png", i).into());
+                image.into()
+            })
+            .collect::<Vec<Value>>();
+        let textures = (0..len)
+            .map(|i| {
+                let mut texture = serde_json::Map::new();
+                texture.insert("source".into(), i.into());
+                texture.into()
+            })
+            .collect::<Vec<Value>>();
+        let mut texture_properties = serde_json::Map::new();
+        for (i, slot) in MTOON_TEXTURE_SLOTS.iter().enumerate() {
+            texture_properties.insert(slot.to_string(), (i + 1).into());
+        }
+        let gltf = format!(
+            r#"{{
+                "asset": {{"version": "2.0"}},
+                "scenes": [{{"nodes": [0]}}],
+                "nodes": [{{"mesh": 0}}],
+                "meshes": [{{"primitives": [{{"attributes": {{}}, "material": 0}}]}}],
+                "materials": [{{"name": "mtoon"}}],
+                "textures": {},
+                "images": {},
+                "extensionsUsed": ["VRM"],
+                "extensions": {{"VRM": {{"materialProperties": [{{
+                    "name": "mtoon",
+                    "shader": "VRM/MToon",
+                    "textureProperties": {}
+                }}]}}}}
+            }}"#,
+            Value::from(textures),
+            Value::from(images),
+            Value::from(texture_properties)
+        );
+        serde_json::from_str(&gltf).unwrap()
+    }
+
+    #[test]
+    fn clean_keeps_all_mtoon_textures() {
+        let (gltf, _) = clean_with_removed(mtoon_gltf());
+        let textures = gltf["textures"].as_array().unwrap();
+        assert_eq!(textures.len(), MTOON_TEXTURE_SLOTS.len());
+        let texture_properties =
+            &gltf["extensions"]["VRM"]["materialProperties"][0]["textureProperties"];
+        for (i, slot) in MTOON_TEXTURE_SLOTS.iter().enumerate() {
+            let texture = texture_properties[*slot].as_u64().unwrap();
+            assert_eq!(texture, i as u64, "{}", slot);
+            let image = textures[texture as usize]["source"].as_u64().unwrap();
+            assert_eq!(
+                gltf["images"][image as usize]["name"],
+                Value::from(format!("image{}", i + 1)),
+                "{}",
+                slot
+            );
+        }
+    }
+
+    // 0, 1, 2...を並べたバッファを1つ持つglTF
+    fn buffer_gltf(buffer_views: &str, accessors: &str, chunk_len: usize) -> (Value, Vec<u8>) {
+        let gltf = format!(
+            r#"{{
+                "asset": {{"version": "2.0"}},
+                "buffers": [{{"byteLength": {}}}],
+                "bufferViews": {},
+                "accessors": {}
+            }}"#,
+            chunk_len, buffer_views, accessors
+        );
+        let chunk = (0..chunk_len).map(|i| i as u8).collect();
+        (serde_json::from_str(&gltf).unwrap(), chunk)
+    }
+
+    fn byte_range(buffer_view: &Value) -> (usize, usize) {
+        let byte_offset = buffer_view["byteOffset"].as_u64().unwrap_or(0) as usize;
+        let byte_length = buffer_view["byteLength"].as_u64().unwrap() as usize;
+        (byte_offset, byte_offset + byte_length)
+    }
+
+    // 詰めたbufferViewがアラインメントを満たし、元と同じバイト列を指し、重なっていたbufferViewの相対位置が変わらない
+    fn assert_relocated(gltf: Value, chunk: Vec<u8>, alignments: &[u64]) {
+        assert_eq!(buffer_view_alignments(&gltf), alignments);
+        let (relocated_gltf, buffer_relocator) = relocate_buffers(gltf.clone());
+        let relocated_chunks = buffer_relocator.relocate(&[chunk.clone()]);
+        assert_eq!(relocated_chunks.len(), 1);
+        let relocated_chunk = &relocated_chunks[0];
+        assert_eq!(relocated_chunk.len() % 4, 0);
+        assert!(relocated_chunk.len() <= chunk.len());
+
+        let buffer_views = gltf["bufferViews"].as_array().unwrap();
+        let relocated_buffer_views = relocated_gltf["bufferViews"].as_array().unwrap();
+        for (i, (buffer_view, relocated_buffer_view)) in buffer_views
+            .iter()
+            .zip(relocated_buffer_views.iter())
+            .enumerate()
+        {
+            let (start, end) = byte_range(buffer_view);
+            let (relocated_start, relocated_end) = byte_range(relocated_buffer_view);
+            assert_eq!(
+                relocated_start as u64 % alignments[i],
+                0,
+                "bufferView {}",
+                i
+            );
+            assert_eq!(
+                &relocated_chunk[relocated_start..relocated_end],
+                &chunk[start..end],
+                "bufferView {}",
+                i
+            );
+            for (j, other) in buffer_views.iter().enumerate() {
+                let (other_start, other_end) = byte_range(other);
+                if other_start < end && start < other_end {
+                    let (relocated_other_start, _) = byte_range(&relocated_buffer_views[j]);
+                    assert_eq!(
+                        relocated_other_start as i64 - relocated_start as i64,
+                        other_start as i64 - start as i64,
+                        "bufferView {} and {}",
+                        i,
+                        j
+                    );
                 }
-                chunk_bytes.resize((chunk_bytes.len() + 3) / 4 * 4, 0);
-                chunks.push(chunk_bytes);
-            } else {
-                let mut skip_bytes = Vec::new();
-                skip_bytes.resize(chunk_length as usize, 0);
-                println!("skip {}", skip_bytes.len());
-                reader.read_exact(&mut skip_bytes)?;
-                //println!("chunk skipped: {} len={}", chunk_index, chunk_length);
             }
-            offset += 8 + chunk_length;
-            chunk_index += 1;
         }
-        Ok(chunks)
+    }
+
+    #[test]
+    fn relocate_overlapping_buffer_views() {
+        let (gltf, chunk) = buffer_gltf(
+            r#"[
+                {"buffer": 0, "byteOffset": 5, "byteLength": 3},
+                {"buffer": 0, "byteOffset": 12, "byteLength": 16},
+                {"buffer": 0, "byteOffset": 20, "byteLength": 12},
+                {"buffer": 0, "byteOffset": 12, "byteLength": 16}
+            ]"#,
+            r#"[
+                {"bufferView": 1, "componentType": 5126, "count": 4, "type": "SCALAR"},
+                {"bufferView": 2, "componentType": 5126, "count": 3, "type": "SCALAR"},
+                {"bufferView": 3, "componentType": 5123, "count": 8, "type": "SCALAR"}
+            ]"#,
+            40,
+        );
+        assert_relocated(gltf, chunk, &[1, 4, 4, 2]);
+    }
+
+    #[test]
+    fn relocate_strided_array_buffer() {
+        let (gltf, chunk) = buffer_gltf(
+            r#"[
+                {"buffer": 0, "byteOffset": 1, "byteLength": 3},
+                {"buffer": 0, "byteOffset": 8, "byteLength": 24, "byteStride": 12, "target": 34962}
+            ]"#,
+            "[]",
+            36,
+        );
+        assert_relocated(gltf, chunk, &[1, 4]);
+    }
+
+    #[test]
+    fn relocate_sparse_accessor() {
+        let (gltf, chunk) = buffer_gltf(
+            r#"[
+                {"buffer": 0, "byteOffset": 0, "byteLength": 1},
+                {"buffer": 0, "byteOffset": 3, "byteLength": 4},
+                {"buffer": 0, "byteOffset": 9, "byteLength": 24}
+            ]"#,
+            r#"[{
+                "componentType": 5126,
+                "count": 4,
+                "type": "VEC3",
+                "sparse": {
+                    "count": 2,
+                    "indices": {"bufferView": 1, "componentType": 5123},
+                    "values": {"bufferView": 2}
+                }
+            }]"#,
+            36,
+        );
+        assert_relocated(gltf, chunk, &[1, 2, 4]);
+    }
+
+    #[test]
+    fn relocate_mixed_alignment_cluster() {
+        let (gltf, chunk) = buffer_gltf(
+            r#"[
+                {"buffer": 0, "byteOffset": 0, "byteLength": 2},
+                {"buffer": 0, "byteOffset": 5, "byteLength": 8},
+                {"buffer": 0, "byteOffset": 8, "byteLength": 8}
+            ]"#,
+            r#"[{"bufferView": 2, "componentType": 5126, "count": 2, "type": "SCALAR"}]"#,
+            20,
+        );
+        assert_relocated(gltf, chunk, &[1, 1, 4]);
+    }
+
+    #[test]
+    fn relocate_meshopt_compressed_regions() {
+        let gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "buffers": [
+                    {"byteLength": 20},
+                    {"byteLength": 40, "extensions": {"EXT_meshopt_compression": {"fallback": true}}}
+                ],
+                "bufferViews": [
+                    {"buffer": 0, "byteOffset": 1, "byteLength": 3},
+                    {"buffer": 1, "byteOffset": 24, "byteLength": 16, "extensions": {
+                        "EXT_meshopt_compression": {
+                            "buffer": 0, "byteOffset": 9, "byteLength": 5,
+                            "byteStride": 4, "count": 4, "mode": "ATTRIBUTES"
+                        }
+                    }}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let chunk = (0..20).map(|i| i as u8).collect::<Vec<_>>();
+        let (relocated_gltf, buffer_relocator) = relocate_buffers(gltf);
+        let relocated_chunks = buffer_relocator.relocate(&[chunk.clone()]);
+        assert_eq!(relocated_chunks.len(), 1);
+
+        // 圧縮データは4バイトに揃えて元のバッファに詰める
+        let extension = &relocated_gltf["bufferViews"][1]["extensions"]["EXT_meshopt_compression"];
+        assert_eq!(extension["buffer"], 0);
+        let (start, end) = byte_range(extension);
+        assert_eq!(start % 4, 0);
+        assert_eq!(&relocated_chunks[0][start..end], &chunk[9..14]);
+        let (start, end) = byte_range(&relocated_gltf["bufferViews"][0]);
+        assert_eq!(&relocated_chunks[0][start..end], &chunk[1..4]);
+        assert_eq!(relocated_gltf["buffers"][0]["byteLength"], 9);
+
+        // フォールバックのバッファは展開後の大きさだけを持つ
+        assert_eq!(relocated_gltf["bufferViews"][1]["buffer"], 1);
+        assert_eq!(relocated_gltf["bufferViews"][1]["byteOffset"], 0);
+        assert_eq!(relocated_gltf["buffers"][1]["byteLength"], 16);
+    }
+
+    #[test]
+    fn repack_two_buffers_by_usage() {
+        // 画像、頂点属性、インデックス、モーフターゲットが2つのバッファに散らばっている
+        let gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "buffers": [{"byteLength": 30}, {"byteLength": 20}],
+                "bufferViews": [
+                    {"buffer": 1, "byteOffset": 1, "byteLength": 7},
+                    {"buffer": 0, "byteOffset": 2, "byteLength": 12},
+                    {"buffer": 1, "byteOffset": 10, "byteLength": 6},
+                    {"buffer": 0, "byteOffset": 15, "byteLength": 12}
+                ],
+                "accessors": [
+                    {"bufferView": 1, "componentType": 5126, "count": 1, "type": "VEC3"},
+                    {"bufferView": 3, "componentType": 5126, "count": 1, "type": "VEC3"},
+                    {"bufferView": 2, "componentType": 5123, "count": 3, "type": "SCALAR"}
+                ],
+                "meshes": [{"primitives": [{
+                    "attributes": {"POSITION": 0},
+                    "targets": [{"POSITION": 1}],
+                    "indices": 2
+                }]}],
+                "images": [{"bufferView": 0, "mimeType": "image/png"}]
+            }"#,
+        )
+        .unwrap();
+        let chunks = vec![
+            (0..30).map(|i| i as u8).collect::<Vec<_>>(),
+            (100..120).map(|i| i as u8).collect::<Vec<_>>(),
+        ];
+        let alignments = buffer_view_alignments(&gltf);
+        assert_eq!(alignments, &[1, 4, 2, 4]);
+        let (repacked_gltf, repacked_chunks) = repack_buffers(gltf.clone(), &chunks);
+
+        assert_eq!(repacked_chunks.len(), 1);
+        let repacked_chunk = &repacked_chunks[0];
+        assert_eq!(repacked_chunk.len() % 4, 0);
+        assert_eq!(
+            repacked_gltf["buffers"],
+            serde_json::from_str::<Value>(&format!(
+                r#"[{{"byteLength": {}}}]"#,
+                repacked_chunk.len()
+            ))
+            .unwrap()
+        );
+        let buffer_views = gltf["bufferViews"].as_array().unwrap();
+        let repacked_buffer_views = repacked_gltf["bufferViews"].as_array().unwrap();
+        let mut starts = Vec::new();
+        for (i, (buffer_view, repacked_buffer_view)) in buffer_views
+            .iter()
+            .zip(repacked_buffer_views.iter())
+            .enumerate()
+        {
+            let buffer = buffer_view["buffer"].as_u64().unwrap() as usize;
+            let (start, end) = byte_range(buffer_view);
+            let (repacked_start, repacked_end) = byte_range(repacked_buffer_view);
+            assert_eq!(repacked_buffer_view["buffer"], 0, "bufferView {}", i);
+            assert_eq!(repacked_start as u64 % alignments[i], 0, "bufferView {}", i);
+            assert_eq!(
+                &repacked_chunk[repacked_start..repacked_end],
+                &chunks[buffer][start..end],
+                "bufferView {}",
+                i
+            );
+            starts.push(repacked_start);
+        }
+        // インデックス、頂点属性、モーフターゲット、画像の順に並ぶ
+        assert!(starts[2] < starts[1]);
+        assert!(starts[1] < starts[3]);
+        assert!(starts[3] < starts[0]);
     }
 }
@@ -1,27 +1,458 @@
-//#!/usr/bin/env python
-//# -*- coding: utf-8 -*-
-//
-//
-//def print_stat(gltf):
-//    """
-//    モデル情報表示
-//    :param gltf: glTFオブジェクト
-//    """
-//    vrm = gltf['extensions']['VRM']
-//    print 'vrm materials:', len(vrm['materialProperties'])
-//    print 'materials:', len(gltf['materials'])
-//    print 'textures:', len(gltf['textures'])
-//    print 'images:', len(gltf['images'])
-//
-//    meshes = gltf['meshes']
-//    print 'meshes:', len(meshes)
-//    print 'primitives:', sum([len(m['primitives']) for m in meshes])
-//    for mesh in meshes:
-//        print '\t', mesh['name'], ':', len(mesh['primitives'])
-/*
-use gltf_json::Root;
-
-pub fn _print_stat(_gltf: &Root) {
-    //
-}
-*/
+use super::buffer::*;
+use super::cleaner::*;
+use super::ktx2::*;
+use image::GenericImageView;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+const TRIANGLES: u64 = 4;
+const TRIANGLE_STRIP: u64 = 5;
+const TRIANGLE_FAN: u64 = 6;
+
+// buffer_view_categoriesの用途の名前
+const BYTE_CATEGORIES: [&str; 6] = [
+    "indices",
+    "vertices",
+    "morph_targets",
+    "other_accessors",
+    "images",
+    "other",
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct ImageStats {
+    pub name: String,
+    pub mime_type: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// エンコードされた画像のバイト数
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MeshStats {
+    pub name: String,
+    pub primitives: usize,
+    pub triangles: usize,
+    pub vertices: usize,
+    pub morph_targets: usize,
+}
+
+/// モデル情報
+#[derive(Debug, Clone, Default)]
+pub struct Stats {
+    pub materials: usize,
+    pub vrm_materials: usize,
+    pub textures: usize,
+    pub images: Vec<ImageStats>,
+    pub meshes: Vec<MeshStats>,
+    pub joints: usize,
+    /// skinを持つノードが参照しているメッシュの数
+    pub skinned_meshes: usize,
+    pub spring_bone_groups: usize,
+    /// 揺れもののチェーンに含まれるノードの数
+    pub spring_bones: usize,
+    /// 用途ごとのバイナリのバイト数。jsonはJSONチャンクのバイト数
+    pub bytes: BTreeMap<String, usize>,
+}
+
+fn array_len(gltf: &Value, pointer: &str) -> usize {
+    gltf.pointer(pointer)
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0)
+}
+
+fn accessor_count(gltf: &Value, index: Option<&Value>) -> Option<usize> {
+    gltf.get("accessors")?
+        .get(index?.as_u64()? as usize)?
+        .get("count")?
+        .as_u64()
+        .map(|v| v as usize)
+}
+
+// WebPの画像サイズ
+// https://developers.google.com/speed/webp/docs/riff_container
+fn webp_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    let u24 = |b: &[u8]| u32::from(b[0]) | u32::from(b[1]) << 8 | u32::from(b[2]) << 16;
+    match bytes.get(12..16)? {
+        b"VP8X" => {
+            let b = bytes.get(24..30)?;
+            Some((u24(&b[0..3]) + 1, u24(&b[3..6]) + 1))
+        }
+        b"VP8L" => {
+            let b = bytes.get(21..25)?;
+            let bits = u32::from(b[0])
+                | u32::from(b[1]) << 8
+                | u32::from(b[2]) << 16
+                | u32::from(b[3]) << 24;
+            Some(((bits & 0x3fff) + 1, (bits >> 14 & 0x3fff) + 1))
+        }
+        b"VP8 " => {
+            let b = bytes.get(26..30)?;
+            Some((
+                (u32::from(b[0]) | u32::from(b[1]) << 8) & 0x3fff,
+                (u32::from(b[2]) | u32::from(b[3]) << 8) & 0x3fff,
+            ))
+        }
+        _ => None,
+    }
+}
+
+fn image_dimensions(bytes: &[u8], mime_type: &str) -> Option<(u32, u32)> {
+    match mime_type {
+        "image/ktx2" => read_ktx2_header(bytes)
+            .ok()
+            .map(|header| (header.pixel_width, header.pixel_height)),
+        "image/webp" => webp_dimensions(bytes),
+        _ => image::load_from_memory(bytes)
+            .ok()
+            .map(|image| image.dimensions()),
+    }
+}
+
+fn image_stats(gltf: &Value, chunks: &[Vec<u8>]) -> Vec<ImageStats> {
+    let mut images = Vec::new();
+    for image in gltf
+        .get("images")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        let mime_type = image
+            .get("mimeType")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        let bytes = image
+            .get("bufferView")
+            .and_then(|v| v.as_u64())
+            .and_then(|index| buffer_view_bytes(gltf, chunks, index))
+            .unwrap_or(&[]);
+        let dimensions = image_dimensions(bytes, &mime_type);
+        images.push(ImageStats {
+            name: image
+                .get("name")
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string(),
+            mime_type,
+            width: dimensions.map(|(width, _)| width),
+            height: dimensions.map(|(_, height)| height),
+            bytes: bytes.len(),
+        });
+    }
+    images
+}
+
+fn mesh_stats(gltf: &Value) -> Vec<MeshStats> {
+    let mut meshes = Vec::new();
+    for mesh in gltf
+        .get("meshes")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        let primitives = mesh
+            .get("primitives")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default();
+        let mut triangles = 0;
+        // 頂点属性を共有するプリミティブの頂点は1度だけ数える
+        let mut positions = BTreeSet::new();
+        let mut morph_targets = 0;
+        for primitive in &primitives {
+            let vertex_count =
+                accessor_count(gltf, primitive.pointer("/attributes/POSITION")).unwrap_or(0);
+            if let Some(position) = primitive
+                .pointer("/attributes/POSITION")
+                .and_then(|v| v.as_u64())
+            {
+                positions.insert((position, vertex_count));
+            }
+            let index_count =
+                accessor_count(gltf, primitive.get("indices")).unwrap_or(vertex_count);
+            triangles += match primitive
+                .get("mode")
+                .and_then(|v| v.as_u64())
+                .unwrap_or(TRIANGLES)
+            {
+                TRIANGLES => index_count / 3,
+                TRIANGLE_STRIP | TRIANGLE_FAN => index_count.saturating_sub(2),
+                _ => 0,
+            };
+            morph_targets = morph_targets.max(
+                primitive
+                    .get("targets")
+                    .and_then(|v| v.as_array())
+                    .map(|v| v.len())
+                    .unwrap_or(0),
+            );
+        }
+        meshes.push(MeshStats {
+            name: mesh
+                .get("name")
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string(),
+            primitives: primitives.len(),
+            triangles,
+            vertices: positions.iter().map(|(_, count)| count).sum(),
+            morph_targets,
+        });
+    }
+    meshes
+}
+
+fn joint_len(gltf: &Value) -> usize {
+    gltf.get("skins")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .flat_map(|skin| {
+            skin.get("joints")
+                .and_then(|v| v.as_array())
+                .cloned()
+                .unwrap_or_default()
+        })
+        .filter_map(|v| v.as_u64())
+        .collect::<BTreeSet<_>>()
+        .len()
+}
+
+fn skinned_mesh_len(gltf: &Value) -> usize {
+    gltf.get("nodes")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .filter(|node| node.get("skin").is_some())
+        .filter_map(|node| node.get("mesh").and_then(|v| v.as_u64()))
+        .collect::<BTreeSet<_>>()
+        .len()
+}
+
+// 揺れもののチェーンの根元とその子孫のノード数
+fn spring_bone_len(gltf: &Value) -> usize {
+    let mut nodes = BTreeSet::new();
+    let mut stack = gltf
+        .pointer("/extensions/VRM/secondaryAnimation/boneGroups")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .flat_map(|group| {
+            group
+                .get("bones")
+                .and_then(|v| v.as_array())
+                .cloned()
+                .unwrap_or_default()
+        })
+        .filter_map(|v| v.as_u64())
+        .collect::<Vec<_>>();
+    while let Some(node_index) = stack.pop() {
+        if !nodes.insert(node_index) {
+            continue;
+        }
+        for child in gltf
+            .get("nodes")
+            .and_then(|v| v.get(node_index as usize))
+            .and_then(|v| v.get("children"))
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+        {
+            if let Some(child) = child.as_u64() {
+                stack.push(child);
+            }
+        }
+    }
+    nodes.len()
+}
+
+// 用途ごとのbufferViewのバイト数。圧縮されたbufferViewは圧縮後のバイト数
+fn byte_stats(gltf: &Value) -> BTreeMap<String, usize> {
+    let mut bytes = BTreeMap::new();
+    for name in BYTE_CATEGORIES.iter() {
+        bytes.insert(name.to_string(), 0);
+    }
+    let categories = buffer_view_categories(gltf);
+    for (buffer_view, category) in gltf
+        .get("bufferViews")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .zip(categories.iter())
+    {
+        let byte_length = buffer_view
+            .pointer("/extensions/EXT_meshopt_compression/byteLength")
+            .or_else(|| buffer_view.get("byteLength"))
+            .and_then(|v| v.as_u64())
+            .unwrap_or(0) as usize;
+        *bytes
+            .entry(BYTE_CATEGORIES[*category as usize].to_string())
+            .or_insert(0) += byte_length;
+    }
+    bytes.insert("json".into(), gltf.to_string().len());
+    bytes
+}
+
+/// モデル情報を集計する
+pub fn collect_stats(gltf: &Value, chunks: &[Vec<u8>]) -> Stats {
+    Stats {
+        materials: array_len(gltf, "/materials"),
+        vrm_materials: array_len(gltf, "/extensions/VRM/materialProperties"),
+        textures: array_len(gltf, "/textures"),
+        images: image_stats(gltf, chunks),
+        meshes: mesh_stats(gltf),
+        joints: joint_len(gltf),
+        skinned_meshes: skinned_mesh_len(gltf),
+        spring_bone_groups: array_len(gltf, "/extensions/VRM/secondaryAnimation/boneGroups"),
+        spring_bones: spring_bone_len(gltf),
+        bytes: byte_stats(gltf),
+    }
+}
+
+impl Stats {
+    pub fn primitives(&self) -> usize {
+        self.meshes.iter().map(|m| m.primitives).sum()
+    }
+
+    pub fn triangles(&self) -> usize {
+        self.meshes.iter().map(|m| m.triangles).sum()
+    }
+
+    pub fn vertices(&self) -> usize {
+        self.meshes.iter().map(|m| m.vertices).sum()
+    }
+
+    pub fn morph_targets(&self) -> usize {
+        self.meshes.iter().map(|m| m.morph_targets).sum()
+    }
+
+    /// テクスチャをRGBA8、ミップマップ付きで展開したときのバイト数
+    pub fn texture_memory(&self) -> usize {
+        self.images
+            .iter()
+            .map(|image| {
+                let pixels = image.width.unwrap_or(0) as usize * image.height.unwrap_or(0) as usize;
+                pixels * 4 * 4 / 3
+            })
+            .sum()
+    }
+
+    pub fn total_bytes(&self) -> usize {
+        self.bytes.values().sum()
+    }
+
+    // 表とJSONに出す集計値
+    fn summary(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("materials", self.materials),
+            ("vrm_materials", self.vrm_materials),
+            ("textures", self.textures),
+            ("images", self.images.len()),
+            ("meshes", self.meshes.len()),
+            ("primitives", self.primitives()),
+            ("triangles", self.triangles()),
+            ("vertices", self.vertices()),
+            ("morph_targets", self.morph_targets()),
+            ("joints", self.joints),
+            ("skinned_meshes", self.skinned_meshes),
+            ("spring_bone_groups", self.spring_bone_groups),
+            ("spring_bones", self.spring_bones),
+            ("texture_memory", self.texture_memory()),
+        ]
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut json = serde_json::Map::new();
+        for (name, value) in self.summary() {
+            json.insert(name.into(), value.into());
+        }
+        let images = self
+            .images
+            .iter()
+            .map(|image| {
+                let mut value = serde_json::Map::new();
+                value.insert("name".into(), image.name.clone().into());
+                value.insert("mimeType".into(), image.mime_type.clone().into());
+                value.insert(
+                    "width".into(),
+                    image.width.map(Value::from).unwrap_or(Value::Null),
+                );
+                value.insert(
+                    "height".into(),
+                    image.height.map(Value::from).unwrap_or(Value::Null),
+                );
+                value.insert("bytes".into(), image.bytes.into());
+                value.into()
+            })
+            .collect::<Vec<Value>>();
+        json.insert("image_list".into(), images.into());
+        let meshes = self
+            .meshes
+            .iter()
+            .map(|mesh| {
+                let mut value = serde_json::Map::new();
+                value.insert("name".into(), mesh.name.clone().into());
+                value.insert("primitives".into(), mesh.primitives.into());
+                value.insert("triangles".into(), mesh.triangles.into());
+                value.insert("vertices".into(), mesh.vertices.into());
+                value.insert("morph_targets".into(), mesh.morph_targets.into());
+                value.into()
+            })
+            .collect::<Vec<Value>>();
+        json.insert("mesh_list".into(), meshes.into());
+        let mut bytes = serde_json::Map::new();
+        for (name, value) in &self.bytes {
+            bytes.insert(name.clone(), (*value).into());
+        }
+        bytes.insert("total".into(), self.total_bytes().into());
+        json.insert("bytes".into(), bytes.into());
+        json.into()
+    }
+}
+
+fn print_details(label: &str, stats: &Stats) {
+    println!("{} meshes:", label);
+    for mesh in &stats.meshes {
+        println!(
+            "\t{}: primitives={} triangles={} vertices={} morph_targets={}",
+            mesh.name, mesh.primitives, mesh.triangles, mesh.vertices, mesh.morph_targets
+        );
+    }
+    println!("{} images:", label);
+    for image in &stats.images {
+        let size = match (image.width, image.height) {
+            (Some(width), Some(height)) => format!("{}x{}", width, height),
+            _ => "?".into(),
+        };
+        println!(
+            "\t{}: {} {} {} bytes",
+            image.name, image.mime_type, size, image.bytes
+        );
+    }
+}
+
+/// 削減前と削減後のモデル情報を表で表示する
+pub fn print_stats(before: &Stats, after: &Stats) {
+    let mut rows = before
+        .summary()
+        .into_iter()
+        .zip(after.summary().into_iter())
+        .map(|((name, before), (_, after))| (name.to_string(), before, after))
+        .collect::<Vec<_>>();
+    for (name, before_bytes) in &before.bytes {
+        let after_bytes = after.bytes.get(name).cloned().unwrap_or(0);
+        rows.push((format!("bytes.{}", name), *before_bytes, after_bytes));
+    }
+    rows.push((
+        "bytes.total".into(),
+        before.total_bytes(),
+        after.total_bytes(),
+    ));
+
+    println!("{:<24} {:>12} {:>12}", "", "before", "after");
+    for (name, before, after) in rows {
+        println!("{:<24} {:>12} {:>12}", name, before, after);
+    }
+    print_details("before", before);
+    print_details("after", after);
+}
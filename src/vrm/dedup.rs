@@ -0,0 +1,285 @@
+use super::buffer::*;
+use super::cleaner::*;
+use log::info;
+use serde_json::Value;
+use std::collections::HashMap;
+
+// 同じ内容のリソースへの参照を先頭のリソースに付け替える
+// 参照されなくなったリソースはcleanで削除される
+macro_rules! merge_resources {
+    ($generator_function: ident, $resource_pointer: expr, $json: expr, $keys: expr) => {{
+        let mut json = $json;
+        let mut first_indexes = HashMap::new();
+        let mut index_map = HashMap::new();
+        for (index, key) in $keys.into_iter().enumerate() {
+            if let Some(key) = key {
+                let first_index = *first_indexes.entry(key).or_insert(index as u64);
+                if first_index != index as u64 {
+                    index_map.insert(index as u64, first_index);
+                }
+            }
+        }
+
+        if !index_map.is_empty() {
+            info!(
+                "merge {}: {} duplicates",
+                $resource_pointer,
+                index_map.len()
+            );
+            $generator_function(&mut json, |index| {
+                if let Some(first_index) = index.as_u64().and_then(|i| index_map.get(&i)) {
+                    *index = (*first_index).into();
+                }
+            });
+        }
+        json
+    }};
+}
+
+// nameを除いたJSON。VRoidはリソースごとに違う名前を付けるので、名前は比較しない
+fn key_without_name(value: &Value) -> String {
+    let mut value = value.clone();
+    value.as_object_mut().map(|v| v.remove("name"));
+    value.to_string()
+}
+
+fn json_keys(gltf: &Value, resource_pointer: &str) -> Vec<Option<String>> {
+    gltf.pointer(resource_pointer)
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|v| Some(key_without_name(v)))
+        .collect()
+}
+
+// bufferViewの画像は統合済みのbufferViewとmimeTypeで比較する
+fn image_keys(gltf: &Value) -> Vec<Option<String>> {
+    gltf.get("images")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(
+            |image| match image.get("bufferView").and_then(|v| v.as_u64()) {
+                Some(buffer_view) => Some(format!(
+                    "{} {}",
+                    buffer_view,
+                    image.get("mimeType").unwrap_or(&Value::Null)
+                )),
+                None => Some(key_without_name(image)),
+            },
+        )
+        .collect()
+}
+
+fn buffer_view_keys<'a>(
+    gltf: &Value,
+    chunks: &'a [Vec<u8>],
+) -> Vec<Option<(Option<u64>, Option<u64>, &'a [u8])>> {
+    gltf.get("bufferViews")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .enumerate()
+        .map(|(index, buffer_view)| {
+            buffer_view_bytes(gltf, chunks, index as u64).map(|bytes| {
+                (
+                    buffer_view.get("byteStride").and_then(|v| v.as_u64()),
+                    buffer_view.get("target").and_then(|v| v.as_u64()),
+                    bytes,
+                )
+            })
+        })
+        .collect()
+}
+
+// glTFマテリアルとVRMマテリアルの組で比較する
+fn material_keys(gltf: &Value) -> Vec<Option<String>> {
+    let vrm_materials = gltf
+        .pointer("/extensions/VRM/materialProperties")
+        .and_then(|v| v.as_array());
+    gltf.get("materials")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .enumerate()
+        .map(|(index, material)| match vrm_materials {
+            Some(vrm_materials) => vrm_materials.get(index).map(|vrm_material| {
+                format!(
+                    "{}{}",
+                    key_without_name(material),
+                    key_without_name(vrm_material)
+                )
+            }),
+            None => Some(key_without_name(material)),
+        })
+        .collect()
+}
+
+/// 重複したbufferView、accessor、image、sampler、texture、materialを統合する
+pub fn dedup(gltf: Value, chunks: &[Vec<u8>]) -> Value {
+    let keys = buffer_view_keys(&gltf, chunks);
+    let gltf = merge_resources!(
+        for_each_buffer_view_index_references,
+        "/bufferViews",
+        gltf,
+        keys
+    );
+    let keys = json_keys(&gltf, "/accessors");
+    let gltf = merge_resources!(for_each_accessor_index_references, "/accessors", gltf, keys);
+    let keys = image_keys(&gltf);
+    let gltf = merge_resources!(for_each_image_index_references, "/images", gltf, keys);
+    let keys = json_keys(&gltf, "/samplers");
+    let gltf = merge_resources!(for_each_sampler_index_references, "/samplers", gltf, keys);
+    let keys = json_keys(&gltf, "/textures");
+    let gltf = merge_resources!(for_each_texture_index_references, "/textures", gltf, keys);
+    let keys = material_keys(&gltf);
+    merge_resources!(for_each_material_index_references, "/materials", gltf, keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // bufferView 0と1、2と3が同じバイト列で、それぞれをaccessor、名前の違うimageが参照するglTF
+    fn duplicated_gltf() -> (Value, Vec<Vec<u8>>) {
+        let gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "scenes": [{"nodes": [0]}],
+                "nodes": [{"mesh": 0}],
+                "meshes": [{"primitives": [
+                    {"attributes": {"POSITION": 0}, "material": 0},
+                    {"attributes": {"POSITION": 1}, "material": 1}
+                ]}],
+                "materials": [
+                    {"name": "Body", "pbrMetallicRoughness": {"baseColorTexture": {"index": 0}}},
+                    {"name": "Face", "pbrMetallicRoughness": {"baseColorTexture": {"index": 1}}}
+                ],
+                "textures": [{"source": 0}, {"source": 1}],
+                "images": [
+                    {"name": "Body", "bufferView": 2, "mimeType": "image/png"},
+                    {"name": "Face", "bufferView": 3, "mimeType": "image/png"}
+                ],
+                "accessors": [
+                    {"name": "a", "bufferView": 0, "componentType": 5126, "count": 1, "type": "VEC3"},
+                    {"name": "b", "bufferView": 1, "componentType": 5126, "count": 1, "type": "VEC3"}
+                ],
+                "bufferViews": [
+                    {"buffer": 0, "byteOffset": 0, "byteLength": 12, "target": 34962},
+                    {"buffer": 0, "byteOffset": 12, "byteLength": 12, "target": 34962},
+                    {"buffer": 0, "byteOffset": 24, "byteLength": 4},
+                    {"buffer": 0, "byteOffset": 28, "byteLength": 4}
+                ],
+                "buffers": [{"byteLength": 32}]
+            }"#,
+        )
+        .unwrap();
+        let mut chunk = Vec::new();
+        for _ in 0..2 {
+            chunk.extend_from_slice(&[0, 0, 128, 63, 0, 0, 0, 64, 0, 0, 64, 64]);
+        }
+        for _ in 0..2 {
+            chunk.extend_from_slice(b"\x89PNG");
+        }
+        (gltf, vec![chunk])
+    }
+
+    fn array_len(gltf: &Value, key: &str) -> usize {
+        gltf[key].as_array().map(|v| v.len()).unwrap_or(0)
+    }
+
+    #[test]
+    fn dedup_merges_buffer_views_accessors_and_images() {
+        let (gltf, chunks) = duplicated_gltf();
+        let gltf = dedup(gltf, &chunks);
+        for (pointer, index) in &[
+            ("/accessors/1/bufferView", 0),
+            ("/images/1/bufferView", 2),
+            ("/meshes/0/primitives/1/attributes/POSITION", 0),
+            ("/textures/1/source", 0),
+            (
+                "/materials/1/pbrMetallicRoughness/baseColorTexture/index",
+                0,
+            ),
+            ("/meshes/0/primitives/1/material", 0),
+        ] {
+            assert_eq!(
+                gltf.pointer(pointer),
+                Some(&Value::from(*index)),
+                "{}",
+                pointer
+            );
+        }
+
+        let (gltf, _) = clean_with_removed(gltf);
+        for (key, len) in &[
+            ("bufferViews", 2),
+            ("accessors", 1),
+            ("images", 1),
+            ("textures", 1),
+            ("materials", 1),
+        ] {
+            assert_eq!(array_len(&gltf, key), *len, "{}", key);
+        }
+    }
+
+    #[test]
+    fn dedup_keeps_different_resources() {
+        let (gltf, mut chunks) = duplicated_gltf();
+        chunks[0][12] = 1;
+        chunks[0][31] = 0;
+        let gltf = dedup(gltf, &chunks);
+        let (gltf, _) = clean_with_removed(gltf);
+        for (key, len) in &[
+            ("bufferViews", 4),
+            ("accessors", 2),
+            ("images", 2),
+            ("textures", 2),
+            ("materials", 2),
+        ] {
+            assert_eq!(array_len(&gltf, key), *len, "{}", key);
+        }
+    }
+
+    #[test]
+    fn dedup_materials_keeps_vrm_material_properties_paired() {
+        let gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "scenes": [{"nodes": [0]}],
+                "nodes": [{"mesh": 0}],
+                "meshes": [{"primitives": [
+                    {"attributes": {}, "material": 0},
+                    {"attributes": {}, "material": 1},
+                    {"attributes": {}, "material": 2}
+                ]}],
+                "materials": [{"name": "A"}, {"name": "A"}, {"name": "B"}],
+                "extensions": {"VRM": {"materialProperties": [
+                    {"name": "A", "shader": "VRM/MToon"},
+                    {"name": "A", "shader": "VRM/MToon"},
+                    {"name": "B", "shader": "VRM/UnlitTexture"}
+                ]}}
+            }"#,
+        )
+        .unwrap();
+        let (gltf, _) = clean_with_removed(dedup(gltf, &[]));
+
+        let materials = gltf["materials"].as_array().unwrap();
+        let vrm_materials = gltf
+            .pointer("/extensions/VRM/materialProperties")
+            .and_then(|v| v.as_array())
+            .unwrap();
+        assert_eq!(materials.len(), 2);
+        assert_eq!(vrm_materials.len(), 2);
+        for (material, vrm_material) in materials.iter().zip(vrm_materials.iter()) {
+            assert_eq!(material["name"], vrm_material["name"]);
+        }
+        let primitive_materials = gltf["meshes"][0]["primitives"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| p["material"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(primitive_materials, vec![0, 0, 1]);
+    }
+}
@@ -0,0 +1,505 @@
+use super::buffer::*;
+use super::cleaner::*;
+use super::texture::*;
+use byteorder::{ReadBytesExt, LE};
+use image::GenericImageView;
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::process::Command;
+use std::str::FromStr;
+
+const KTX2_IDENTIFIER: [u8; 12] = [
+    0xab, 0x4b, 0x54, 0x58, 0x20, 0x32, 0x30, 0xbb, 0x0d, 0x0a, 0x1a, 0x0a,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BasisMode {
+    Etc1s,
+    Uastc,
+}
+
+impl FromStr for BasisMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<BasisMode, String> {
+        match s.to_lowercase().as_str() {
+            "etc1s" => Ok(BasisMode::Etc1s),
+            "uastc" => Ok(BasisMode::Uastc),
+            _ => Err(format!("unknown basis mode: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Ktx2Options {
+    pub mode: BasisMode,
+    /// 元の画像をtexture.sourceとして残す。残さないとVRM 0.xのローダーで読めない
+    pub fallback: bool,
+    /// KTX-Softwareのtoktxコマンド
+    pub toktx: String,
+}
+
+// ヘッダーとインデックスの後にレベルインデックスが続く
+const KTX2_LEVEL_INDEX_OFFSET: usize = 80;
+
+#[derive(Debug, PartialEq)]
+pub struct Ktx2Level {
+    pub byte_offset: u64,
+    pub byte_length: u64,
+    pub uncompressed_byte_length: u64,
+}
+
+#[derive(Debug)]
+pub struct Ktx2Header {
+    pub vk_format: u32,
+    pub pixel_width: u32,
+    pub pixel_height: u32,
+    pub pixel_depth: u32,
+    pub layer_count: u32,
+    pub face_count: u32,
+    pub level_count: u32,
+    pub supercompression_scheme: u32,
+    /// ミップマップのレベルごとのデータの位置。先頭が最も大きいレベル
+    pub levels: Vec<Ktx2Level>,
+}
+
+/// KTX2ヘッダーとレベルインデックスを読み込む
+/// https://github.khronos.org/KTX-Specification/
+pub fn read_ktx2_header(bytes: &[u8]) -> Result<Ktx2Header, Box<std::error::Error>> {
+    if bytes.len() < KTX2_LEVEL_INDEX_OFFSET || bytes[..12] != KTX2_IDENTIFIER {
+        return Err("not a KTX2 file".into());
+    }
+    let mut reader = Cursor::new(&bytes[12..]);
+    let vk_format = reader.read_u32::<LE>()?;
+    let _type_size = reader.read_u32::<LE>()?;
+    let pixel_width = reader.read_u32::<LE>()?;
+    let pixel_height = reader.read_u32::<LE>()?;
+    let pixel_depth = reader.read_u32::<LE>()?;
+    let layer_count = reader.read_u32::<LE>()?;
+    let face_count = reader.read_u32::<LE>()?;
+    let level_count = reader.read_u32::<LE>()?;
+    let supercompression_scheme = reader.read_u32::<LE>()?;
+
+    // levelCountが0のときもレベルは1つ
+    let mut reader = Cursor::new(&bytes[KTX2_LEVEL_INDEX_OFFSET..]);
+    let mut levels = Vec::new();
+    for _ in 0..level_count.max(1) {
+        let level = Ktx2Level {
+            byte_offset: reader.read_u64::<LE>()?,
+            byte_length: reader.read_u64::<LE>()?,
+            uncompressed_byte_length: reader.read_u64::<LE>()?,
+        };
+        if level
+            .byte_offset
+            .checked_add(level.byte_length)
+            .map(|end| end > bytes.len() as u64)
+            .unwrap_or(true)
+        {
+            return Err(format!("level {} is out of file", levels.len()).into());
+        }
+        levels.push(level);
+    }
+
+    Ok(Ktx2Header {
+        vk_format,
+        pixel_width,
+        pixel_height,
+        pixel_depth,
+        layer_count,
+        face_count,
+        level_count,
+        supercompression_scheme,
+        levels,
+    })
+}
+
+// KHR_texture_basisuの要件を満たしているか確認する
+// https://github.com/KhronosGroup/glTF/tree/master/extensions/2.0/Khronos/KHR_texture_basisu
+fn validate_ktx2(bytes: &[u8], mode: BasisMode) -> Result<Ktx2Header, Box<std::error::Error>> {
+    let header = read_ktx2_header(bytes)?;
+    if header.vk_format != 0 {
+        return Err(format!("vkFormat must be VK_FORMAT_UNDEFINED: {}", header.vk_format).into());
+    }
+    if header.pixel_width % 4 != 0 || header.pixel_height % 4 != 0 {
+        return Err(format!(
+            "size must be multiple of 4: {}x{}",
+            header.pixel_width, header.pixel_height
+        )
+        .into());
+    }
+    if header.pixel_depth != 0 || header.layer_count != 0 || header.face_count != 1 {
+        return Err("KTX2 must be a 2D texture".into());
+    }
+    // --genmipmapで全てのレベルを生成している
+    let full_level_count = 32 - header.pixel_width.max(header.pixel_height).leading_zeros();
+    if header.level_count != full_level_count {
+        return Err(format!(
+            "mipmap levels must be complete: {} (expected {})",
+            header.level_count, full_level_count
+        )
+        .into());
+    }
+    let supercompression_ok = match mode {
+        BasisMode::Etc1s => header.supercompression_scheme == 1,
+        BasisMode::Uastc => {
+            header.supercompression_scheme == 0 || header.supercompression_scheme == 2
+        }
+    };
+    if !supercompression_ok {
+        return Err(format!(
+            "unexpected supercompression scheme: {}",
+            header.supercompression_scheme
+        )
+        .into());
+    }
+    Ok(header)
+}
+
+// 幅、高さをそれぞれ最も近い4の倍数にする
+// 余白を足すとUVがずれるので拡大縮小するが、縦横比の変化は1辺あたり2ピクセル以内に抑える
+fn block_aligned_dimensions(width: u32, height: u32) -> (u32, u32) {
+    let align = |v: u32| ((v + 2) / 4 * 4).max(4);
+    (align(width), align(height))
+}
+
+fn encode_ktx2(
+    bytes: &[u8],
+    linear: bool,
+    options: &Ktx2Options,
+) -> Result<Vec<u8>, Box<std::error::Error>> {
+    // 幅、高さを4の倍数にしてPNGとしてtoktxに渡す
+    let image = image::load_from_memory(bytes)?;
+    let (width, height) = image.dimensions();
+    let (block_width, block_height) = block_aligned_dimensions(width, height);
+    let image = if (block_width, block_height) != (width, height) {
+        debug!(
+            "resize {}x{} -> {}x{}",
+            width, height, block_width, block_height
+        );
+        image.resize_exact(block_width, block_height, image::FilterType::Lanczos3)
+    } else {
+        image
+    };
+    let mut png = Vec::new();
+    image.write_to(&mut png, image::ImageOutputFormat::PNG)?;
+
+    let ktx2 = convert_image_with_command(&png, "png", "ktx2", |input_path, output_path| {
+        let mut command = Command::new(&options.toktx);
+        command.args(&["--t2", "--genmipmap"]);
+        match options.mode {
+            BasisMode::Etc1s => command.args(&["--encode", "etc1s"]),
+            BasisMode::Uastc => command.args(&["--encode", "uastc", "--zcmp", "18"]),
+        };
+        if linear {
+            command.args(&["--assign_oetf", "linear"]);
+        }
+        command.arg(output_path).arg(input_path);
+        command
+    })?;
+    let header = validate_ktx2(&ktx2, options.mode)?;
+    debug!(
+        "KTX2 {}x{} levels={}",
+        header.pixel_width, header.pixel_height, header.level_count
+    );
+    Ok(ktx2)
+}
+
+/// テクスチャをKTX2(Basis Universal)に変換し、KHR_texture_basisuとして参照する
+pub fn transcode_ktx2(gltf_: Value, chunks: &mut Vec<Vec<u8>>, options: &Ktx2Options) -> Value {
+    let mut gltf = gltf_.clone();
+    let linear_images = non_color_images(&gltf);
+    let texture_len = gltf
+        .get("textures")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+
+    let mut ktx2_images = BTreeMap::new();
+    for texture_index in 0..texture_len {
+        let source = if let Some(source) = gltf["textures"][texture_index]
+            .get("source")
+            .and_then(|v| v.as_u64())
+        {
+            source
+        } else {
+            continue;
+        };
+
+        if !ktx2_images.contains_key(&source) {
+            let bytes = if let Some(bytes) = gltf
+                .get("images")
+                .and_then(|v| v.get(source as usize))
+                .and_then(|v| v.get("bufferView"))
+                .and_then(|v| v.as_u64())
+                .and_then(|index| buffer_view_bytes(&gltf, chunks, index))
+            {
+                bytes.to_vec()
+            } else {
+                continue;
+            };
+            let ktx2 = match encode_ktx2(&bytes, linear_images.contains(&source), options) {
+                Ok(ktx2) => ktx2,
+                Err(e) => {
+                    warn!("Failed to encode image {} to KTX2: {:?}", source, e);
+                    continue;
+                }
+            };
+            info!(
+                "image {}: {} bytes -> KTX2 {} bytes",
+                source,
+                bytes.len(),
+                ktx2.len()
+            );
+
+            let buffer_view_index = push_buffer_view(&mut gltf, chunks, &ktx2, None);
+            let mut image = serde_json::map::Map::new();
+            image.insert("bufferView".into(), buffer_view_index.into());
+            image.insert("mimeType".into(), "image/ktx2".into());
+            if let Some(name) = gltf["images"][source as usize].get("name") {
+                image.insert("name".into(), name.clone());
+            }
+            let images = gltf["images"].as_array_mut().unwrap();
+            images.push(image.into());
+            ktx2_images.insert(source, images.len() - 1);
+        }
+
+        let texture = &mut gltf["textures"][texture_index];
+        texture["extensions"]["KHR_texture_basisu"]["source"] = ktx2_images[&source].into();
+        if !options.fallback {
+            texture.as_object_mut().map(|t| t.remove("source"));
+        }
+    }
+
+    if !ktx2_images.is_empty() {
+        use_extension(&mut gltf, "KHR_texture_basisu", !options.fallback);
+    }
+    gltf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2D、ETC1S、ミップマップ付きのKTX2。レベルのデータは中身を持たない
+    fn ktx2_fixture(width: u32, height: u32, level_count: u32, scheme: u32) -> Vec<u8> {
+        let mut bytes = KTX2_IDENTIFIER.to_vec();
+        let push_u32 = |bytes: &mut Vec<u8>, v: u32| {
+            for i in 0..4 {
+                bytes.push((v >> (i * 8)) as u8);
+            }
+        };
+        for v in &[0, 1, width, height, 0, 0, 1, level_count, scheme] {
+            push_u32(&mut bytes, *v);
+        }
+        // dfd, kvd, sgdのインデックス
+        bytes.resize(KTX2_LEVEL_INDEX_OFFSET, 0);
+
+        let level_index_end = KTX2_LEVEL_INDEX_OFFSET + level_count.max(1) as usize * 24;
+        let byte_lengths = (0..level_count.max(1))
+            .map(|level| u64::from((width >> level).max(1) * (height >> level).max(1)))
+            .collect::<Vec<_>>();
+        // 小さいレベルから順にファイルに置かれる
+        let mut byte_offset = level_index_end as u64;
+        let mut byte_offsets = vec![0; byte_lengths.len()];
+        for level in (0..byte_lengths.len()).rev() {
+            byte_offsets[level] = byte_offset;
+            byte_offset += byte_lengths[level];
+        }
+        for (byte_offset, byte_length) in byte_offsets.iter().zip(byte_lengths.iter()) {
+            for v in &[*byte_offset, *byte_length, *byte_length] {
+                push_u32(&mut bytes, *v as u32);
+                push_u32(&mut bytes, (*v >> 32) as u32);
+            }
+        }
+        bytes.resize(byte_offset as usize, 0);
+        bytes
+    }
+
+    #[test]
+    fn read_ktx2_header_and_levels() {
+        let bytes = ktx2_fixture(64, 32, 7, 1);
+        let header = read_ktx2_header(&bytes).unwrap();
+        assert_eq!((header.pixel_width, header.pixel_height), (64, 32));
+        assert_eq!(header.vk_format, 0);
+        assert_eq!(header.face_count, 1);
+        assert_eq!(header.level_count, 7);
+        assert_eq!(header.supercompression_scheme, 1);
+        assert_eq!(header.levels.len(), 7);
+        assert_eq!(
+            header.levels[0],
+            Ktx2Level {
+                byte_offset: 80 + 7 * 24 + (1 + 2 + 8 + 32 + 128 + 512),
+                byte_length: 64 * 32,
+                uncompressed_byte_length: 64 * 32,
+            }
+        );
+        assert_eq!(
+            (header.levels[6].byte_offset, header.levels[6].byte_length),
+            (80 + 7 * 24, 1)
+        );
+        assert!(validate_ktx2(&bytes, BasisMode::Etc1s).is_ok());
+        assert!(validate_ktx2(&bytes, BasisMode::Uastc).is_err());
+    }
+
+    #[test]
+    fn read_ktx2_header_rejects_invalid_files() {
+        let bytes = ktx2_fixture(64, 32, 7, 1);
+        // レベルのデータがファイルからはみ出す
+        assert!(read_ktx2_header(&bytes[..bytes.len() - 1]).is_err());
+        // レベルインデックスが途中で切れている
+        assert!(read_ktx2_header(&bytes[..100]).is_err());
+        let mut bytes = bytes;
+        bytes[0] = 0;
+        assert!(read_ktx2_header(&bytes).is_err());
+
+        // ミップマップが足りない、4の倍数でない
+        assert!(validate_ktx2(&ktx2_fixture(64, 32, 1, 1), BasisMode::Etc1s).is_err());
+        assert!(validate_ktx2(&ktx2_fixture(62, 32, 6, 1), BasisMode::Etc1s).is_err());
+    }
+
+    #[test]
+    fn block_aligned_dimensions_keep_aspect_ratio() {
+        assert_eq!(block_aligned_dimensions(512, 256), (512, 256));
+        assert_eq!(block_aligned_dimensions(513, 257), (512, 256));
+        assert_eq!(block_aligned_dimensions(1022, 511), (1024, 512));
+        assert_eq!(block_aligned_dimensions(1, 2), (4, 4));
+        for (width, height) in &[(1001, 333), (250, 1000), (97, 61)] {
+            let (w, h) = block_aligned_dimensions(*width, *height);
+            assert!((w as i64 - *width as i64).abs() <= 2);
+            assert!((h as i64 - *height as i64).abs() <= 2);
+        }
+    }
+
+    // 色のテクスチャ0と法線マップのテクスチャ1が、それぞれwidth x heightのPNGを参照するglTF
+    fn textured_gltf(width: u32, height: u32) -> (Value, Vec<Vec<u8>>) {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "materials": [{
+                    "pbrMetallicRoughness": {"baseColorTexture": {"index": 0}},
+                    "normalTexture": {"index": 1}
+                }],
+                "textures": [{"source": 0}, {"source": 1}],
+                "images": [
+                    {"name": "color", "mimeType": "image/png"},
+                    {"name": "normal", "mimeType": "image/png"}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        let image =
+            image::DynamicImage::ImageRgb8(image::RgbImage::from_fn(width, height, |x, y| {
+                image::Rgb([x as u8 * 16, y as u8 * 16, 128])
+            }));
+        let png = encode_png(&image).unwrap();
+        for image_index in 0..2 {
+            let buffer_view = push_buffer_view(&mut gltf, &mut chunks, &png, None);
+            gltf["images"][image_index]["bufferView"] = buffer_view.into();
+        }
+        (gltf, chunks)
+    }
+
+    // 変換したテクスチャが参照するKTX2を読み込む
+    fn ktx2_headers(gltf: &Value, chunks: &[Vec<u8>]) -> Vec<Ktx2Header> {
+        gltf["textures"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|texture| {
+                let source = texture
+                    .pointer("/extensions/KHR_texture_basisu/source")
+                    .and_then(|v| v.as_u64())
+                    .unwrap();
+                let image = &gltf["images"][source as usize];
+                assert_eq!(image["mimeType"], Value::from("image/ktx2"));
+                let bytes =
+                    buffer_view_bytes(gltf, chunks, image["bufferView"].as_u64().unwrap()).unwrap();
+                read_ktx2_header(bytes).unwrap()
+            })
+            .collect()
+    }
+
+    // 引数を記録し、用意したKTX2を出力するtoktxの代わり
+    #[cfg(unix)]
+    fn fake_toktx(name: &str, ktx2: &[u8]) -> std::path::PathBuf {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("vreducer-{}-{}-toktx", std::process::id(), name));
+        std::fs::write(path.with_extension("ktx2"), ktx2).unwrap();
+        std::fs::write(
+            &path,
+            "#!/bin/sh\n\
+             echo \"$@\" >> \"$0.log\"\n\
+             output=\n\
+             while [ $# -gt 1 ]; do output=\"$1\"; shift; done\n\
+             cp \"$0.ktx2\" \"$output\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700)).unwrap();
+        path
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transcode_ktx2_keeps_fallback_and_encodes_normal_maps_as_linear() {
+        let toktx = fake_toktx("fallback", &ktx2_fixture(8, 4, 4, 1));
+        let (gltf, mut chunks) = textured_gltf(8, 4);
+        let options = Ktx2Options {
+            mode: BasisMode::Etc1s,
+            fallback: true,
+            toktx: toktx.to_string_lossy().to_string(),
+        };
+        let gltf = transcode_ktx2(gltf, &mut chunks, &options);
+        let log = std::fs::read_to_string(format!("{}.log", toktx.display())).unwrap();
+        for extension in &["", ".ktx2", ".log"] {
+            let _ = std::fs::remove_file(format!("{}{}", toktx.display(), extension));
+        }
+
+        for header in ktx2_headers(&gltf, &chunks) {
+            assert_eq!((header.pixel_width, header.pixel_height), (8, 4));
+            assert_eq!(header.levels.len(), 4);
+        }
+        assert_eq!(gltf["textures"][0]["source"], Value::from(0));
+        assert_eq!(gltf["textures"][1]["source"], Value::from(1));
+        assert_eq!(
+            gltf["extensionsUsed"],
+            Value::from(vec!["KHR_texture_basisu"])
+        );
+        assert!(gltf.get("extensionsRequired").is_none());
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(!lines[0].contains("--assign_oetf linear"), "{}", lines[0]);
+        assert!(lines[1].contains("--assign_oetf linear"), "{}", lines[1]);
+    }
+
+    // toktxがあれば実際に変換した結果を読み込む
+    #[test]
+    fn transcode_ktx2_with_toktx() {
+        if Command::new("toktx").arg("--version").output().is_err() {
+            eprintln!("toktx is not found; skipped");
+            return;
+        }
+        let (gltf, mut chunks) = textured_gltf(30, 18);
+        for mode in &[BasisMode::Etc1s, BasisMode::Uastc] {
+            let options = Ktx2Options {
+                mode: *mode,
+                fallback: false,
+                toktx: "toktx".to_string(),
+            };
+            let transcoded = transcode_ktx2(gltf.clone(), &mut chunks, &options);
+            for header in ktx2_headers(&transcoded, &chunks) {
+                assert_eq!((header.pixel_width, header.pixel_height), (32, 20));
+                assert_eq!(header.levels.len(), 6);
+            }
+            assert!(transcoded["textures"][0].get("source").is_none());
+            assert_eq!(
+                transcoded["extensionsRequired"],
+                Value::from(vec!["KHR_texture_basisu"])
+            );
+        }
+    }
+}
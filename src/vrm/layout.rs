@@ -0,0 +1,491 @@
+use super::accessor::*;
+use super::buffer::*;
+use log::info;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+
+// byteStrideの上限
+const MAX_BYTE_STRIDE: usize = 252;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VertexLayout {
+    /// 頂点属性を1つのbufferViewに交互に並べる
+    Interleaved,
+    /// 頂点属性ごとにbufferViewを分ける
+    Separate,
+}
+
+impl FromStr for VertexLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<VertexLayout, String> {
+        match s.to_lowercase().as_str() {
+            "interleaved" => Ok(VertexLayout::Interleaved),
+            "separate" | "non-interleaved" => Ok(VertexLayout::Separate),
+            _ => Err(format!("unknown vertex layout: {}", s)),
+        }
+    }
+}
+
+// accessorの要素を詰めて並べたバイト列。sparseなaccessorは対象外
+fn packed_accessor_bytes(gltf: &Value, chunks: &[Vec<u8>], index: u64) -> Option<Vec<u8>> {
+    let accessor = gltf.get("accessors")?.get(index as usize)?;
+    if accessor.get("sparse").is_some() {
+        return None;
+    }
+    let buffer_view_index = accessor.get("bufferView")?.as_u64()?;
+    let bytes = buffer_view_bytes(gltf, chunks, buffer_view_index)?;
+    let element_size = element_size(accessor)?;
+    let count = accessor.get("count")?.as_u64()? as usize;
+    let byte_offset = accessor
+        .get("byteOffset")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0) as usize;
+    let byte_stride = gltf["bufferViews"][buffer_view_index as usize]
+        .get("byteStride")
+        .and_then(|v| v.as_u64())
+        .map(|v| v as usize)
+        .unwrap_or(element_size);
+
+    let mut packed = Vec::with_capacity(count * element_size);
+    for i in 0..count {
+        let start = byte_offset + i * byte_stride;
+        packed.extend_from_slice(bytes.get(start..start + element_size)?);
+    }
+    Some(packed)
+}
+
+// 元のaccessorを複製し、新しいbufferViewを参照させる
+fn push_accessor_view(
+    gltf: &mut Value,
+    index: u64,
+    buffer_view_index: u64,
+    byte_offset: usize,
+) -> u64 {
+    let mut accessor = gltf["accessors"][index as usize].clone();
+    accessor["bufferView"] = buffer_view_index.into();
+    if byte_offset > 0 {
+        accessor["byteOffset"] = byte_offset.into();
+    } else if let Some(accessor) = accessor.as_object_mut() {
+        accessor.remove("byteOffset");
+    }
+    let accessors = gltf["accessors"].as_array_mut().unwrap();
+    accessors.push(accessor);
+    (accessors.len() - 1) as u64
+}
+
+// 要素を4バイト境界に揃え、byteStrideを付けたbufferViewを作成する
+fn push_vertex_buffer_view(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    elements: &[(usize, Vec<u8>)],
+    count: usize,
+    byte_stride: usize,
+) -> u64 {
+    let mut bytes = vec![0; count * byte_stride];
+    for (offset, packed) in elements {
+        let element_size = packed.len() / count.max(1);
+        for i in 0..count {
+            let start = i * byte_stride + offset;
+            bytes[start..start + element_size]
+                .copy_from_slice(&packed[i * element_size..(i + 1) * element_size]);
+        }
+    }
+    let buffer_view_index = push_buffer_view(gltf, chunks, &bytes, Some(ARRAY_BUFFER));
+    // 1つの属性が隙間なく並んでいる場合はbyteStrideを省く
+    let tightly_packed = elements.len() == 1 && elements[0].1.len() == bytes.len();
+    if !tightly_packed {
+        gltf["bufferViews"][buffer_view_index as usize]["byteStride"] = byte_stride.into();
+    }
+    buffer_view_index
+}
+
+// accessorごとに詰めたbufferViewを作成する
+fn separate_accessor(gltf: &mut Value, chunks: &mut Vec<Vec<u8>>, index: u64) -> Option<u64> {
+    let packed = packed_accessor_bytes(gltf, chunks, index)?;
+    let count = gltf["accessors"][index as usize].get("count")?.as_u64()? as usize;
+    let element_size = packed.len() / count.max(1);
+    let buffer_view_index = push_vertex_buffer_view(
+        gltf,
+        chunks,
+        &[(0, packed)],
+        count,
+        (element_size + 3) / 4 * 4,
+    );
+    Some(push_accessor_view(gltf, index, buffer_view_index, 0))
+}
+
+// 頂点属性を1つのbufferViewに交互に並べる。並べられないものはaccessorごとに分ける
+fn interleave_accessors(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    indices: &[u64],
+) -> BTreeMap<u64, u64> {
+    let mut new_indices = BTreeMap::new();
+    let count = indices
+        .iter()
+        .filter_map(|index| gltf["accessors"][*index as usize].get("count"))
+        .filter_map(|v| v.as_u64())
+        .next()
+        .unwrap_or(0) as usize;
+
+    let mut elements = Vec::new();
+    let mut byte_stride = 0;
+    for index in indices {
+        let same_count = gltf["accessors"][*index as usize]
+            .get("count")
+            .and_then(|v| v.as_u64())
+            == Some(count as u64);
+        let packed = if same_count {
+            packed_accessor_bytes(gltf, chunks, *index)
+        } else {
+            None
+        };
+        match packed {
+            Some(packed) => {
+                let element_size = packed.len() / count.max(1);
+                if byte_stride + element_size > MAX_BYTE_STRIDE {
+                    if let Some(new_index) = separate_accessor(gltf, chunks, *index) {
+                        new_indices.insert(*index, new_index);
+                    }
+                    continue;
+                }
+                elements.push((*index, byte_stride, packed));
+                byte_stride += (element_size + 3) / 4 * 4;
+            }
+            None => {
+                if let Some(new_index) = separate_accessor(gltf, chunks, *index) {
+                    new_indices.insert(*index, new_index);
+                }
+            }
+        }
+    }
+    if elements.is_empty() {
+        return new_indices;
+    }
+
+    let buffer_view_index = push_vertex_buffer_view(
+        gltf,
+        chunks,
+        &elements
+            .iter()
+            .map(|(_, offset, packed)| (*offset, packed.clone()))
+            .collect::<Vec<_>>(),
+        count,
+        byte_stride,
+    );
+    for (index, offset, _) in elements {
+        new_indices.insert(
+            index,
+            push_accessor_view(gltf, index, buffer_view_index, offset),
+        );
+    }
+    new_indices
+}
+
+fn replace_accessors(attributes: &mut Value, new_indices: &BTreeMap<u64, u64>) {
+    if let Some(attributes) = attributes.as_object_mut() {
+        for value in attributes.values_mut() {
+            if let Some(new_index) = value.as_u64().and_then(|v| new_indices.get(&v)) {
+                *value = (*new_index).into();
+            }
+        }
+    }
+}
+
+// インデックスだけが使っているbufferViewにtargetを設定する
+fn set_index_targets(gltf: &mut Value) {
+    let mut index_accessors = BTreeSet::new();
+    for mesh in gltf
+        .get("meshes")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        for primitive in mesh
+            .get("primitives")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+        {
+            if let Some(index) = primitive.get("indices").and_then(|v| v.as_u64()) {
+                index_accessors.insert(index as usize);
+            }
+        }
+    }
+
+    let mut index_buffer_views = BTreeSet::new();
+    let mut other_buffer_views = BTreeSet::new();
+    for (accessor_index, accessor) in gltf
+        .get("accessors")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .enumerate()
+    {
+        if let Some(buffer_view_index) = accessor.get("bufferView").and_then(|v| v.as_u64()) {
+            if index_accessors.contains(&accessor_index) {
+                index_buffer_views.insert(buffer_view_index);
+            } else {
+                other_buffer_views.insert(buffer_view_index);
+            }
+        }
+    }
+    for buffer_view_index in index_buffer_views.difference(&other_buffer_views) {
+        if let Some(buffer_view) = gltf
+            .get_mut("bufferViews")
+            .and_then(|v| v.get_mut(*buffer_view_index as usize))
+            .and_then(|v| v.as_object_mut())
+        {
+            buffer_view.insert("target".into(), ELEMENT_ARRAY_BUFFER.into());
+            buffer_view.remove("byteStride");
+        }
+    }
+}
+
+/// プリミティブの頂点属性を指定したレイアウトのbufferViewに書き直す
+/// モーフターゲットはどちらのレイアウトでもaccessorごとのbufferViewにする
+/// 使われなくなったaccessor、bufferViewはcleanとrelocate_buffersで削除される
+pub fn layout_vertex_attributes(
+    gltf_: Value,
+    chunks: &mut Vec<Vec<u8>>,
+    layout: VertexLayout,
+) -> Value {
+    let mut gltf = gltf_.clone();
+    let mesh_len = gltf
+        .get("meshes")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+
+    // 同じaccessorの組み合わせは1度だけ書き直す
+    let mut attribute_cache: BTreeMap<Vec<u64>, BTreeMap<u64, u64>> = BTreeMap::new();
+    let mut target_cache: BTreeMap<u64, u64> = BTreeMap::new();
+    for mesh_index in 0..mesh_len {
+        let primitive_len = gltf["meshes"][mesh_index]
+            .get("primitives")
+            .and_then(|v| v.as_array())
+            .map(|v| v.len())
+            .unwrap_or(0);
+        for primitive_index in 0..primitive_len {
+            let primitive = gltf["meshes"][mesh_index]["primitives"][primitive_index].clone();
+            let attributes: Vec<u64> = primitive
+                .get("attributes")
+                .and_then(|v| v.as_object())
+                .map(|v| v.values().filter_map(|v| v.as_u64()).collect())
+                .unwrap_or_default();
+            if !attribute_cache.contains_key(&attributes) {
+                let new_indices = match layout {
+                    VertexLayout::Interleaved => {
+                        interleave_accessors(&mut gltf, chunks, &attributes)
+                    }
+                    VertexLayout::Separate => attributes
+                        .iter()
+                        .filter_map(|index| {
+                            separate_accessor(&mut gltf, chunks, *index).map(|new| (*index, new))
+                        })
+                        .collect(),
+                };
+                attribute_cache.insert(attributes.clone(), new_indices);
+            }
+
+            let new_indices = attribute_cache[&attributes].clone();
+            for target in primitive
+                .get("targets")
+                .and_then(|v| v.as_array())
+                .unwrap_or(&Vec::new())
+            {
+                for index in target
+                    .as_object()
+                    .map(|v| v.values().filter_map(|v| v.as_u64()).collect::<Vec<_>>())
+                    .unwrap_or_default()
+                {
+                    if !target_cache.contains_key(&index) {
+                        if let Some(new_index) = separate_accessor(&mut gltf, chunks, index) {
+                            target_cache.insert(index, new_index);
+                        }
+                    }
+                }
+            }
+
+            let primitive = &mut gltf["meshes"][mesh_index]["primitives"][primitive_index];
+            replace_accessors(&mut primitive["attributes"], &new_indices);
+            if let Some(targets) = primitive.get_mut("targets").and_then(|v| v.as_array_mut()) {
+                for target in targets.iter_mut() {
+                    replace_accessors(target, &target_cache);
+                }
+            }
+        }
+    }
+    set_index_targets(&mut gltf);
+    info!(
+        "vertex layout {:?}: {} attribute sets, {} morph target accessors",
+        layout,
+        attribute_cache.len(),
+        target_cache.len()
+    );
+    gltf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // POSITION(12バイト)、COLOR_0(3バイト)、JOINTS_0(4バイト)とモーフターゲットを持つプリミティブ
+    fn layout_gltf() -> (Value, Vec<Vec<u8>>) {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "meshes": [{"primitives": [{"attributes": {}, "targets": [{}]}]}]
+            }"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        let position = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "VEC3",
+            &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0],
+            Some(ARRAY_BUFFER),
+        );
+        let color = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            UNSIGNED_BYTE,
+            true,
+            "VEC3",
+            &[10.0, 20.0, 30.0, 40.0, 50.0, 60.0, 70.0, 80.0, 90.0],
+            Some(ARRAY_BUFFER),
+        );
+        let joints = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            UNSIGNED_BYTE,
+            false,
+            "VEC4",
+            &[0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0],
+            Some(ARRAY_BUFFER),
+        );
+        let target = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "VEC3",
+            &[0.5, 0.0, 0.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.5],
+            Some(ARRAY_BUFFER),
+        );
+        let indices = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            UNSIGNED_SHORT,
+            false,
+            "SCALAR",
+            &[0.0, 1.0, 2.0],
+            None,
+        );
+        let primitive = &mut gltf["meshes"][0]["primitives"][0];
+        primitive["attributes"]["POSITION"] = position.into();
+        primitive["attributes"]["COLOR_0"] = color.into();
+        primitive["attributes"]["JOINTS_0"] = joints.into();
+        primitive["targets"][0]["POSITION"] = target.into();
+        primitive["indices"] = indices.into();
+        (gltf, chunks)
+    }
+
+    fn primitive_accessors(gltf: &Value) -> Vec<(String, u64)> {
+        let primitive = &gltf["meshes"][0]["primitives"][0];
+        let mut accessors = primitive["attributes"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .map(|(name, index)| (name.clone(), index.as_u64().unwrap()))
+            .collect::<Vec<_>>();
+        accessors.push((
+            "TARGET".to_string(),
+            primitive["targets"][0]["POSITION"].as_u64().unwrap(),
+        ));
+        accessors
+    }
+
+    fn buffer_view(gltf: &Value, accessor_index: u64) -> &Value {
+        let index = gltf["accessors"][accessor_index as usize]["bufferView"]
+            .as_u64()
+            .unwrap();
+        &gltf["bufferViews"][index as usize]
+    }
+
+    // 書き直したaccessorが元と同じ値を持ち、要素が4バイト境界に揃っている
+    fn assert_same_values(gltf: &Value, chunks: &[Vec<u8>], layouted: &Value) {
+        for ((name, index), (_, new_index)) in primitive_accessors(gltf)
+            .iter()
+            .zip(primitive_accessors(layouted).iter())
+        {
+            assert_ne!(index, new_index, "{}", name);
+            assert_eq!(
+                read_accessor(layouted, chunks, *new_index),
+                read_accessor(gltf, chunks, *index),
+                "{}",
+                name
+            );
+            let byte_offset = layouted["accessors"][*new_index as usize]["byteOffset"]
+                .as_u64()
+                .unwrap_or(0);
+            let buffer_view = buffer_view(layouted, *new_index);
+            let byte_stride = buffer_view["byteStride"].as_u64().unwrap_or(0);
+            assert_eq!(byte_offset % 4, 0, "{}", name);
+            assert_eq!(byte_stride % 4, 0, "{}", name);
+        }
+        let indices = layouted["meshes"][0]["primitives"][0]["indices"]
+            .as_u64()
+            .unwrap();
+        assert_eq!(
+            buffer_view(layouted, indices)["target"],
+            Value::from(ELEMENT_ARRAY_BUFFER)
+        );
+    }
+
+    #[test]
+    fn layout_interleaved_vertex_attributes() {
+        let (gltf, mut chunks) = layout_gltf();
+        let layouted =
+            layout_vertex_attributes(gltf.clone(), &mut chunks, VertexLayout::Interleaved);
+        assert_same_values(&gltf, &chunks, &layouted);
+
+        let accessors = primitive_accessors(&layouted);
+        let (attributes, targets) = accessors.split_at(3);
+        let attribute_view = buffer_view(&layouted, attributes[0].1);
+        // COLOR_0は4バイトに揃えるので、12 + 4 + 4バイト
+        assert_eq!(attribute_view["byteStride"], Value::from(20));
+        assert_eq!(attribute_view["target"], Value::from(ARRAY_BUFFER));
+        for (name, index) in attributes {
+            assert_eq!(buffer_view(&layouted, *index), attribute_view, "{}", name);
+        }
+        // モーフターゲットは詰めたbufferViewに分ける
+        let target_view = buffer_view(&layouted, targets[0].1);
+        assert_ne!(target_view, attribute_view);
+        assert!(target_view.get("byteStride").is_none());
+    }
+
+    #[test]
+    fn layout_separate_vertex_attributes() {
+        let (gltf, mut chunks) = layout_gltf();
+        let layouted = layout_vertex_attributes(gltf.clone(), &mut chunks, VertexLayout::Separate);
+        assert_same_values(&gltf, &chunks, &layouted);
+
+        let mut buffer_views = BTreeSet::new();
+        for (name, index) in primitive_accessors(&layouted) {
+            let view = layouted["accessors"][index as usize]["bufferView"]
+                .as_u64()
+                .unwrap();
+            assert!(buffer_views.insert(view), "{}", name);
+            let byte_stride = buffer_view(&layouted, index).get("byteStride").cloned();
+            match name.as_str() {
+                // 3バイトの要素だけ4バイトのbyteStrideを付ける
+                "COLOR_0" => assert_eq!(byte_stride, Some(Value::from(4))),
+                _ => assert_eq!(byte_stride, None, "{}", name),
+            }
+        }
+    }
+}
@@ -0,0 +1,801 @@
+use super::accessor::*;
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::iter::repeat;
+
+const IDENTITY: [f64; 16] = [
+    1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+];
+
+// 同じスケルトンを使うスキン付きメッシュのまとまり
+struct MergeGroup {
+    // (node, mesh, skin)
+    nodes: Vec<(usize, u64, u64)>,
+    // ジョイントのノードと逆バインド行列
+    joints: Vec<(u64, Vec<f64>)>,
+}
+
+// 頂点属性をまとめる単位。属性を共有するプリミティブは1つにまとめる
+struct VertexBlock {
+    mesh: u64,
+    primitive: Value,
+    joint_map: Vec<usize>,
+    offset: usize,
+    count: usize,
+}
+
+// スキンのジョイントと逆バインド行列
+fn skin_joints(gltf: &Value, chunks: &[Vec<u8>], skin_index: u64) -> Option<Vec<(u64, Vec<f64>)>> {
+    let skin = gltf.get("skins")?.get(skin_index as usize)?;
+    let joints = skin
+        .get("joints")?
+        .as_array()?
+        .iter()
+        .map(|v| v.as_u64())
+        .collect::<Option<Vec<_>>>()?;
+    let matrices = match skin.get("inverseBindMatrices").and_then(|v| v.as_u64()) {
+        Some(index) => read_accessor(gltf, chunks, index)?,
+        None => IDENTITY
+            .iter()
+            .cycle()
+            .take(joints.len() * 16)
+            .cloned()
+            .collect(),
+    };
+    if matrices.len() != joints.len() * 16 {
+        return None;
+    }
+    Some(
+        joints
+            .into_iter()
+            .zip(matrices.chunks(16).map(|m| m.to_vec()))
+            .collect(),
+    )
+}
+
+// 1つのノードからだけスキン付きで使われているメッシュ
+fn skinned_mesh_nodes(gltf: &Value) -> Vec<(usize, u64, u64)> {
+    let nodes = gltf
+        .get("nodes")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let mut mesh_users = BTreeMap::new();
+    for node in &nodes {
+        if let Some(mesh) = node.get("mesh").and_then(|v| v.as_u64()) {
+            *mesh_users.entry(mesh).or_insert(0) += 1;
+        }
+    }
+    nodes
+        .iter()
+        .enumerate()
+        .filter_map(|(node_index, node)| {
+            let mesh = node.get("mesh")?.as_u64()?;
+            let skin = node.get("skin")?.as_u64()?;
+            if mesh_users[&mesh] == 1 {
+                Some((node_index, mesh, skin))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+// ジョイントを共有し、共有するジョイントの逆バインド行列が同じスキンをまとめる
+fn merge_groups(gltf: &Value, chunks: &[Vec<u8>]) -> Vec<MergeGroup> {
+    let mut groups: Vec<MergeGroup> = Vec::new();
+    for (node_index, mesh, skin) in skinned_mesh_nodes(gltf) {
+        let joints = if let Some(joints) = skin_joints(gltf, chunks, skin) {
+            joints
+        } else {
+            warn!("Failed to read skin {}", skin);
+            continue;
+        };
+        let group = groups.iter_mut().find(|group| {
+            let mut shared = false;
+            for (node, matrix) in &joints {
+                if let Some((_, group_matrix)) = group.joints.iter().find(|(n, _)| n == node) {
+                    if matrix
+                        .iter()
+                        .zip(group_matrix.iter())
+                        .any(|(a, b)| (a - b).abs() > 1e-5)
+                    {
+                        return false;
+                    }
+                    shared = true;
+                }
+            }
+            shared
+        });
+        if let Some(group) = group {
+            group.nodes.push((node_index, mesh, skin));
+            for (node, matrix) in joints {
+                if group.joints.iter().all(|(n, _)| *n != node) {
+                    group.joints.push((node, matrix));
+                }
+            }
+            continue;
+        }
+        groups.push(MergeGroup {
+            nodes: vec![(node_index, mesh, skin)],
+            joints,
+        });
+    }
+    groups
+}
+
+fn accessor_format(gltf: &Value, index: u64) -> Option<(String, u64, bool)> {
+    let accessor = gltf.get("accessors")?.get(index as usize)?;
+    Some((
+        accessor.get("type")?.as_str()?.to_string(),
+        accessor.get("componentType")?.as_u64()?,
+        accessor
+            .get("normalized")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false),
+    ))
+}
+
+// ブロックごとのaccessorを連結する。accessorがないブロックは0で埋める
+// 型が揃っていない場合はFLOATにする
+fn concat_accessors(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    sources: &[(Option<u64>, usize)],
+) -> Option<u64> {
+    let formats = sources
+        .iter()
+        .filter_map(|(index, _)| *index)
+        .map(|index| accessor_format(gltf, index))
+        .collect::<Option<Vec<_>>>()?;
+    let (type_, component_type, normalized) = formats.get(0)?.clone();
+    if formats.iter().any(|format| format.0 != type_) {
+        return None;
+    }
+    let uniform = formats
+        .iter()
+        .all(|format| format.1 == component_type && format.2 == normalized);
+    let components = component_count(&type_)?;
+
+    let mut values = Vec::new();
+    for (index, count) in sources {
+        if let Some(index) = index {
+            let (_, source_component_type, source_normalized) = accessor_format(gltf, *index)?;
+            let source_values = read_accessor(gltf, chunks, *index)?;
+            if source_values.len() != count * components {
+                return None;
+            }
+            if uniform || !source_normalized {
+                values.extend(source_values);
+            } else {
+                let max = normalized_max(source_component_type);
+                values.extend(source_values.iter().map(|v| v / max));
+            }
+        } else {
+            values.extend(repeat(0.0).take(count * components));
+        }
+    }
+    let (component_type, normalized) = if uniform {
+        (component_type, normalized)
+    } else {
+        (FLOAT, false)
+    };
+    Some(push_accessor(
+        gltf,
+        chunks,
+        component_type,
+        normalized,
+        &type_,
+        &values,
+        Some(ARRAY_BUFFER),
+    ))
+}
+
+// ジョイント番号をまとめたスキンの番号に置き換えて連結する
+fn concat_joints(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    sources: &[(Option<u64>, &VertexBlock)],
+    joint_len: usize,
+) -> Option<u64> {
+    let mut values = Vec::new();
+    for (index, block) in sources {
+        if let Some(index) = index {
+            let source_values = read_accessor(gltf, chunks, *index)?;
+            if source_values.len() != block.count * 4 {
+                return None;
+            }
+            values.extend(source_values.iter().map(|joint| {
+                block
+                    .joint_map
+                    .get(*joint as usize)
+                    .map(|j| *j as f64)
+                    .unwrap_or(0.0)
+            }));
+        } else {
+            values.extend(repeat(0.0).take(block.count * 4));
+        }
+    }
+    let component_type = if joint_len <= 256 {
+        UNSIGNED_BYTE
+    } else {
+        UNSIGNED_SHORT
+    };
+    Some(push_accessor(
+        gltf,
+        chunks,
+        component_type,
+        false,
+        "VEC4",
+        &values,
+        Some(ARRAY_BUFFER),
+    ))
+}
+
+fn attribute_index(primitive: &Value, semantic: &str) -> Option<u64> {
+    primitive.get("attributes")?.get(semantic)?.as_u64()
+}
+
+fn target_index(primitive: &Value, target: usize, semantic: &str) -> Option<u64> {
+    primitive
+        .get("targets")?
+        .get(target)?
+        .get(semantic)?
+        .as_u64()
+}
+
+fn mesh_target_len(mesh: &Value) -> usize {
+    mesh.get("primitives")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .map(|p| {
+            p.get("targets")
+                .and_then(|v| v.as_array())
+                .map(|v| v.len())
+                .unwrap_or(0)
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn target_names(mesh: &Value) -> Vec<String> {
+    let len = mesh_target_len(mesh);
+    let names = mesh
+        .pointer("/extras/targetNames")
+        .or_else(|| mesh.pointer("/primitives/0/extras/targetNames"))
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    (0..len)
+        .map(|i| {
+            names
+                .get(i)
+                .and_then(|v| v.as_str())
+                .unwrap_or("")
+                .to_string()
+        })
+        .collect()
+}
+
+// まとめたメッシュとスキンを追加し、ノードとVRM拡張の参照を付け替える
+fn merge_group(gltf: &mut Value, chunks: &mut Vec<Vec<u8>>, group: &MergeGroup) -> Option<()> {
+    let meshes = group
+        .nodes
+        .iter()
+        .map(|(_, mesh, _)| gltf.get("meshes")?.get(*mesh as usize).cloned())
+        .collect::<Option<Vec<_>>>()?;
+
+    let mut target_offsets = BTreeMap::new();
+    let mut names = Vec::new();
+    let mut weights = Vec::new();
+    for ((_, mesh_index, _), mesh) in group.nodes.iter().zip(meshes.iter()) {
+        target_offsets.insert(*mesh_index, names.len());
+        let len = mesh_target_len(mesh);
+        for i in 0..len {
+            weights.push(
+                mesh.get("weights")
+                    .and_then(|v| v.get(i))
+                    .and_then(|v| v.as_f64())
+                    .unwrap_or(0.0),
+            );
+        }
+        names.extend(target_names(mesh));
+    }
+    let target_len = names.len();
+
+    // 頂点属性を共有するプリミティブは同じブロックを使う
+    let mut blocks: Vec<VertexBlock> = Vec::new();
+    let mut block_keys = BTreeMap::new();
+    let mut primitives = Vec::new();
+    let mut vertex_len = 0;
+    for ((_, mesh_index, skin_index), mesh) in group.nodes.iter().zip(meshes.iter()) {
+        let joints = skin_joints(gltf, chunks, *skin_index)?;
+        let joint_map = joints
+            .iter()
+            .map(|(node, _)| group.joints.iter().position(|(n, _)| n == node))
+            .collect::<Option<Vec<_>>>()?;
+        for primitive in mesh.get("primitives")?.as_array()? {
+            let key = format!(
+                "{} {}{}",
+                mesh_index,
+                primitive.get("attributes").unwrap_or(&Value::Null),
+                primitive.get("targets").unwrap_or(&Value::Null)
+            );
+            let block_index = if let Some(block_index) = block_keys.get(&key) {
+                *block_index
+            } else {
+                let position = attribute_index(primitive, "POSITION")?;
+                let count = gltf
+                    .get("accessors")?
+                    .get(position as usize)?
+                    .get("count")?
+                    .as_u64()? as usize;
+                block_keys.insert(key, blocks.len());
+                blocks.push(VertexBlock {
+                    mesh: *mesh_index,
+                    primitive: primitive.clone(),
+                    joint_map: joint_map.clone(),
+                    offset: vertex_len,
+                    count,
+                });
+                vertex_len += count;
+                blocks.len() - 1
+            };
+            primitives.push((primitive.clone(), block_index));
+        }
+    }
+
+    let mut semantics = BTreeSet::new();
+    let mut target_semantics = vec![BTreeSet::new(); target_len];
+    for block in &blocks {
+        for semantic in block
+            .primitive
+            .get("attributes")
+            .and_then(|v| v.as_object())
+            .map(|v| v.keys().cloned().collect())
+            .unwrap_or_else(Vec::new)
+        {
+            semantics.insert(semantic);
+        }
+        for (i, target) in block
+            .primitive
+            .get("targets")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+            .iter()
+            .enumerate()
+        {
+            for semantic in target
+                .as_object()
+                .map(|v| v.keys().cloned().collect())
+                .unwrap_or_else(Vec::new)
+            {
+                target_semantics[target_offsets[&block.mesh] + i].insert(semantic);
+            }
+        }
+    }
+
+    let mut attributes = serde_json::Map::new();
+    for semantic in semantics {
+        let index = if semantic.starts_with("JOINTS_") {
+            let sources = blocks
+                .iter()
+                .map(|block| (attribute_index(&block.primitive, &semantic), block))
+                .collect::<Vec<_>>();
+            concat_joints(gltf, chunks, &sources, group.joints.len())
+        } else {
+            let sources = blocks
+                .iter()
+                .map(|block| (attribute_index(&block.primitive, &semantic), block.count))
+                .collect::<Vec<_>>();
+            concat_accessors(gltf, chunks, &sources)
+        };
+        attributes.insert(semantic, index?.into());
+    }
+
+    let mut targets = Vec::new();
+    for (target, semantics) in target_semantics.into_iter().enumerate() {
+        let mut target_attributes = serde_json::Map::new();
+        for semantic in semantics {
+            let sources = blocks
+                .iter()
+                .map(|block| {
+                    let offset = target_offsets[&block.mesh];
+                    let index = if target >= offset {
+                        target_index(&block.primitive, target - offset, &semantic)
+                    } else {
+                        None
+                    };
+                    (index, block.count)
+                })
+                .collect::<Vec<_>>();
+            target_attributes.insert(semantic, concat_accessors(gltf, chunks, &sources)?.into());
+        }
+        targets.push(Value::from(target_attributes));
+    }
+
+    let mut new_primitives = Vec::new();
+    for (primitive, block_index) in primitives {
+        let block = &blocks[block_index];
+        let indices = match primitive.get("indices").and_then(|v| v.as_u64()) {
+            Some(index) => read_accessor(gltf, chunks, index)?,
+            None => (0..block.count).map(|i| i as f64).collect(),
+        };
+        let indices = indices
+            .iter()
+            .map(|i| i + block.offset as f64)
+            .collect::<Vec<_>>();
+        let component_type = if vertex_len <= 65535 {
+            UNSIGNED_SHORT
+        } else {
+            UNSIGNED_INT
+        };
+        let indices = push_accessor(
+            gltf,
+            chunks,
+            component_type,
+            false,
+            "SCALAR",
+            &indices,
+            Some(ELEMENT_ARRAY_BUFFER),
+        );
+        let mut new_primitive = primitive.clone();
+        new_primitive["attributes"] = attributes.clone().into();
+        new_primitive["indices"] = indices.into();
+        if let Some(new_primitive) = new_primitive.as_object_mut() {
+            new_primitive.remove("targets");
+            if let Some(extras) = new_primitive
+                .get_mut("extras")
+                .and_then(|v| v.as_object_mut())
+            {
+                extras.remove("targetNames");
+            }
+        }
+        if target_len > 0 {
+            new_primitive["targets"] = targets.clone().into();
+            new_primitive["extras"]["targetNames"] = names.clone().into();
+        }
+        new_primitives.push(new_primitive);
+    }
+
+    let mut new_mesh = json_object();
+    if let Some(name) = meshes[0].get("name") {
+        new_mesh["name"] = name.clone();
+    }
+    new_mesh["primitives"] = new_primitives.into();
+    if target_len > 0 {
+        if meshes.iter().any(|mesh| mesh.get("weights").is_some()) {
+            new_mesh["weights"] = weights.clone().into();
+        }
+        new_mesh["extras"]["targetNames"] = names.into();
+    }
+
+    let matrices = group
+        .joints
+        .iter()
+        .flat_map(|(_, matrix)| matrix.clone())
+        .collect::<Vec<_>>();
+    let inverse_bind_matrices = push_accessor(gltf, chunks, FLOAT, false, "MAT4", &matrices, None);
+    let first_skin = gltf["skins"][group.nodes[0].2 as usize].clone();
+    let mut new_skin = json_object();
+    if let Some(name) = first_skin.get("name") {
+        new_skin["name"] = name.clone();
+    }
+    new_skin["inverseBindMatrices"] = inverse_bind_matrices.into();
+    new_skin["joints"] = group
+        .joints
+        .iter()
+        .map(|(node, _)| Value::from(*node))
+        .collect::<Vec<_>>()
+        .into();
+    if let Some(skeleton) = first_skin.get("skeleton") {
+        if group
+            .nodes
+            .iter()
+            .all(|(_, _, skin)| gltf["skins"][*skin as usize].get("skeleton") == Some(skeleton))
+        {
+            new_skin["skeleton"] = skeleton.clone();
+        }
+    }
+
+    let mesh_index = push_array(gltf, "meshes", new_mesh);
+    let skin_index = push_array(gltf, "skins", new_skin);
+
+    let node_weights = group
+        .nodes
+        .iter()
+        .any(|(node, _, _)| gltf["nodes"][*node].get("weights").is_some());
+    let mut new_node_weights = Vec::new();
+    for (node, mesh, _) in &group.nodes {
+        let offset = target_offsets[mesh];
+        let len = mesh_target_len(&gltf["meshes"][*mesh as usize]);
+        for i in 0..len {
+            new_node_weights.push(
+                gltf["nodes"][*node]
+                    .get("weights")
+                    .and_then(|v| v.get(i))
+                    .and_then(|v| v.as_f64())
+                    .unwrap_or(weights[offset + i]),
+            );
+        }
+        if let Some(node) = gltf["nodes"][*node].as_object_mut() {
+            for key in &["mesh", "skin", "weights"] {
+                node.remove(*key);
+            }
+        }
+    }
+    let first_node = &mut gltf["nodes"][group.nodes[0].0];
+    first_node["mesh"] = mesh_index.into();
+    first_node["skin"] = skin_index.into();
+    if node_weights {
+        first_node["weights"] = new_node_weights.into();
+    }
+
+    update_vrm_meshes(gltf, &target_offsets, mesh_index);
+    info!(
+        "merge {} skinned meshes: {} vertices, {} joints, {} morph targets",
+        group.nodes.len(),
+        vertex_len,
+        group.joints.len(),
+        target_len
+    );
+    Some(())
+}
+
+fn json_object() -> Value {
+    Value::Object(serde_json::Map::new())
+}
+
+fn push_array(gltf: &mut Value, key: &str, value: Value) -> u64 {
+    if !gltf.get(key).map(|v| v.is_array()).unwrap_or(false) {
+        gltf[key] = Value::Array(Vec::new());
+    }
+    let array = gltf[key].as_array_mut().unwrap();
+    array.push(value);
+    (array.len() - 1) as u64
+}
+
+// blendShapeMasterのbindsとfirstPersonのmeshAnnotationsをまとめたメッシュに付け替える
+fn update_vrm_meshes(gltf: &mut Value, target_offsets: &BTreeMap<u64, usize>, mesh_index: u64) {
+    for group in gltf
+        .pointer_mut("/extensions/VRM/blendShapeMaster/blendShapeGroups")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        for bind in group
+            .get_mut("binds")
+            .and_then(|v| v.as_array_mut())
+            .unwrap_or(&mut Vec::new())
+        {
+            let offset = if let Some(offset) = bind
+                .get("mesh")
+                .and_then(|v| v.as_u64())
+                .and_then(|mesh| target_offsets.get(&mesh))
+            {
+                *offset
+            } else {
+                continue;
+            };
+            let index = bind.get("index").and_then(|v| v.as_u64()).unwrap_or(0);
+            bind["mesh"] = mesh_index.into();
+            bind["index"] = (index + offset as u64).into();
+        }
+    }
+
+    let mesh_annotations = if let Some(mesh_annotations) = gltf
+        .pointer_mut("/extensions/VRM/firstPerson/meshAnnotations")
+        .and_then(|v| v.as_array_mut())
+    {
+        mesh_annotations
+    } else {
+        return;
+    };
+    let mut flags = BTreeSet::new();
+    mesh_annotations.retain(|annotation| {
+        let merged = annotation
+            .get("mesh")
+            .and_then(|v| v.as_u64())
+            .map(|mesh| target_offsets.contains_key(&mesh))
+            .unwrap_or(false);
+        if merged {
+            if let Some(flag) = annotation.get("firstPersonFlag").and_then(|v| v.as_str()) {
+                flags.insert(flag.to_string());
+            }
+        }
+        !merged
+    });
+    if flags.is_empty() {
+        return;
+    }
+    // 一人称の表示方法が異なるメッシュをまとめた場合は頭のボーンの頂点だけ消すAutoにする
+    let flag = if flags.len() == 1 {
+        flags.into_iter().next().unwrap()
+    } else {
+        warn!("Merged meshes have different firstPersonFlag, use Auto");
+        "Auto".to_string()
+    };
+    let mut annotation = serde_json::Map::new();
+    annotation.insert("mesh".into(), mesh_index.into());
+    annotation.insert("firstPersonFlag".into(), flag.into());
+    mesh_annotations.push(annotation.into());
+}
+
+/// 同じスケルトンを使うスキン付きメッシュを、1つのメッシュと1つのスキンにまとめる
+/// 頂点属性を連結し、モーフターゲットは持っていないメッシュの部分を0で埋める
+pub fn merge_skinned_meshes(gltf_: Value, chunks: &mut Vec<Vec<u8>>) -> Value {
+    let mut gltf = gltf_.clone();
+    for group in merge_groups(&gltf, chunks) {
+        if group.nodes.len() < 2 {
+            continue;
+        }
+        let mut merged = gltf.clone();
+        if merge_group(&mut merged, chunks, &group).is_some() {
+            gltf = merged;
+        } else {
+            warn!(
+                "Failed to merge skinned meshes: {:?}",
+                group
+                    .nodes
+                    .iter()
+                    .map(|(_, mesh, _)| *mesh)
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+    gltf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hipsだけを使うbodyと、head、hipsの順のスキンを使うface。それぞれモーフターゲットを1つ持つ
+    fn two_mesh_gltf() -> (Value, Vec<Vec<u8>>) {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "scenes": [{"nodes": [0, 1, 2]}],
+                "nodes": [
+                    {"name": "hips", "children": [3]},
+                    {"name": "body", "mesh": 0, "skin": 0},
+                    {"name": "face", "mesh": 1, "skin": 1},
+                    {"name": "head"}
+                ],
+                "meshes": [
+                    {"name": "body", "primitives": [{"attributes": {}, "targets": [{}]}],
+                     "extras": {"targetNames": ["A"]}},
+                    {"name": "face", "primitives": [{"attributes": {}, "targets": [{}]}],
+                     "extras": {"targetNames": ["B"]}}
+                ],
+                "skins": [{"joints": [0]}, {"joints": [3, 0]}],
+                "extensions": {"VRM": {
+                    "blendShapeMaster": {"blendShapeGroups": [
+                        {"name": "A", "binds": [{"mesh": 0, "index": 0, "weight": 100}]},
+                        {"name": "B", "binds": [{"mesh": 1, "index": 0, "weight": 100}]}
+                    ]},
+                    "firstPerson": {"meshAnnotations": [
+                        {"mesh": 0, "firstPersonFlag": "Auto"},
+                        {"mesh": 1, "firstPersonFlag": "Auto"}
+                    ]}
+                }}
+            }"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        let mut push = |gltf: &mut Value, component_type, type_: &str, values: &[f64]| {
+            push_accessor(
+                gltf,
+                &mut chunks,
+                component_type,
+                false,
+                type_,
+                values,
+                Some(ARRAY_BUFFER),
+            )
+        };
+        let body = [
+            push(&mut gltf, FLOAT, "VEC3", &[0.0, 0.0, 0.0, 1.0, 0.0, 0.0]),
+            push(&mut gltf, UNSIGNED_BYTE, "VEC4", &[0.0; 8]),
+            push(
+                &mut gltf,
+                FLOAT,
+                "VEC4",
+                &[1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+            ),
+            push(&mut gltf, FLOAT, "VEC3", &[0.0, 1.0, 0.0, 0.0, 1.0, 0.0]),
+        ];
+        let face = [
+            push(
+                &mut gltf,
+                FLOAT,
+                "VEC3",
+                &[0.0, 2.0, 0.0, 1.0, 2.0, 0.0, 0.0, 3.0, 0.0],
+            ),
+            push(
+                &mut gltf,
+                UNSIGNED_BYTE,
+                "VEC4",
+                &[0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            ),
+            push(
+                &mut gltf,
+                FLOAT,
+                "VEC4",
+                &[0.5, 0.5, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+            ),
+            push(
+                &mut gltf,
+                FLOAT,
+                "VEC3",
+                &[0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0],
+            ),
+        ];
+        for (mesh, accessors) in [body, face].iter().enumerate() {
+            let primitive = &mut gltf["meshes"][mesh]["primitives"][0];
+            primitive["attributes"]["POSITION"] = accessors[0].into();
+            primitive["attributes"]["JOINTS_0"] = accessors[1].into();
+            primitive["attributes"]["WEIGHTS_0"] = accessors[2].into();
+            primitive["targets"][0]["POSITION"] = accessors[3].into();
+        }
+        (gltf, chunks)
+    }
+
+    #[test]
+    fn merge_skinned_meshes_remaps_joints_targets_and_binds() {
+        let (gltf, mut chunks) = two_mesh_gltf();
+        let gltf = merge_skinned_meshes(gltf, &mut chunks);
+
+        assert_eq!(gltf["nodes"][1]["mesh"], Value::from(2));
+        assert_eq!(gltf["nodes"][1]["skin"], Value::from(2));
+        assert!(gltf["nodes"][2].get("mesh").is_none());
+        assert!(gltf["nodes"][2].get("skin").is_none());
+        // bodyのスキンのジョイントが先に並ぶ
+        assert_eq!(gltf["skins"][2]["joints"], Value::from(vec![0, 3]));
+
+        let read = |index: &Value| read_accessor(&gltf, &chunks, index.as_u64().unwrap()).unwrap();
+        let primitives = gltf["meshes"][2]["primitives"].as_array().unwrap();
+        assert_eq!(primitives.len(), 2);
+        let attributes = &primitives[0]["attributes"];
+        assert_eq!(attributes, &primitives[1]["attributes"]);
+        assert_eq!(read(&attributes["POSITION"]).len(), 5 * 3);
+        // faceのジョイント0(head)は1、1(hips)は0になる
+        let body_joints = vec![0.0; 8];
+        let face_joints = vec![1.0, 0.0, 1.0, 1.0, 0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0];
+        assert_eq!(
+            read(&attributes["JOINTS_0"]),
+            [body_joints, face_joints].concat()
+        );
+        assert_eq!(read(&primitives[1]["indices"]), vec![2.0, 3.0, 4.0]);
+
+        // 持っていないメッシュの部分は0で埋める
+        let targets = primitives[0]["targets"].as_array().unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(
+            read(&targets[0]["POSITION"]),
+            vec![0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+        );
+        assert_eq!(
+            read(&targets[1]["POSITION"]),
+            vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 1.0]
+        );
+        assert_eq!(
+            gltf["meshes"][2]["extras"]["targetNames"],
+            Value::from(vec!["A", "B"])
+        );
+
+        let groups = gltf
+            .pointer("/extensions/VRM/blendShapeMaster/blendShapeGroups")
+            .unwrap();
+        for (group, index) in groups.as_array().unwrap().iter().zip(0..) {
+            assert_eq!(group["binds"][0]["mesh"], Value::from(2));
+            assert_eq!(group["binds"][0]["index"], Value::from(index));
+        }
+        assert_eq!(
+            gltf.pointer("/extensions/VRM/firstPerson/meshAnnotations")
+                .unwrap(),
+            &serde_json::from_str::<Value>(r#"[{"mesh": 2, "firstPersonFlag": "Auto"}]"#).unwrap()
+        );
+    }
+}
@@ -0,0 +1,1336 @@
+use super::accessor::*;
+use super::buffer::*;
+use super::cleaner::*;
+use log::info;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+// https://github.com/KhronosGroup/glTF/tree/master/extensions/2.0/Vendor/EXT_meshopt_compression
+const EXTENSION_NAME: &str = "EXT_meshopt_compression";
+
+const TRIANGLES: u64 = 4;
+
+const VERTEX_HEADER: u8 = 0xa0;
+const INDEX_HEADER: u8 = 0xe0;
+const SEQUENCE_HEADER: u8 = 0xd0;
+
+const BYTE_GROUP_SIZE: usize = 16;
+const VERTEX_BLOCK_SIZE_BYTES: usize = 8192;
+const VERTEX_BLOCK_MAX_SIZE: usize = 256;
+const TAIL_MAX_SIZE: usize = 32;
+
+const CODE_AUX_TABLE_SIZE: usize = 16;
+// 頻度の高い(feb, fec)の組。最後の2つは符号化に使わない
+const CODE_AUX_ENCODING_TABLE: [u8; CODE_AUX_TABLE_SIZE] = [
+    0x00, 0x76, 0x87, 0x56, 0x67, 0x78, 0xa9, 0x86, 0x65, 0x89, 0x68, 0x98, 0x01, 0x69, 0, 0,
+];
+// 三角形を回転させたときの頂点の順序
+const TRIANGLE_INDEX_ORDER: [[usize; 3]; 3] = [[0, 1, 2], [1, 2, 0], [2, 0, 1]];
+
+fn vertex_block_size(byte_stride: usize) -> usize {
+    let size = (VERTEX_BLOCK_SIZE_BYTES / byte_stride) & !(BYTE_GROUP_SIZE - 1);
+    size.min(VERTEX_BLOCK_MAX_SIZE)
+}
+
+fn zigzag8(v: u8) -> u8 {
+    (((v as i8) >> 7) as u8) ^ (v << 1)
+}
+
+fn unzigzag8(v: u8) -> u8 {
+    0u8.wrapping_sub(v & 1) ^ (v >> 1)
+}
+
+fn write_vbyte(output: &mut Vec<u8>, mut v: u32) {
+    loop {
+        if v > 127 {
+            output.push((v & 127) as u8 | 128);
+        } else {
+            output.push(v as u8);
+            break;
+        }
+        v >>= 7;
+    }
+}
+
+fn read_vbyte(data: &[u8], position: &mut usize) -> Result<u32, String> {
+    let mut result = 0u32;
+    for shift in &[0, 7, 14, 21, 28] {
+        let byte = *data.get(*position).ok_or("unexpected end of data")?;
+        *position += 1;
+        result |= u32::from(byte & 127) << shift;
+        if byte < 128 {
+            return Ok(result);
+        }
+    }
+    Err("too long vbyte".into())
+}
+
+// 2ビットごとに符号化したビット幅(0, 2, 4, 8)のうち最小のものを選んで書き込む
+fn encode_byte_group(output: &mut Vec<u8>, group: &[u8]) -> u8 {
+    let encoded_size = |bits: usize| {
+        let sentinel = (1usize << bits) - 1;
+        BYTE_GROUP_SIZE * bits / 8 + group.iter().filter(|v| **v as usize >= sentinel).count()
+    };
+    let (bits_code, bits) = if group.iter().all(|v| *v == 0) {
+        (0, 0)
+    } else {
+        [(1, 2), (2, 4), (3, 8)]
+            .iter()
+            .cloned()
+            .min_by_key(|(_, bits)| encoded_size(*bits))
+            .unwrap()
+    };
+
+    match bits {
+        0 => {}
+        8 => output.extend_from_slice(group),
+        _ => {
+            let sentinel = ((1u32 << bits) - 1) as u8;
+            for values in group.chunks(8 / bits) {
+                let mut byte = 0u8;
+                for v in values {
+                    byte = (byte << bits) | (*v).min(sentinel);
+                }
+                output.push(byte);
+            }
+            output.extend(group.iter().filter(|v| **v >= sentinel));
+        }
+    }
+    bits_code
+}
+
+fn encode_bytes(output: &mut Vec<u8>, buffer: &[u8]) {
+    let header_start = output.len();
+    let group_count = buffer.len() / BYTE_GROUP_SIZE;
+    output.resize(header_start + (group_count + 3) / 4, 0);
+    for (group_index, group) in buffer.chunks(BYTE_GROUP_SIZE).enumerate() {
+        let bits_code = encode_byte_group(output, group);
+        output[header_start + group_index / 4] |= bits_code << ((group_index % 4) * 2);
+    }
+}
+
+fn decode_bytes(
+    data: &[u8],
+    position: &mut usize,
+    data_end: usize,
+    buffer: &mut [u8],
+) -> Result<(), String> {
+    let group_count = buffer.len() / BYTE_GROUP_SIZE;
+    let header_start = *position;
+    *position += (group_count + 3) / 4;
+    if *position > data_end {
+        return Err("unexpected end of vertex data".into());
+    }
+    for group_index in 0..group_count {
+        if *position > data_end {
+            return Err("unexpected end of vertex data".into());
+        }
+        let bits_code = (data[header_start + group_index / 4] >> ((group_index % 4) * 2)) & 3;
+        let group = &mut buffer[group_index * BYTE_GROUP_SIZE..(group_index + 1) * BYTE_GROUP_SIZE];
+        match bits_code {
+            0 => {
+                for v in group.iter_mut() {
+                    *v = 0;
+                }
+            }
+            3 => {
+                let bytes = data
+                    .get(*position..*position + BYTE_GROUP_SIZE)
+                    .ok_or("unexpected end of vertex data")?;
+                group.copy_from_slice(bytes);
+                *position += BYTE_GROUP_SIZE;
+            }
+            _ => {
+                let bits = if bits_code == 1 { 2 } else { 4 };
+                let sentinel = ((1u32 << bits) - 1) as u8;
+                let packed_size = BYTE_GROUP_SIZE * bits / 8;
+                let packed = data
+                    .get(*position..*position + packed_size)
+                    .ok_or("unexpected end of vertex data")?;
+                let mut escape_position = *position + packed_size;
+                for (i, v) in group.iter_mut().enumerate() {
+                    let shift = 8 - bits - (i % (8 / bits)) * bits;
+                    let value = (packed[i / (8 / bits)] >> shift) & sentinel;
+                    *v = if value == sentinel {
+                        let escaped = *data
+                            .get(escape_position)
+                            .ok_or("unexpected end of vertex data")?;
+                        escape_position += 1;
+                        escaped
+                    } else {
+                        value
+                    };
+                }
+                *position = escape_position;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 頂点データをATTRIBUTESモードで符号化する
+pub fn encode_vertex_buffer(data: &[u8], byte_stride: usize) -> Vec<u8> {
+    let count = data.len() / byte_stride;
+    let block_size = vertex_block_size(byte_stride);
+    let first_vertex = data[..byte_stride.min(data.len())].to_vec();
+    let mut output = vec![VERTEX_HEADER];
+    let mut last_vertex = first_vertex.clone();
+    last_vertex.resize(byte_stride, 0);
+
+    let mut start = 0;
+    while start < count {
+        let block_count = block_size.min(count - start);
+        let aligned_count = (block_count + BYTE_GROUP_SIZE - 1) / BYTE_GROUP_SIZE * BYTE_GROUP_SIZE;
+        let mut buffer = vec![0u8; aligned_count];
+        for k in 0..byte_stride {
+            let mut p = last_vertex[k];
+            for i in 0..block_count {
+                let v = data[(start + i) * byte_stride + k];
+                buffer[i] = zigzag8(v.wrapping_sub(p));
+                p = v;
+            }
+            encode_bytes(&mut output, &buffer);
+        }
+        let last_start = (start + block_count - 1) * byte_stride;
+        last_vertex.copy_from_slice(&data[last_start..last_start + byte_stride]);
+        start += block_count;
+    }
+
+    let tail_size = byte_stride.max(TAIL_MAX_SIZE);
+    output.resize(output.len() + tail_size - byte_stride, 0);
+    output.extend_from_slice(&first_vertex);
+    output.resize(output.len() + byte_stride - first_vertex.len(), 0);
+    output
+}
+
+/// ATTRIBUTESモードで符号化された頂点データを復号する
+pub fn decode_vertex_buffer(
+    data: &[u8],
+    count: usize,
+    byte_stride: usize,
+) -> Result<Vec<u8>, String> {
+    if byte_stride == 0 || byte_stride > 256 || byte_stride % 4 != 0 {
+        return Err(format!("invalid byteStride: {}", byte_stride));
+    }
+    let tail_size = byte_stride.max(TAIL_MAX_SIZE);
+    if data.len() < 1 + tail_size || data[0] != VERTEX_HEADER {
+        return Err("invalid vertex data header".into());
+    }
+    let data_end = data.len() - tail_size;
+    let mut last_vertex = data[data.len() - byte_stride..].to_vec();
+    let block_size = vertex_block_size(byte_stride);
+    let mut output = vec![0u8; count * byte_stride];
+    let mut buffer = vec![0u8; block_size];
+    let mut position = 1;
+
+    let mut start = 0;
+    while start < count {
+        let block_count = block_size.min(count - start);
+        let aligned_count = (block_count + BYTE_GROUP_SIZE - 1) / BYTE_GROUP_SIZE * BYTE_GROUP_SIZE;
+        for k in 0..byte_stride {
+            decode_bytes(data, &mut position, data_end, &mut buffer[..aligned_count])?;
+            let mut p = last_vertex[k];
+            for i in 0..block_count {
+                p = p.wrapping_add(unzigzag8(buffer[i]));
+                output[(start + i) * byte_stride + k] = p;
+            }
+        }
+        let last_start = (start + block_count - 1) * byte_stride;
+        last_vertex.copy_from_slice(&output[last_start..last_start + byte_stride]);
+        start += block_count;
+    }
+
+    if position != data_end {
+        return Err("vertex data size mismatch".into());
+    }
+    Ok(output)
+}
+
+/// インデックスをINDICESモードで符号化する
+pub fn encode_index_sequence(indices: &[u32]) -> Vec<u8> {
+    let mut output = vec![SEQUENCE_HEADER | 1];
+    let mut last = [0u32; 2];
+    let mut current = 0;
+    for index in indices {
+        // 差分が大きくなったらもう一方の基準値に切り替える
+        let cd = index.wrapping_sub(last[current]) as i32;
+        if i64::from(cd).abs() >= 30 {
+            current ^= 1;
+        }
+        let d = index.wrapping_sub(last[current]);
+        let v = (d << 1) ^ ((d as i32 >> 31) as u32);
+        write_vbyte(&mut output, (v << 1) | current as u32);
+        last[current] = *index;
+    }
+    output.extend_from_slice(&[0; 4]);
+    output
+}
+
+/// INDICESモードで符号化されたインデックスを復号する
+pub fn decode_index_sequence(data: &[u8], count: usize) -> Result<Vec<u32>, String> {
+    if data.len() < 1 + count + 4 || data[0] & 0xf0 != SEQUENCE_HEADER || data[0] & 0x0f > 1 {
+        return Err("invalid index sequence header".into());
+    }
+    let data_end = data.len() - 4;
+    let mut last = [0u32; 2];
+    let mut position = 1;
+    let mut indices = Vec::with_capacity(count);
+    for _ in 0..count {
+        let v = read_vbyte(&data[..data_end], &mut position)?;
+        let current = (v & 1) as usize;
+        let v = v >> 1;
+        let d = (v >> 1) ^ 0u32.wrapping_sub(v & 1);
+        let index = last[current].wrapping_add(d);
+        last[current] = index;
+        indices.push(index);
+    }
+    if position != data_end {
+        return Err("index sequence size mismatch".into());
+    }
+    Ok(indices)
+}
+
+fn push_vertex(fifo: &mut [u32; 16], offset: &mut usize, v: u32, cond: bool) {
+    fifo[*offset] = v;
+    *offset = (*offset + cond as usize) & 15;
+}
+
+fn push_edge(fifo: &mut [[u32; 2]; 16], offset: &mut usize, a: u32, b: u32) {
+    fifo[*offset] = [a, b];
+    *offset = (*offset + 1) & 15;
+}
+
+// 三角形のいずれかの辺と一致する辺のFIFOの位置と、その辺が先頭になる三角形の回転
+fn find_edge(fifo: &[[u32; 2]; 16], offset: usize, triangle: &[u32]) -> Option<(usize, usize)> {
+    (0..16).find_map(|i| {
+        let edge = fifo[offset.wrapping_sub(1 + i) & 15];
+        (0..3)
+            .find(|rotation| {
+                let order = TRIANGLE_INDEX_ORDER[*rotation];
+                edge == [triangle[order[0]], triangle[order[1]]]
+            })
+            .map(|rotation| (i, rotation))
+    })
+}
+
+fn find_vertex(fifo: &[u32; 16], offset: usize, v: u32) -> Option<usize> {
+    (0..16).find(|i| fifo[offset.wrapping_sub(1 + i) & 15] == v)
+}
+
+// 直前のインデックスからの差分をzigzagで符号化する
+fn encode_index(output: &mut Vec<u8>, index: u32, last: u32) {
+    let d = index.wrapping_sub(last);
+    write_vbyte(output, (d << 1) ^ ((d as i32 >> 31) as u32));
+}
+
+/// 三角形リストのインデックスをTRIANGLESモード(バージョン1)で符号化する
+/// 三角形の向きを保ったまま頂点の順序が回転することがある
+pub fn encode_index_buffer(indices: &[u32]) -> Vec<u8> {
+    let mut codes = Vec::with_capacity(indices.len() / 3);
+    let mut data = Vec::new();
+    let mut edge_fifo = [[!0u32; 2]; 16];
+    let mut vertex_fifo = [!0u32; 16];
+    let mut edge_fifo_offset = 0usize;
+    let mut vertex_fifo_offset = 0usize;
+    let mut next = 0u32;
+    let mut last = 0u32;
+
+    for triangle in indices.chunks(3) {
+        match find_edge(&edge_fifo, edge_fifo_offset, triangle) {
+            Some((fe, rotation)) if fe < 15 => {
+                // 既にある辺と残りの頂点
+                let order = TRIANGLE_INDEX_ORDER[rotation];
+                let (a, b, c) = (triangle[order[0]], triangle[order[1]], triangle[order[2]]);
+                let mut fec = match find_vertex(&vertex_fifo, vertex_fifo_offset, c) {
+                    Some(fc) if fc >= 1 && fc < 13 => fc,
+                    _ if c == next => {
+                        next += 1;
+                        0
+                    }
+                    _ => 15,
+                };
+                // 13, 14は直前のインデックスからの差分-1, 1
+                if fec == 15 && c.wrapping_add(1) == last {
+                    fec = 13;
+                    last = c;
+                } else if fec == 15 && c == last.wrapping_add(1) {
+                    fec = 14;
+                    last = c;
+                }
+                codes.push((fe << 4 | fec) as u8);
+                if fec == 15 {
+                    encode_index(&mut data, c, last);
+                    last = c;
+                }
+                if fec == 0 || fec >= 13 {
+                    push_vertex(&mut vertex_fifo, &mut vertex_fifo_offset, c, true);
+                }
+                push_edge(&mut edge_fifo, &mut edge_fifo_offset, c, b);
+                push_edge(&mut edge_fifo, &mut edge_fifo_offset, a, c);
+            }
+            _ => {
+                // 次の新しい頂点が先頭になるように回転する
+                let rotation = if triangle[1] == next {
+                    1
+                } else if triangle[2] == next {
+                    2
+                } else {
+                    0
+                };
+                let order = TRIANGLE_INDEX_ORDER[rotation];
+                let (a, b, c) = (triangle[order[0]], triangle[order[1]], triangle[order[2]]);
+
+                // 0, 1, 2の三角形で新しい頂点の番号を0から数え直す
+                let reset = a == 0 && b == 1 && c == 2 && next > 0;
+                if reset {
+                    next = 0;
+                    vertex_fifo = [!0u32; 16];
+                }
+                let fb = find_vertex(&vertex_fifo, vertex_fifo_offset, b);
+                let fc = find_vertex(&vertex_fifo, vertex_fifo_offset, c);
+                let mut fifo_or_next = |f: Option<usize>, v: u32| match f {
+                    Some(f) if f < 14 => f + 1,
+                    _ if v == next => {
+                        next += 1;
+                        0
+                    }
+                    _ => 15,
+                };
+                let fea = fifo_or_next(None, a);
+                let feb = fifo_or_next(fb, b);
+                let fec = fifo_or_next(fc, c);
+
+                let code_aux = (feb << 4 | fec) as u8;
+                match CODE_AUX_ENCODING_TABLE.iter().position(|v| *v == code_aux) {
+                    Some(i) if fea == 0 && i < 14 && !reset => codes.push(0xf0 | i as u8),
+                    _ => {
+                        codes.push(0xf0 | 14 | fea as u8);
+                        data.push(code_aux);
+                    }
+                }
+                for (v, fe) in &[(a, fea), (b, feb), (c, fec)] {
+                    if *fe == 15 {
+                        encode_index(&mut data, *v, last);
+                        last = *v;
+                    }
+                }
+                for (v, fe) in &[(a, fea), (b, feb), (c, fec)] {
+                    if *fe == 0 || *fe == 15 {
+                        push_vertex(&mut vertex_fifo, &mut vertex_fifo_offset, *v, true);
+                    }
+                }
+                push_edge(&mut edge_fifo, &mut edge_fifo_offset, b, a);
+                push_edge(&mut edge_fifo, &mut edge_fifo_offset, c, b);
+                push_edge(&mut edge_fifo, &mut edge_fifo_offset, a, c);
+            }
+        }
+    }
+
+    let mut output = vec![INDEX_HEADER | 1];
+    output.extend_from_slice(&codes);
+    output.extend_from_slice(&data);
+    // 復号時の表と、1つの三角形の読み過ぎを防ぐ余白を兼ねる
+    output.extend_from_slice(&CODE_AUX_ENCODING_TABLE);
+    output
+}
+
+fn decode_index(data: &[u8], position: &mut usize, last: u32) -> Result<u32, String> {
+    let v = read_vbyte(data, position)?;
+    let d = (v >> 1) ^ 0u32.wrapping_sub(v & 1);
+    Ok(last.wrapping_add(d))
+}
+
+/// TRIANGLESモードで符号化されたインデックスを復号する
+pub fn decode_index_buffer(data: &[u8], count: usize) -> Result<Vec<u32>, String> {
+    if count % 3 != 0
+        || data.len() < 1 + count / 3 + CODE_AUX_TABLE_SIZE
+        || data[0] & 0xf0 != INDEX_HEADER
+        || data[0] & 0x0f > 1
+    {
+        return Err("invalid index buffer header".into());
+    }
+    let version = data[0] & 0x0f;
+    let fec_max = if version >= 1 { 13 } else { 15 };
+
+    let mut edge_fifo = [[!0u32; 2]; 16];
+    let mut vertex_fifo = [!0u32; 16];
+    let mut edge_fifo_offset = 0usize;
+    let mut vertex_fifo_offset = 0usize;
+    let mut next = 0u32;
+    let mut last = 0u32;
+
+    let data_safe_end = data.len() - CODE_AUX_TABLE_SIZE;
+    let code_aux_table = &data[data_safe_end..];
+    let mut code_position = 1;
+    let mut position = 1 + count / 3;
+    let mut indices = Vec::with_capacity(count);
+
+    for _ in 0..count / 3 {
+        if position > data_safe_end {
+            return Err("unexpected end of index data".into());
+        }
+        let code_tri = data[code_position];
+        code_position += 1;
+
+        if code_tri < 0xf0 {
+            let fe = (code_tri >> 4) as usize;
+            let edge = edge_fifo[(edge_fifo_offset.wrapping_sub(1 + fe)) & 15];
+            let (a, b) = (edge[0], edge[1]);
+            let fec = (code_tri & 15) as usize;
+            let c = if fec < fec_max {
+                let c = if fec == 0 {
+                    next
+                } else {
+                    vertex_fifo[(vertex_fifo_offset.wrapping_sub(1 + fec)) & 15]
+                };
+                if fec == 0 {
+                    next += 1;
+                }
+                push_vertex(&mut vertex_fifo, &mut vertex_fifo_offset, c, fec == 0);
+                c
+            } else {
+                // 13, 14は直前のインデックスからの差分-1, 1
+                let c = if fec != 15 {
+                    last.wrapping_add(fec as u32).wrapping_sub(fec as u32 ^ 3)
+                } else {
+                    decode_index(&data[..data_safe_end], &mut position, last)?
+                };
+                last = c;
+                push_vertex(&mut vertex_fifo, &mut vertex_fifo_offset, c, true);
+                c
+            };
+            indices.extend_from_slice(&[a, b, c]);
+            push_edge(&mut edge_fifo, &mut edge_fifo_offset, c, b);
+            push_edge(&mut edge_fifo, &mut edge_fifo_offset, a, c);
+        } else {
+            let (code_aux, fea) = if code_tri < 0xfe {
+                (code_aux_table[(code_tri & 15) as usize], 0)
+            } else {
+                let code_aux = *data
+                    .get(position)
+                    .filter(|_| position < data_safe_end)
+                    .ok_or("unexpected end of index data")?;
+                position += 1;
+                if version >= 1 && code_aux == 0 {
+                    next = 0;
+                }
+                (code_aux, if code_tri == 0xfe { 0 } else { 15 })
+            };
+            let feb = (code_aux >> 4) as usize;
+            let fec = (code_aux & 15) as usize;
+
+            let mut a = if fea == 0 {
+                next += 1;
+                next - 1
+            } else {
+                0
+            };
+            let mut b = if feb == 0 {
+                next += 1;
+                next - 1
+            } else {
+                vertex_fifo[(vertex_fifo_offset.wrapping_sub(feb)) & 15]
+            };
+            let mut c = if fec == 0 {
+                next += 1;
+                next - 1
+            } else {
+                vertex_fifo[(vertex_fifo_offset.wrapping_sub(fec)) & 15]
+            };
+            if fea == 15 {
+                a = decode_index(&data[..data_safe_end], &mut position, last)?;
+                last = a;
+            }
+            if feb == 15 {
+                b = decode_index(&data[..data_safe_end], &mut position, last)?;
+                last = b;
+            }
+            if fec == 15 {
+                c = decode_index(&data[..data_safe_end], &mut position, last)?;
+                last = c;
+            }
+            indices.extend_from_slice(&[a, b, c]);
+            push_vertex(&mut vertex_fifo, &mut vertex_fifo_offset, a, true);
+            push_vertex(
+                &mut vertex_fifo,
+                &mut vertex_fifo_offset,
+                b,
+                feb == 0 || feb == 15,
+            );
+            push_vertex(
+                &mut vertex_fifo,
+                &mut vertex_fifo_offset,
+                c,
+                fec == 0 || fec == 15,
+            );
+            push_edge(&mut edge_fifo, &mut edge_fifo_offset, b, a);
+            push_edge(&mut edge_fifo, &mut edge_fifo_offset, c, b);
+            push_edge(&mut edge_fifo, &mut edge_fifo_offset, a, c);
+        }
+    }
+
+    if position != data_safe_end {
+        return Err("index buffer size mismatch".into());
+    }
+    Ok(indices)
+}
+
+fn read_indices(bytes: &[u8], index_size: usize) -> Vec<u32> {
+    bytes
+        .chunks(index_size)
+        .map(|c| match index_size {
+            2 => u32::from(c[0]) | u32::from(c[1]) << 8,
+            _ => {
+                u32::from(c[0])
+                    | u32::from(c[1]) << 8
+                    | u32::from(c[2]) << 16
+                    | u32::from(c[3]) << 24
+            }
+        })
+        .collect()
+}
+
+fn write_indices(indices: &[u32], index_size: usize) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(indices.len() * index_size);
+    for index in indices {
+        for i in 0..index_size {
+            bytes.push((index >> (i * 8)) as u8);
+        }
+    }
+    bytes
+}
+
+// EXPONENTIALフィルター: 上位8ビットが指数、下位24ビットが仮数
+fn decode_exponential_filter(bytes: &mut [u8]) {
+    for c in bytes.chunks_mut(4) {
+        let v =
+            u32::from(c[0]) | u32::from(c[1]) << 8 | u32::from(c[2]) << 16 | u32::from(c[3]) << 24;
+        let e = (v as i32) >> 24;
+        let m = ((v << 8) as i32) >> 8;
+        let r = (m as f32 * 2f32.powi(e)).to_bits();
+        for (i, b) in c.iter_mut().enumerate() {
+            *b = (r >> (i * 8)) as u8;
+        }
+    }
+}
+
+// フィルターの成分。8ビットか16ビットの符号付き整数
+fn read_component(c: &[u8], i: usize, component_size: usize) -> i32 {
+    match component_size {
+        1 => i32::from(c[i] as i8),
+        _ => i32::from((u16::from(c[i * 2]) | u16::from(c[i * 2 + 1]) << 8) as i16),
+    }
+}
+
+fn write_component(c: &mut [u8], i: usize, component_size: usize, v: i32) {
+    match component_size {
+        1 => c[i] = v as u8,
+        _ => {
+            c[i * 2] = v as u8;
+            c[i * 2 + 1] = (v >> 8) as u8;
+        }
+    }
+}
+
+// 四捨五入して符号付き整数にする
+fn round_to_int(v: f32) -> i32 {
+    (v + if v >= 0.0 { 0.5 } else { -0.5 }) as i32
+}
+
+// OCTAHEDRALフィルター: 八面体に写した単位ベクトル。zに1.0を表す値が入っている
+// 4成分の8ビットか16ビットの符号付き整数で、wは変更しない
+fn decode_octahedral_filter(bytes: &mut [u8], byte_stride: usize) {
+    let component_size = byte_stride / 4;
+    let max = ((1 << (component_size * 8 - 1)) - 1) as f32;
+    for c in bytes.chunks_mut(byte_stride) {
+        let mut x = read_component(c, 0, component_size) as f32;
+        let mut y = read_component(c, 1, component_size) as f32;
+        let z = read_component(c, 2, component_size) as f32 - x.abs() - y.abs();
+
+        // z < 0の面を折り返す
+        let t = z.min(0.0);
+        x += if x >= 0.0 { t } else { -t };
+        y += if y >= 0.0 { t } else { -t };
+
+        let s = max / (x * x + y * y + z * z).sqrt();
+        write_component(c, 0, component_size, round_to_int(x * s));
+        write_component(c, 1, component_size, round_to_int(y * s));
+        write_component(c, 2, component_size, round_to_int(z * s));
+    }
+}
+
+// QUATERNIONフィルター: 最大の成分を除いた3成分と、最大の成分の位置
+// 4成分の16ビット符号付き整数で、4番目の成分の下位2ビットが最大の成分の位置、残りが量子化の最大値
+fn decode_quaternion_filter(bytes: &mut [u8]) {
+    let scale = 1.0 / 2f32.sqrt();
+    for c in bytes.chunks_mut(8) {
+        let sf = read_component(c, 3, 2);
+        let ss = scale / (sf | 3) as f32;
+        let x = read_component(c, 0, 2) as f32 * ss;
+        let y = read_component(c, 1, 2) as f32 * ss;
+        let z = read_component(c, 2, 2) as f32 * ss;
+        let w = (1.0 - x * x - y * y - z * z).max(0.0).sqrt();
+
+        let qc = (sf & 3) as usize;
+        write_component(c, (qc + 1) & 3, 2, round_to_int(x * 32767.0));
+        write_component(c, (qc + 2) & 3, 2, round_to_int(y * 32767.0));
+        write_component(c, (qc + 3) & 3, 2, round_to_int(z * 32767.0));
+        write_component(c, qc, 2, (w * 32767.0 + 0.5) as i32);
+    }
+}
+
+fn decode_buffer_view(extension: &Value, chunks: &[Vec<u8>]) -> Result<Vec<u8>, String> {
+    let get_u64 = |key: &str| {
+        extension
+            .get(key)
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| format!("missing {}", key))
+    };
+    let buffer = get_u64("buffer")? as usize;
+    let byte_offset = extension
+        .get("byteOffset")
+        .and_then(|v| v.as_u64())
+        .unwrap_or(0) as usize;
+    let byte_length = get_u64("byteLength")? as usize;
+    let byte_stride = get_u64("byteStride")? as usize;
+    let count = get_u64("count")? as usize;
+    let data = chunks
+        .get(buffer)
+        .and_then(|c| c.get(byte_offset..byte_offset + byte_length))
+        .ok_or("compressed data is out of buffer")?;
+
+    let mut bytes = match extension.get("mode").and_then(|v| v.as_str()) {
+        Some("ATTRIBUTES") => decode_vertex_buffer(data, count, byte_stride)?,
+        Some("TRIANGLES") if byte_stride == 2 || byte_stride == 4 => {
+            write_indices(&decode_index_buffer(data, count)?, byte_stride)
+        }
+        Some("INDICES") if byte_stride == 2 || byte_stride == 4 => {
+            write_indices(&decode_index_sequence(data, count)?, byte_stride)
+        }
+        mode => return Err(format!("unsupported mode {:?}", mode)),
+    };
+    match extension.get("filter").and_then(|v| v.as_str()) {
+        None | Some("NONE") => {}
+        Some("OCTAHEDRAL") if byte_stride == 4 || byte_stride == 8 => {
+            decode_octahedral_filter(&mut bytes, byte_stride)
+        }
+        Some("QUATERNION") if byte_stride == 8 => decode_quaternion_filter(&mut bytes),
+        Some("EXPONENTIAL") if byte_stride % 4 == 0 => decode_exponential_filter(&mut bytes),
+        Some(filter) => {
+            return Err(format!(
+                "unsupported filter {} for byteStride {}",
+                filter, byte_stride
+            ))
+        }
+    }
+    Ok(bytes)
+}
+
+/// EXT_meshopt_compressionで圧縮されたbufferViewを復号し、通常のbufferViewに置き換える
+/// 1つでも復号できなければ何も変更せずにエラーを返す
+pub fn decompress_meshopt(gltf_: Value, chunks: &mut Vec<Vec<u8>>) -> Result<Value, String> {
+    let mut gltf = gltf_.clone();
+    let mut decoded = Vec::new();
+    for (buffer_view_index, buffer_view) in gltf
+        .get("bufferViews")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .enumerate()
+    {
+        if let Some(extension) = buffer_view
+            .get("extensions")
+            .and_then(|v| v.get(EXTENSION_NAME))
+        {
+            let bytes = decode_buffer_view(extension, chunks)
+                .map_err(|e| format!("Failed to decode bufferView {}: {}", buffer_view_index, e))?;
+            decoded.push((buffer_view_index, bytes));
+        }
+    }
+    if decoded.is_empty() {
+        return Ok(gltf);
+    }
+
+    for (buffer_view_index, bytes) in decoded {
+        let byte_offset = append_to_buffer(&mut gltf, chunks, &bytes);
+        let buffer_view = &mut gltf["bufferViews"][buffer_view_index];
+        buffer_view["buffer"] = 0.into();
+        buffer_view["byteOffset"] = byte_offset.into();
+        buffer_view["byteLength"] = bytes.len().into();
+        if let Some(extensions) = buffer_view
+            .get_mut("extensions")
+            .and_then(|v| v.as_object_mut())
+        {
+            extensions.remove(EXTENSION_NAME);
+        }
+    }
+    for buffer in gltf
+        .get_mut("buffers")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(extensions) = buffer.get_mut("extensions").and_then(|v| v.as_object_mut()) {
+            extensions.remove(EXTENSION_NAME);
+        }
+    }
+    for key in &["extensionsUsed", "extensionsRequired"] {
+        if let Some(extensions) = gltf.get_mut(*key).and_then(|v| v.as_array_mut()) {
+            extensions.retain(|v| v.as_str() != Some(EXTENSION_NAME));
+        }
+    }
+    Ok(gltf)
+}
+
+enum BufferViewUsage {
+    Attributes(usize),
+    /// 三角形リストのインデックス
+    Triangles(usize),
+    Indices(usize),
+    Unsupported,
+}
+
+// accessorの使われ方からbufferViewの圧縮モードを決める
+fn buffer_view_usages(gltf: &Value) -> BTreeMap<u64, BufferViewUsage> {
+    let mut index_accessors = BTreeSet::new();
+    let mut non_triangle_index_accessors = BTreeSet::new();
+    for mesh in gltf
+        .get("meshes")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        for primitive in mesh
+            .get("primitives")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+        {
+            if let Some(index) = primitive.get("indices").and_then(|v| v.as_u64()) {
+                index_accessors.insert(index);
+                if primitive
+                    .get("mode")
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(TRIANGLES)
+                    != TRIANGLES
+                {
+                    non_triangle_index_accessors.insert(index);
+                }
+            }
+        }
+    }
+
+    let buffer_views = gltf.get("bufferViews").and_then(|v| v.as_array());
+    let mut usages = BTreeMap::new();
+    for (accessor_index, accessor) in gltf
+        .get("accessors")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .enumerate()
+    {
+        if let Some(sparse) = accessor.get("sparse") {
+            for pointer in &["/indices/bufferView", "/values/bufferView"] {
+                if let Some(index) = sparse.pointer(pointer).and_then(|v| v.as_u64()) {
+                    usages.insert(index, BufferViewUsage::Unsupported);
+                }
+            }
+        }
+        let buffer_view_index =
+            if let Some(index) = accessor.get("bufferView").and_then(|v| v.as_u64()) {
+                index
+            } else {
+                continue;
+            };
+        let byte_stride = buffer_views
+            .and_then(|v| v.get(buffer_view_index as usize))
+            .and_then(|v| v.get("byteStride"))
+            .and_then(|v| v.as_u64())
+            .map(|v| v as usize);
+        // 三角形の回転が他のアクセサーにずれて伝わらないように、三角形の境界から始まるものだけ
+        let triangles = !non_triangle_index_accessors.contains(&(accessor_index as u64))
+            && element_size(accessor)
+                .map(|size| {
+                    accessor
+                        .get("byteOffset")
+                        .and_then(|v| v.as_u64())
+                        .unwrap_or(0)
+                        % (size as u64 * 3)
+                        == 0
+                })
+                .unwrap_or(false)
+            && accessor.get("count").and_then(|v| v.as_u64()).unwrap_or(0) % 3 == 0;
+        let usage = match (
+            index_accessors.contains(&(accessor_index as u64)),
+            element_size(accessor),
+            byte_stride,
+        ) {
+            (true, Some(size), None) if (size == 2 || size == 4) && triangles => {
+                BufferViewUsage::Triangles(size)
+            }
+            (true, Some(size), None) if size == 2 || size == 4 => BufferViewUsage::Indices(size),
+            (false, Some(size), None) if size % 4 == 0 && size <= 256 => {
+                BufferViewUsage::Attributes(size)
+            }
+            (false, _, Some(stride)) if stride % 4 == 0 && stride <= 256 => {
+                BufferViewUsage::Attributes(stride)
+            }
+            _ => BufferViewUsage::Unsupported,
+        };
+        let merged = match (usages.remove(&buffer_view_index), usage) {
+            (None, usage) => usage,
+            (Some(BufferViewUsage::Attributes(l)), BufferViewUsage::Attributes(r)) if l == r => {
+                BufferViewUsage::Attributes(l)
+            }
+            (Some(BufferViewUsage::Triangles(l)), BufferViewUsage::Triangles(r)) if l == r => {
+                BufferViewUsage::Triangles(l)
+            }
+            (Some(BufferViewUsage::Indices(l)), BufferViewUsage::Indices(r))
+            | (Some(BufferViewUsage::Indices(l)), BufferViewUsage::Triangles(r))
+            | (Some(BufferViewUsage::Triangles(l)), BufferViewUsage::Indices(r))
+                if l == r =>
+            {
+                BufferViewUsage::Indices(l)
+            }
+            _ => BufferViewUsage::Unsupported,
+        };
+        usages.insert(buffer_view_index, merged);
+    }
+    usages
+}
+
+/// 頂点、インデックスのbufferViewをEXT_meshopt_compressionで圧縮し、先頭バッファの末尾に追加する
+/// 元のデータの代わりにデータを持たないフォールバックバッファを参照する
+/// 使われなくなった元のデータはrelocate_buffersで削除する
+pub fn compress_meshopt(gltf_: Value, chunks: &mut Vec<Vec<u8>>) -> Value {
+    let mut gltf = gltf_.clone();
+    let usages = buffer_view_usages(&gltf);
+    let buffer_view_len = gltf
+        .get("bufferViews")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+    let fallback_buffer_index = gltf
+        .get("buffers")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0)
+        .max(1);
+
+    let mut compressed_byte_length = 0;
+    let mut fallback_used = false;
+    for buffer_view_index in 0..buffer_view_len {
+        let compressed = {
+            let bytes =
+                if let Some(bytes) = buffer_view_bytes(&gltf, chunks, buffer_view_index as u64) {
+                    bytes
+                } else {
+                    continue;
+                };
+            match usages.get(&(buffer_view_index as u64)) {
+                Some(BufferViewUsage::Attributes(stride)) if bytes.len() % stride == 0 => Some((
+                    "ATTRIBUTES",
+                    *stride,
+                    bytes.len() / stride,
+                    encode_vertex_buffer(bytes, *stride),
+                )),
+                Some(BufferViewUsage::Triangles(size)) if bytes.len() % (size * 3) == 0 => Some((
+                    "TRIANGLES",
+                    *size,
+                    bytes.len() / size,
+                    encode_index_buffer(&read_indices(bytes, *size)),
+                )),
+                Some(BufferViewUsage::Triangles(size)) | Some(BufferViewUsage::Indices(size))
+                    if bytes.len() % size == 0 =>
+                {
+                    Some((
+                        "INDICES",
+                        *size,
+                        bytes.len() / size,
+                        encode_index_sequence(&read_indices(bytes, *size)),
+                    ))
+                }
+                _ => None,
+            }
+        };
+
+        if let Some((mode, byte_stride, count, encoded)) = compressed {
+            // 圧縮データは先頭バッファの末尾に追加し、元の領域はrelocate_buffersで削除する
+            let byte_offset = append_to_buffer(&mut gltf, chunks, &encoded);
+            compressed_byte_length += encoded.len();
+            let mut extension = serde_json::map::Map::new();
+            extension.insert("buffer".into(), 0.into());
+            extension.insert("byteOffset".into(), byte_offset.into());
+            extension.insert("byteLength".into(), encoded.len().into());
+            extension.insert("byteStride".into(), byte_stride.into());
+            extension.insert("mode".into(), mode.into());
+            extension.insert("count".into(), count.into());
+            let buffer_view = &mut gltf["bufferViews"][buffer_view_index];
+            buffer_view["extensions"][EXTENSION_NAME] = extension.into();
+            // 展開先はデータを持たないフォールバックのバッファ
+            buffer_view["buffer"] = fallback_buffer_index.into();
+            fallback_used = true;
+        }
+    }
+    if !fallback_used {
+        return gltf;
+    }
+    info!("meshopt: compressed {} bytes", compressed_byte_length);
+
+    let mut fallback_buffer = serde_json::map::Map::new();
+    fallback_buffer.insert("byteLength".into(), 0.into());
+    fallback_buffer.insert(
+        "extensions".into(),
+        json_object(EXTENSION_NAME, json_object("fallback", true.into())),
+    );
+    if let Some(buffers) = gltf["buffers"].as_array_mut() {
+        buffers.push(fallback_buffer.into());
+    }
+    use_extension(&mut gltf, EXTENSION_NAME, true);
+    gltf
+}
+
+fn json_object(key: &str, value: Value) -> Value {
+    let mut object = serde_json::map::Map::new();
+    object.insert(key.into(), value);
+    object.into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quantize_snorm(v: f32, bits: u32) -> i32 {
+        round_to_int(v.max(-1.0).min(1.0) * ((1 << (bits - 1)) - 1) as f32)
+    }
+
+    fn normalize(v: [f32; 4]) -> [f32; 4] {
+        let l = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+        [v[0] / l, v[1] / l, v[2] / l, v[3]]
+    }
+
+    fn encode_octahedral_filter(vectors: &[[f32; 4]], component_size: usize) -> Vec<u8> {
+        let bits = component_size as u32 * 8;
+        let mut bytes = vec![0u8; vectors.len() * component_size * 4];
+        for (v, c) in vectors.iter().zip(bytes.chunks_mut(component_size * 4)) {
+            let l = v[0].abs() + v[1].abs() + v[2].abs();
+            let (x, y) = (v[0] / l, v[1] / l);
+            let sign = |v: f32| if v >= 0.0 { 1.0 } else { -1.0 };
+            let u = if v[2] >= 0.0 {
+                x
+            } else {
+                (1.0 - y.abs()) * sign(x)
+            };
+            let w = if v[2] >= 0.0 {
+                y
+            } else {
+                (1.0 - x.abs()) * sign(y)
+            };
+            write_component(c, 0, component_size, quantize_snorm(u, bits));
+            write_component(c, 1, component_size, quantize_snorm(w, bits));
+            write_component(c, 2, component_size, quantize_snorm(1.0, bits));
+            write_component(c, 3, component_size, quantize_snorm(v[3], bits));
+        }
+        bytes
+    }
+
+    fn encode_quaternion_filter(quaternions: &[[f32; 4]], bits: u32) -> Vec<u8> {
+        let mut bytes = vec![0u8; quaternions.len() * 8];
+        for (q, c) in quaternions.iter().zip(bytes.chunks_mut(8)) {
+            let qc = (0..4)
+                .max_by(|l, r| q[*l].abs().partial_cmp(&q[*r].abs()).unwrap())
+                .unwrap();
+            let sign = if q[qc] < 0.0 { -1.0 } else { 1.0 };
+            for i in 0..3 {
+                let v = q[(qc + 1 + i) & 3] * 2f32.sqrt() * sign;
+                write_component(c, i, 2, quantize_snorm(v, bits));
+            }
+            write_component(c, 3, 2, (quantize_snorm(1.0, bits) & !3) | qc as i32);
+        }
+        bytes
+    }
+
+    fn encode_exponential_filter(values: &[f32]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for v in values {
+            // 仮数が符号付き24ビットに収まる指数
+            let e = if *v == 0.0 {
+                0
+            } else {
+                v.abs().log2().floor() as i32 + 1 - 23
+            };
+            let m = round_to_int(v * 2f32.powi(-e));
+            let encoded = (e << 24) as u32 | (m as u32 & 0xff_ffff);
+            for i in 0..4 {
+                bytes.push((encoded >> (i * 8)) as u8);
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn octahedral_filter_round_trip() {
+        let vectors = [
+            [0.0, 0.0, 1.0, 1.0],
+            [0.0, 0.0, -1.0, -1.0],
+            [1.0, 0.0, 0.0, 1.0],
+            [0.0, -1.0, 0.0, 1.0],
+            [0.3, -0.5, 0.8, 1.0],
+            [-0.6, 0.2, -0.7, -1.0],
+            [0.1, 0.9, -0.4, 1.0],
+        ];
+        for (component_size, tolerance) in &[(1, 0.02), (2, 0.0002)] {
+            let mut bytes = encode_octahedral_filter(
+                &vectors.iter().map(|v| normalize(*v)).collect::<Vec<_>>(),
+                *component_size,
+            );
+            decode_octahedral_filter(&mut bytes, component_size * 4);
+            let max = ((1 << (component_size * 8 - 1)) - 1) as f32;
+            for (v, c) in vectors.iter().zip(bytes.chunks(component_size * 4)) {
+                let expected = normalize(*v);
+                for i in 0..4 {
+                    let actual = read_component(c, i, *component_size) as f32 / max;
+                    assert!(
+                        (actual - expected[i]).abs() <= *tolerance,
+                        "{:?} {}: {} != {}",
+                        v,
+                        i,
+                        actual,
+                        expected[i]
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quaternion_filter_round_trip() {
+        let quaternions = [
+            [0.0, 0.0, 0.0, 1.0],
+            [0.0, 0.0, 0.0, -1.0],
+            [1.0, 0.0, 0.0, 0.0],
+            [0.5, -0.5, 0.5, 0.5],
+            [0.1, -0.9, 0.3, 0.2],
+            [-0.7, 0.1, 0.2, -0.4],
+        ];
+        for q in quaternions.iter() {
+            let l = q.iter().map(|v| v * v).sum::<f32>().sqrt();
+            let q = [q[0] / l, q[1] / l, q[2] / l, q[3] / l];
+            let mut bytes = encode_quaternion_filter(&[q], 12);
+            decode_quaternion_filter(&mut bytes);
+            let decoded = (0..4)
+                .map(|i| read_component(&bytes, i, 2) as f32 / 32767.0)
+                .collect::<Vec<_>>();
+            // qと-qは同じ回転
+            let dot = (0..4).map(|i| q[i] * decoded[i]).sum::<f32>();
+            for i in 0..4 {
+                assert!(
+                    (decoded[i] * dot.signum() - q[i]).abs() < 0.002,
+                    "{:?} != {:?}",
+                    decoded,
+                    q
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn exponential_filter_round_trip() {
+        let values = [0.0, 1.0, -1.5, 0.25, 3.0, -1000.5, 1.0e-6, 123_456.75];
+        let mut bytes = encode_exponential_filter(&values);
+        decode_exponential_filter(&mut bytes);
+        for (v, c) in values.iter().zip(bytes.chunks(4)) {
+            let decoded = f32::from_bits(
+                u32::from(c[0])
+                    | u32::from(c[1]) << 8
+                    | u32::from(c[2]) << 16
+                    | u32::from(c[3]) << 24,
+            );
+            // 仮数は符号付き24ビットなので、floatの24ビットの仮数は1ビット丸められる
+            assert!(
+                (decoded - v).abs() <= v.abs() / (1 << 23) as f32,
+                "{} != {}",
+                decoded,
+                v
+            );
+        }
+    }
+
+    // 向きを保ったまま、最小のインデックスが先頭になるように回転する
+    fn canonical_triangles(indices: &[u32]) -> Vec<[u32; 3]> {
+        indices
+            .chunks(3)
+            .map(|t| {
+                let r = (0..3).min_by_key(|i| t[*i]).unwrap();
+                [t[r], t[(r + 1) % 3], t[(r + 2) % 3]]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn index_buffer_round_trip() {
+        // 辺を共有するグリッド
+        let mut indices = Vec::new();
+        for y in 0..8u32 {
+            for x in 0..8u32 {
+                let i = y * 9 + x;
+                indices.extend_from_slice(&[i, i + 9, i + 1, i + 1, i + 9, i + 10]);
+            }
+        }
+        // 離れたインデックス、直前からの差分が±1のもの、0, 1, 2からのやり直し
+        indices.extend_from_slice(&[1000, 5, 70_000, 71, 72, 73, 72, 71, 70]);
+        indices.extend_from_slice(&[0, 1, 2, 2, 1, 3, 4, 6, 5, 7, 8, 9]);
+
+        let encoded = encode_index_buffer(&indices);
+        assert_eq!(encoded[0], INDEX_HEADER | 1);
+        let decoded = decode_index_buffer(&encoded, indices.len()).unwrap();
+        assert_eq!(canonical_triangles(&decoded), canonical_triangles(&indices));
+    }
+
+    // meshoptimizerのmeshopt_encodeIndexBuffer(バージョン0)の出力
+    const REFERENCE_INDICES: [u32; 12] = [0, 1, 2, 2, 1, 3, 4, 6, 5, 7, 8, 9];
+    const REFERENCE_INDEX_BUFFER: [u8; 27] = [
+        0xe0, 0xf0, 0x10, 0xfe, 0xff, 0xf0, 0x0c, 0xff, 0x02, 0x02, 0x02, 0x00, 0x76, 0x87, 0x56,
+        0x67, 0x78, 0xa9, 0x86, 0x65, 0x89, 0x68, 0x98, 0x01, 0x69, 0x00, 0x00,
+    ];
+
+    // meshoptimizerのmeshopt_encodeVertexBufferの出力。末尾の0は省略
+    // 頂点は{u16 px, py, pz; u8 nu, nv; u16 tx, ty}
+    const REFERENCE_VERTEX_BUFFER: [u8; 50] = [
+        0xa0, 0x01, 0x3f, 0x00, 0x00, 0x00, 0x58, 0x57, 0x58, 0x01, 0x26, 0x00, 0x00, 0x00, 0x01,
+        0x0c, 0x00, 0x00, 0x00, 0x58, 0x01, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+        0x3f, 0x00, 0x00, 0x00, 0x17, 0x18, 0x17, 0x01, 0x26, 0x00, 0x00, 0x00, 0x01, 0x0c, 0x00,
+        0x00, 0x00, 0x17, 0x01, 0x08,
+    ];
+    const REFERENCE_VERTEX_BUFFER_LEN: usize = 85;
+
+    fn reference_vertices() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (x, y) in &[(0u16, 0u16), (1, 0), (0, 1), (1, 1)] {
+            for v in &[x * 300, y * 300, 0] {
+                bytes.extend_from_slice(&[*v as u8, (*v >> 8) as u8]);
+            }
+            bytes.extend_from_slice(&[0, 0]);
+            for v in &[x * 500, y * 500] {
+                bytes.extend_from_slice(&[*v as u8, (*v >> 8) as u8]);
+            }
+        }
+        bytes
+    }
+
+    fn reference_vertex_buffer() -> Vec<u8> {
+        let mut data = REFERENCE_VERTEX_BUFFER.to_vec();
+        data.resize(REFERENCE_VERTEX_BUFFER_LEN, 0);
+        data
+    }
+
+    #[test]
+    fn decode_reference_index_buffer() {
+        assert_eq!(
+            decode_index_buffer(&REFERENCE_INDEX_BUFFER, REFERENCE_INDICES.len()).unwrap(),
+            REFERENCE_INDICES.to_vec()
+        );
+    }
+
+    #[test]
+    fn decode_reference_vertex_buffer() {
+        let data = reference_vertex_buffer();
+        assert_eq!(
+            decode_vertex_buffer(&data, 4, 12).unwrap(),
+            reference_vertices()
+        );
+        assert_eq!(encode_vertex_buffer(&reference_vertices(), 12), data);
+    }
+
+    #[test]
+    fn vertex_buffer_round_trip() {
+        // 複数のブロックにまたがり、16の倍数でない頂点数
+        for byte_stride in &[4, 12, 16, 36] {
+            let count = vertex_block_size(*byte_stride) * 2 + 7;
+            let bytes = (0..count * byte_stride)
+                .map(|i| {
+                    let (vertex, k) = (i / byte_stride, i % byte_stride);
+                    (vertex * (k + 1) / 3 + (vertex * 7919 % 13) * (k % 2)) as u8
+                })
+                .collect::<Vec<_>>();
+            let encoded = encode_vertex_buffer(&bytes, *byte_stride);
+            assert_eq!(encoded[0], VERTEX_HEADER);
+            assert_eq!(
+                decode_vertex_buffer(&encoded, count, *byte_stride).unwrap(),
+                bytes,
+                "byteStride {}",
+                byte_stride
+            );
+        }
+        assert!(decode_vertex_buffer(&reference_vertex_buffer()[..84], 4, 12).is_err());
+    }
+
+    #[test]
+    fn index_sequence_round_trip() {
+        let indices = [0, 1, 2, 100_000, 3, 4, 99_990, 5, 0xffff_ffff, 6, 0, 7];
+        let encoded = encode_index_sequence(&indices);
+        assert_eq!(encoded[0], SEQUENCE_HEADER | 1);
+        assert_eq!(
+            decode_index_sequence(&encoded, indices.len()).unwrap(),
+            indices.to_vec()
+        );
+        assert!(decode_index_sequence(&encoded, indices.len() + 1).is_err());
+    }
+
+    fn compressed_gltf(second_byte_length: usize) -> Value {
+        serde_json::from_str(&format!(
+            r#"{{
+                "asset": {{"version": "2.0"}},
+                "extensionsUsed": ["EXT_meshopt_compression"],
+                "extensionsRequired": ["EXT_meshopt_compression"],
+                "buffers": [
+                    {{"byteLength": 112}},
+                    {{"byteLength": 96, "extensions": {{"EXT_meshopt_compression": {{"fallback": true}}}}}}
+                ],
+                "bufferViews": [
+                    {{"buffer": 1, "byteOffset": 0, "byteLength": 48, "extensions": {{
+                        "EXT_meshopt_compression": {{
+                            "buffer": 0, "byteOffset": 0, "byteLength": 85,
+                            "byteStride": 12, "count": 4, "mode": "ATTRIBUTES"
+                        }}
+                    }}}},
+                    {{"buffer": 1, "byteOffset": 48, "byteLength": 48, "extensions": {{
+                        "EXT_meshopt_compression": {{
+                            "buffer": 0, "byteOffset": 85, "byteLength": {},
+                            "byteStride": 4, "count": 12, "mode": "TRIANGLES"
+                        }}
+                    }}}}
+                ]
+            }}"#,
+            second_byte_length
+        ))
+        .unwrap()
+    }
+
+    fn compressed_chunks() -> Vec<Vec<u8>> {
+        let mut chunk = reference_vertex_buffer();
+        chunk.extend_from_slice(&REFERENCE_INDEX_BUFFER);
+        vec![chunk]
+    }
+
+    #[test]
+    fn decompress_reference_buffer_views() {
+        let mut chunks = compressed_chunks();
+        let gltf =
+            decompress_meshopt(compressed_gltf(REFERENCE_INDEX_BUFFER.len()), &mut chunks).unwrap();
+        assert_eq!(gltf["extensionsUsed"], Value::Array(Vec::new()));
+        assert_eq!(
+            buffer_view_bytes(&gltf, &chunks, 0).unwrap(),
+            reference_vertices().as_slice()
+        );
+        assert_eq!(
+            buffer_view_bytes(&gltf, &chunks, 1).unwrap(),
+            write_indices(&REFERENCE_INDICES, 4).as_slice()
+        );
+    }
+
+    #[test]
+    fn decompress_failure_leaves_input_unchanged() {
+        // 2つ目のbufferViewは圧縮データが途中で切れている
+        let mut chunks = compressed_chunks();
+        assert!(decompress_meshopt(compressed_gltf(20), &mut chunks).is_err());
+        assert_eq!(chunks, compressed_chunks());
+    }
+}
@@ -0,0 +1,427 @@
+use super::buffer::*;
+use super::texture::*;
+use image::{DynamicImage, RgbaImage};
+use log::{debug, warn};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+// 焼き込みに使う光源の向き。正面やや上から照らす
+const LIGHT_DIRECTION: [f64; 3] = [0.0, 0.447_213_6, 0.894_427_2];
+// 法線の平均を取るときの分割数
+const NORMAL_SAMPLES: usize = 64;
+
+// 視点側を向いた法線。画面上の球の各点に相当する
+fn view_normals() -> Vec<[f64; 3]> {
+    let mut normals = Vec::new();
+    for y in 0..NORMAL_SAMPLES {
+        for x in 0..NORMAL_SAMPLES {
+            let nx = (x as f64 + 0.5) / NORMAL_SAMPLES as f64 * 2.0 - 1.0;
+            let ny = (y as f64 + 0.5) / NORMAL_SAMPLES as f64 * 2.0 - 1.0;
+            let r2 = nx * nx + ny * ny;
+            if r2 < 1.0 {
+                normals.push([nx, ny, (1.0 - r2).sqrt()]);
+            }
+        }
+    }
+    normals
+}
+
+fn float_property(material_properties: &Value, name: &str, default: f64) -> f64 {
+    material_properties
+        .get("floatProperties")
+        .and_then(|v| v.get(name))
+        .and_then(|v| v.as_f64())
+        .unwrap_or(default)
+}
+
+fn color_property(material_properties: &Value, name: &str, default: [f64; 4]) -> [f64; 4] {
+    let mut color = default;
+    if let Some(values) = material_properties
+        .get("vectorProperties")
+        .and_then(|v| v.get(name))
+        .and_then(|v| v.as_array())
+    {
+        for (c, v) in color.iter_mut().zip(values.iter()) {
+            if let Some(v) = v.as_f64() {
+                *c = v;
+            }
+        }
+    }
+    color
+}
+
+fn texture_property(material_properties: &Value, name: &str) -> Option<u64> {
+    material_properties
+        .get("textureProperties")?
+        .get(name)?
+        .as_u64()
+}
+
+fn load_texture(gltf: &Value, chunks: &[Vec<u8>], texture_index: u64) -> Option<RgbaImage> {
+    let source = gltf
+        .get("textures")?
+        .get(texture_index as usize)?
+        .get("source")?
+        .as_u64()?;
+    let buffer_view = gltf
+        .get("images")?
+        .get(source as usize)?
+        .get("bufferView")?
+        .as_u64()?;
+    let bytes = buffer_view_bytes(gltf, chunks, buffer_view)?;
+    match image::load_from_memory(bytes) {
+        Ok(image) => Some(image.to_rgba()),
+        Err(e) => {
+            warn!("Failed to decode texture {}: {:?}", texture_index, e);
+            None
+        }
+    }
+}
+
+fn load_texture_sized(
+    gltf: &Value,
+    chunks: &[Vec<u8>],
+    texture_index: Option<u64>,
+    width: u32,
+    height: u32,
+) -> Option<RgbaImage> {
+    let image = load_texture(gltf, chunks, texture_index?)?;
+    if image.dimensions() == (width, height) {
+        Some(image)
+    } else {
+        Some(image::imageops::resize(
+            &image,
+            width,
+            height,
+            image::FilterType::Triangle,
+        ))
+    }
+}
+
+// 視点側を向いた法線での陰の割合(0が陰、1が光)、リムライトの強さ、MatCapの色の平均
+fn average_shading(
+    material_properties: &Value,
+    matcap: Option<&RgbaImage>,
+    normals: &[[f64; 3]],
+) -> (f64, f64, [f64; 3]) {
+    // https://github.com/Santarh/MToon/blob/bf08610b38e27ae915b0613ee4dfb574bc8e381b/MToon/Resources/Shaders/MToonCore.cginc
+    let shade_shift = float_property(material_properties, "_ShadeShift", 0.0);
+    let shade_toony = float_property(material_properties, "_ShadeToony", 0.9);
+    let rim_lift = float_property(material_properties, "_RimLift", 0.0);
+    let rim_fresnel_power = float_property(material_properties, "_RimFresnelPower", 1.0);
+
+    let mut lighting = 0.0;
+    let mut rim = 0.0;
+    let mut matcap_color = [0.0; 3];
+    for normal in normals {
+        let n_dot_l = normal
+            .iter()
+            .zip(LIGHT_DIRECTION.iter())
+            .map(|(n, l)| n * l)
+            .sum::<f64>();
+        lighting += ((n_dot_l - shade_shift) / (1.0 - shade_toony).max(1e-3))
+            .max(0.0)
+            .min(1.0);
+        rim += (1.0 - normal[2] + rim_lift)
+            .max(0.0)
+            .min(1.0)
+            .powf(rim_fresnel_power);
+        if let Some(matcap) = matcap {
+            let (width, height) = matcap.dimensions();
+            let x = ((normal[0] * 0.5 + 0.5) * f64::from(width)) as u32;
+            let y = ((0.5 - normal[1] * 0.5) * f64::from(height)) as u32;
+            let pixel = matcap.get_pixel(x.min(width - 1), y.min(height - 1));
+            for (c, v) in matcap_color.iter_mut().zip(pixel.data.iter()) {
+                *c += f64::from(*v) / 255.0;
+            }
+        }
+    }
+    let len = normals.len().max(1) as f64;
+    for c in matcap_color.iter_mut() {
+        *c /= len;
+    }
+    (lighting / len, rim / len, matcap_color)
+}
+
+// 陰、リムライト、MatCapを焼き込んだ基本色のテクスチャ
+fn bake_texture(
+    gltf: &Value,
+    chunks: &[Vec<u8>],
+    material_properties: &Value,
+    normals: &[[f64; 3]],
+) -> Option<RgbaImage> {
+    let main = load_texture(
+        gltf,
+        chunks,
+        texture_property(material_properties, "_MainTex")?,
+    )?;
+    let (width, height) = main.dimensions();
+    let shade = load_texture_sized(
+        gltf,
+        chunks,
+        texture_property(material_properties, "_ShadeTexture"),
+        width,
+        height,
+    );
+    let rim = load_texture_sized(
+        gltf,
+        chunks,
+        texture_property(material_properties, "_RimTexture"),
+        width,
+        height,
+    );
+    let matcap = texture_property(material_properties, "_SphereAdd")
+        .and_then(|index| load_texture(gltf, chunks, index));
+
+    let color = color_property(material_properties, "_Color", [1.0; 4]);
+    let shade_color = color_property(material_properties, "_ShadeColor", [0.97, 0.81, 0.86, 1.0]);
+    let rim_color = color_property(material_properties, "_RimColor", [0.0, 0.0, 0.0, 1.0]);
+    let (lighting, rim_intensity, matcap_color) =
+        average_shading(material_properties, matcap.as_ref(), normals);
+
+    let mut baked = main.clone();
+    for (x, y, pixel) in baked.enumerate_pixels_mut() {
+        let main_pixel = main.get_pixel(x, y);
+        for c in 0..3 {
+            let texel = |image: &Option<RgbaImage>| {
+                image
+                    .as_ref()
+                    .map(|image| f64::from(image.get_pixel(x, y).data[c]) / 255.0)
+                    .unwrap_or(1.0)
+            };
+            let lit = color[c] * f64::from(main_pixel.data[c]) / 255.0;
+            let shaded = shade_color[c] * texel(&shade);
+            let value = lit * lighting
+                + shaded * (1.0 - lighting)
+                + rim_intensity * rim_color[c] * texel(&rim)
+                + matcap_color[c];
+            pixel.data[c] = (value.max(0.0).min(1.0) * 255.0).round() as u8;
+        }
+    }
+    Some(baked)
+}
+
+// 焼き込んだテクスチャを追加する。サンプラーは元のテクスチャのものを使う
+fn push_texture(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    image: RgbaImage,
+    original_texture: u64,
+) -> Option<u64> {
+    let bytes = match encode_png(&DynamicImage::ImageRgba8(image)) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("Failed to encode baked texture: {:?}", e);
+            return None;
+        }
+    };
+    let buffer_view = push_buffer_view(gltf, chunks, &bytes, None);
+    let mut new_image = serde_json::Map::new();
+    new_image.insert("bufferView".into(), buffer_view.into());
+    new_image.insert("mimeType".into(), "image/png".into());
+    let images = gltf.get_mut("images")?.as_array_mut()?;
+    images.push(new_image.into());
+    let image_index = images.len() - 1;
+
+    let mut texture = serde_json::Map::new();
+    if let Some(sampler) = gltf
+        .get("textures")?
+        .get(original_texture as usize)?
+        .get("sampler")
+    {
+        texture.insert("sampler".into(), sampler.clone());
+    }
+    texture.insert("source".into(), image_index.into());
+    let textures = gltf.get_mut("textures")?.as_array_mut()?;
+    textures.push(texture.into());
+    Some((textures.len() - 1) as u64)
+}
+
+// 焼き込んだテクスチャを使い、陰、リムライト、MatCapが見た目に影響しないようにする
+fn replace_material(gltf: &mut Value, material_index: usize, texture_index: u64) {
+    let material_properties = &mut gltf["extensions"]["VRM"]["materialProperties"][material_index];
+    if let Some(texture_properties) = material_properties
+        .get_mut("textureProperties")
+        .and_then(|v| v.as_object_mut())
+    {
+        texture_properties.insert("_MainTex".into(), texture_index.into());
+        texture_properties.insert("_ShadeTexture".into(), texture_index.into());
+        texture_properties.remove("_RimTexture");
+        texture_properties.remove("_SphereAdd");
+    }
+    let alpha = color_property(material_properties, "_Color", [1.0; 4])[3];
+    if let Some(vector_properties) = material_properties
+        .get_mut("vectorProperties")
+        .and_then(|v| v.as_object_mut())
+    {
+        vector_properties.insert("_Color".into(), vec![1.0, 1.0, 1.0, alpha].into());
+        vector_properties.insert("_ShadeColor".into(), vec![1.0, 1.0, 1.0, 1.0].into());
+        vector_properties.insert("_RimColor".into(), vec![0.0, 0.0, 0.0, 1.0].into());
+    }
+
+    if let Some(material) = gltf
+        .get_mut("materials")
+        .and_then(|v| v.get_mut(material_index))
+    {
+        let pbr = &mut material["pbrMetallicRoughness"];
+        pbr["baseColorTexture"]["index"] = texture_index.into();
+        let alpha = pbr
+            .get("baseColorFactor")
+            .and_then(|v| v.get(3))
+            .and_then(|v| v.as_f64())
+            .unwrap_or(1.0);
+        pbr["baseColorFactor"] = vec![1.0, 1.0, 1.0, alpha].into();
+    }
+}
+
+/// MToonの陰、リムライト、MatCapを固定した光源の向きで近似して基本色のテクスチャに焼き込む
+/// 焼き込んだ後は陰、リムライト、MatCapのテクスチャを使わない
+pub fn bake_mtoon_materials(gltf_: Value, chunks: &mut Vec<Vec<u8>>) -> Value {
+    let mut gltf = gltf_.clone();
+    let materials = gltf
+        .pointer("/extensions/VRM/materialProperties")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let normals = view_normals();
+    let mut cache = BTreeMap::new();
+    for (material_index, material_properties) in materials.iter().enumerate() {
+        if material_properties.get("shader").and_then(|v| v.as_str()) != Some("VRM/MToon") {
+            continue;
+        }
+        let main_texture = if let Some(index) = texture_property(material_properties, "_MainTex") {
+            index
+        } else {
+            continue;
+        };
+
+        // 焼き込みに使うパラメーターが同じマテリアルはテクスチャを共有する
+        let mut key = material_properties.clone();
+        if let Some(key) = key.as_object_mut() {
+            for name in &["name", "renderQueue", "keywordMap", "tagMap"] {
+                key.remove(*name);
+            }
+        }
+        let key = key.to_string();
+        let texture_index = if let Some(texture_index) = cache.get(&key) {
+            *texture_index
+        } else {
+            let texture_index = bake_texture(&gltf, chunks, material_properties, &normals)
+                .and_then(|image| push_texture(&mut gltf, chunks, image, main_texture));
+            cache.insert(key, texture_index);
+            texture_index
+        };
+        if let Some(texture_index) = texture_index {
+            replace_material(&mut gltf, material_index, texture_index);
+            debug!(
+                "bake mtoon material: {}",
+                material_properties
+                    .get("name")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or("")
+            );
+        } else {
+            warn!("Failed to bake mtoon material {}", material_index);
+        }
+    }
+    gltf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    // 白いテクスチャを使う赤いMToonマテリアル2つと、glTFのシェーダーを使うマテリアル
+    fn mtoon_gltf() -> (Value, Vec<Vec<u8>>) {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "images": [],
+                "samplers": [{"magFilter": 9729}],
+                "textures": [{"sampler": 0, "source": 0}],
+                "materials": [
+                    {"pbrMetallicRoughness": {"baseColorTexture": {"index": 0}, "baseColorFactor": [1.0, 0.0, 0.0, 0.5]}},
+                    {"pbrMetallicRoughness": {"baseColorTexture": {"index": 0}, "baseColorFactor": [1.0, 0.0, 0.0, 0.5]}},
+                    {"pbrMetallicRoughness": {"baseColorTexture": {"index": 0}}}
+                ],
+                "extensions": {"VRM": {"materialProperties": [
+                    {
+                        "name": "red",
+                        "shader": "VRM/MToon",
+                        "vectorProperties": {"_Color": [1.0, 0.0, 0.0, 0.5], "_ShadeColor": [0.0, 0.0, 1.0, 1.0]},
+                        "textureProperties": {"_MainTex": 0, "_ShadeTexture": 0, "_RimTexture": 0}
+                    },
+                    {
+                        "name": "red2",
+                        "shader": "VRM/MToon",
+                        "vectorProperties": {"_Color": [1.0, 0.0, 0.0, 0.5], "_ShadeColor": [0.0, 0.0, 1.0, 1.0]},
+                        "textureProperties": {"_MainTex": 0, "_ShadeTexture": 0, "_RimTexture": 0}
+                    },
+                    {"name": "gltf", "shader": "VRM_USE_GLTFSHADER"}
+                ]}}
+            }"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        let image = RgbaImage::from_pixel(4, 4, Rgba([255, 255, 255, 255]));
+        let bytes = encode_png(&DynamicImage::ImageRgba8(image)).unwrap();
+        let buffer_view = push_buffer_view(&mut gltf, &mut chunks, &bytes, None);
+        let mut image = serde_json::Map::new();
+        image.insert("bufferView".into(), buffer_view.into());
+        image.insert("mimeType".into(), "image/png".into());
+        gltf["images"] = vec![Value::from(image)].into();
+        (gltf, chunks)
+    }
+
+    #[test]
+    fn bake_mtoon_materials_replaces_textures() {
+        let (gltf, mut chunks) = mtoon_gltf();
+        let baked = bake_mtoon_materials(gltf.clone(), &mut chunks);
+
+        // 同じパラメーターのマテリアルは1つの焼き込んだテクスチャを共有する
+        let textures = baked["textures"].as_array().unwrap();
+        assert_eq!(textures.len(), 2);
+        assert_eq!(textures[1]["sampler"], 0);
+        assert_eq!(textures[1]["source"], 1);
+        assert_eq!(baked["images"][1]["mimeType"], "image/png");
+        for material_index in 0..2 {
+            let material_properties =
+                &baked["extensions"]["VRM"]["materialProperties"][material_index];
+            let texture_properties = &material_properties["textureProperties"];
+            assert_eq!(texture_properties["_MainTex"], 1);
+            assert_eq!(texture_properties["_ShadeTexture"], 1);
+            assert!(texture_properties.get("_RimTexture").is_none());
+            let vector_properties = &material_properties["vectorProperties"];
+            assert_eq!(
+                vector_properties["_Color"],
+                Value::from(vec![1.0, 1.0, 1.0, 0.5])
+            );
+            assert_eq!(
+                vector_properties["_ShadeColor"],
+                Value::from(vec![1.0, 1.0, 1.0, 1.0])
+            );
+            let pbr = &baked["materials"][material_index]["pbrMetallicRoughness"];
+            assert_eq!(pbr["baseColorTexture"]["index"], 1);
+            assert_eq!(
+                pbr["baseColorFactor"],
+                Value::from(vec![1.0, 1.0, 1.0, 0.5])
+            );
+        }
+        assert_eq!(baked["materials"][2], gltf["materials"][2]);
+        assert_eq!(
+            baked["extensions"]["VRM"]["materialProperties"][2],
+            gltf["extensions"]["VRM"]["materialProperties"][2]
+        );
+
+        // 光が当たる部分は赤、陰の部分は青で、その間の色になる
+        let pixel = load_texture(&baked, &chunks, 1)
+            .unwrap()
+            .get_pixel(0, 0)
+            .data;
+        assert!(pixel[0] > 0 && pixel[2] > 0, "{:?}", pixel);
+        assert_eq!(pixel[1], 0);
+        assert!((i32::from(pixel[0]) + i32::from(pixel[2]) - 255).abs() <= 1);
+        assert_eq!(pixel[3], 255);
+    }
+}
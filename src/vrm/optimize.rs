@@ -0,0 +1,536 @@
+use super::accessor::*;
+use log::info;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+const TRIANGLES: u64 = 4;
+const CACHE_SIZE: usize = 16;
+
+// 頂点キャッシュの効率が良くなるように三角形を並べ替える
+// Tipsify: https://gfx.cs.princeton.edu/pubs/Sander_2007_%3ETR/tipsy.pdf
+fn tipsify(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    let mut live = vec![0usize; vertex_count];
+    for index in indices {
+        live[*index as usize] += 1;
+    }
+    let mut adjacency_offsets = Vec::with_capacity(vertex_count + 1);
+    adjacency_offsets.push(0);
+    for count in &live {
+        let last = adjacency_offsets[adjacency_offsets.len() - 1];
+        adjacency_offsets.push(last + count);
+    }
+    let mut adjacency = vec![0usize; indices.len()];
+    let mut filled = adjacency_offsets.clone();
+    for (i, index) in indices.iter().enumerate() {
+        adjacency[filled[*index as usize]] = i / 3;
+        filled[*index as usize] += 1;
+    }
+
+    let mut cache_time = vec![0usize; vertex_count];
+    let mut emitted = vec![false; triangle_count];
+    let mut dead_end = Vec::new();
+    let mut time = CACHE_SIZE + 1;
+    let mut cursor = 0;
+    let mut output = Vec::with_capacity(indices.len());
+    let mut fanning = indices.get(0).map(|v| *v as usize);
+    while let Some(f) = fanning {
+        let mut candidates = Vec::new();
+        for triangle in &adjacency[adjacency_offsets[f]..adjacency_offsets[f + 1]] {
+            if emitted[*triangle] {
+                continue;
+            }
+            for index in &indices[triangle * 3..triangle * 3 + 3] {
+                let v = *index as usize;
+                output.push(*index);
+                dead_end.push(v);
+                candidates.push(v);
+                live[v] -= 1;
+                if time - cache_time[v] > CACHE_SIZE {
+                    cache_time[v] = time;
+                    time += 1;
+                }
+            }
+            emitted[*triangle] = true;
+        }
+
+        // キャッシュに残っていて、参照される三角形が多い頂点を次に使う
+        // 扇を作ってもキャッシュに残らない頂点しかなければ、行き止まりのスタックから選ぶ
+        let mut best = None;
+        let mut best_priority = 0;
+        for v in candidates {
+            if live[v] == 0 {
+                continue;
+            }
+            let priority = if time - cache_time[v] + 2 * live[v] <= CACHE_SIZE {
+                time - cache_time[v]
+            } else {
+                0
+            };
+            if priority > best_priority {
+                best = Some(v);
+                best_priority = priority;
+            }
+        }
+        fanning = best.or_else(|| {
+            while let Some(v) = dead_end.pop() {
+                if live[v] > 0 {
+                    return Some(v);
+                }
+            }
+            while cursor < vertex_count {
+                if live[cursor] > 0 {
+                    return Some(cursor);
+                }
+                cursor += 1;
+            }
+            None
+        });
+    }
+    output
+}
+
+fn read_indices(gltf: &Value, chunks: &[Vec<u8>], index: u64) -> Option<Vec<u32>> {
+    let accessor = gltf.get("accessors")?.get(index as usize)?;
+    match accessor.get("componentType").and_then(|v| v.as_u64()) {
+        Some(UNSIGNED_BYTE) | Some(UNSIGNED_SHORT) | Some(UNSIGNED_INT) => {}
+        _ => return None,
+    }
+    if accessor.get("type").and_then(|v| v.as_str()) != Some("SCALAR") {
+        return None;
+    }
+    read_accessor(gltf, chunks, index).map(|values| values.iter().map(|v| *v as u32).collect())
+}
+
+// 最大値が収まる型のインデックスを作成する。UNSIGNED_SHORTの最大値はプリミティブリスタートに使われるので避ける
+fn push_indices(gltf: &mut Value, chunks: &mut Vec<Vec<u8>>, indices: &[u32]) -> u64 {
+    let max = indices.iter().max().cloned().unwrap_or(0);
+    let component_type = if max < 65535 {
+        UNSIGNED_SHORT
+    } else {
+        UNSIGNED_INT
+    };
+    let values = indices.iter().map(|v| f64::from(*v)).collect::<Vec<_>>();
+    push_accessor(
+        gltf,
+        chunks,
+        component_type,
+        false,
+        "SCALAR",
+        &values,
+        Some(ELEMENT_ARRAY_BUFFER),
+    )
+}
+
+// 頂点属性、モーフターゲットのaccessor
+fn vertex_accessors(primitive: &Value) -> Vec<u64> {
+    let mut accessors = Vec::new();
+    for value in primitive
+        .get("attributes")
+        .and_then(|v| v.as_object())
+        .map(|v| v.values().cloned().collect())
+        .unwrap_or_else(Vec::new)
+        .into_iter()
+        .chain(
+            primitive
+                .get("targets")
+                .and_then(|v| v.as_array())
+                .unwrap_or(&Vec::new())
+                .iter()
+                .filter_map(|t| t.as_object())
+                .flat_map(|t| t.values().cloned()),
+        )
+    {
+        if let Some(index) = value.as_u64() {
+            if !accessors.contains(&index) {
+                accessors.push(index);
+            }
+        }
+    }
+    accessors
+}
+
+// 頂点属性を並べ替えた新しいaccessorを作成する
+fn remap_accessor(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    index: u64,
+    remap: &[u32],
+) -> Option<u64> {
+    let accessor = gltf.get("accessors")?.get(index as usize)?.clone();
+    let component_type = accessor.get("componentType")?.as_u64()?;
+    let type_ = accessor.get("type")?.as_str()?;
+    let components = component_count(type_)?;
+    let values = read_accessor(gltf, chunks, index)?;
+    let new_vertex_count = remap.iter().filter(|v| **v != !0).count();
+    let mut new_values = vec![0.0; new_vertex_count * components];
+    for (old, new) in remap.iter().enumerate() {
+        if *new != !0 {
+            let new = *new as usize;
+            new_values[new * components..(new + 1) * components]
+                .copy_from_slice(&values[old * components..(old + 1) * components]);
+        }
+    }
+    let normalized = accessor
+        .get("normalized")
+        .and_then(|v| v.as_bool())
+        .unwrap_or(false);
+    Some(push_accessor(
+        gltf,
+        chunks,
+        component_type,
+        normalized,
+        type_,
+        &new_values,
+        Some(ARRAY_BUFFER),
+    ))
+}
+
+fn accessor_count(gltf: &Value, index: u64) -> Option<usize> {
+    gltf.get("accessors")?
+        .get(index as usize)?
+        .get("count")?
+        .as_u64()
+        .map(|v| v as usize)
+}
+
+// 頂点属性を共有するプリミティブをまとめて最適化する
+// 三角形を並べ替え、頂点を最初に使われる順に並べ直し、使われていない頂点を削除する
+fn optimize_primitives(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    primitive_indices: &[(usize, usize)],
+) -> Option<()> {
+    let first =
+        gltf["meshes"][primitive_indices[0].0]["primitives"][primitive_indices[0].1].clone();
+    let accessors = vertex_accessors(&first);
+    let vertex_count = accessor_count(gltf, *accessors.get(0)?)?;
+    if accessors
+        .iter()
+        .any(|a| accessor_count(gltf, *a) != Some(vertex_count))
+    {
+        return None;
+    }
+
+    let mut optimized_indices = Vec::new();
+    for (mesh_index, primitive_index) in primitive_indices {
+        let primitive = &gltf["meshes"][*mesh_index]["primitives"][*primitive_index];
+        if primitive
+            .get("mode")
+            .and_then(|v| v.as_u64())
+            .unwrap_or(TRIANGLES)
+            != TRIANGLES
+        {
+            return None;
+        }
+        let indices = read_indices(gltf, chunks, primitive.get("indices")?.as_u64()?)?;
+        if indices.len() % 3 != 0 || indices.iter().any(|i| *i as usize >= vertex_count) {
+            return None;
+        }
+        optimized_indices.push(tipsify(&indices, vertex_count));
+    }
+
+    let mut remap = vec![!0u32; vertex_count];
+    let mut new_vertex_count = 0;
+    for indices in &mut optimized_indices {
+        for index in indices.iter_mut() {
+            if remap[*index as usize] == !0 {
+                remap[*index as usize] = new_vertex_count;
+                new_vertex_count += 1;
+            }
+            *index = remap[*index as usize];
+        }
+    }
+
+    let mut new_accessors = BTreeMap::new();
+    for accessor in accessors {
+        new_accessors.insert(accessor, remap_accessor(gltf, chunks, accessor, &remap)?);
+    }
+    info!(
+        "optimize {} primitives: {} vertices -> {} vertices",
+        primitive_indices.len(),
+        vertex_count,
+        new_vertex_count
+    );
+
+    for ((mesh_index, primitive_index), indices) in primitive_indices.iter().zip(optimized_indices)
+    {
+        let new_indices = push_indices(gltf, chunks, &indices);
+        let primitive = &mut gltf["meshes"][*mesh_index]["primitives"][*primitive_index];
+        primitive["indices"] = new_indices.into();
+        let replace = |value: &mut Value| {
+            if let Some(new_index) = value.as_u64().and_then(|i| new_accessors.get(&i)) {
+                *value = (*new_index).into();
+            }
+        };
+        for value in primitive
+            .get_mut("attributes")
+            .and_then(|v| v.as_object_mut())
+            .unwrap_or(&mut serde_json::map::Map::new())
+            .values_mut()
+        {
+            replace(value);
+        }
+        for target in primitive
+            .get_mut("targets")
+            .and_then(|v| v.as_array_mut())
+            .unwrap_or(&mut Vec::new())
+        {
+            for value in target
+                .as_object_mut()
+                .unwrap_or(&mut serde_json::map::Map::new())
+                .values_mut()
+            {
+                replace(value);
+            }
+        }
+    }
+    Some(())
+}
+
+// 並べ替えられない場合もインデックスの型は小さくする
+fn narrow_indices(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    mesh_index: usize,
+    primitive_index: usize,
+) {
+    let index = if let Some(index) = gltf["meshes"][mesh_index]["primitives"][primitive_index]
+        .get("indices")
+        .and_then(|v| v.as_u64())
+    {
+        index
+    } else {
+        return;
+    };
+    if gltf["accessors"][index as usize]
+        .get("componentType")
+        .and_then(|v| v.as_u64())
+        != Some(UNSIGNED_INT)
+    {
+        return;
+    }
+    let indices = if let Some(indices) = read_indices(gltf, chunks, index) {
+        indices
+    } else {
+        return;
+    };
+    if indices.iter().all(|v| *v < 65535) {
+        let new_indices = push_indices(gltf, chunks, &indices);
+        gltf["meshes"][mesh_index]["primitives"][primitive_index]["indices"] = new_indices.into();
+    }
+}
+
+/// インデックスを小さい型にし、頂点キャッシュとフェッチの効率が良くなるように並べ替える
+pub fn optimize_meshes(gltf_: Value, chunks: &mut Vec<Vec<u8>>) -> Value {
+    let mut gltf = gltf_.clone();
+    let mut groups = BTreeMap::new();
+    for (mesh_index, mesh) in gltf
+        .get("meshes")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .enumerate()
+    {
+        for (primitive_index, primitive) in mesh
+            .get("primitives")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+            .iter()
+            .enumerate()
+        {
+            let key = format!(
+                "{}{}",
+                primitive.get("attributes").unwrap_or(&Value::Null),
+                primitive.get("targets").unwrap_or(&Value::Null)
+            );
+            groups
+                .entry(key)
+                .or_insert_with(Vec::new)
+                .push((mesh_index, primitive_index));
+        }
+    }
+
+    for primitive_indices in groups.values() {
+        if optimize_primitives(&mut gltf, chunks, primitive_indices).is_none() {
+            for (mesh_index, primitive_index) in primitive_indices {
+                narrow_indices(&mut gltf, chunks, *mesh_index, *primitive_index);
+            }
+        }
+    }
+    gltf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sorted_triangles(indices: &[u32]) -> Vec<Vec<u32>> {
+        let mut triangles = indices.chunks(3).map(|t| t.to_vec()).collect::<Vec<_>>();
+        triangles.sort();
+        triangles
+    }
+
+    #[test]
+    fn tipsify_falls_back_to_dead_end_stack() {
+        // 最初の三角形の後、頂点1と2はどちらも参照される三角形が多すぎてキャッシュに残らない
+        let mut indices = vec![0, 1, 2];
+        for k in 0..8 {
+            indices.extend_from_slice(&[1, 3 + 2 * k, 4 + 2 * k]);
+        }
+        for k in 0..8 {
+            indices.extend_from_slice(&[2, 19 + 2 * k, 20 + 2 * k]);
+        }
+        let output = tipsify(&indices, 35);
+        assert_eq!(sorted_triangles(&output), sorted_triangles(&indices));
+        // 最後に使った頂点2から扇を作る
+        assert_eq!(&output[3..6], &[2, 19, 20]);
+    }
+
+    // 頂点iはPOSITION (i, 0, 0)、JOINTS_0 (i, 0, 0, 0)、WEIGHTS_0 (i / 16, 0, 0, 0)、ターゲット (0, i, 0)
+    fn indexed_gltf(indices: &[f64], vertex_count: usize) -> (Value, Vec<Vec<u8>>) {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{"asset": {"version": "2.0"}, "meshes": [{"primitives": [{"attributes": {}, "targets": [{}]}]}]}"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        let vertices = (0..vertex_count).map(|i| i as f64).collect::<Vec<_>>();
+        let vec4 = |scale: f64| {
+            vertices
+                .iter()
+                .flat_map(|i| vec![i * scale, 0.0, 0.0, 0.0])
+                .collect::<Vec<_>>()
+        };
+        let position = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "VEC3",
+            &vertices
+                .iter()
+                .flat_map(|i| vec![*i, 0.0, 0.0])
+                .collect::<Vec<_>>(),
+            Some(ARRAY_BUFFER),
+        );
+        let joints = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            UNSIGNED_BYTE,
+            false,
+            "VEC4",
+            &vec4(1.0),
+            Some(ARRAY_BUFFER),
+        );
+        let weights = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "VEC4",
+            &vec4(1.0 / 16.0),
+            Some(ARRAY_BUFFER),
+        );
+        let target = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "VEC3",
+            &vertices
+                .iter()
+                .flat_map(|i| vec![0.0, *i, 0.0])
+                .collect::<Vec<_>>(),
+            Some(ARRAY_BUFFER),
+        );
+        let indices = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            UNSIGNED_INT,
+            false,
+            "SCALAR",
+            indices,
+            Some(ELEMENT_ARRAY_BUFFER),
+        );
+        let primitive = &mut gltf["meshes"][0]["primitives"][0];
+        primitive["attributes"]["POSITION"] = position.into();
+        primitive["attributes"]["JOINTS_0"] = joints.into();
+        primitive["attributes"]["WEIGHTS_0"] = weights.into();
+        primitive["targets"][0]["POSITION"] = target.into();
+        primitive["indices"] = indices.into();
+        (gltf, chunks)
+    }
+
+    fn read_attribute(gltf: &Value, chunks: &[Vec<u8>], pointer: &str) -> Vec<f64> {
+        let index = gltf["meshes"][0]["primitives"][0]
+            .pointer(pointer)
+            .and_then(|v| v.as_u64())
+            .unwrap();
+        read_accessor(gltf, chunks, index).unwrap()
+    }
+
+    #[test]
+    fn optimize_meshes_remaps_vertex_attributes_together() {
+        // 頂点6は使われない
+        let indices = [5.0, 3.0, 1.0, 0.0, 2.0, 4.0, 1.0, 3.0, 2.0, 4.0, 2.0, 3.0];
+        let (gltf, mut chunks) = indexed_gltf(&indices, 7);
+        let optimized = optimize_meshes(gltf, &mut chunks);
+
+        let new_indices = read_attribute(&optimized, &chunks, "/indices");
+        let indices_accessor = optimized["meshes"][0]["primitives"][0]["indices"]
+            .as_u64()
+            .unwrap();
+        assert_eq!(
+            optimized["accessors"][indices_accessor as usize]["componentType"],
+            Value::from(UNSIGNED_SHORT)
+        );
+        // 頂点は最初に使われる順に並ぶ
+        let mut first_use = Vec::new();
+        for i in &new_indices {
+            if !first_use.contains(i) {
+                first_use.push(*i);
+            }
+        }
+        assert_eq!(first_use, (0..6).map(f64::from).collect::<Vec<_>>());
+
+        let positions = read_attribute(&optimized, &chunks, "/attributes/POSITION");
+        let joints = read_attribute(&optimized, &chunks, "/attributes/JOINTS_0");
+        let weights = read_attribute(&optimized, &chunks, "/attributes/WEIGHTS_0");
+        let targets = read_attribute(&optimized, &chunks, "/targets/0/POSITION");
+        assert_eq!(positions.len(), 6 * 3);
+        let original = (0..6).map(|v| positions[v * 3]).collect::<Vec<_>>();
+        for v in 0..6 {
+            assert_eq!(joints[v * 4], original[v]);
+            assert_eq!(weights[v * 4], original[v] / 16.0);
+            assert_eq!(targets[v * 3 + 1], original[v]);
+        }
+        let restored = new_indices
+            .iter()
+            .map(|i| original[*i as usize] as u32)
+            .collect::<Vec<_>>();
+        let indices = indices.iter().map(|i| *i as u32).collect::<Vec<_>>();
+        assert_eq!(sorted_triangles(&restored), sorted_triangles(&indices));
+    }
+
+    #[test]
+    fn optimize_meshes_narrows_indices_it_cannot_reorder() {
+        let indices = [0.0, 1.0, 1.0, 2.0];
+        let (mut gltf, mut chunks) = indexed_gltf(&indices, 3);
+        // LINESは並べ替えない
+        gltf["meshes"][0]["primitives"][0]["mode"] = 1.into();
+        let position = gltf["meshes"][0]["primitives"][0]["attributes"]["POSITION"].clone();
+        let optimized = optimize_meshes(gltf, &mut chunks);
+
+        let primitive = &optimized["meshes"][0]["primitives"][0];
+        assert_eq!(primitive["attributes"]["POSITION"], position);
+        let indices_accessor = primitive["indices"].as_u64().unwrap();
+        assert_eq!(
+            optimized["accessors"][indices_accessor as usize]["componentType"],
+            Value::from(UNSIGNED_SHORT)
+        );
+        assert_eq!(
+            read_attribute(&optimized, &chunks, "/indices"),
+            indices.to_vec()
+        );
+    }
+}
@@ -0,0 +1,713 @@
+use super::accessor::*;
+use super::cleaner::*;
+use log::{debug, info, warn};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+const EXTENSION_NAME: &str = "KHR_mesh_quantization";
+
+// 量子化した位置を元に戻す変換。法線、接線をそのまま使えるように一様スケールにする
+struct Dequantization {
+    offset: [f64; 3],
+    scale: f64,
+}
+
+impl Dequantization {
+    fn new(positions: &[Vec<f64>]) -> Option<Dequantization> {
+        let mut min = [std::f64::INFINITY; 3];
+        let mut max = [std::f64::NEG_INFINITY; 3];
+        for position in positions.iter().flat_map(|p| p.chunks(3)) {
+            for i in 0..3 {
+                min[i] = min[i].min(position[i]);
+                max[i] = max[i].max(position[i]);
+            }
+        }
+        if min.iter().zip(max.iter()).any(|(l, r)| l > r) {
+            return None;
+        }
+        let mut offset = [0.0; 3];
+        let mut scale: f64 = 0.0;
+        for i in 0..3 {
+            offset[i] = (min[i] + max[i]) / 2.0;
+            scale = scale.max((max[i] - min[i]) / 2.0);
+        }
+        Some(Dequantization {
+            offset,
+            scale: if scale > 0.0 { scale } else { 1.0 },
+        })
+    }
+
+    // 列優先の4x4行列
+    fn matrix(&self) -> [f64; 16] {
+        let s = self.scale;
+        let [x, y, z] = self.offset;
+        [
+            s, 0.0, 0.0, 0.0, 0.0, s, 0.0, 0.0, 0.0, 0.0, s, 0.0, x, y, z, 1.0,
+        ]
+    }
+}
+
+fn multiply_matrix(l: &[f64], r: &[f64]) -> [f64; 16] {
+    let mut result = [0.0; 16];
+    for column in 0..4 {
+        for row in 0..4 {
+            result[column * 4 + row] = (0..4).map(|k| l[k * 4 + row] * r[column * 4 + k]).sum();
+        }
+    }
+    result
+}
+
+fn is_float_accessor(gltf: &Value, index: u64, type_: &str) -> bool {
+    gltf.get("accessors")
+        .and_then(|v| v.get(index as usize))
+        .map(|accessor| {
+            accessor.get("componentType").and_then(|v| v.as_u64()) == Some(FLOAT)
+                && accessor.get("type").and_then(|v| v.as_str()) == Some(type_)
+        })
+        .unwrap_or(false)
+}
+
+fn read_float_accessor(
+    gltf: &Value,
+    chunks: &[Vec<u8>],
+    index: u64,
+    type_: &str,
+) -> Option<Vec<f64>> {
+    if !is_float_accessor(gltf, index, type_) {
+        return None;
+    }
+    read_accessor(gltf, chunks, index)
+}
+
+fn quantize_positions(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    index: u64,
+    dequantization: &Dequantization,
+) -> Option<u64> {
+    let values = read_float_accessor(gltf, chunks, index, "VEC3")?
+        .chunks(3)
+        .flat_map(|p| {
+            (0..3)
+                .map(|i| (p[i] - dequantization.offset[i]) / dequantization.scale * 32767.0)
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    Some(push_accessor(
+        gltf,
+        chunks,
+        SHORT,
+        true,
+        "VEC3",
+        &values,
+        Some(ARRAY_BUFFER),
+    ))
+}
+
+// モーフターゲットの位置の差分。範囲に収まらない場合はスケールだけ合わせてFLOATのままにする
+fn quantize_position_deltas(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    index: u64,
+    dequantization: &Dequantization,
+) -> Option<u64> {
+    let values = read_float_accessor(gltf, chunks, index, "VEC3")?
+        .iter()
+        .map(|v| v / dequantization.scale)
+        .collect::<Vec<_>>();
+    if values.iter().all(|v| v.abs() <= 1.0) {
+        let values = values.iter().map(|v| v * 32767.0).collect::<Vec<_>>();
+        Some(push_accessor(
+            gltf,
+            chunks,
+            SHORT,
+            true,
+            "VEC3",
+            &values,
+            Some(ARRAY_BUFFER),
+        ))
+    } else {
+        Some(push_accessor(
+            gltf,
+            chunks,
+            FLOAT,
+            false,
+            "VEC3",
+            &values,
+            Some(ARRAY_BUFFER),
+        ))
+    }
+}
+
+// 法線と、その差分
+fn quantize_normals(gltf: &mut Value, chunks: &mut Vec<Vec<u8>>, index: u64) -> Option<u64> {
+    let values = read_float_accessor(gltf, chunks, index, "VEC3")?;
+    if values.iter().any(|v| v.abs() > 1.0) {
+        return None;
+    }
+    let values = values.iter().map(|v| v * 127.0).collect::<Vec<_>>();
+    Some(push_accessor(
+        gltf,
+        chunks,
+        BYTE,
+        true,
+        "VEC3",
+        &values,
+        Some(ARRAY_BUFFER),
+    ))
+}
+
+// UVが0から1の範囲に収まっている場合のみ量子化する
+fn quantize_texcoords(gltf: &mut Value, chunks: &mut Vec<Vec<u8>>, index: u64) -> Option<u64> {
+    let values = read_float_accessor(gltf, chunks, index, "VEC2")?;
+    if values.iter().any(|v| *v < 0.0 || *v > 1.0) {
+        return None;
+    }
+    let values = values.iter().map(|v| v * 65535.0).collect::<Vec<_>>();
+    Some(push_accessor(
+        gltf,
+        chunks,
+        UNSIGNED_SHORT,
+        true,
+        "VEC2",
+        &values,
+        Some(ARRAY_BUFFER),
+    ))
+}
+
+// 同じaccessorを何度も変換しないように結果を覚えておく
+fn quantize_cached<F>(
+    cache: &mut BTreeMap<u64, Option<u64>>,
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    index: &mut Value,
+    f: F,
+) where
+    F: FnOnce(&mut Value, &mut Vec<Vec<u8>>, u64) -> Option<u64>,
+{
+    let original_index = if let Some(i) = index.as_u64() {
+        i
+    } else {
+        return;
+    };
+    let quantized_index = if let Some(quantized_index) = cache.get(&original_index) {
+        *quantized_index
+    } else {
+        let quantized_index = f(gltf, chunks, original_index);
+        cache.insert(original_index, quantized_index);
+        quantized_index
+    };
+    if let Some(quantized_index) = quantized_index {
+        *index = quantized_index.into();
+    }
+}
+
+// 位置と、モーフターゲットの位置の差分を全て読み込めるメッシュ
+fn position_quantizable_meshes(gltf: &Value, chunks: &[Vec<u8>]) -> BTreeSet<usize> {
+    let readable = |index: Option<&Value>| {
+        index
+            .and_then(|v| v.as_u64())
+            .and_then(|i| read_float_accessor(gltf, chunks, i, "VEC3"))
+            .is_some()
+    };
+    let mut meshes = BTreeSet::new();
+    for (mesh_index, mesh) in gltf
+        .get("meshes")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .enumerate()
+    {
+        let primitives = mesh
+            .get("primitives")
+            .and_then(|v| v.as_array())
+            .cloned()
+            .unwrap_or_default();
+        let quantizable = primitives.iter().all(|primitive| {
+            readable(primitive.pointer("/attributes/POSITION"))
+                && primitive
+                    .get("targets")
+                    .and_then(|v| v.as_array())
+                    .unwrap_or(&Vec::new())
+                    .iter()
+                    .all(|target| {
+                        target.get("POSITION").is_none() || readable(target.get("POSITION"))
+                    })
+        });
+        if !primitives.is_empty() && quantizable {
+            meshes.insert(mesh_index);
+        }
+    }
+    meshes
+}
+
+// 位置の量子化を打ち消す
+// スキンメッシュはinverseBindMatricesに、それ以外は子ノードのTRSに逆量子化の変換を入れる
+fn compensate_dequantization(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    meshes: &BTreeSet<usize>,
+    dequantization: &Dequantization,
+) {
+    let mut quantized_skin_users = BTreeMap::new();
+    let mut other_skin_users = BTreeSet::new();
+    let mut mesh_nodes = Vec::new();
+    for (node_index, node) in gltf
+        .get("nodes")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .enumerate()
+    {
+        let quantized = node
+            .get("mesh")
+            .and_then(|v| v.as_u64())
+            .map(|m| meshes.contains(&(m as usize)))
+            .unwrap_or(false);
+        match (node.get("skin").and_then(|v| v.as_u64()), quantized) {
+            (Some(skin), true) => quantized_skin_users
+                .entry(skin)
+                .or_insert_with(Vec::new)
+                .push(node_index),
+            (Some(skin), false) => {
+                other_skin_users.insert(skin);
+            }
+            (None, true) => mesh_nodes.push(node_index),
+            (None, false) => {}
+        }
+    }
+
+    let matrix = dequantization.matrix();
+    let mut inverse_bind_matrices_cache = BTreeMap::new();
+    for (skin_index, node_indices) in quantized_skin_users {
+        let skin = if let Some(skin) = gltf
+            .get("skins")
+            .and_then(|v| v.get(skin_index as usize))
+            .cloned()
+        {
+            skin
+        } else {
+            continue;
+        };
+        let original_accessor = skin.get("inverseBindMatrices").and_then(|v| v.as_u64());
+        let accessor_index = if let Some(accessor_index) =
+            original_accessor.and_then(|i| inverse_bind_matrices_cache.get(&i))
+        {
+            *accessor_index
+        } else {
+            let joint_len = skin
+                .get("joints")
+                .and_then(|v| v.as_array())
+                .map(|v| v.len())
+                .unwrap_or(0);
+            let inverse_bind_matrices = match original_accessor {
+                Some(index) => read_float_accessor(gltf, chunks, index, "MAT4"),
+                None => Some(
+                    (0..joint_len)
+                        .flat_map(|_| {
+                            vec![
+                                1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0,
+                                0.0, 0.0, 1.0,
+                            ]
+                        })
+                        .collect(),
+                ),
+            };
+            let values = if let Some(values) = inverse_bind_matrices {
+                values
+                    .chunks(16)
+                    .flat_map(|m| multiply_matrix(m, &matrix).to_vec())
+                    .collect::<Vec<_>>()
+            } else {
+                warn!("Failed to read inverseBindMatrices of skin {}", skin_index);
+                continue;
+            };
+            let accessor_index = push_accessor(gltf, chunks, FLOAT, false, "MAT4", &values, None);
+            if let Some(original_accessor) = original_accessor {
+                inverse_bind_matrices_cache.insert(original_accessor, accessor_index);
+            }
+            accessor_index
+        };
+
+        if other_skin_users.contains(&skin_index) {
+            // 量子化していないメッシュと共有しているスキンは複製する
+            let mut new_skin = skin.clone();
+            new_skin["inverseBindMatrices"] = accessor_index.into();
+            let skins = gltf["skins"].as_array_mut().unwrap();
+            skins.push(new_skin);
+            let new_skin_index = skins.len() - 1;
+            for node_index in node_indices {
+                gltf["nodes"][node_index]["skin"] = new_skin_index.into();
+            }
+        } else {
+            gltf["skins"][skin_index as usize]["inverseBindMatrices"] = accessor_index.into();
+        }
+    }
+
+    // ノードはヒューマノイドのボーンやコライダーとして参照されていることがあるため、
+    // 変換を直接書き換えずにメッシュを子ノードに移す
+    for node_index in mesh_nodes {
+        let node = gltf["nodes"][node_index].as_object_mut().unwrap();
+        let mut child = serde_json::map::Map::new();
+        if let Some(name) = node.get("name") {
+            child.insert("name".into(), name.clone());
+        }
+        for key in &["mesh", "weights"] {
+            if let Some(value) = node.remove(*key) {
+                child.insert((*key).into(), value);
+            }
+        }
+        child.insert("translation".into(), dequantization.offset.to_vec().into());
+        child.insert("scale".into(), vec![dequantization.scale; 3].into());
+
+        let nodes = gltf["nodes"].as_array_mut().unwrap();
+        nodes.push(child.into());
+        let child_index = nodes.len() - 1;
+        let node = &mut gltf["nodes"][node_index];
+        if !node.get("children").map(|v| v.is_array()).unwrap_or(false) {
+            node["children"] = Value::Array(Vec::new());
+        }
+        node["children"]
+            .as_array_mut()
+            .unwrap()
+            .push(child_index.into());
+    }
+}
+
+/// 頂点属性をKHR_mesh_quantizationで量子化する
+/// 位置、法線、UVとモーフターゲットの位置、法線の差分を正規化された整数にする
+pub fn quantize_meshes(gltf_: Value, chunks: &mut Vec<Vec<u8>>) -> Value {
+    let mut gltf = gltf_.clone();
+    let position_meshes = position_quantizable_meshes(&gltf, chunks);
+    let mut positions = Vec::new();
+    for mesh_index in &position_meshes {
+        for primitive in gltf["meshes"][*mesh_index]["primitives"]
+            .as_array()
+            .unwrap_or(&Vec::new())
+        {
+            if let Some(values) = primitive
+                .pointer("/attributes/POSITION")
+                .and_then(|v| v.as_u64())
+                .and_then(|i| read_float_accessor(&gltf, chunks, i, "VEC3"))
+            {
+                positions.push(values);
+            }
+        }
+    }
+    let dequantization = Dequantization::new(&positions);
+    if let Some(ref dequantization) = dequantization {
+        debug!(
+            "quantize POSITION: offset={:?} scale={}",
+            dequantization.offset, dequantization.scale
+        );
+    }
+
+    let mut position_cache = BTreeMap::new();
+    let mut position_delta_cache = BTreeMap::new();
+    let mut normal_cache = BTreeMap::new();
+    let mut texcoord_cache = BTreeMap::new();
+    let mut quantized_meshes = BTreeSet::new();
+    let mesh_len = gltf
+        .get("meshes")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+    for mesh_index in 0..mesh_len {
+        let mut primitives = gltf["meshes"][mesh_index]["primitives"]
+            .as_array()
+            .cloned()
+            .unwrap_or_default();
+        let position_dequantization = dequantization
+            .as_ref()
+            .filter(|_| position_meshes.contains(&mesh_index));
+        for primitive in &mut primitives {
+            for (key, index) in primitive
+                .get_mut("attributes")
+                .and_then(|v| v.as_object_mut())
+                .unwrap_or(&mut serde_json::map::Map::new())
+            {
+                match (key.as_str(), position_dequantization) {
+                    ("POSITION", Some(d)) => quantize_cached(
+                        &mut position_cache,
+                        &mut gltf,
+                        chunks,
+                        index,
+                        |gltf, chunks, i| quantize_positions(gltf, chunks, i, d),
+                    ),
+                    ("NORMAL", _) => quantize_cached(
+                        &mut normal_cache,
+                        &mut gltf,
+                        chunks,
+                        index,
+                        quantize_normals,
+                    ),
+                    (key, _) if key.starts_with("TEXCOORD_") => quantize_cached(
+                        &mut texcoord_cache,
+                        &mut gltf,
+                        chunks,
+                        index,
+                        quantize_texcoords,
+                    ),
+                    _ => {}
+                }
+            }
+            for target in primitive
+                .get_mut("targets")
+                .and_then(|v| v.as_array_mut())
+                .unwrap_or(&mut Vec::new())
+            {
+                for (key, index) in target
+                    .as_object_mut()
+                    .unwrap_or(&mut serde_json::map::Map::new())
+                {
+                    match (key.as_str(), position_dequantization) {
+                        ("POSITION", Some(d)) => quantize_cached(
+                            &mut position_delta_cache,
+                            &mut gltf,
+                            chunks,
+                            index,
+                            |gltf, chunks, i| quantize_position_deltas(gltf, chunks, i, d),
+                        ),
+                        ("NORMAL", _) => quantize_cached(
+                            &mut normal_cache,
+                            &mut gltf,
+                            chunks,
+                            index,
+                            quantize_normals,
+                        ),
+                        _ => {}
+                    }
+                }
+            }
+        }
+        if position_dequantization.is_some() {
+            quantized_meshes.insert(mesh_index);
+        }
+        gltf["meshes"][mesh_index]["primitives"] = primitives.into();
+    }
+
+    if let Some(ref dequantization) = dequantization {
+        compensate_dequantization(&mut gltf, chunks, &quantized_meshes, dequantization);
+    }
+
+    let quantized_len = [
+        &position_cache,
+        &position_delta_cache,
+        &normal_cache,
+        &texcoord_cache,
+    ]
+    .iter()
+    .map(|cache| cache.values().filter(|v| v.is_some()).count())
+    .sum::<usize>();
+    info!("quantize: {} accessors", quantized_len);
+    if quantized_len > 0 {
+        use_extension(&mut gltf, EXTENSION_NAME, true);
+    }
+    gltf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // translationとscaleだけのノードの変換
+    fn local_matrix(node: &Value) -> [f64; 16] {
+        let mut matrix = [0.0; 16];
+        let component = |key: &str, i: usize, default: f64| {
+            node.get(key)
+                .and_then(|v| v.get(i))
+                .and_then(|v| v.as_f64())
+                .unwrap_or(default)
+        };
+        for i in 0..3 {
+            matrix[i * 5] = component("scale", i, 1.0);
+            matrix[12 + i] = component("translation", i, 0.0);
+        }
+        matrix[15] = 1.0;
+        matrix
+    }
+
+    fn global_matrix(gltf: &Value, node_index: usize) -> [f64; 16] {
+        let nodes = gltf["nodes"].as_array().unwrap();
+        let parent = nodes.iter().position(|node| {
+            node.get("children")
+                .and_then(|v| v.as_array())
+                .map(|children| children.contains(&Value::from(node_index)))
+                .unwrap_or(false)
+        });
+        let local = local_matrix(&nodes[node_index]);
+        match parent {
+            Some(parent) => multiply_matrix(&global_matrix(gltf, parent), &local),
+            None => local,
+        }
+    }
+
+    fn transform(matrix: &[f64], position: &[f64]) -> Vec<f64> {
+        (0..3)
+            .map(|row| {
+                (0..3)
+                    .map(|k| matrix[k * 4 + row] * position[k])
+                    .sum::<f64>()
+                    + matrix[12 + row]
+            })
+            .collect()
+    }
+
+    // メッシュを使うノードの、ワールド空間の頂点の位置
+    // スキンメッシュは全ての頂点がジョイント0だけに従う
+    fn world_positions(gltf: &Value, chunks: &[Vec<u8>], mesh: u64) -> Vec<Vec<f64>> {
+        let node_index = gltf["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .position(|node| node.get("mesh") == Some(&Value::from(mesh)))
+            .unwrap();
+        let node = &gltf["nodes"][node_index];
+        let matrix = match node.get("skin").and_then(|v| v.as_u64()) {
+            Some(skin) => {
+                let skin = &gltf["skins"][skin as usize];
+                let joint = skin["joints"][0].as_u64().unwrap() as usize;
+                let inverse_bind_matrix =
+                    read_accessor(gltf, chunks, skin["inverseBindMatrices"].as_u64().unwrap())
+                        .unwrap();
+                multiply_matrix(&global_matrix(gltf, joint), &inverse_bind_matrix[0..16])
+            }
+            None => global_matrix(gltf, node_index),
+        };
+        let accessor_index = gltf["meshes"][mesh as usize]["primitives"][0]["attributes"]
+            ["POSITION"]
+            .as_u64()
+            .unwrap();
+        let accessor = &gltf["accessors"][accessor_index as usize];
+        let max = if accessor["normalized"].as_bool() == Some(true) {
+            normalized_max(accessor["componentType"].as_u64().unwrap())
+        } else {
+            1.0
+        };
+        read_accessor(gltf, chunks, accessor_index)
+            .unwrap()
+            .chunks(3)
+            .map(|p| {
+                let p = p.iter().map(|v| v / max).collect::<Vec<_>>();
+                transform(&matrix, &p)
+            })
+            .collect()
+    }
+
+    // 移動した親の下のスキンのないメッシュ0と、ジョイント3に従うスキンメッシュ1
+    // スキン0は量子化できないUNSIGNED_BYTEの位置を持つメッシュ2とも共有する
+    fn quantizable_gltf() -> (Value, Vec<Vec<u8>>) {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "scenes": [{"nodes": [0, 2, 3, 4]}],
+                "nodes": [
+                    {"name": "root", "translation": [1.0, 0.0, 0.0], "scale": [2.0, 2.0, 2.0], "children": [1]},
+                    {"name": "static", "mesh": 0, "translation": [0.0, 0.5, 0.0]},
+                    {"name": "skinned", "mesh": 1, "skin": 0},
+                    {"name": "joint", "translation": [0.0, 1.0, 0.0]},
+                    {"name": "unquantized", "mesh": 2, "skin": 0}
+                ],
+                "meshes": [
+                    {"primitives": [{"attributes": {}}]},
+                    {"primitives": [{"attributes": {}}]},
+                    {"primitives": [{"attributes": {}}]}
+                ],
+                "skins": [{"joints": [3]}]
+            }"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        let static_positions = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "VEC3",
+            &[0.0, 0.0, 0.0, 1.0, 0.25, -0.5, -0.75, 1.5, 0.125],
+            Some(ARRAY_BUFFER),
+        );
+        let skinned_positions = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "VEC3",
+            &[0.0, 1.0, 0.0, 0.5, 1.5, -0.25, -1.0, 2.0, 0.75],
+            Some(ARRAY_BUFFER),
+        );
+        let unquantized_positions = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            UNSIGNED_BYTE,
+            false,
+            "VEC3",
+            &[0.0, 1.0, 0.0, 1.0, 1.0, 0.0],
+            Some(ARRAY_BUFFER),
+        );
+        // ジョイントの位置の逆行列
+        let inverse_bind_matrices = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "MAT4",
+            &[
+                1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, -1.0, 0.0, 1.0,
+            ],
+            None,
+        );
+        for (mesh, positions) in [static_positions, skinned_positions, unquantized_positions]
+            .iter()
+            .enumerate()
+        {
+            gltf["meshes"][mesh]["primitives"][0]["attributes"]["POSITION"] = (*positions).into();
+        }
+        gltf["skins"][0]["inverseBindMatrices"] = inverse_bind_matrices.into();
+        (gltf, chunks)
+    }
+
+    fn assert_positions_eq(left: &[Vec<f64>], right: &[Vec<f64>]) {
+        assert_eq!(left.len(), right.len());
+        for (l, r) in left.iter().zip(right.iter()) {
+            for (a, b) in l.iter().zip(r.iter()) {
+                assert!((a - b).abs() < 1e-3, "{:?} != {:?}", left, right);
+            }
+        }
+    }
+
+    #[test]
+    fn quantize_meshes_keeps_world_positions() {
+        let (gltf, mut chunks) = quantizable_gltf();
+        let original = (0..3)
+            .map(|mesh| world_positions(&gltf, &chunks, mesh))
+            .collect::<Vec<_>>();
+        let quantized = quantize_meshes(gltf.clone(), &mut chunks);
+
+        for mesh in 0..2 {
+            let position = quantized["meshes"][mesh]["primitives"][0]["attributes"]["POSITION"]
+                .as_u64()
+                .unwrap();
+            assert_eq!(
+                quantized["accessors"][position as usize]["componentType"],
+                Value::from(SHORT)
+            );
+        }
+        for (mesh, original) in original.iter().enumerate() {
+            assert_positions_eq(&world_positions(&quantized, &chunks, mesh as u64), original);
+        }
+
+        // スキンのないメッシュは逆量子化の変換を持つ子ノードに移る
+        assert!(quantized["nodes"][1].get("mesh").is_none());
+        assert_eq!(quantized["nodes"][1]["children"], Value::from(vec![5]));
+        assert_eq!(quantized["nodes"][5]["mesh"], Value::from(0));
+        // 量子化していないメッシュと共有しているスキンは複製する
+        assert_eq!(quantized["nodes"][2]["skin"], Value::from(1));
+        assert_eq!(quantized["nodes"][4]["skin"], Value::from(0));
+        assert_eq!(quantized["skins"][0], gltf["skins"][0]);
+        assert_eq!(
+            quantized["extensionsRequired"],
+            Value::from(vec![EXTENSION_NAME])
+        );
+    }
+}
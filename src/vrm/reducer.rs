@@ -1,4 +1,8 @@
+use super::cleaner::use_extension;
+use log::info;
 use serde_json::Value;
+use std::collections::BTreeSet;
+use std::str::FromStr;
 
 //#!/usr/bin/env python
 //# -*- coding:utf-8 -*-
@@ -127,6 +131,48 @@ use serde_json::Value;
 //    for mesh in gltf['meshes']:
 //        mesh['primitives'] = filter(lambda p: contain_name(p['material']['name']), mesh['primitives'])
 //    return gltf
+
+/// マテリアルの削減内容
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ShrinkRule {
+    /// 法線マップ
+    NormalMap,
+    /// スフィアマップ(MatCap)
+    Matcap,
+    /// 輪郭線
+    Outline,
+    /// 輪郭線の幅のテクスチャ
+    OutlineWidthTexture,
+    /// 発光
+    Emission,
+    /// 発光色が黒の場合の発光テクスチャ
+    BlackEmission,
+}
+
+impl FromStr for ShrinkRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ShrinkRule, String> {
+        match s.to_lowercase().as_str() {
+            "normal-map" => Ok(ShrinkRule::NormalMap),
+            "matcap" => Ok(ShrinkRule::Matcap),
+            "outline" => Ok(ShrinkRule::Outline),
+            "outline-width-texture" => Ok(ShrinkRule::OutlineWidthTexture),
+            "emission" => Ok(ShrinkRule::Emission),
+            "black-emission" => Ok(ShrinkRule::BlackEmission),
+            _ => Err(format!("unknown shrink rule: {}", s)),
+        }
+    }
+}
+
+// マテリアル名に部分一致するパターンの削減内容
+fn material_shrink_rules(rules: &[(String, ShrinkRule)], name: &str) -> BTreeSet<ShrinkRule> {
+    rules
+        .iter()
+        .filter(|(pattern, _)| name.contains(pattern.as_str()))
+        .map(|(_, rule)| *rule)
+        .collect()
+}
 //
 //
 //def shrink_gltf_materials(materials):
@@ -138,7 +184,7 @@ use serde_json::Value;
 //        for tex_name in ['emissiveTexture', 'normalTexture']:
 //            if tex_name in material:
 //                del material[tex_name]
-pub fn shrink_gltf_materials(gltf_: Value) -> Value {
+pub fn shrink_gltf_materials(gltf_: Value, rules: &[(String, ShrinkRule)]) -> Value {
     // //    shrink_gltf_materials(gltf['materials'])
     //    shrink_vrm_materials(gltf['extensions']['VRM']['materialProperties'])
     let mut gltf = gltf_.clone();
@@ -148,8 +194,31 @@ pub fn shrink_gltf_materials(gltf_: Value) -> Value {
         .iter_mut()
         .filter_map(|m| m.as_object_mut())
     {
-        material.remove("emissiveTexture");
-        material.remove("normalTexture");
+        let name = material
+            .get("name")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        let black_emission = material
+            .get("emissiveFactor")
+            .and_then(|v| v.as_array())
+            .map(|v| v.iter().all(|c| c.as_f64() == Some(0.0)))
+            .unwrap_or(true);
+        for rule in material_shrink_rules(rules, &name) {
+            match rule {
+                ShrinkRule::NormalMap => {
+                    material.remove("normalTexture");
+                }
+                ShrinkRule::Emission => {
+                    material.remove("emissiveTexture");
+                    material.remove("emissiveFactor");
+                }
+                ShrinkRule::BlackEmission if black_emission => {
+                    material.remove("emissiveTexture");
+                }
+                _ => {}
+            }
+        }
     }
     gltf
 }
@@ -169,25 +238,83 @@ pub fn shrink_gltf_materials(gltf_: Value) -> Value {
 //        #
 //        remove_options = ['_NORMALMAP']
 //        material['keywordMap'] = {k: v for k, v in material['keywordMap'].items() if k not in remove_options}
-pub fn shrink_vrm_materials(gltf_: Value) -> Value {
+pub fn shrink_vrm_materials(gltf_: Value, rules: &[(String, ShrinkRule)]) -> Value {
     let mut gltf = gltf_.clone();
-    for material_properties in gltf["extensions"]["VRM"]["materialProperties"]
-        .as_array_mut()
+    for material_properties in gltf
+        .pointer_mut("/extensions/VRM/materialProperties")
+        .and_then(|v| v.as_array_mut())
         .unwrap_or(&mut Vec::new())
         .iter_mut()
         .filter_map(|m| m.as_object_mut())
     {
-        // バンプマップ、スフィアマップ削除
+        let name = material_properties
+            .get("name")
+            .and_then(|v| v.as_str())
+            .unwrap_or("")
+            .to_string();
+        let black_emission = material_properties
+            .get("vectorProperties")
+            .and_then(|v| v.get("_EmissionColor"))
+            .and_then(|v| v.as_array())
+            .map(|v| v.iter().take(3).all(|c| c.as_f64() == Some(0.0)))
+            .unwrap_or(true);
+
         // https://github.com/Santarh/MToon/blob/bf08610b38e27ae915b0613ee4dfb574bc8e381b/MToon/Resources/Shaders/MToon.shader
-        if let Value::Object(ref mut texture_properties) = material_properties["textureProperties"]
-        {
-            texture_properties.remove("_BumpMap");
-            texture_properties.remove("_SphereAdd");
+        let mut textures = Vec::new();
+        let mut keywords = Vec::new();
+        for rule in material_shrink_rules(rules, &name) {
+            match rule {
+                ShrinkRule::NormalMap => {
+                    textures.push("_BumpMap");
+                    keywords.push("_NORMALMAP");
+                }
+                ShrinkRule::Matcap => textures.push("_SphereAdd"),
+                ShrinkRule::Outline => {
+                    // 輪郭線なし
+                    textures.push("_OutlineWidthTexture");
+                    keywords.extend(&[
+                        "MTOON_OUTLINE_WIDTH_WORLD",
+                        "MTOON_OUTLINE_WIDTH_SCREEN",
+                        "MTOON_OUTLINE_COLOR_FIXED",
+                        "MTOON_OUTLINE_COLOR_MIXED",
+                    ]);
+                    // floatPropertiesがなければ作る
+                    if let Value::Object(ref mut float_properties) = material_properties
+                        .entry("floatProperties")
+                        .or_insert_with(|| serde_json::map::Map::new().into())
+                    {
+                        float_properties.insert("_OutlineWidthMode".into(), 0.into());
+                    }
+                }
+                ShrinkRule::OutlineWidthTexture => textures.push("_OutlineWidthTexture"),
+                ShrinkRule::Emission => {
+                    textures.push("_EmissionMap");
+                    if let Some(Value::Object(ref mut vector_properties)) =
+                        material_properties.get_mut("vectorProperties")
+                    {
+                        if vector_properties.contains_key("_EmissionColor") {
+                            vector_properties
+                                .insert("_EmissionColor".into(), vec![0, 0, 0, 1].into());
+                        }
+                    }
+                }
+                ShrinkRule::BlackEmission if black_emission => textures.push("_EmissionMap"),
+                ShrinkRule::BlackEmission => {}
+            }
         }
 
-        // 法線マップ無効化
-        if let Value::Object(ref mut keyword_map) = material_properties["keywordMap"] {
-            keyword_map.remove("_NORMALMAP");
+        if let Some(Value::Object(ref mut texture_properties)) =
+            material_properties.get_mut("textureProperties")
+        {
+            for texture in textures {
+                texture_properties.remove(texture);
+            }
+        }
+        if let Some(Value::Object(ref mut keyword_map)) = material_properties.get_mut("keywordMap")
+        {
+            for keyword in keywords {
+                keyword_map.remove(keyword);
+            }
         }
     }
     gltf
@@ -205,9 +332,213 @@ pub fn shrink_vrm_materials(gltf_: Value) -> Value {
 //    shrink_vrm_materials(gltf['extensions']['VRM']['materialProperties'])
 //    return gltf
 
-/// バンプマップ、スフィアマップを削除する
-pub fn shrink_materials(gltf: Value) -> Value {
-    shrink_vrm_materials(shrink_gltf_materials(gltf))
+/// マテリアル名(部分一致)ごとに指定したテクスチャや効果を削除する
+pub fn shrink_materials(gltf: Value, rules: &[(String, ShrinkRule)]) -> Value {
+    shrink_vrm_materials(shrink_gltf_materials(gltf, rules), rules)
+}
+
+/// MToonマテリアルの変換先
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MaterialConversion {
+    /// KHR_materials_unlit
+    Unlit,
+    /// pbrMetallicRoughness
+    Pbr,
+}
+
+impl FromStr for MaterialConversion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<MaterialConversion, String> {
+        match s.to_lowercase().as_str() {
+            "unlit" => Ok(MaterialConversion::Unlit),
+            "pbr" => Ok(MaterialConversion::Pbr),
+            _ => Err(format!("unknown material conversion: {}", s)),
+        }
+    }
+}
+
+fn srgb_to_linear(value: f64) -> f64 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+// MToonの色(sRGB)をglTFの色(リニア)にする
+fn vrm_color(material_properties: &Value, name: &str, components: usize) -> Option<Vec<f64>> {
+    let color = material_properties
+        .get("vectorProperties")?
+        .get(name)?
+        .as_array()?
+        .iter()
+        .map(|v| v.as_f64())
+        .collect::<Option<Vec<_>>>()?;
+    if color.len() < components {
+        return None;
+    }
+    Some(
+        color
+            .iter()
+            .take(components)
+            .enumerate()
+            .map(|(i, v)| if i < 3 { srgb_to_linear(*v) } else { *v })
+            .collect(),
+    )
+}
+
+fn vrm_texture(material_properties: &Value, name: &str) -> Option<Value> {
+    let index = material_properties
+        .get("textureProperties")?
+        .get(name)?
+        .as_u64()?;
+    let mut texture_info = serde_json::Map::new();
+    texture_info.insert("index".into(), index.into());
+    Some(texture_info.into())
+}
+
+fn vrm_float(material_properties: &Value, name: &str) -> Option<f64> {
+    material_properties
+        .get("floatProperties")?
+        .get(name)?
+        .as_f64()
+}
+
+// MToonのパラメーターからglTFのマテリアルを作る
+fn convert_material(
+    material: &mut Value,
+    material_properties: &Value,
+    conversion: MaterialConversion,
+) {
+    let mut pbr = serde_json::Map::new();
+    pbr.insert(
+        "baseColorFactor".into(),
+        vrm_color(material_properties, "_Color", 4)
+            .unwrap_or_else(|| vec![1.0, 1.0, 1.0, 1.0])
+            .into(),
+    );
+    if let Some(texture_info) = vrm_texture(material_properties, "_MainTex") {
+        pbr.insert("baseColorTexture".into(), texture_info);
+    }
+    pbr.insert("metallicFactor".into(), 0.0.into());
+    pbr.insert("roughnessFactor".into(), 1.0.into());
+    material["pbrMetallicRoughness"] = pbr.into();
+
+    let emissive_factor = vrm_color(material_properties, "_EmissionColor", 3)
+        .filter(|color| color.iter().any(|v| *v > 0.0));
+    let emissive_texture = vrm_texture(material_properties, "_EmissionMap");
+    if let Some(material) = material.as_object_mut() {
+        for key in &["emissiveFactor", "emissiveTexture", "normalTexture"] {
+            material.remove(*key);
+        }
+    }
+    if let Some(emissive_factor) = emissive_factor {
+        material["emissiveFactor"] = emissive_factor.into();
+        if let Some(emissive_texture) = emissive_texture {
+            material["emissiveTexture"] = emissive_texture;
+        }
+    }
+
+    // https://github.com/vrm-c/UniVRM/blob/v0.53.0/Assets/VRM/UniVRM/Scripts/Format/VRMMaterialExporter.cs
+    match vrm_float(material_properties, "_BlendMode").map(|v| v as u64) {
+        Some(1) => {
+            material["alphaMode"] = "MASK".into();
+            material["alphaCutoff"] = vrm_float(material_properties, "_Cutoff")
+                .unwrap_or(0.5)
+                .into();
+        }
+        Some(2) | Some(3) => {
+            material["alphaMode"] = "BLEND".into();
+            material.as_object_mut().map(|m| m.remove("alphaCutoff"));
+        }
+        _ => {
+            material["alphaMode"] = "OPAQUE".into();
+            material.as_object_mut().map(|m| m.remove("alphaCutoff"));
+        }
+    }
+    if let Some(cull_mode) = vrm_float(material_properties, "_CullMode") {
+        material["doubleSided"] = (cull_mode == 0.0).into();
+    }
+
+    match conversion {
+        MaterialConversion::Unlit => {
+            material["extensions"]["KHR_materials_unlit"] = serde_json::Map::new().into();
+        }
+        MaterialConversion::Pbr => {
+            if let Some(normal_texture) = vrm_texture(material_properties, "_BumpMap") {
+                material["normalTexture"] = normal_texture;
+                if let Some(scale) = vrm_float(material_properties, "_BumpScale") {
+                    material["normalTexture"]["scale"] = scale.into();
+                }
+            }
+            let empty = if let Some(extensions) = material
+                .get_mut("extensions")
+                .and_then(|v| v.as_object_mut())
+            {
+                extensions.remove("KHR_materials_unlit");
+                extensions.is_empty()
+            } else {
+                false
+            };
+            if empty {
+                material.as_object_mut().map(|m| m.remove("extensions"));
+            }
+        }
+    }
+}
+
+/// VRMのmaterialPropertiesを元に、VRMに対応していないビューアー向けのglTFマテリアルを作る
+/// glTFのマテリアルとmaterialPropertiesは同じ番号のものが対応する
+pub fn convert_materials(gltf_: Value, conversion: MaterialConversion) -> Value {
+    let mut gltf = gltf_.clone();
+    let material_properties = gltf
+        .pointer("/extensions/VRM/materialProperties")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let mut converted = 0;
+    for (material, material_properties) in gltf
+        .get_mut("materials")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+        .iter_mut()
+        .zip(material_properties.iter())
+    {
+        match material_properties.get("shader").and_then(|v| v.as_str()) {
+            Some("VRM_USE_GLTFSHADER") | None => continue,
+            _ => {}
+        }
+        convert_material(material, material_properties, conversion);
+        converted += 1;
+    }
+    if conversion == MaterialConversion::Unlit && converted > 0 {
+        use_extension(&mut gltf, "KHR_materials_unlit", false);
+    }
+    info!("convert {} materials to {:?}", converted, conversion);
+    gltf
+}
+
+/// VRM拡張を削除して通常のglTFにする
+pub fn strip_vrm(gltf_: Value) -> Value {
+    let mut gltf = gltf_.clone();
+    if let Some(extensions) = gltf.get_mut("extensions").and_then(|v| v.as_object_mut()) {
+        extensions.remove("VRM");
+    }
+    let empty = gltf
+        .get("extensions")
+        .and_then(|v| v.as_object())
+        .map(|v| v.is_empty())
+        .unwrap_or(false);
+    if empty {
+        gltf.as_object_mut().map(|g| g.remove("extensions"));
+    }
+    for key in &["extensionsUsed", "extensionsRequired"] {
+        if let Some(extensions) = gltf.get_mut(*key).and_then(|v| v.as_array_mut()) {
+            extensions.retain(|extension| extension.as_str() != Some("VRM"));
+        }
+    }
+    gltf
 }
 
 //
@@ -493,3 +824,130 @@ pub fn reduce_vroid(mut gltf: Value) -> Value {
     //    return clean(gltf)
     gltf
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shrink_vrm_materials_without_properties() {
+        let gltf = serde_json::from_str::<Value>(
+            r#"{"extensions": {"VRM": {"materialProperties": [{"name": "Body"}]}}}"#,
+        )
+        .unwrap();
+        let rules = [
+            ShrinkRule::NormalMap,
+            ShrinkRule::Matcap,
+            ShrinkRule::Outline,
+            ShrinkRule::Emission,
+        ]
+        .iter()
+        .map(|rule| ("".to_string(), *rule))
+        .collect::<Vec<_>>();
+        let gltf = shrink_vrm_materials(gltf, &rules);
+        assert_eq!(
+            gltf.pointer("/extensions/VRM/materialProperties/0/floatProperties/_OutlineWidthMode"),
+            Some(&Value::from(0))
+        );
+        assert!(gltf
+            .pointer("/extensions/VRM/materialProperties/0/textureProperties")
+            .is_none());
+    }
+
+    // 不透明、カットアウト、半透明のMToonと、glTFのシェーダーを使うマテリアル
+    fn mtoon_materials_gltf() -> Value {
+        serde_json::from_str::<Value>(
+            r#"{
+                "materials": [
+                    {"name": "opaque"},
+                    {"name": "cutout"},
+                    {"name": "transparent", "alphaCutoff": 0.5},
+                    {"name": "gltf", "alphaMode": "MASK"}
+                ],
+                "extensions": {"VRM": {"materialProperties": [
+                    {
+                        "name": "opaque",
+                        "shader": "VRM/MToon",
+                        "floatProperties": {"_BlendMode": 0, "_CullMode": 2, "_BumpScale": 0.5},
+                        "vectorProperties": {"_Color": [0.5, 0.04, 1.0, 0.8], "_EmissionColor": [0.0, 0.0, 0.0, 1.0]},
+                        "textureProperties": {"_MainTex": 0, "_BumpMap": 1}
+                    },
+                    {
+                        "name": "cutout",
+                        "shader": "VRM/MToon",
+                        "floatProperties": {"_BlendMode": 1, "_Cutoff": 0.3, "_CullMode": 0},
+                        "vectorProperties": {"_EmissionColor": [1.0, 0.5, 0.0, 1.0]},
+                        "textureProperties": {"_EmissionMap": 2}
+                    },
+                    {
+                        "name": "transparent",
+                        "shader": "VRM/MToon",
+                        "floatProperties": {"_BlendMode": 2}
+                    },
+                    {"name": "gltf", "shader": "VRM_USE_GLTFSHADER"}
+                ]}}
+            }"#,
+        )
+        .unwrap()
+    }
+
+    fn assert_color_eq(color: &Value, expected: &[f64]) {
+        let color = color.as_array().unwrap();
+        assert_eq!(color.len(), expected.len());
+        for (v, e) in color.iter().zip(expected.iter()) {
+            assert!((v.as_f64().unwrap() - e).abs() < 1e-4, "{:?}", color);
+        }
+    }
+
+    #[test]
+    fn convert_materials_to_unlit() {
+        let gltf = mtoon_materials_gltf();
+        let converted = convert_materials(gltf.clone(), MaterialConversion::Unlit);
+        let materials = converted["materials"].as_array().unwrap();
+
+        // RGBだけをsRGBからリニアにする
+        let pbr = &materials[0]["pbrMetallicRoughness"];
+        assert_color_eq(&pbr["baseColorFactor"], &[0.214_041, 0.003_096, 1.0, 0.8]);
+        assert_eq!(pbr["baseColorTexture"]["index"], 0);
+        assert_eq!(materials[0]["alphaMode"], "OPAQUE");
+        assert_eq!(materials[0]["doubleSided"], false);
+        assert!(materials[0].get("emissiveFactor").is_none());
+        assert!(materials[0].get("normalTexture").is_none());
+
+        assert_color_eq(
+            &materials[1]["pbrMetallicRoughness"]["baseColorFactor"],
+            &[1.0, 1.0, 1.0, 1.0],
+        );
+        assert_eq!(materials[1]["alphaMode"], "MASK");
+        assert_eq!(materials[1]["alphaCutoff"], 0.3);
+        assert_eq!(materials[1]["doubleSided"], true);
+        assert_color_eq(&materials[1]["emissiveFactor"], &[1.0, 0.214_041, 0.0]);
+        assert_eq!(materials[1]["emissiveTexture"]["index"], 2);
+
+        assert_eq!(materials[2]["alphaMode"], "BLEND");
+        assert!(materials[2].get("alphaCutoff").is_none());
+
+        for material in &materials[0..3] {
+            assert!(material
+                .pointer("/extensions/KHR_materials_unlit")
+                .is_some());
+        }
+        assert_eq!(materials[3], gltf["materials"][3]);
+        assert_eq!(
+            converted["extensionsUsed"],
+            Value::from(vec!["KHR_materials_unlit"])
+        );
+    }
+
+    #[test]
+    fn convert_materials_to_pbr() {
+        let converted = convert_materials(mtoon_materials_gltf(), MaterialConversion::Pbr);
+        let material = &converted["materials"][0];
+        assert_eq!(material["pbrMetallicRoughness"]["metallicFactor"], 0.0);
+        assert_eq!(material["pbrMetallicRoughness"]["roughnessFactor"], 1.0);
+        assert_eq!(material["normalTexture"]["index"], 1);
+        assert_eq!(material["normalTexture"]["scale"], 0.5);
+        assert!(material.get("extensions").is_none());
+        assert!(converted.get("extensionsUsed").is_none());
+    }
+}
@@ -0,0 +1,219 @@
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+// 数の変化を報告するリソース
+const RESOURCE_POINTERS: [&str; 13] = [
+    "/nodes",
+    "/meshes",
+    "/materials",
+    "/textures",
+    "/images",
+    "/samplers",
+    "/skins",
+    "/accessors",
+    "/bufferViews",
+    "/buffers",
+    "/extensions/VRM/materialProperties",
+    "/extensions/VRM/blendShapeMaster/blendShapeGroups",
+    "/extensions/VRM/secondaryAnimation/boneGroups",
+];
+
+// 名前の変化を報告するリソース
+const NAMED_RESOURCE_POINTERS: [&str; 3] = ["/meshes", "/materials", "/images"];
+
+/// 1つの削減処理での変更
+#[derive(Debug, Clone, Default)]
+pub struct PassReport {
+    pub pass: String,
+    pub changes: Vec<String>,
+}
+
+/// 削減処理ごとの変更の記録
+#[derive(Debug, Clone, Default)]
+pub struct ReductionReport {
+    pub passes: Vec<PassReport>,
+}
+
+fn resources(gltf: &Value, pointer: &str) -> Vec<Value> {
+    gltf.pointer(pointer)
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default()
+}
+
+fn resource_name(resource: &Value, index: usize) -> String {
+    resource
+        .get("name")
+        .and_then(|v| v.as_str())
+        .map(|name| format!("{} ({})", index, name))
+        .unwrap_or_else(|| index.to_string())
+}
+
+fn names(gltf: &Value, pointer: &str) -> Vec<String> {
+    resources(gltf, pointer)
+        .iter()
+        .filter_map(|v| v.get("name").and_then(|v| v.as_str()))
+        .map(|v| v.to_string())
+        .collect()
+}
+
+// beforeにあってafterにない名前。同じ名前が複数ある場合は数の差だけ返す
+fn missing_names(before: &[String], after: &[String]) -> Vec<String> {
+    let mut remaining = after.to_vec();
+    let mut missing = Vec::new();
+    for name in before {
+        if let Some(index) = remaining.iter().position(|v| v == name) {
+            remaining.remove(index);
+        } else {
+            missing.push(name.clone());
+        }
+    }
+    missing
+}
+
+fn extension_names(gltf: &Value) -> BTreeSet<String> {
+    resources(gltf, "/extensionsUsed")
+        .iter()
+        .filter_map(|v| v.as_str())
+        .map(|v| v.to_string())
+        .collect()
+}
+
+// 削減処理の前後のglTFの違い
+fn diff_gltf(before: &Value, after: &Value) -> Vec<String> {
+    let mut changes = Vec::new();
+    for pointer in RESOURCE_POINTERS.iter() {
+        let (before_len, after_len) = (
+            resources(before, pointer).len(),
+            resources(after, pointer).len(),
+        );
+        if before_len != after_len {
+            changes.push(format!("{}: {} -> {}", pointer, before_len, after_len));
+        }
+    }
+    for pointer in NAMED_RESOURCE_POINTERS.iter() {
+        let (before_names, after_names) = (names(before, pointer), names(after, pointer));
+        let removed = missing_names(&before_names, &after_names);
+        if !removed.is_empty() {
+            changes.push(format!("{} removed: {}", pointer, removed.join(", ")));
+        }
+        let added = missing_names(&after_names, &before_names);
+        if !added.is_empty() {
+            changes.push(format!("{} added: {}", pointer, added.join(", ")));
+        }
+    }
+
+    // 同じ名前のメッシュのプリミティブ数の変化
+    let after_meshes = resources(after, "/meshes");
+    for mesh in resources(before, "/meshes") {
+        let name = if let Some(name) = mesh.get("name").and_then(|v| v.as_str()) {
+            name
+        } else {
+            continue;
+        };
+        let primitive_len = |mesh: &Value| resources(mesh, "/primitives").len();
+        if let Some(after_mesh) = after_meshes
+            .iter()
+            .find(|v| v.get("name").and_then(|v| v.as_str()) == Some(name))
+        {
+            if primitive_len(&mesh) != primitive_len(after_mesh) {
+                changes.push(format!(
+                    "mesh {}: primitives {} -> {}",
+                    name,
+                    primitive_len(&mesh),
+                    primitive_len(after_mesh)
+                ));
+            }
+        }
+    }
+
+    let (before_extensions, after_extensions) = (extension_names(before), extension_names(after));
+    for name in before_extensions.difference(&after_extensions) {
+        changes.push(format!("extension removed: {}", name));
+    }
+    for name in after_extensions.difference(&before_extensions) {
+        changes.push(format!("extension added: {}", name));
+    }
+    changes
+}
+
+impl ReductionReport {
+    /// 削減処理の前後のglTFとバイナリのバイト数の違いを記録する
+    pub fn record(
+        &mut self,
+        pass: &str,
+        before: &Value,
+        after: &Value,
+        before_bytes: usize,
+        after_bytes: usize,
+    ) {
+        let mut changes = diff_gltf(before, after);
+        if before_bytes != after_bytes {
+            changes.push(format!(
+                "binary bytes: {} -> {} ({:+})",
+                before_bytes,
+                after_bytes,
+                after_bytes as i64 - before_bytes as i64
+            ));
+        }
+        self.push(pass, changes);
+    }
+
+    /// cleanで削除されたリソースを元の番号と名前で記録する
+    pub fn record_removed(&mut self, pass: &str, before: &Value, removed: &[(&str, Vec<u64>)]) {
+        let changes = removed
+            .iter()
+            .filter(|(_, indexes)| !indexes.is_empty())
+            .map(|(pointer, indexes)| {
+                let resources = resources(before, pointer);
+                let names = indexes
+                    .iter()
+                    .map(|index| {
+                        resources
+                            .get(*index as usize)
+                            .map(|v| resource_name(v, *index as usize))
+                            .unwrap_or_else(|| index.to_string())
+                    })
+                    .collect::<Vec<_>>();
+                format!("{} removed: {}", pointer, names.join(", "))
+            })
+            .collect();
+        self.push(pass, changes);
+    }
+
+    pub fn push(&mut self, pass: &str, changes: Vec<String>) {
+        self.passes.push(PassReport {
+            pass: pass.to_string(),
+            changes,
+        });
+    }
+
+    /// 変更があった削減処理を表示する
+    pub fn print(&self) {
+        for pass in &self.passes {
+            if pass.changes.is_empty() {
+                continue;
+            }
+            println!("{}:", pass.pass);
+            for change in &pass.changes {
+                println!("\t{}", change);
+            }
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let passes = self
+            .passes
+            .iter()
+            .map(|pass| {
+                let mut value = serde_json::Map::new();
+                value.insert("pass".into(), pass.pass.clone().into());
+                value.insert("changes".into(), pass.changes.clone().into());
+                value.into()
+            })
+            .collect::<Vec<Value>>();
+        let mut json = serde_json::Map::new();
+        json.insert("passes".into(), passes.into());
+        json.into()
+    }
+}
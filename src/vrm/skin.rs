@@ -0,0 +1,932 @@
+use super::accessor::*;
+use super::cleaner::*;
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+// プリミティブのJOINTS_n、WEIGHTS_nの組
+fn joint_weight_attributes(primitive: &Value) -> Vec<(u64, u64)> {
+    let mut attributes = Vec::new();
+    for n in 0.. {
+        let joints = primitive
+            .pointer(&format!("/attributes/JOINTS_{}", n))
+            .and_then(|v| v.as_u64());
+        let weights = primitive
+            .pointer(&format!("/attributes/WEIGHTS_{}", n))
+            .and_then(|v| v.as_u64());
+        match (joints, weights) {
+            (Some(joints), Some(weights)) => attributes.push((joints, weights)),
+            _ => break,
+        }
+    }
+    attributes
+}
+
+// スキンごとに、そのスキンで描画されるメッシュ
+// 複数のスキンで描画されるメッシュがあれば、関係するスキンは編集しない
+fn skin_meshes(gltf: &Value) -> BTreeMap<u64, BTreeSet<u64>> {
+    let mut mesh_skins = BTreeMap::new();
+    for node in gltf
+        .get("nodes")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        if let (Some(mesh), Some(skin)) = (
+            node.get("mesh").and_then(|v| v.as_u64()),
+            node.get("skin").and_then(|v| v.as_u64()),
+        ) {
+            mesh_skins
+                .entry(mesh)
+                .or_insert_with(BTreeSet::new)
+                .insert(skin);
+        }
+    }
+
+    let mut shared_skins = BTreeSet::new();
+    let mut skin_meshes = BTreeMap::new();
+    for (mesh, skins) in &mesh_skins {
+        if skins.len() > 1 {
+            shared_skins.extend(skins.iter().cloned());
+        }
+        for skin in skins {
+            skin_meshes
+                .entry(*skin)
+                .or_insert_with(BTreeSet::new)
+                .insert(*mesh);
+        }
+    }
+    for skin in shared_skins {
+        skin_meshes.remove(&skin);
+    }
+    skin_meshes
+}
+
+fn primitives_of_meshes(gltf: &Value, meshes: &BTreeSet<u64>) -> Vec<(usize, usize)> {
+    let mut primitives = Vec::new();
+    for mesh in meshes {
+        let len = gltf
+            .get("meshes")
+            .and_then(|v| v.get(*mesh as usize))
+            .and_then(|v| v.get("primitives"))
+            .and_then(|v| v.as_array())
+            .map(|v| v.len())
+            .unwrap_or(0);
+        for primitive in 0..len {
+            primitives.push((*mesh as usize, primitive));
+        }
+    }
+    primitives
+}
+
+// ウェイトが0より大きい頂点から参照されているジョイント
+fn weighted_joints(
+    gltf: &Value,
+    chunks: &[Vec<u8>],
+    primitives: &[(usize, usize)],
+) -> Option<BTreeSet<usize>> {
+    let mut joints = BTreeSet::new();
+    for (mesh_index, primitive_index) in primitives {
+        let primitive = &gltf["meshes"][*mesh_index]["primitives"][*primitive_index];
+        for (joints_index, weights_index) in joint_weight_attributes(primitive) {
+            let joint_values = read_accessor(gltf, chunks, joints_index)?;
+            let weight_values = read_accessor(gltf, chunks, weights_index)?;
+            if joint_values.len() != weight_values.len() {
+                return None;
+            }
+            for (joint, weight) in joint_values.iter().zip(weight_values.iter()) {
+                if *weight > 0.0 {
+                    joints.insert(*joint as usize);
+                }
+            }
+        }
+    }
+    Some(joints)
+}
+
+// JOINTS_nを新しいジョイントの並びに合わせて書き換える。ウェイトが0の要素は0にする
+fn remap_joints(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    joints_index: u64,
+    weights_index: u64,
+    joint_map: &BTreeMap<usize, usize>,
+) -> Option<u64> {
+    let joint_values = read_accessor(gltf, chunks, joints_index)?;
+    let weight_values = read_accessor(gltf, chunks, weights_index)?;
+    let values = joint_values
+        .iter()
+        .zip(weight_values.iter())
+        .map(|(joint, weight)| match joint_map.get(&(*joint as usize)) {
+            Some(new_joint) if *weight > 0.0 => *new_joint as f64,
+            _ => 0.0,
+        })
+        .collect::<Vec<_>>();
+    let component_type = if joint_map.len() <= 256 {
+        UNSIGNED_BYTE
+    } else {
+        UNSIGNED_SHORT
+    };
+    Some(push_accessor(
+        gltf,
+        chunks,
+        component_type,
+        false,
+        "VEC4",
+        &values,
+        Some(ARRAY_BUFFER),
+    ))
+}
+
+// ウェイトを持たず、removableがtrueを返すノードをジョイントから削除する
+fn reduce_skin_joints<F>(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    skin_index: u64,
+    primitives: &[(usize, usize)],
+    removable: F,
+) -> Option<()>
+where
+    F: Fn(u64) -> bool,
+{
+    let skin = gltf.get("skins")?.get(skin_index as usize)?.clone();
+    let joints = skin
+        .get("joints")?
+        .as_array()?
+        .iter()
+        .map(|v| v.as_u64())
+        .collect::<Option<Vec<_>>>()?;
+    let weighted_joints = weighted_joints(gltf, chunks, primitives)?;
+
+    let mut joint_map = BTreeMap::new();
+    let mut new_joints = Vec::new();
+    for (joint_index, node_index) in joints.iter().enumerate() {
+        if weighted_joints.contains(&joint_index) || !removable(*node_index) {
+            joint_map.insert(joint_index, new_joints.len());
+            new_joints.push(*node_index);
+        }
+    }
+    if new_joints.len() == joints.len() {
+        return Some(());
+    }
+
+    let inverse_bind_matrices =
+        if let Some(index) = skin.get("inverseBindMatrices").and_then(|v| v.as_u64()) {
+            let values = read_accessor(gltf, chunks, index)?;
+            let values = joint_map
+                .keys()
+                .flat_map(|joint_index| {
+                    values
+                        .get(joint_index * 16..(joint_index + 1) * 16)
+                        .map(|m| m.to_vec())
+                        .unwrap_or_default()
+                })
+                .collect::<Vec<_>>();
+            if values.len() != new_joints.len() * 16 {
+                return None;
+            }
+            Some(push_accessor(
+                gltf, chunks, FLOAT, false, "MAT4", &values, None,
+            ))
+        } else {
+            None
+        };
+
+    let mut new_joint_accessors = BTreeMap::new();
+    for (mesh_index, primitive_index) in primitives {
+        let primitive = gltf["meshes"][*mesh_index]["primitives"][*primitive_index].clone();
+        for (n, (joints_index, weights_index)) in
+            joint_weight_attributes(&primitive).into_iter().enumerate()
+        {
+            let new_joints_index = if let Some(index) =
+                new_joint_accessors.get(&(joints_index, weights_index))
+            {
+                *index
+            } else {
+                let index = remap_joints(gltf, chunks, joints_index, weights_index, &joint_map)?;
+                new_joint_accessors.insert((joints_index, weights_index), index);
+                index
+            };
+            gltf["meshes"][*mesh_index]["primitives"][*primitive_index]["attributes"]
+                [format!("JOINTS_{}", n)] = new_joints_index.into();
+        }
+    }
+
+    info!(
+        "skin {}: {} joints -> {} joints",
+        skin_index,
+        joints.len(),
+        new_joints.len()
+    );
+    let skin = &mut gltf["skins"][skin_index as usize];
+    skin["joints"] = new_joints.into();
+    if let Some(index) = inverse_bind_matrices {
+        skin["inverseBindMatrices"] = index.into();
+    }
+    Some(())
+}
+
+// 頂点ごとにウェイトの大きい順にmax_influences個のジョイントを残し、合計が1になるようにする
+fn limit_influences(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    attributes: &[(u64, u64)],
+    max_influences: usize,
+) -> Option<Vec<(u64, u64)>> {
+    // 全てのセットのうち最も大きいコンポーネントの型で書き出す
+    let (joints_type, weights_type) = {
+        let accessors = gltf.get("accessors")?;
+        let widest_component_type = |indices: Vec<u64>| {
+            indices
+                .into_iter()
+                .map(|index| {
+                    accessors
+                        .get(index as usize)
+                        .and_then(|v| v.get("componentType"))
+                        .and_then(|v| v.as_u64())
+                })
+                .collect::<Option<Vec<_>>>()?
+                .into_iter()
+                .max_by_key(|component_type| component_size(*component_type))
+        };
+        (
+            widest_component_type(attributes.iter().map(|v| v.0).collect())?,
+            widest_component_type(attributes.iter().map(|v| v.1).collect())?,
+        )
+    };
+    let weights_max = normalized_max(weights_type);
+
+    let mut influences = Vec::new();
+    for (joints_index, weights_index) in attributes {
+        let joint_values = read_accessor(gltf, chunks, *joints_index)?;
+        let weight_values = read_accessor(gltf, chunks, *weights_index)?;
+        if joint_values.len() != weight_values.len() {
+            return None;
+        }
+        let weight_type = gltf["accessors"][*weights_index as usize]
+            .get("componentType")
+            .and_then(|v| v.as_u64())?;
+        let vertex_len = joint_values.len() / 4;
+        if influences.is_empty() {
+            influences.resize(vertex_len, Vec::new());
+        } else if influences.len() != vertex_len {
+            return None;
+        }
+        for (i, vertex_influences) in influences.iter_mut().enumerate() {
+            for k in i * 4..i * 4 + 4 {
+                let weight = weight_values[k] / normalized_max(weight_type);
+                if weight > 0.0 {
+                    vertex_influences.push((joint_values[k], weight));
+                }
+            }
+        }
+    }
+
+    // 元のセットより増やさない
+    let set_len = ((max_influences + 3) / 4).min(attributes.len());
+    let mut joint_sets = vec![Vec::with_capacity(influences.len() * 4); set_len];
+    let mut weight_sets = vec![Vec::with_capacity(influences.len() * 4); set_len];
+    for mut vertex_influences in influences {
+        vertex_influences
+            .sort_by(|l, r| r.1.partial_cmp(&l.1).unwrap_or(std::cmp::Ordering::Equal));
+        vertex_influences.truncate(max_influences);
+        let sum = vertex_influences.iter().fold(0.0, |sum, i| sum + i.1);
+        let mut weights = vertex_influences
+            .iter()
+            .map(|i| {
+                if sum > 0.0 {
+                    i.1 / sum * weights_max
+                } else {
+                    0.0
+                }
+            })
+            .collect::<Vec<_>>();
+        if weights_type != FLOAT {
+            // 整数に丸めた誤差は最も大きいウェイトで吸収する
+            for weight in &mut weights {
+                *weight = weight.round();
+            }
+            let error = weights_max - weights.iter().sum::<f64>();
+            if let Some(weight) = weights.get_mut(0) {
+                *weight += error;
+            }
+        }
+        for k in 0..set_len * 4 {
+            joint_sets[k / 4].push(vertex_influences.get(k).map(|i| i.0).unwrap_or(0.0));
+            weight_sets[k / 4].push(weights.get(k).cloned().unwrap_or(0.0));
+        }
+    }
+
+    let mut new_attributes = Vec::new();
+    for (joint_values, weight_values) in joint_sets.iter().zip(weight_sets.iter()) {
+        let joints_index = push_accessor(
+            gltf,
+            chunks,
+            joints_type,
+            false,
+            "VEC4",
+            joint_values,
+            Some(ARRAY_BUFFER),
+        );
+        let weights_index = push_accessor(
+            gltf,
+            chunks,
+            weights_type,
+            weights_type != FLOAT,
+            "VEC4",
+            weight_values,
+            Some(ARRAY_BUFFER),
+        );
+        new_attributes.push((joints_index, weights_index));
+    }
+    Some(new_attributes)
+}
+
+/// 頂点ごとのジョイントの影響数を制限し、余ったJOINTS_n、WEIGHTS_nを削除する
+pub fn limit_bone_influences(
+    gltf_: Value,
+    chunks: &mut Vec<Vec<u8>>,
+    max_influences: usize,
+) -> Value {
+    let mut gltf = gltf_.clone();
+    let max_influences = max_influences.max(1);
+    let mut cache: BTreeMap<Vec<(u64, u64)>, Option<Vec<(u64, u64)>>> = BTreeMap::new();
+    let mesh_len = gltf
+        .get("meshes")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+    for mesh_index in 0..mesh_len {
+        let primitive_len = gltf["meshes"][mesh_index]
+            .get("primitives")
+            .and_then(|v| v.as_array())
+            .map(|v| v.len())
+            .unwrap_or(0);
+        for primitive_index in 0..primitive_len {
+            let attributes =
+                joint_weight_attributes(&gltf["meshes"][mesh_index]["primitives"][primitive_index]);
+            if attributes.is_empty() {
+                continue;
+            }
+            let new_attributes = if let Some(new_attributes) = cache.get(&attributes) {
+                new_attributes.clone()
+            } else {
+                let new_attributes =
+                    limit_influences(&mut gltf, chunks, &attributes, max_influences);
+                if new_attributes.is_none() {
+                    warn!(
+                        "Failed to limit bone influences of mesh {} primitive {}",
+                        mesh_index, primitive_index
+                    );
+                }
+                cache.insert(attributes.clone(), new_attributes.clone());
+                new_attributes
+            };
+            let new_attributes = if let Some(new_attributes) = new_attributes {
+                new_attributes
+            } else {
+                continue;
+            };
+
+            let primitive_attributes = gltf["meshes"][mesh_index]["primitives"][primitive_index]
+                ["attributes"]
+                .as_object_mut()
+                .unwrap();
+            for n in 0..attributes.len() {
+                primitive_attributes.remove(&format!("JOINTS_{}", n));
+                primitive_attributes.remove(&format!("WEIGHTS_{}", n));
+            }
+            for (n, (joints_index, weights_index)) in new_attributes.into_iter().enumerate() {
+                primitive_attributes.insert(format!("JOINTS_{}", n), joints_index.into());
+                primitive_attributes.insert(format!("WEIGHTS_{}", n), weights_index.into());
+            }
+        }
+    }
+    info!(
+        "limit bone influences to {}: {} attribute sets",
+        max_influences,
+        cache.len()
+    );
+    gltf
+}
+
+fn descendant_nodes(gltf: &Value, roots: &BTreeSet<u64>) -> BTreeSet<u64> {
+    let mut descendants = BTreeSet::new();
+    let mut stack = roots.iter().cloned().collect::<Vec<_>>();
+    while let Some(node_index) = stack.pop() {
+        if !descendants.insert(node_index) {
+            continue;
+        }
+        for child in gltf
+            .get("nodes")
+            .and_then(|v| v.get(node_index as usize))
+            .and_then(|v| v.get("children"))
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+        {
+            if let Some(child) = child.as_u64() {
+                stack.push(child);
+            }
+        }
+    }
+    descendants
+}
+
+// 揺れもののルートとその子孫のノード。揺れもので動く
+fn spring_bone_nodes(gltf: &Value) -> BTreeSet<u64> {
+    let spring_bone_roots = gltf
+        .pointer("/extensions/VRM/secondaryAnimation/boneGroups")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .flat_map(|bone_group| {
+            bone_group
+                .get("bones")
+                .and_then(|v| v.as_array())
+                .cloned()
+                .unwrap_or_default()
+        })
+        .filter_map(|v| v.as_u64())
+        .collect();
+    descendant_nodes(gltf, &spring_bone_roots)
+}
+
+// 全てのスキンのジョイント
+fn skin_joint_nodes(gltf: &Value) -> BTreeSet<u64> {
+    gltf.get("skins")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .flat_map(|skin| {
+            skin.get("joints")
+                .and_then(|v| v.as_array())
+                .cloned()
+                .unwrap_or_default()
+        })
+        .filter_map(|v| v.as_u64())
+        .collect()
+}
+
+// 動かないジョイントと、ウェイトを移す先の最も近い動く祖先
+// 動かないジョイントは親と一緒に動くだけなので、バインドポーズが階層と一致していれば
+// ウェイトを祖先に移しても見た目は変わらない
+fn chain_collapse_map(gltf: &Value, animated_nodes: &BTreeSet<u64>) -> BTreeMap<u64, u64> {
+    let mut parents = BTreeMap::new();
+    for (node_index, node) in gltf
+        .get("nodes")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+        .iter()
+        .enumerate()
+    {
+        for child in node
+            .get("children")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+        {
+            if let Some(child) = child.as_u64() {
+                parents.insert(child, node_index as u64);
+            }
+        }
+    }
+
+    let mut node_map = BTreeMap::new();
+    for joint in skin_joint_nodes(gltf) {
+        if animated_nodes.contains(&joint) {
+            continue;
+        }
+        let mut ancestor = parents.get(&joint);
+        // 循環している階層で止まらないように、ノード数までしか辿らない
+        for _ in 0..parents.len() {
+            match ancestor {
+                Some(a) if animated_nodes.contains(a) => {
+                    node_map.insert(joint, *a);
+                    break;
+                }
+                Some(a) => ancestor = parents.get(a),
+                None => break,
+            }
+        }
+    }
+    node_map
+}
+
+/// どの頂点にも影響しないジョイントをスキンから削除し、スキンから外れた末端のノードを削除する
+/// ヒューマノイドのボーンや揺れものなど、VRM拡張から参照されているノードは残す
+/// collapse_chainsのときは、ヒューマノイドのボーン、揺れもの、アニメーションで動かないジョイントの
+/// ウェイトを動く祖先に移してから削除する
+pub fn reduce_bones(gltf_: Value, chunks: &mut Vec<Vec<u8>>, collapse_chains: bool) -> Value {
+    let mut gltf = gltf_.clone();
+    let mut protected_nodes = BTreeSet::new();
+    for_each_vrm_node_index_references(&mut gltf, |index| {
+        if let Some(i) = index.as_u64() {
+            protected_nodes.insert(i);
+        }
+    });
+    let spring_bone_nodes = spring_bone_nodes(&gltf);
+    let joints = skin_joint_nodes(&gltf);
+
+    if collapse_chains {
+        let mut animated_nodes = protected_nodes.clone();
+        animated_nodes.extend(spring_bone_nodes.iter().cloned());
+        for animation in gltf
+            .get("animations")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+        {
+            for channel in animation
+                .get("channels")
+                .and_then(|v| v.as_array())
+                .unwrap_or(&Vec::new())
+            {
+                if let Some(node) = channel.pointer("/target/node").and_then(|v| v.as_u64()) {
+                    animated_nodes.insert(node);
+                }
+            }
+        }
+        let node_map = chain_collapse_map(&gltf, &animated_nodes);
+        info!("collapse {} joints into their ancestors", node_map.len());
+        gltf = collapse_joints(gltf, chunks, &node_map);
+    }
+
+    for (skin_index, meshes) in skin_meshes(&gltf) {
+        let primitives = primitives_of_meshes(&gltf, &meshes);
+        if reduce_skin_joints(&mut gltf, chunks, skin_index, &primitives, |node| {
+            !protected_nodes.contains(&node)
+        })
+        .is_none()
+        {
+            warn!("Failed to reduce joints of skin {}", skin_index);
+        }
+    }
+
+    // 揺れものの子孫は揺れもので使うので残す
+    let removed_joints = joints
+        .difference(&skin_joint_nodes(&gltf))
+        .filter(|node| !spring_bone_nodes.contains(node))
+        .cloned()
+        .collect();
+    remove_nodes(gltf, &removed_joints)
+}
+
+// JOINTS_nのジョイントを置き換える
+fn replace_joints(
+    gltf: &mut Value,
+    chunks: &mut Vec<Vec<u8>>,
+    joints_index: u64,
+    joint_map: &BTreeMap<usize, usize>,
+) -> Option<u64> {
+    let component_type = gltf
+        .get("accessors")?
+        .get(joints_index as usize)?
+        .get("componentType")?
+        .as_u64()?;
+    let values = read_accessor(gltf, chunks, joints_index)?
+        .iter()
+        .map(|joint| {
+            joint_map
+                .get(&(*joint as usize))
+                .map(|j| *j as f64)
+                .unwrap_or(*joint)
+        })
+        .collect::<Vec<_>>();
+    Some(push_accessor(
+        gltf,
+        chunks,
+        component_type,
+        false,
+        "VEC4",
+        &values,
+        Some(ARRAY_BUFFER),
+    ))
+}
+
+/// ノードのウェイトをnode_mapの移動先のノードに移し、ウェイトがなくなったノードをスキンから削除する
+/// 移動先が同じスキンのジョイントでない場合は移さない
+pub fn collapse_joints(
+    gltf_: Value,
+    chunks: &mut Vec<Vec<u8>>,
+    node_map: &BTreeMap<u64, u64>,
+) -> Value {
+    let mut gltf = gltf_.clone();
+    for (skin_index, meshes) in skin_meshes(&gltf) {
+        let joints = gltf["skins"][skin_index as usize]
+            .get("joints")
+            .and_then(|v| v.as_array())
+            .map(|v| v.iter().filter_map(|j| j.as_u64()).collect::<Vec<_>>())
+            .unwrap_or_default();
+        let mut joint_map = BTreeMap::new();
+        for (joint_index, node_index) in joints.iter().enumerate() {
+            if let Some(target) = node_map
+                .get(node_index)
+                .and_then(|target| joints.iter().position(|j| j == target))
+            {
+                joint_map.insert(joint_index, target);
+            }
+        }
+        if joint_map.is_empty() {
+            continue;
+        }
+
+        let primitives = primitives_of_meshes(&gltf, &meshes);
+        let mut new_joint_accessors = BTreeMap::new();
+        for (mesh_index, primitive_index) in &primitives {
+            let primitive = gltf["meshes"][*mesh_index]["primitives"][*primitive_index].clone();
+            for (n, (joints_index, _)) in
+                joint_weight_attributes(&primitive).into_iter().enumerate()
+            {
+                let new_joints_index = if let Some(index) = new_joint_accessors.get(&joints_index) {
+                    *index
+                } else if let Some(index) =
+                    replace_joints(&mut gltf, chunks, joints_index, &joint_map)
+                {
+                    new_joint_accessors.insert(joints_index, index);
+                    index
+                } else {
+                    warn!("Failed to collapse joints of skin {}", skin_index);
+                    continue;
+                };
+                gltf["meshes"][*mesh_index]["primitives"][*primitive_index]["attributes"]
+                    [format!("JOINTS_{}", n)] = new_joints_index.into();
+            }
+        }
+
+        let collapsed_nodes = joint_map
+            .keys()
+            .map(|joint_index| joints[*joint_index])
+            .collect::<BTreeSet<_>>();
+        if reduce_skin_joints(&mut gltf, chunks, skin_index, &primitives, |node| {
+            collapsed_nodes.contains(&node)
+        })
+        .is_none()
+        {
+            warn!("Failed to reduce joints of skin {}", skin_index);
+        }
+    }
+    gltf
+}
+
+/// 指定したノードを子孫ごと階層から外して削除する
+/// メッシュを持つノードや、子ノード以外として参照されているノードは残す
+pub fn remove_nodes(gltf_: Value, nodes: &BTreeSet<u64>) -> Value {
+    let mut gltf = gltf_.clone();
+    let mut referenced_nodes = BTreeSet::new();
+    let mut without_children = gltf.clone();
+    for node in without_children
+        .get_mut("nodes")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        node.as_object_mut().map(|n| n.remove("children"));
+    }
+    for_each_node_index_references(&mut without_children, |index| {
+        if let Some(i) = index.as_u64() {
+            referenced_nodes.insert(i);
+        }
+    });
+
+    // 子孫が全て削除できるノードだけを削除する
+    fn removable(
+        gltf: &Value,
+        node_index: u64,
+        nodes: &BTreeSet<u64>,
+        referenced_nodes: &BTreeSet<u64>,
+    ) -> bool {
+        let node = if let Some(node) = gltf.get("nodes").and_then(|v| v.get(node_index as usize)) {
+            node
+        } else {
+            return false;
+        };
+        nodes.contains(&node_index)
+            && !referenced_nodes.contains(&node_index)
+            && ["mesh", "skin", "camera"]
+                .iter()
+                .all(|key| node.get(*key).is_none())
+            && node
+                .get("children")
+                .and_then(|v| v.as_array())
+                .unwrap_or(&Vec::new())
+                .iter()
+                .all(|child| {
+                    child
+                        .as_u64()
+                        .map(|c| removable(gltf, c, nodes, referenced_nodes))
+                        .unwrap_or(false)
+                })
+    }
+    let removed = nodes
+        .iter()
+        .cloned()
+        .filter(|node_index| removable(&gltf, *node_index, nodes, &referenced_nodes))
+        .collect::<BTreeSet<_>>();
+
+    for node in gltf
+        .get_mut("nodes")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(children) = node.get_mut("children").and_then(|v| v.as_array_mut()) {
+            children.retain(|child| {
+                child
+                    .as_u64()
+                    .map(|c| !removed.contains(&c))
+                    .unwrap_or(true)
+            });
+            if children.is_empty() {
+                node.as_object_mut().map(|n| n.remove("children"));
+            }
+        }
+    }
+    info!("remove {} nodes", removed.len());
+    clean_nodes(gltf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // hipsの下に、ウェイトのある布のジョイント、その先のウェイトのないジョイント、空のノード、メッシュ
+    fn skinned_gltf() -> (Value, Vec<Vec<u8>>) {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "scenes": [{"nodes": [0]}],
+                "nodes": [
+                    {"name": "hips", "children": [1, 3, 4]},
+                    {"name": "cloth", "children": [2]},
+                    {"name": "cloth_end"},
+                    {"name": "empty"},
+                    {"name": "body", "mesh": 0, "skin": 0}
+                ],
+                "meshes": [{"primitives": [{"attributes": {}}]}],
+                "skins": [{"joints": [0, 1, 2]}],
+                "extensions": {"VRM": {"humanoid": {"humanBones": [{"bone": "hips", "node": 0}]}}}
+            }"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        let identity = [
+            1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+        ];
+        let matrices = identity
+            .iter()
+            .cycle()
+            .take(16 * 3)
+            .cloned()
+            .collect::<Vec<_>>();
+        let inverse_bind_matrices = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "MAT4",
+            &matrices,
+            None,
+        );
+        let joints = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            UNSIGNED_BYTE,
+            false,
+            "VEC4",
+            &[1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+            Some(ARRAY_BUFFER),
+        );
+        let weights = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "VEC4",
+            &[1.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0],
+            Some(ARRAY_BUFFER),
+        );
+        gltf["skins"][0]["inverseBindMatrices"] = inverse_bind_matrices.into();
+        let attributes = &mut gltf["meshes"][0]["primitives"][0]["attributes"];
+        attributes["JOINTS_0"] = joints.into();
+        attributes["WEIGHTS_0"] = weights.into();
+        (gltf, chunks)
+    }
+
+    fn node_names(gltf: &Value, indices: &Value) -> Vec<String> {
+        indices
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|i| {
+                gltf["nodes"][i.as_u64().unwrap() as usize]["name"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    fn joint_values(gltf: &Value, chunks: &[Vec<u8>]) -> Vec<f64> {
+        let joints = gltf["meshes"][0]["primitives"][0]["attributes"]["JOINTS_0"]
+            .as_u64()
+            .unwrap();
+        read_accessor(gltf, chunks, joints).unwrap()
+    }
+
+    #[test]
+    fn reduce_bones_removes_only_unweighted_joints() {
+        let (gltf, mut chunks) = skinned_gltf();
+        let gltf = reduce_bones(gltf, &mut chunks, false);
+        assert_eq!(
+            node_names(&gltf, &gltf["skins"][0]["joints"]),
+            vec!["hips", "cloth"]
+        );
+        // スキンから外れていない末端のノードは残す
+        let names = gltf["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| node["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["hips", "cloth", "empty", "body"]);
+        assert_eq!(
+            joint_values(&gltf, &chunks),
+            vec![1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0]
+        );
+        assert_eq!(
+            read_accessor(
+                &gltf,
+                &chunks,
+                gltf["skins"][0]["inverseBindMatrices"].as_u64().unwrap()
+            )
+            .unwrap()
+            .len(),
+            16 * 2
+        );
+    }
+
+    #[test]
+    fn reduce_bones_collapses_chains_into_animated_ancestors() {
+        let (gltf, mut chunks) = skinned_gltf();
+        let gltf = reduce_bones(gltf, &mut chunks, true);
+        assert_eq!(node_names(&gltf, &gltf["skins"][0]["joints"]), vec!["hips"]);
+        let names = gltf["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|node| node["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["hips", "empty", "body"]);
+        assert_eq!(joint_values(&gltf, &chunks), vec![0.0; 8]);
+    }
+
+    #[test]
+    fn limit_bone_influences_renormalizes_and_drops_sets() {
+        let (mut gltf, mut chunks) = skinned_gltf();
+        // 1頂点に8個のジョイント。JOINTS_1だけがUNSIGNED_SHORTで255より大きい番号を持つ
+        let mut push = |component_type, values: &[f64]| {
+            push_accessor(
+                &mut gltf,
+                &mut chunks,
+                component_type,
+                false,
+                "VEC4",
+                values,
+                Some(ARRAY_BUFFER),
+            )
+        };
+        let joints_0 = push(UNSIGNED_BYTE, &[0.0, 1.0, 2.0, 3.0]);
+        let weights_0 = push(FLOAT, &[0.3, 0.2, 0.1, 0.05]);
+        let joints_1 = push(UNSIGNED_SHORT, &[300.0, 5.0, 6.0, 7.0]);
+        let weights_1 = push(FLOAT, &[0.25, 0.05, 0.03, 0.02]);
+        let attributes = &mut gltf["meshes"][0]["primitives"][0]["attributes"];
+        attributes["JOINTS_0"] = joints_0.into();
+        attributes["WEIGHTS_0"] = weights_0.into();
+        attributes["JOINTS_1"] = joints_1.into();
+        attributes["WEIGHTS_1"] = weights_1.into();
+
+        let gltf = limit_bone_influences(gltf, &mut chunks, 4);
+        let attributes = &gltf["meshes"][0]["primitives"][0]["attributes"];
+        assert!(attributes.get("JOINTS_1").is_none());
+        assert!(attributes.get("WEIGHTS_1").is_none());
+        let joints_0 = attributes["JOINTS_0"].as_u64().unwrap();
+        assert_eq!(
+            gltf["accessors"][joints_0 as usize]["componentType"],
+            Value::from(UNSIGNED_SHORT)
+        );
+        assert_eq!(
+            read_accessor(&gltf, &chunks, joints_0),
+            Some(vec![0.0, 300.0, 1.0, 2.0])
+        );
+        let weights =
+            read_accessor(&gltf, &chunks, attributes["WEIGHTS_0"].as_u64().unwrap()).unwrap();
+        for (weight, expected) in weights.iter().zip([0.3, 0.25, 0.2, 0.1].iter()) {
+            assert!((weight - expected / 0.85).abs() < 1e-6, "{:?}", weights);
+        }
+    }
+
+    #[test]
+    fn limit_bone_influences_does_not_add_sets() {
+        let (gltf, mut chunks) = skinned_gltf();
+        let gltf = limit_bone_influences(gltf, &mut chunks, 8);
+        let attributes = &gltf["meshes"][0]["primitives"][0]["attributes"];
+        assert!(attributes.get("JOINTS_0").is_some());
+        assert!(attributes.get("JOINTS_1").is_none());
+        assert!(attributes.get("WEIGHTS_1").is_none());
+    }
+}
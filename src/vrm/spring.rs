@@ -0,0 +1,452 @@
+use super::skin::*;
+use log::info;
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Clone, Default)]
+pub struct SpringBoneOptions {
+    /// 揺れものを全て削除する
+    pub remove: bool,
+    /// 揺れもののチェーンの最大数
+    pub max_chains: Option<usize>,
+    /// チェーンの根元からの最大ジョイント数。これより先のジョイントのウェイトは親に移す
+    pub max_joints: Option<usize>,
+}
+
+impl SpringBoneOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.remove || self.max_chains.is_some() || self.max_joints.is_some()
+    }
+}
+
+// boneGroupのbones、comment以外のパラメーター
+fn bone_group_key(bone_group: &Value) -> String {
+    let mut parameters = bone_group.clone();
+    if let Some(parameters) = parameters.as_object_mut() {
+        parameters.remove("bones");
+        parameters.remove("comment");
+        if let Some(collider_groups) = parameters
+            .get_mut("colliderGroups")
+            .and_then(|v| v.as_array_mut())
+        {
+            collider_groups.sort_by_key(|v| v.as_u64());
+            collider_groups.dedup();
+        }
+    }
+    parameters.to_string()
+}
+
+// パラメーターが同じboneGroupを1つにまとめる
+fn merge_bone_groups(bone_groups: Vec<Value>) -> Vec<Value> {
+    let mut merged_groups: Vec<Value> = Vec::new();
+    let mut group_indices = BTreeMap::new();
+    for bone_group in bone_groups {
+        let key = bone_group_key(&bone_group);
+        if let Some(index) = group_indices.get(&key) {
+            let merged: &mut Value = &mut merged_groups[*index];
+            for bone in bone_group
+                .get("bones")
+                .and_then(|v| v.as_array())
+                .unwrap_or(&Vec::new())
+            {
+                if let Some(bones) = merged.get_mut("bones").and_then(|v| v.as_array_mut()) {
+                    if !bones.contains(bone) {
+                        bones.push(bone.clone());
+                    }
+                }
+            }
+            continue;
+        }
+        group_indices.insert(key, merged_groups.len());
+        merged_groups.push(bone_group);
+    }
+    merged_groups
+}
+
+// boneGroupから参照されていないcolliderGroupを削除する
+fn clean_collider_groups(secondary_animation: &mut Value) {
+    let mut referenced = BTreeSet::new();
+    for bone_group in secondary_animation
+        .get("boneGroups")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        for index in bone_group
+            .get("colliderGroups")
+            .and_then(|v| v.as_array())
+            .unwrap_or(&Vec::new())
+        {
+            if let Some(index) = index.as_u64() {
+                referenced.insert(index);
+            }
+        }
+    }
+
+    let collider_groups = secondary_animation
+        .get("colliderGroups")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let mut index_map = BTreeMap::new();
+    let mut new_collider_groups = Vec::new();
+    for (index, collider_group) in collider_groups.into_iter().enumerate() {
+        if referenced.contains(&(index as u64)) {
+            index_map.insert(index as u64, new_collider_groups.len());
+            new_collider_groups.push(collider_group);
+        }
+    }
+    secondary_animation["colliderGroups"] = new_collider_groups.into();
+
+    for bone_group in secondary_animation
+        .get_mut("boneGroups")
+        .and_then(|v| v.as_array_mut())
+        .unwrap_or(&mut Vec::new())
+    {
+        if let Some(indices) = bone_group
+            .get_mut("colliderGroups")
+            .and_then(|v| v.as_array_mut())
+        {
+            let new_indices = indices
+                .iter()
+                .filter_map(|v| v.as_u64())
+                .filter_map(|v| index_map.get(&v))
+                .map(|v| Value::from(*v))
+                .collect();
+            *indices = new_indices;
+        }
+    }
+}
+
+// チェーンの数を制限する。先に出てきたものから残す
+fn limit_chains(bone_groups: &mut Vec<Value>, max_chains: usize) {
+    let mut chain_len = 0;
+    for bone_group in bone_groups.iter_mut() {
+        if let Some(bones) = bone_group.get_mut("bones").and_then(|v| v.as_array_mut()) {
+            let len = bones.len().min(max_chains - chain_len.min(max_chains));
+            bones.truncate(len);
+            chain_len += len;
+        }
+    }
+}
+
+// 根元からmax_joints個より深いジョイントと、ウェイトの移動先
+fn deep_joints(gltf: &Value, bone_groups: &[Value], max_joints: usize) -> BTreeMap<u64, u64> {
+    let nodes = gltf
+        .get("nodes")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let children = |node_index: u64| {
+        nodes
+            .get(node_index as usize)
+            .and_then(|v| v.get("children"))
+            .and_then(|v| v.as_array())
+            .map(|v| v.iter().filter_map(|c| c.as_u64()).collect::<Vec<_>>())
+            .unwrap_or_default()
+    };
+
+    let mut node_map = BTreeMap::new();
+    for root in bone_groups
+        .iter()
+        .flat_map(|g| {
+            g.get("bones")
+                .and_then(|v| v.as_array())
+                .cloned()
+                .unwrap_or_default()
+        })
+        .filter_map(|v| v.as_u64())
+    {
+        // (ノード, 根元からの深さ, 深すぎる場合の移動先)
+        let mut stack = vec![(root, 1, None)];
+        while let Some((node_index, depth, target)) = stack.pop() {
+            if let Some(target) = target {
+                node_map.insert(node_index, target);
+            }
+            let child_target = target.or_else(|| {
+                if depth >= max_joints {
+                    Some(node_index)
+                } else {
+                    None
+                }
+            });
+            for child in children(node_index) {
+                stack.push((child, depth + 1, child_target));
+            }
+        }
+    }
+    node_map
+}
+
+/// VRMの揺れもの(secondaryAnimation)を簡略化する
+/// パラメーターが同じボーングループをまとめ、使われていないコライダーグループを削除する
+pub fn simplify_spring_bones(
+    gltf_: Value,
+    chunks: &mut Vec<Vec<u8>>,
+    options: &SpringBoneOptions,
+) -> Value {
+    let mut gltf = gltf_.clone();
+    let mut secondary_animation = if let Some(secondary_animation) = gltf
+        .pointer("/extensions/VRM/secondaryAnimation")
+        .filter(|v| v.is_object())
+    {
+        secondary_animation.clone()
+    } else {
+        return gltf;
+    };
+
+    let bone_groups = secondary_animation
+        .get("boneGroups")
+        .and_then(|v| v.as_array())
+        .cloned()
+        .unwrap_or_default();
+    let bone_group_len = bone_groups.len();
+    let mut bone_groups = if options.remove {
+        Vec::new()
+    } else {
+        merge_bone_groups(bone_groups)
+    };
+    if let Some(max_chains) = options.max_chains {
+        limit_chains(&mut bone_groups, max_chains);
+    }
+    bone_groups.retain(|bone_group| {
+        bone_group
+            .get("bones")
+            .and_then(|v| v.as_array())
+            .map(|v| !v.is_empty())
+            .unwrap_or(false)
+    });
+    info!(
+        "spring bone groups: {} -> {}",
+        bone_group_len,
+        bone_groups.len()
+    );
+
+    let node_map = options
+        .max_joints
+        .map(|max_joints| deep_joints(&gltf, &bone_groups, max_joints.max(1)))
+        .unwrap_or_default();
+    secondary_animation["boneGroups"] = bone_groups.into();
+    clean_collider_groups(&mut secondary_animation);
+    gltf["extensions"]["VRM"]["secondaryAnimation"] = secondary_animation;
+
+    if node_map.is_empty() {
+        return gltf;
+    }
+    let gltf = collapse_joints(gltf, chunks, &node_map);
+    remove_nodes(gltf, &node_map.keys().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vrm::accessor::*;
+
+    // hairは3ジョイント、skirtは2ジョイントのチェーン。頂点iはジョイントiだけに従う
+    fn spring_gltf() -> (Value, Vec<Vec<u8>>) {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "scenes": [{"nodes": [0]}],
+                "nodes": [
+                    {"name": "hips", "children": [1, 4, 6]},
+                    {"name": "hair", "children": [2]},
+                    {"name": "hair2", "children": [3]},
+                    {"name": "hair3"},
+                    {"name": "skirt", "children": [5]},
+                    {"name": "skirt2"},
+                    {"name": "body", "mesh": 0, "skin": 0}
+                ],
+                "meshes": [{"primitives": [{"attributes": {}}]}],
+                "skins": [{"joints": [0, 1, 2, 3, 4, 5]}],
+                "extensions": {"VRM": {"secondaryAnimation": {
+                    "boneGroups": [
+                        {"comment": "hair", "stiffiness": 1.0, "bones": [1], "colliderGroups": [2]},
+                        {"comment": "skirt", "stiffiness": 1.0, "bones": [4], "colliderGroups": [2, 2]},
+                        {"comment": "empty", "stiffiness": 0.5, "bones": [], "colliderGroups": [1]}
+                    ],
+                    "colliderGroups": [
+                        {"node": 0, "colliders": []},
+                        {"node": 1, "colliders": []},
+                        {"node": 4, "colliders": []}
+                    ]
+                }}}
+            }"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        let identity = [
+            1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+        ];
+        let matrices = identity
+            .iter()
+            .cycle()
+            .take(16 * 6)
+            .cloned()
+            .collect::<Vec<_>>();
+        let inverse_bind_matrices = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "MAT4",
+            &matrices,
+            None,
+        );
+        let joints = (0..6)
+            .flat_map(|i| vec![f64::from(i), 0.0, 0.0, 0.0])
+            .collect::<Vec<_>>();
+        let joints = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            UNSIGNED_BYTE,
+            false,
+            "VEC4",
+            &joints,
+            Some(ARRAY_BUFFER),
+        );
+        let weights = (0..6)
+            .flat_map(|_| vec![1.0, 0.0, 0.0, 0.0])
+            .collect::<Vec<_>>();
+        let weights = push_accessor(
+            &mut gltf,
+            &mut chunks,
+            FLOAT,
+            false,
+            "VEC4",
+            &weights,
+            Some(ARRAY_BUFFER),
+        );
+        gltf["skins"][0]["inverseBindMatrices"] = inverse_bind_matrices.into();
+        let attributes = &mut gltf["meshes"][0]["primitives"][0]["attributes"];
+        attributes["JOINTS_0"] = joints.into();
+        attributes["WEIGHTS_0"] = weights.into();
+        (gltf, chunks)
+    }
+
+    fn node_names(gltf: &Value, indices: &[Value]) -> Vec<String> {
+        indices
+            .iter()
+            .map(|i| {
+                gltf["nodes"][i.as_u64().unwrap() as usize]["name"]
+                    .as_str()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn simplify_spring_bones_merges_groups_and_reindexes_collider_groups() {
+        let (gltf, mut chunks) = spring_gltf();
+        let options = SpringBoneOptions {
+            max_chains: Some(8),
+            ..Default::default()
+        };
+        let simplified = simplify_spring_bones(gltf.clone(), &mut chunks, &options);
+
+        let secondary_animation = &simplified["extensions"]["VRM"]["secondaryAnimation"];
+        let bone_groups = secondary_animation["boneGroups"].as_array().unwrap();
+        assert_eq!(bone_groups.len(), 1);
+        assert_eq!(bone_groups[0]["comment"], Value::from("hair"));
+        assert_eq!(bone_groups[0]["bones"], Value::from(vec![1, 4]));
+        // 参照されているコライダーグループ2だけが残り、0番になる
+        assert_eq!(bone_groups[0]["colliderGroups"], Value::from(vec![0]));
+        assert_eq!(
+            secondary_animation["colliderGroups"],
+            Value::from(vec![gltf["extensions"]["VRM"]["secondaryAnimation"]
+                ["colliderGroups"][2]
+                .clone()])
+        );
+    }
+
+    #[test]
+    fn simplify_spring_bones_removes_all_groups() {
+        let (gltf, mut chunks) = spring_gltf();
+        let options = SpringBoneOptions {
+            remove: true,
+            ..Default::default()
+        };
+        let simplified = simplify_spring_bones(gltf, &mut chunks, &options);
+
+        let secondary_animation = &simplified["extensions"]["VRM"]["secondaryAnimation"];
+        assert_eq!(
+            secondary_animation["boneGroups"],
+            Value::from(Vec::<Value>::new())
+        );
+        assert_eq!(
+            secondary_animation["colliderGroups"],
+            Value::from(Vec::<Value>::new())
+        );
+    }
+
+    #[test]
+    fn limit_chains_keeps_first_chains() {
+        let mut bone_groups = vec![
+            serde_json::from_str::<Value>(r#"{"bones": [1, 2]}"#).unwrap(),
+            serde_json::from_str::<Value>(r#"{"bones": [3]}"#).unwrap(),
+            serde_json::from_str::<Value>(r#"{"bones": [4]}"#).unwrap(),
+        ];
+        limit_chains(&mut bone_groups, 3);
+        let bones = bone_groups
+            .iter()
+            .map(|g| g["bones"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bones,
+            vec![
+                Value::from(vec![1, 2]),
+                Value::from(vec![3]),
+                Value::from(Vec::<Value>::new())
+            ]
+        );
+    }
+
+    #[test]
+    fn deep_joints_maps_to_last_kept_joint() {
+        let (gltf, _) = spring_gltf();
+        let bone_groups = gltf["extensions"]["VRM"]["secondaryAnimation"]["boneGroups"]
+            .as_array()
+            .unwrap()
+            .clone();
+        let node_map = deep_joints(&gltf, &bone_groups, 1);
+        let expected = [(2, 1), (3, 1), (5, 4)].iter().cloned().collect();
+        assert_eq!(node_map, expected);
+    }
+
+    #[test]
+    fn simplify_spring_bones_collapses_weights_of_deep_joints() {
+        let (gltf, mut chunks) = spring_gltf();
+        let options = SpringBoneOptions {
+            max_joints: Some(2),
+            ..Default::default()
+        };
+        let simplified = simplify_spring_bones(gltf, &mut chunks, &options);
+
+        // hair3は削除され、そのウェイトはhair2に移る
+        let names = simplified["nodes"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|n| n["name"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert!(!names.contains(&"hair3"));
+        let skin_joints = simplified["skins"][0]["joints"].as_array().unwrap().clone();
+        let joints_accessor = simplified["meshes"][0]["primitives"][0]["attributes"]["JOINTS_0"]
+            .as_u64()
+            .unwrap();
+        let vertex_joints = read_accessor(&simplified, &chunks, joints_accessor)
+            .unwrap()
+            .chunks(4)
+            .map(|j| skin_joints[j[0] as usize].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            node_names(&simplified, &vertex_joints),
+            vec!["hips", "hair", "hair2", "hair2", "skirt", "skirt2"]
+        );
+        let bones = simplified["extensions"]["VRM"]["secondaryAnimation"]["boneGroups"][0]["bones"]
+            .as_array()
+            .unwrap()
+            .clone();
+        assert_eq!(node_names(&simplified, &bones), vec!["hair", "skirt"]);
+    }
+}
@@ -0,0 +1,595 @@
+use super::buffer::*;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat};
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static TEMP_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextureFormat {
+    Png,
+    Jpeg,
+}
+
+impl FromStr for TextureFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TextureFormat, String> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(TextureFormat::Png),
+            "jpeg" | "jpg" => Ok(TextureFormat::Jpeg),
+            _ => Err(format!("unknown texture format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TextureOptions {
+    /// テクスチャの最大幅、高さ
+    pub max_size: Option<u32>,
+    /// マテリアル名(部分一致)ごとの最大幅、高さ
+    pub max_size_overrides: Vec<(String, u32)>,
+    /// 再エンコード形式。JPEGは不透明な色のテクスチャにのみ適用する
+    pub format: Option<TextureFormat>,
+    pub jpeg_quality: u8,
+}
+
+impl Default for TextureOptions {
+    fn default() -> TextureOptions {
+        TextureOptions {
+            max_size: None,
+            max_size_overrides: Vec::new(),
+            format: None,
+            jpeg_quality: 90,
+        }
+    }
+}
+
+impl TextureOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.max_size.is_some() || !self.max_size_overrides.is_empty() || self.format.is_some()
+    }
+}
+
+// マテリアル名と、そのマテリアルが参照するテクスチャを列挙する
+fn material_texture_indices(gltf: &Value) -> Vec<(String, BTreeSet<u64>)> {
+    let mut result = Vec::new();
+    for material in gltf
+        .get("materials")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        let mut texture_indices = BTreeSet::new();
+        for key in &["baseColorTexture", "metallicRoughnessTexture"] {
+            if let Some(index) = material
+                .get("pbrMetallicRoughness")
+                .and_then(|v| v.get(*key))
+                .and_then(|v| v.get("index"))
+                .and_then(|v| v.as_u64())
+            {
+                texture_indices.insert(index);
+            }
+        }
+        for key in &["normalTexture", "occlusionTexture", "emissiveTexture"] {
+            if let Some(index) = material
+                .get(*key)
+                .and_then(|v| v.get("index"))
+                .and_then(|v| v.as_u64())
+            {
+                texture_indices.insert(index);
+            }
+        }
+        let name = material.get("name").and_then(|v| v.as_str()).unwrap_or("");
+        result.push((name.to_string(), texture_indices));
+    }
+
+    for material_properties in gltf
+        .pointer("/extensions/VRM/materialProperties")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        let texture_indices = material_properties
+            .get("textureProperties")
+            .and_then(|v| v.as_object())
+            .map(|t| t.values().filter_map(|v| v.as_u64()).collect())
+            .unwrap_or_else(BTreeSet::new);
+        let name = material_properties
+            .get("name")
+            .and_then(|v| v.as_str())
+            .unwrap_or("");
+        result.push((name.to_string(), texture_indices));
+    }
+    result
+}
+
+/// テクスチャが参照する画像。拡張による参照も含む
+pub fn texture_sources(texture: &Value) -> Vec<u64> {
+    let mut sources = Vec::new();
+    if let Some(source) = texture.get("source").and_then(|v| v.as_u64()) {
+        sources.push(source);
+    }
+    for key in &["KHR_texture_basisu", "EXT_texture_webp"] {
+        if let Some(source) = texture
+            .get("extensions")
+            .and_then(|v| v.get(*key))
+            .and_then(|v| v.get("source"))
+            .and_then(|v| v.as_u64())
+        {
+            sources.push(source);
+        }
+    }
+    sources
+}
+
+// 色ではなくデータとして読まれるglTFマテリアルのテクスチャ
+const NON_COLOR_TEXTURES: [&str; 3] = [
+    "/normalTexture/index",
+    "/occlusionTexture/index",
+    "/pbrMetallicRoughness/metallicRoughnessTexture/index",
+];
+
+// 色ではなくデータとして読まれるMToonのテクスチャ
+const NON_COLOR_MTOON_TEXTURES: [&str; 5] = [
+    "_BumpMap",
+    "_ReceiveShadowTexture",
+    "_ShadingGradeTexture",
+    "_OutlineWidthTexture",
+    "_UvAnimMaskTexture",
+];
+
+/// 法線マップやマスクなど、色ではなくデータとして使われる画像。sRGBとして扱わない
+pub fn non_color_images(gltf: &Value) -> BTreeSet<u64> {
+    let mut texture_indices = BTreeSet::new();
+    for material in gltf
+        .get("materials")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        for pointer in &NON_COLOR_TEXTURES {
+            if let Some(index) = material.pointer(pointer).and_then(|v| v.as_u64()) {
+                texture_indices.insert(index);
+            }
+        }
+    }
+    for material_properties in gltf
+        .pointer("/extensions/VRM/materialProperties")
+        .and_then(|v| v.as_array())
+        .unwrap_or(&Vec::new())
+    {
+        for key in &NON_COLOR_MTOON_TEXTURES {
+            if let Some(index) = material_properties
+                .get("textureProperties")
+                .and_then(|v| v.get(*key))
+                .and_then(|v| v.as_u64())
+            {
+                texture_indices.insert(index);
+            }
+        }
+    }
+    texture_indices
+        .iter()
+        .filter_map(|index| gltf.get("textures").and_then(|v| v.get(*index as usize)))
+        .flat_map(texture_sources)
+        .collect()
+}
+
+// 画像ごとの最大幅、高さ。複数のマテリアルから参照されている場合は大きい方を使う
+fn image_max_sizes(gltf: &Value, options: &TextureOptions) -> Vec<Option<u32>> {
+    let image_len = gltf
+        .get("images")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+    let mut overrides: Vec<Option<u32>> = vec![None; image_len];
+    for (name, texture_indices) in material_texture_indices(gltf) {
+        let max_size = if let Some((_, max_size)) = options
+            .max_size_overrides
+            .iter()
+            .find(|(pattern, _)| name.contains(pattern.as_str()))
+        {
+            *max_size
+        } else {
+            continue;
+        };
+        for texture_index in texture_indices {
+            for source in gltf
+                .get("textures")
+                .and_then(|v| v.get(texture_index as usize))
+                .map(texture_sources)
+                .unwrap_or_default()
+            {
+                if let Some(image_override) = overrides.get_mut(source as usize) {
+                    *image_override = Some(image_override.map_or(max_size, |s| s.max(max_size)));
+                }
+            }
+        }
+    }
+    overrides
+        .into_iter()
+        .map(|o| o.or(options.max_size))
+        .collect()
+}
+
+fn is_opaque(image: &DynamicImage) -> bool {
+    match image {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => true,
+        _ => image.to_rgba().pixels().all(|p| p[3] == 255),
+    }
+}
+
+fn is_gray(image: &DynamicImage) -> bool {
+    match image {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) => true,
+        _ => image
+            .to_rgba()
+            .pixels()
+            .all(|p| p[0] == p[1] && p[1] == p[2]),
+    }
+}
+
+/// 不要なチャンネルを落としてPNGにエンコードする
+pub fn encode_png(image: &DynamicImage) -> Result<Vec<u8>, image::ImageError> {
+    let reduced = match (is_opaque(image), is_gray(image)) {
+        (true, true) => DynamicImage::ImageLuma8(image.to_luma()),
+        (true, false) => DynamicImage::ImageRgb8(image.to_rgb()),
+        (false, true) => DynamicImage::ImageLumaA8(image.to_luma_alpha()),
+        (false, false) => DynamicImage::ImageRgba8(image.to_rgba()),
+    };
+    let mut bytes = Vec::new();
+    reduced.write_to(&mut bytes, ImageOutputFormat::PNG)?;
+    Ok(bytes)
+}
+
+fn encode_jpeg(image: &DynamicImage, quality: u8) -> Result<Vec<u8>, image::ImageError> {
+    let mut bytes = Vec::new();
+    DynamicImage::ImageRgb8(image.to_rgb())
+        .write_to(&mut bytes, ImageOutputFormat::JPEG(quality))?;
+    Ok(bytes)
+}
+
+// 他のユーザーが読み書きできない一時ディレクトリを作る。既にある名前は使わない
+fn create_temp_dir() -> std::io::Result<PathBuf> {
+    let mut builder = DirBuilder::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.subsec_nanos())
+        .unwrap_or(0);
+    for _ in 0..100 {
+        let dir = std::env::temp_dir().join(format!(
+            "vreducer-{}-{}-{}",
+            std::process::id(),
+            nanos,
+            TEMP_FILE_COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+        match builder.create(&dir) {
+            Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            result => return result.map(|_| dir),
+        }
+    }
+    Err(std::io::Error::new(
+        std::io::ErrorKind::AlreadyExists,
+        "failed to create a unique temporary directory",
+    ))
+}
+
+/// 一時ファイルを介して外部コマンドで画像を変換する
+pub fn convert_image_with_command<F>(
+    input: &[u8],
+    input_extension: &str,
+    output_extension: &str,
+    f: F,
+) -> Result<Vec<u8>, Box<std::error::Error>>
+where
+    F: FnOnce(&Path, &Path) -> Command,
+{
+    let dir = create_temp_dir()?;
+    let input_path = dir.join(format!("input.{}", input_extension));
+    let output_path = dir.join(format!("output.{}", output_extension));
+    let convert = || -> Result<Vec<u8>, Box<std::error::Error>> {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&input_path)?
+            .write_all(input)?;
+        let mut command = f(&input_path, &output_path);
+        let status = command.status()?;
+        if !status.success() {
+            return Err(format!("{:?} failed: {}", command, status).into());
+        }
+        Ok(std::fs::read(&output_path)?)
+    };
+    let output = convert();
+    let _ = std::fs::remove_dir_all(&dir);
+    output
+}
+
+/// テクスチャを縮小、再エンコードする
+pub fn reduce_textures(gltf_: Value, chunks: &mut Vec<Vec<u8>>, options: &TextureOptions) -> Value {
+    let mut gltf = gltf_.clone();
+    if !options.is_enabled() {
+        return gltf;
+    }
+
+    let max_sizes = image_max_sizes(&gltf, options);
+    let non_color = non_color_images(&gltf);
+    for (image_index, max_size) in max_sizes.into_iter().enumerate() {
+        let buffer_view_index = if let Some(index) = gltf["images"][image_index]
+            .get("bufferView")
+            .and_then(|v| v.as_u64())
+        {
+            index
+        } else {
+            continue;
+        };
+        if max_size.is_none() && options.format.is_none() {
+            continue;
+        }
+
+        // WebP、KTX2は拡張から参照されているので形式を変えない
+        let original_format = match gltf["images"][image_index]
+            .get("mimeType")
+            .and_then(|v| v.as_str())
+        {
+            Some("image/jpeg") => TextureFormat::Jpeg,
+            Some("image/png") | None => TextureFormat::Png,
+            Some(_) => continue,
+        };
+        let original_bytes =
+            if let Some(bytes) = buffer_view_bytes(&gltf, chunks, buffer_view_index) {
+                bytes.to_vec()
+            } else {
+                continue;
+            };
+        let original_image = match image::load_from_memory(&original_bytes) {
+            Ok(image) => image,
+            Err(e) => {
+                warn!("Failed to decode image {}: {:?}", image_index, e);
+                continue;
+            }
+        };
+
+        let (width, height) = original_image.dimensions();
+        let resized = max_size
+            .map(|max_size| width > max_size || height > max_size)
+            .unwrap_or(false);
+        let image = if resized {
+            let max_size = max_size.unwrap_or(0).max(1);
+            original_image.resize(max_size, max_size, image::FilterType::Lanczos3)
+        } else {
+            original_image
+        };
+
+        // 法線マップなどのデータテクスチャはJPEGのノイズで陰影が崩れるので、元がJPEGでなければ変えない
+        let jpeg_allowed =
+            original_format == TextureFormat::Jpeg || !non_color.contains(&(image_index as u64));
+        let format = match options.format.unwrap_or(original_format) {
+            TextureFormat::Jpeg if jpeg_allowed && is_opaque(&image) => TextureFormat::Jpeg,
+            _ => TextureFormat::Png,
+        };
+        let encoded = match format {
+            TextureFormat::Png => encode_png(&image),
+            TextureFormat::Jpeg => encode_jpeg(&image, options.jpeg_quality),
+        };
+        let bytes = match encoded {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Failed to encode image {}: {:?}", image_index, e);
+                continue;
+            }
+        };
+        if !resized && bytes.len() >= original_bytes.len() {
+            continue;
+        }
+
+        let (new_width, new_height) = image.dimensions();
+        info!(
+            "image {}: {}x{} {} bytes -> {}x{} {} bytes",
+            image_index,
+            width,
+            height,
+            original_bytes.len(),
+            new_width,
+            new_height,
+            bytes.len()
+        );
+        let new_buffer_view_index = push_buffer_view(&mut gltf, chunks, &bytes, None);
+        let image = &mut gltf["images"][image_index];
+        image["bufferView"] = new_buffer_view_index.into();
+        image["mimeType"] = match format {
+            TextureFormat::Png => "image/png",
+            TextureFormat::Jpeg => "image/jpeg",
+        }
+        .into();
+    }
+    gltf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_color_images_collects_data_textures() {
+        let gltf = serde_json::from_str::<Value>(
+            r#"{
+                "materials": [{
+                    "pbrMetallicRoughness": {
+                        "baseColorTexture": {"index": 0},
+                        "metallicRoughnessTexture": {"index": 1}
+                    },
+                    "normalTexture": {"index": 2},
+                    "occlusionTexture": {"index": 3},
+                    "emissiveTexture": {"index": 4}
+                }],
+                "textures": [
+                    {"source": 0}, {"source": 1}, {"source": 2}, {"source": 3}, {"source": 4},
+                    {"source": 5}, {"source": 6}, {"source": 7}, {"source": 8}, {"source": 9}
+                ],
+                "extensions": {"VRM": {"materialProperties": [{"textureProperties": {
+                    "_MainTex": 5,
+                    "_ShadeTexture": 5,
+                    "_BumpMap": 6,
+                    "_OutlineWidthTexture": 7,
+                    "_UvAnimMaskTexture": 8,
+                    "_SphereAdd": 9
+                }}]}}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            non_color_images(&gltf).into_iter().collect::<Vec<_>>(),
+            vec![1, 2, 3, 6, 7, 8]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn convert_image_with_command_uses_private_temp_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut temp_dir = None;
+        let output = convert_image_with_command(b"image", "png", "webp", |input, output| {
+            let dir = input.parent().unwrap().to_path_buf();
+            let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+            assert_eq!(output.parent(), Some(dir.as_path()));
+            temp_dir = Some(dir);
+            let mut command = Command::new("cp");
+            command.arg(input).arg(output);
+            command
+        })
+        .unwrap();
+        assert_eq!(output, b"image");
+        assert!(!temp_dir.unwrap().exists());
+
+        let failed =
+            convert_image_with_command(b"image", "png", "webp", |_, _| Command::new("false"));
+        assert!(failed.is_err());
+    }
+
+    // 圧縮しにくいノイズの画像。alphaがNoneなら不透明なRGB
+    fn noise_png(width: u32, height: u32, alpha: Option<u8>) -> Vec<u8> {
+        let mut seed = 1u32;
+        let mut image = image::RgbaImage::new(width, height);
+        for pixel in image.pixels_mut() {
+            for c in 0..3 {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                pixel.data[c] = (seed >> 16) as u8;
+            }
+            pixel.data[3] = alpha.unwrap_or(255);
+        }
+        let image = match alpha {
+            Some(_) => DynamicImage::ImageRgba8(image),
+            None => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).to_rgb()),
+        };
+        encode_png(&image).unwrap()
+    }
+
+    // Bodyは128x64の基本色(画像0)と法線マップ(画像1)、Faceは64x64(画像2)、Hairは半透明の64x64(画像3)を使う
+    fn textured_gltf() -> (Value, Vec<Vec<u8>>) {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "materials": [
+                    {"name": "Body", "pbrMetallicRoughness": {"baseColorTexture": {"index": 0}}, "normalTexture": {"index": 1}},
+                    {"name": "Face", "pbrMetallicRoughness": {"baseColorTexture": {"index": 2}}},
+                    {"name": "Hair", "pbrMetallicRoughness": {"baseColorTexture": {"index": 3}}}
+                ],
+                "textures": [{"source": 0}, {"source": 1}, {"source": 2}, {"source": 3}],
+                "images": []
+            }"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        for (width, height, alpha) in &[
+            (128, 64, None),
+            (128, 64, None),
+            (64, 64, None),
+            (64, 64, Some(128)),
+        ] {
+            let bytes = noise_png(*width, *height, *alpha);
+            let buffer_view = push_buffer_view(&mut gltf, &mut chunks, &bytes, None);
+            let mut image = serde_json::Map::new();
+            image.insert("bufferView".into(), buffer_view.into());
+            image.insert("mimeType".into(), "image/png".into());
+            gltf["images"].as_array_mut().unwrap().push(image.into());
+        }
+        (gltf, chunks)
+    }
+
+    // 画像ごとの(mimeType, 幅, 高さ)
+    fn image_formats(gltf: &Value, chunks: &[Vec<u8>]) -> Vec<(String, u32, u32)> {
+        gltf["images"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|image| {
+                let bytes =
+                    buffer_view_bytes(gltf, chunks, image["bufferView"].as_u64().unwrap()).unwrap();
+                let (width, height) = image::load_from_memory(bytes).unwrap().dimensions();
+                (
+                    image["mimeType"].as_str().unwrap().to_string(),
+                    width,
+                    height,
+                )
+            })
+            .collect()
+    }
+
+    fn format(mime_type: &str, width: u32, height: u32) -> (String, u32, u32) {
+        (mime_type.to_string(), width, height)
+    }
+
+    #[test]
+    fn reduce_textures_downscales_with_material_overrides() {
+        let (gltf, mut chunks) = textured_gltf();
+        let options = TextureOptions {
+            max_size: Some(32),
+            max_size_overrides: vec![("Face".to_string(), 64), ("Hair".to_string(), 16)],
+            ..Default::default()
+        };
+        let reduced = reduce_textures(gltf, &mut chunks, &options);
+        // 縦横比を保って縮小する。Faceは上限が画像より大きいのでそのまま
+        assert_eq!(
+            image_formats(&reduced, &chunks),
+            vec![
+                format("image/png", 32, 16),
+                format("image/png", 32, 16),
+                format("image/png", 64, 64),
+                format("image/png", 16, 16)
+            ]
+        );
+    }
+
+    #[test]
+    fn reduce_textures_encodes_only_opaque_color_textures_as_jpeg() {
+        let (gltf, mut chunks) = textured_gltf();
+        let options = TextureOptions {
+            format: Some(TextureFormat::Jpeg),
+            ..Default::default()
+        };
+        let reduced = reduce_textures(gltf, &mut chunks, &options);
+        assert_eq!(
+            image_formats(&reduced, &chunks),
+            vec![
+                format("image/jpeg", 128, 64),
+                format("image/png", 128, 64),
+                format("image/jpeg", 64, 64),
+                format("image/png", 64, 64)
+            ]
+        );
+    }
+}
@@ -0,0 +1,238 @@
+use super::buffer::*;
+use super::cleaner::*;
+use super::texture::*;
+use log::{info, warn};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::process::Command;
+
+#[derive(Debug, Clone)]
+pub struct WebpOptions {
+    pub lossless: bool,
+    /// 非可逆圧縮の品質
+    pub quality: u8,
+    /// 元の画像をtexture.sourceとして残す
+    pub fallback: bool,
+    /// libwebpのcwebpコマンド
+    pub cwebp: String,
+}
+
+fn is_webp(bytes: &[u8]) -> bool {
+    bytes.len() >= 12 && &bytes[0..4] == b"RIFF" && &bytes[8..12] == b"WEBP"
+}
+
+fn encode_webp(
+    bytes: &[u8],
+    mime_type: &str,
+    options: &WebpOptions,
+) -> Result<Vec<u8>, Box<std::error::Error>> {
+    let input_extension = match mime_type {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        _ => return Err(format!("unsupported image type: {}", mime_type).into()),
+    };
+    let webp =
+        convert_image_with_command(bytes, input_extension, "webp", |input_path, output_path| {
+            let mut command = Command::new(&options.cwebp);
+            command.args(&["-quiet", "-metadata", "none"]);
+            if options.lossless {
+                command.args(&["-lossless", "-exact"]);
+            } else {
+                command.args(&["-q", &options.quality.to_string()]);
+            }
+            command.arg(input_path).arg("-o").arg(output_path);
+            command
+        })?;
+    if !is_webp(&webp) {
+        return Err("not a WebP file".into());
+    }
+    Ok(webp)
+}
+
+/// テクスチャをWebPに変換し、EXT_texture_webpとして参照する
+pub fn transcode_webp(gltf_: Value, chunks: &mut Vec<Vec<u8>>, options: &WebpOptions) -> Value {
+    let mut gltf = gltf_.clone();
+    let texture_len = gltf
+        .get("textures")
+        .and_then(|v| v.as_array())
+        .map(|v| v.len())
+        .unwrap_or(0);
+
+    let mut webp_images = BTreeMap::new();
+    for texture_index in 0..texture_len {
+        let source = if let Some(source) = gltf["textures"][texture_index]
+            .get("source")
+            .and_then(|v| v.as_u64())
+        {
+            source
+        } else {
+            continue;
+        };
+
+        if !webp_images.contains_key(&source) {
+            let image = gltf
+                .get("images")
+                .and_then(|v| v.get(source as usize))
+                .cloned()
+                .unwrap_or(Value::Null);
+            let mime_type = image.get("mimeType").and_then(|v| v.as_str()).unwrap_or("");
+            let bytes = if let Some(bytes) = image
+                .get("bufferView")
+                .and_then(|v| v.as_u64())
+                .and_then(|index| buffer_view_bytes(&gltf, chunks, index))
+            {
+                bytes.to_vec()
+            } else {
+                continue;
+            };
+            let webp = match encode_webp(&bytes, mime_type, options) {
+                Ok(webp) => webp,
+                Err(e) => {
+                    warn!("Failed to encode image {} to WebP: {:?}", source, e);
+                    continue;
+                }
+            };
+            info!(
+                "image {}: {} bytes -> WebP {} bytes",
+                source,
+                bytes.len(),
+                webp.len()
+            );
+
+            let buffer_view_index = push_buffer_view(&mut gltf, chunks, &webp, None);
+            let mut webp_image = serde_json::map::Map::new();
+            webp_image.insert("bufferView".into(), buffer_view_index.into());
+            webp_image.insert("mimeType".into(), "image/webp".into());
+            if let Some(name) = image.get("name") {
+                webp_image.insert("name".into(), name.clone());
+            }
+            let images = gltf["images"].as_array_mut().unwrap();
+            images.push(webp_image.into());
+            webp_images.insert(source, images.len() - 1);
+        }
+
+        let texture = &mut gltf["textures"][texture_index];
+        texture["extensions"]["EXT_texture_webp"]["source"] = webp_images[&source].into();
+        if !options.fallback {
+            texture.as_object_mut().map(|t| t.remove("source"));
+        }
+    }
+
+    if !webp_images.is_empty() {
+        use_extension(&mut gltf, "EXT_texture_webp", !options.fallback);
+    }
+    gltf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2つのテクスチャが同じPNGを参照するglTF
+    fn png_gltf() -> (Value, Vec<Vec<u8>>) {
+        let mut gltf = serde_json::from_str::<Value>(
+            r#"{
+                "asset": {"version": "2.0"},
+                "textures": [{"source": 0}, {"source": 0}],
+                "images": [{"name": "Body", "mimeType": "image/png"}]
+            }"#,
+        )
+        .unwrap();
+        let mut chunks = Vec::new();
+        let buffer_view = push_buffer_view(&mut gltf, &mut chunks, b"\x89PNG", None);
+        gltf["images"][0]["bufferView"] = buffer_view.into();
+        (gltf, chunks)
+    }
+
+    fn webp_options(cwebp: &str, fallback: bool) -> WebpOptions {
+        WebpOptions {
+            lossless: false,
+            quality: 90,
+            fallback,
+            cwebp: cwebp.to_string(),
+        }
+    }
+
+    // -oの次の引数にWebPのヘッダーを書くcwebpの代わり
+    #[cfg(unix)]
+    fn fake_cwebp(name: &str) -> String {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path =
+            std::env::temp_dir().join(format!("vreducer-{}-{}-cwebp", std::process::id(), name));
+        std::fs::write(
+            &path,
+            "#!/bin/sh\n\
+             while [ $# -gt 0 ]; do\n\
+             if [ \"$1\" = -o ]; then printf 'RIFF\\000\\000\\000\\000WEBPVP8 ' > \"$2\"; fi\n\
+             shift\n\
+             done\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn is_webp_checks_riff_header() {
+        assert!(is_webp(b"RIFF\0\0\0\0WEBPVP8 "));
+        assert!(!is_webp(b"RIFF\0\0\0\0WAVE"));
+        assert!(!is_webp(b"\x89PNG"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transcode_webp_without_fallback() {
+        let cwebp = fake_cwebp("without-fallback");
+        let (gltf, mut chunks) = png_gltf();
+        let gltf = transcode_webp(gltf, &mut chunks, &webp_options(&cwebp, false));
+        let _ = std::fs::remove_file(&cwebp);
+
+        for texture in gltf["textures"].as_array().unwrap() {
+            assert!(texture.get("source").is_none());
+            assert_eq!(
+                texture.pointer("/extensions/EXT_texture_webp/source"),
+                Some(&Value::from(1))
+            );
+        }
+        let image = &gltf["images"][1];
+        assert_eq!(image["mimeType"], Value::from("image/webp"));
+        assert_eq!(image["name"], Value::from("Body"));
+        let bytes = buffer_view_bytes(&gltf, &chunks, image["bufferView"].as_u64().unwrap());
+        assert!(is_webp(bytes.unwrap()));
+        for key in &["extensionsUsed", "extensionsRequired"] {
+            assert_eq!(gltf[*key], Value::from(vec!["EXT_texture_webp"]), "{}", key);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn transcode_webp_with_fallback() {
+        let cwebp = fake_cwebp("with-fallback");
+        let (gltf, mut chunks) = png_gltf();
+        let gltf = transcode_webp(gltf, &mut chunks, &webp_options(&cwebp, true));
+        let _ = std::fs::remove_file(&cwebp);
+
+        assert_eq!(gltf["textures"][0]["source"], Value::from(0));
+        assert_eq!(
+            gltf.pointer("/textures/0/extensions/EXT_texture_webp/source"),
+            Some(&Value::from(1))
+        );
+        assert_eq!(
+            gltf["extensionsUsed"],
+            Value::from(vec!["EXT_texture_webp"])
+        );
+        assert!(gltf.get("extensionsRequired").is_none());
+    }
+
+    #[test]
+    fn transcode_webp_keeps_textures_when_encoding_fails() {
+        let (gltf, mut chunks) = png_gltf();
+        let transcoded = transcode_webp(
+            gltf.clone(),
+            &mut chunks,
+            &webp_options("vreducer-missing-cwebp", false),
+        );
+        assert_eq!(transcoded, gltf);
+    }
+}